@@ -0,0 +1,34 @@
+import io.github.rvm.RVM;
+
+import java.util.ArrayList;
+
+public class Arena {
+
+    public static void main(String[] args) {
+        ArrayList<String> samples = new ArrayList<>();
+        int heap1 = RVM.heapSize();
+        long total = 0;
+        for (int batch = 0; batch < 100; batch++) {
+            total += process(batch, samples);
+        }
+        int heap2 = RVM.heapSize();
+
+        RVM.print("Processed " + total + ", kept " + samples.size() + " samples, heap grew by " + (heap2 - heap1) + " bytes");
+        RVM.println();
+        RVM.print("Last sample " + samples.get(samples.size() - 1));
+        RVM.println();
+    }
+
+    //every temporary of the batch is dropped at once on return
+    @RVM.Arena
+    public static long process(int batch, ArrayList<String> samples) {
+        long sum = 0;
+        for (int i = 0; i < 1000; i++) {
+            String item = batch + ":" + i;
+            sum += item.hashCode() & 0xFF;
+        }
+        samples.add(RVM.copyOut("batch " + batch + " sum " + sum));
+        return sum;
+    }
+
+}
@@ -4,16 +4,16 @@ public class AutoFree {
 
     public static void main(String[] args) {
         RVM.logState();
-        int heap1 = RVM.heapSize();
+        int heap1 = RVM.heapUsed();
         countAllocate(100);
         RVM.logState();
-        int heap2 = RVM.heapSize();
+        int heap2 = RVM.heapUsed();
 
         countAllocateAutoFree(100);
         RVM.logState();
-        int heap3 = RVM.heapSize();
+        int heap3 = RVM.heapUsed();
 
-        RVM.print("Allocated without auto-free " + (heap2 - heap1) + " values, with auto-free - " + (heap3 - heap2) + " values");
+        RVM.print("Allocated without auto-free " + (heap2 - heap1) + " bytes, with auto-free - " + (heap3 - heap2) + " bytes");
     }
 
     public static class Vector<T> {
@@ -1,25 +1,32 @@
 package io.github.rvm;
 
+/**
+ * Cached result of {@link RVM.Mem} method, created and looked up by the VM in a hash table per method.
+ * Reference arguments are compared with {@code equals}, primitive ones by their bits.
+ */
 public class MemEntry {
     public final Object[] arguments;
     public final Object answer;
 
     public MemEntry next = null;
 
-    public MemEntry(Object[] arguments, Object answer) {
-        this.arguments = arguments;
-        this.answer = answer;
-    }
+    public final long[] primitives;
+    public final long primitiveAnswer;
 
-    public Object getAnswer(Object[] arguments) {
-        if (arguments.length != this.arguments.length) return null;
-        for (int i = 0; i < arguments.length; i++) {
-            if (!arguments[i].equals(this.arguments[i])) {
-                return next == null ? null : next.getAnswer(arguments);
-            }
-        }
-        return answer;
-    }
+    public final int hash; //of all arguments, selects bucket of method table
 
+    public final int created; //tick when result was saved
+    public long lastUsed = 0; //sequence number of the last hit in method table
+    public int hits = 0;
+    public long cost; //nanoseconds the computation took
+    public int slot; //position in eviction order of method table
 
+    private MemEntry() {
+        this.arguments = null;
+        this.answer = null;
+        this.primitives = null;
+        this.primitiveAnswer = 0;
+        this.hash = 0;
+        this.created = 0;
+    }
 }
@@ -0,0 +1,31 @@
+package io.github.rvm;
+
+/**
+ * Statistics of {@link RVM.Mem} method, created by the VM in {@link RVM#memStats()}.
+ * Counters are kept when the cache is cleared.
+ */
+public class MemStats {
+    public final String method; //pkg.Class.method
+
+    public final long hits;
+    public final long misses;
+    public final long evictions; //by maxEntries or ttl
+    public final int entries;
+
+    public final long savedNanos; //computation time of results returned from the cache
+
+    private MemStats() {
+        this.method = null;
+        this.hits = 0;
+        this.misses = 0;
+        this.evictions = 0;
+        this.entries = 0;
+        this.savedNanos = 0;
+    }
+
+    @Override
+    public String toString() {
+        return method + ": " + hits + " hits, " + misses + " misses, " + evictions + " evictions, "
+                + entries + " entries, " + savedNanos / 1000000 + " ms saved";
+    }
+}
@@ -19,31 +19,131 @@ public class RVM {
 
     native public static int tick();
 
+    /** Heap size in bytes, including free space between objects. */
     native public static int heapSize();
 
+    /** Bytes taken by objects, including garbage which is not collected yet. */
+    native public static int heapUsed();
+
+    /** Bytes available for allocation, up to the heap limit when it is set. */
+    native public static int heapFree();
+
+    /** Collects garbage. When called from a method invoked by native code, it is postponed until that code returns. */
+    native public static void gc();
+
     @Target(ElementType.METHOD)
     @Retention(RetentionPolicy.RUNTIME)
     public @interface TailRecursion {}
 
+    /**
+     * Reuses frame of the method for any call right before its return, so mutually recursive methods and
+     * trampolines run in constant stack. Calls inside try blocks and calls of {@link Mem}, {@link Arena} and
+     * native methods keep their frames.
+     */
+    @Target(ElementType.METHOD)
+    @Retention(RetentionPolicy.RUNTIME)
+    public @interface TailCall {}
+
+    /**
+     * Caches results by arguments, which are compared with {@code equals} (primitives by value).
+     * With {@code maxEntries} the cache is bounded and an entry chosen by {@code policy} is evicted when it is full.
+     * With {@code ttl} results expire after that many {@link #tick()} milliseconds.
+     * With {@code requirePure} the method is checked as {@link Pure} and is not cached if the check fails.
+     */
     @Target(ElementType.METHOD)
     @Retention(RetentionPolicy.RUNTIME)
-    public @interface Mem {}
+    public @interface Mem {
+        int maxEntries() default 0;
+
+        Eviction policy() default Eviction.LRU;
+
+        int ttl() default 0;
+
+        boolean requirePure() default false;
+    }
 
+    /**
+     * Declares that the method changes no objects it did not make, no statics, and reads no mutable statics.
+     * Its bytecode and methods it calls are checked on the first call; the annotation is ignored with a warning
+     * if the check fails. Stores at runtime are checked with {@code -Drjava.pure.check=warn|error}.
+     */
     @Target(ElementType.METHOD)
     @Retention(RetentionPolicy.RUNTIME)
-    public @interface AutoFree {}
+    public @interface Pure {}
 
-    public static Object getAnswer(MemEntry entry, Object[] arguments) {
-        return entry == null ? null : entry.getAnswer(arguments);
+    /**
+     * Names a boolean method of the same class with the same parameters (static if the annotated one is),
+     * called before each call. If it returns false, {@link IllegalArgumentException} is thrown to the caller.
+     */
+    @Target(ElementType.METHOD)
+    @Retention(RetentionPolicy.RUNTIME)
+    public @interface Requires {
+        String value();
     }
 
-    public static Object getAnswer(MemEntry entry, Object arg1) {
-        return entry == null ? null : entry.getAnswer(new Object[]{arg1});
+    /**
+     * Names a boolean method of the same class taking the parameters and then the result, if not void,
+     * called on each return with arguments the method was called with.
+     * If it returns false, {@link IllegalStateException} is thrown by the annotated method.
+     */
+    @Target(ElementType.METHOD)
+    @Retention(RetentionPolicy.RUNTIME)
+    public @interface Ensures {
+        String value();
     }
 
-    public static Object getAnswer(MemEntry entry, Object arg1, Object arg2) {
-        return entry == null ? null : entry.getAnswer(new Object[]{arg1, arg2});
+    /** Entry dropped from a full {@link Mem} cache. */
+    public enum Eviction {
+        /** least recently used */
+        LRU,
+        /** least frequently used */
+        LFU,
+        /** oldest saved */
+        TTL
     }
 
+    /** Empties caches of all {@link Mem} methods, returns number of dropped results. */
+    native public static int clearMem();
+
+    /** Empties cache of {@link Mem} method given by name or as {@code pkg.Class.method}, returns number of dropped results. */
+    native public static int clearMem(String method);
+
+    /** Statistics of all {@link Mem} methods of loaded classes. */
+    native public static MemStats[] memStats();
 
+    /** Statistics of {@link Mem} method given as in {@link #clearMem(String)}, null if there is no such method. */
+    native public static MemStats memStats(String method);
+
+    @Target(ElementType.METHOD)
+    @Retention(RetentionPolicy.RUNTIME)
+    public @interface AutoFree {}
+
+    /**
+     * Logs arguments, result or exception, elapsed ticks and stack depth of each call, indented by nesting.
+     * With {@code callees} methods called from the annotated one are logged as well.
+     */
+    @Target(ElementType.METHOD)
+    @Retention(RetentionPolicy.RUNTIME)
+    public @interface Trace {
+        boolean callees() default false;
+    }
+
+    /** Writes heap in HPROF format, e.g. for Eclipse MAT. */
+    native public static void dumpHeap(String path) throws java.io.IOException;
+
+    /** Writes objects reachable from stack and statics as Graphviz DOT graph. */
+    native public static void dumpHeapGraph(String path) throws java.io.IOException;
+
+    /**
+     * Writes Graphviz DOT graph of objects of given classes (comma separated, {@code java.util.*} for package, null for all),
+     * at most {@code depth} references from stack and statics (negative for unlimited).
+     */
+    native public static void dumpHeapGraph(String path, String classes, int depth) throws java.io.IOException;
+
+    /** Keeps object allocated in the innermost {@link Arena} (with arena objects it references) after the arena is released. */
+    native public static <T> T copyOut(T object);
+
+    @Target(ElementType.METHOD)
+    @Retention(RetentionPolicy.RUNTIME)
+    public @interface Arena {}
 }
@@ -3,6 +3,15 @@ extern crate core;
 
 pub mod vm;
 
+use crate::vm::heap_graph::{
+    HEAP_GRAPH_CLASSES_PROPERTY, HEAP_GRAPH_DEPTH_PROPERTY, HEAP_GRAPH_PATH_PROPERTY,
+};
+use crate::vm::hprof::{
+    HEAP_DUMP_ON_EXIT_PROPERTY, HEAP_DUMP_ON_OOM_PROPERTY, HEAP_DUMP_PATH_PROPERTY,
+};
+use crate::vm::interpreter::{GC_THRESHOLD_PROPERTY, HEAP_MAX_PROPERTY};
+use crate::vm::memo::MEM_STATS_PROPERTY;
+use crate::vm::trace::{TRACE_CALLEES_PROPERTY, TRACE_PROPERTY};
 use crate::vm::vm::VM;
 use simplelog::*;
 use std::fs::File;
@@ -22,6 +31,84 @@ fn main() {
         File::create("rjava.log").unwrap(),
     );
 
+    //vm reports failures with java stack trace, rust details go to the log
+    std::panic::set_hook(Box::new(|info| log::error!("{}", info)));
+
     let mut vm = VM::new(".");
-    vm.start(&args[1]);
+    //as in java: `rjava -Dkey=value -Xmx64m ClassName`
+    let mut class_name = "";
+    for arg in &args[1..] {
+        if let Some(size) = arg.strip_prefix("-Xmx") {
+            match parse_size(size) {
+                Some(size) => vm.set_property(HEAP_MAX_PROPERTY, &size.to_string()),
+                None => usage_error(&format!("Invalid maximum heap size: {}", arg)),
+            }
+            continue;
+        }
+        //-XX flags are named as in hotspot
+        match arg.as_str() {
+            "-XX:+HeapDumpOnOutOfMemoryError" => vm.set_property(HEAP_DUMP_ON_OOM_PROPERTY, "true"),
+            "-XX:+HeapDumpOnExit" => vm.set_property(HEAP_DUMP_ON_EXIT_PROPERTY, "true"),
+            "-XX:+PrintMemStats" => vm.set_property(MEM_STATS_PROPERTY, "true"),
+            "-XX:+TraceCallees" => vm.set_property(TRACE_CALLEES_PROPERTY, "true"),
+            _ => {}
+        }
+        if arg.starts_with("-XX:") {
+            let properties = [
+                ("-XX:HeapDumpPath=", HEAP_DUMP_PATH_PROPERTY),
+                ("-XX:HeapGraphPath=", HEAP_GRAPH_PATH_PROPERTY),
+                ("-XX:HeapGraphClasses=", HEAP_GRAPH_CLASSES_PROPERTY),
+                ("-XX:HeapGraphDepth=", HEAP_GRAPH_DEPTH_PROPERTY),
+                ("-XX:TraceMethods=", TRACE_PROPERTY),
+            ];
+            for (prefix, key) in properties {
+                if let Some(value) = arg.strip_prefix(prefix) {
+                    if key == HEAP_GRAPH_DEPTH_PROPERTY && value.parse::<usize>().is_err() {
+                        usage_error(&format!("Invalid heap graph depth: {}", arg));
+                    }
+                    vm.set_property(key, value);
+                }
+            }
+            continue;
+        }
+        match arg.strip_prefix("-D").and_then(|x| x.split_once('=')) {
+            Some((key, value)) => vm.set_property(key, value),
+            None => class_name = arg,
+        }
+    }
+    //sizes given by -D are in bytes
+    for key in [
+        GC_THRESHOLD_PROPERTY,
+        HEAP_MAX_PROPERTY,
+        HEAP_GRAPH_DEPTH_PROPERTY,
+    ] {
+        if let Some(value) = vm.get_property(key) {
+            if value.parse::<usize>().is_err() {
+                usage_error(&format!("Invalid {} value: {}", key, value));
+            }
+        }
+    }
+    let status = vm.start(class_name);
+    if vm.get_property(MEM_STATS_PROPERTY).as_deref() == Some("true") {
+        eprint!("{}", vm.mem_report());
+    }
+    std::process::exit(status);
+}
+
+//bytes with optional k, m or g suffix
+fn parse_size(size: &str) -> Option<usize> {
+    let (number, multiplier) = match size.chars().last().map(|x| x.to_ascii_lowercase()) {
+        Some('k') => (&size[..size.len() - 1], 1 << 10),
+        Some('m') => (&size[..size.len() - 1], 1 << 20),
+        Some('g') => (&size[..size.len() - 1], 1 << 30),
+        _ => (size, 1),
+    };
+    number.parse::<usize>().ok()?.checked_mul(multiplier)
+}
+
+//bad command line is reported before vm starts, without a panic
+fn usage_error(message: &str) -> ! {
+    eprintln!("Error: {}", message);
+    eprintln!("Usage: rjava [-Dkey=value] [-Xmx<size>] [-XX:<flag>] ClassName");
+    std::process::exit(1);
 }
@@ -0,0 +1,269 @@
+use crate::vm::classes::{ClassIdx, FieldIdx};
+use crate::vm::java_lang::{new_throwable, ILLEGAL_STATE_EXCEPTION};
+use crate::vm::memory::{HeapObject, HeapPtr};
+use crate::vm::stack::{FrameModifiers, Value};
+use crate::vm::vm::VM;
+use log::debug;
+
+/// How escapes from `@RVM.AutoFree` methods are handled: `ignore`, `promote` or `error`
+pub const AUTO_FREE_ESCAPES_PROPERTY: &str = "rjava.autofree.escapes";
+
+/// What happens to object allocated in `@RVM.AutoFree` method, when it outlives the method:
+/// is returned, stored into static field or into object allocated outside of the method.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum EscapePolicy {
+    /// Objects are freed on return anyway, escaped references are dangling
+    #[default]
+    Ignore,
+    /// Escaped objects (and everything they reference) are handed to garbage collector
+    Promote,
+    /// Same as `Promote`, but `IllegalStateException` is thrown at the escape
+    Error,
+}
+
+impl EscapePolicy {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "ignore" => Some(EscapePolicy::Ignore),
+            "promote" => Some(EscapePolicy::Promote),
+            "error" => Some(EscapePolicy::Error),
+            _ => None,
+        }
+    }
+}
+
+impl VM {
+    pub fn set_escape_policy(&self, policy: EscapePolicy) {
+        self.escape_policy.set(policy);
+        self.heap.set_escape_checks(policy != EscapePolicy::Ignore);
+    }
+
+    pub fn escape_policy(&self) -> EscapePolicy {
+        self.escape_policy.get()
+    }
+
+    //objects are allocated in region of the innermost AutoFree frame
+    pub(crate) fn enter_region(&self) {
+        if self.heap.is_escape_checked() && !self.stack.is_empty() {
+            let depth = self.stack.depth();
+            let is_auto_free = self
+                .stack
+                .top_frame()
+                .modifiers
+                .contains(FrameModifiers::AUTO_FREE);
+            self.heap.set_region(if is_auto_free { depth } else { 0 });
+        }
+    }
+
+    /// Moves returned object to the region of the caller. Returns false if escape is reported.
+    pub(crate) fn check_return_escape(&self, value: &Value) -> bool {
+        let depth = self.stack.depth();
+        let ptr = match value {
+            Value::Reference(ptr) if self.heap.is_escape_checked() && depth > 1 => *ptr,
+            _ => return true,
+        };
+        let caller_modifiers = self.stack.frame_at(depth - 1).modifiers;
+        let target = if caller_modifiers.contains(FrameModifiers::AUTO_FREE) {
+            depth - 1
+        } else {
+            0
+        };
+        let caller_name = self.frame_method_name(depth - 1);
+        match self.heap.escape(ptr, target) {
+            Some(region) => !self.report_escape(ptr, region, &format!("caller {}", caller_name)),
+            None => true,
+        }
+    }
+
+    pub(crate) fn check_static_escape(
+        &self,
+        class_idx: ClassIdx,
+        field_idx: FieldIdx,
+        value: &Value,
+    ) {
+        if let (true, Value::Reference(ptr)) = (self.heap.is_escape_checked(), value) {
+            if let Some(region) = self.heap.escape(*ptr, 0) {
+                let class = self.get_class(class_idx);
+                let sink = format!(
+                    "static field {}.{}",
+                    class.name.replace('/', "."),
+                    class.static_fields[field_idx].name
+                );
+                drop(class);
+                self.report_escape(*ptr, region, &sink);
+            }
+        }
+    }
+
+    //stores into heap are checked by heap itself
+    pub(crate) fn check_store_escape(&self) {
+        if let Some((ptr, region, target)) = self.heap.take_escape() {
+            let sink = match self.heap.get_object(target) {
+                Some(HeapObject::Array(..)) => format!("array {}", self.heap_class_name(target)),
+                _ => format!("field of {}", self.heap_class_name(target)),
+            };
+            self.report_escape(ptr, region, &sink);
+        }
+    }
+
+    /// Frees objects of returning AutoFree frame
+    pub(crate) fn release_frame_region(&self) {
+        if self.heap.is_escape_checked()
+            && self
+                .stack
+                .top_frame()
+                .modifiers
+                .contains(FrameModifiers::AUTO_FREE)
+        {
+            let freed = self.heap.release_region(self.stack.depth());
+            debug!("Auto free {} objects", freed);
+        }
+    }
+
+    /// Objects of AutoFree frame unwound by exception are left to garbage collector
+    pub(crate) fn abandon_frame_region(&self) {
+        if self.heap.is_escape_checked()
+            && self
+                .stack
+                .top_frame()
+                .modifiers
+                .contains(FrameModifiers::AUTO_FREE)
+        {
+            self.heap.abandon_region(self.stack.depth());
+        }
+    }
+
+    //returns true if exception is thrown
+    fn report_escape(&self, ptr: HeapPtr, region: usize, sink: &str) -> bool {
+        let message = format!(
+            "{} allocated in @RVM.AutoFree method {} escapes to {}",
+            self.heap_class_name(ptr),
+            self.frame_method_name(region),
+            sink
+        );
+        debug!("{}", message);
+        if self.escape_policy() != EscapePolicy::Error {
+            return false;
+        }
+        self.throw(new_throwable(self, ILLEGAL_STATE_EXCEPTION, &message));
+        true
+    }
+
+    fn frame_method_name(&self, depth: usize) -> String {
+        let (class_idx, method_idx) = self.stack.frame_at(depth).class_method_idxs;
+        let class = self.get_class(class_idx);
+        format!(
+            "{}.{}",
+            class.name.replace('/', "."),
+            class.methods[method_idx].name
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::AUTO_FREE_ESCAPES_PROPERTY;
+    use crate::vm::testing::run;
+
+    const ESCAPES: &str = r#"
+        import io.github.rvm.RVM;
+
+        public class Escapes {
+            static Object cache;
+            Object field;
+
+            @RVM.AutoFree
+            static String make(int i) {
+                return "made " + i;
+            }
+
+            @RVM.AutoFree
+            static void cache(int i) {
+                cache = "cached " + i;
+            }
+
+            @RVM.AutoFree
+            static void store(Escapes target, int i) {
+                target.field = "stored " + i;
+            }
+
+            public static void main(String[] args) {
+                Escapes target = new Escapes();
+                try {
+                    String made = make(1);
+                    System.out.println(made);
+                } catch (IllegalStateException e) {
+                    System.out.println(e.getMessage());
+                }
+                try {
+                    cache(2);
+                    System.out.println(cache);
+                } catch (IllegalStateException e) {
+                    System.out.println(e.getMessage());
+                }
+                try {
+                    store(target, 3);
+                    System.out.println(target.field);
+                } catch (IllegalStateException e) {
+                    System.out.println(e.getMessage());
+                }
+                RVM.gc();
+                System.out.println(cache + " " + target.field);
+            }
+        }
+    "#;
+
+    #[test]
+    fn promoted_escapes_survive_collection() {
+        let properties = [(AUTO_FREE_ESCAPES_PROPERTY, "promote")];
+        let (status, output) = run("escapes-promote", &[("Escapes", ESCAPES)], &properties);
+        assert_eq!(output, "made 1\ncached 2\nstored 3\ncached 2 stored 3\n");
+        assert_eq!(status, 0);
+    }
+
+    #[test]
+    fn escapes_are_reported_with_sink() {
+        let properties = [(AUTO_FREE_ESCAPES_PROPERTY, "error")];
+        let (status, output) = run("escapes-error", &[("Escapes", ESCAPES)], &properties);
+        let expected = "\
+            java.lang.String allocated in @RVM.AutoFree method Escapes.make escapes to caller Escapes.main\n\
+            java.lang.String allocated in @RVM.AutoFree method Escapes.cache escapes to static field Escapes.cache\n\
+            java.lang.String allocated in @RVM.AutoFree method Escapes.store escapes to field of Escapes\n\
+            cached 2 stored 3\n";
+        assert_eq!(output, expected);
+        assert_eq!(status, 0);
+    }
+
+    #[test]
+    fn temporaries_are_freed_on_return_with_any_policy() {
+        let source = r#"
+            import io.github.rvm.RVM;
+
+            public class Temporaries {
+                @RVM.AutoFree
+                static int count(int n) {
+                    int total = 0;
+                    for (int i = 0; i < n; i++) {
+                        Object[] items = new Object[i];
+                        total += items.length;
+                    }
+                    return total;
+                }
+
+                public static void main(String[] args) {
+                    count(0);
+                    int before = RVM.heapUsed();
+                    count(100);
+                    int after = RVM.heapUsed();
+                    System.out.println(after - before);
+                }
+            }
+        "#;
+        for policy in ["ignore", "promote", "error"] {
+            let properties = [(AUTO_FREE_ESCAPES_PROPERTY, policy)];
+            let name = format!("temporaries-{}", policy);
+            let (status, output) = run(&name, &[("Temporaries", source)], &properties);
+            assert_eq!((status, output.as_str()), (0, "0\n"), "{}", policy);
+        }
+    }
+}
@@ -1,17 +1,27 @@
 use crate::vm::classes::{
-    AccessFlags, Class, ClassIdx, ConstantPoolValue, Field, Method, MethodInClassIdx, Signature,
+    AccessFlags, Annotation, AnnotationValue, Class, ClassIdx, ConstantPoolValue, ExceptionHandler,
+    Field, Method, MethodInClassIdx, Signature,
 };
+use crate::vm::contract::Contract;
+use crate::vm::memo::MemTable;
 use crate::vm::program::Program;
 use crate::vm::stack::{Type, Value};
+use crate::vm::tail_call::tail_calls;
 use crate::vm::vm::VM;
-use class_file::attr::{Code, RuntimeVisibleAnnotations};
-use class_file::{CPEntry, ClassFile};
-use log::trace;
+use class_file::attr::{
+    self, Code, ConstantValue, ConstantValueInfo, ElementValue, LineNumberTable,
+    RuntimeVisibleAnnotations, RuntimeVisibleParameterAnnotations, SourceFile,
+};
+use class_file::{Attributes, CPEntry, ClassFile, ConstantPool};
+use log::{trace, warn};
 use std::fs::File;
 use std::io::Read;
 use std::ops::Deref;
 use std::path::Path;
 
+//access flags above are reused by rjava for annotation-driven modifiers
+const JVM_FLAGS_MASK: u16 = 0x0FFF;
+
 #[derive(Clone)]
 pub struct ClassLoader {
     base_path: String,
@@ -26,9 +36,15 @@ impl Default for ClassLoader {
 //todo: mark as internal
 impl ClassLoader {
     pub fn new(path: &str) -> Self {
-        return Self {
+        Self {
             base_path: path.to_string().clone(),
-        };
+        }
+    }
+
+    pub(crate) fn class_exists(&self, name: &str) -> bool {
+        Path::new(&self.base_path)
+            .join(name.to_owned() + ".class")
+            .exists()
     }
 
     pub fn load_class_into(&self, name: &String, vm: &VM, program: &mut Program) -> ClassIdx {
@@ -38,14 +54,14 @@ impl ClassLoader {
         let mut data = Vec::new();
         let path = Path::new(&self.base_path).join(name.to_owned() + ".class");
         File::open(&path)
-            .expect(&format!("File not found: {:?}", path))
+            .unwrap_or_else(|_| panic!("File not found: {:?}", path))
             .read_to_end(&mut data)
             .unwrap();
         //todo: NoClassDefFound
+        let data = pad_wide_constants(&data);
         let class_file = ClassFile::parse(&data).unwrap().1;
 
         //1. put constant pool to vm
-        let class_idx: ClassIdx = program.classes.len();
         let pool = &class_file.constant_pool;
         let cpidx = program.constant_pool.len();
         for entry in &class_file.constant_pool.entries {
@@ -121,14 +137,19 @@ impl ClassLoader {
                         .to_utf8()
                         .to_string();
                     let ptr = vm.new_string(&string_value);
+                    vm.heap.promote(ptr); //constants may be loaded in AutoFree method
                     ConstantPoolValue::String(Value::Reference(ptr))
                 }
                 CPEntry::Integer(ii) => ConstantPoolValue::Const(Value::Int(ii.bytes as i32)),
                 CPEntry::Float(fi) => {
                     ConstantPoolValue::Const(Value::Float(f32::from_bits(fi.bytes)))
                 }
-                CPEntry::Long(_) => ConstantPoolValue::Unsupported,
-                CPEntry::Double(_) => ConstantPoolValue::Unsupported,
+                CPEntry::Long(li) => ConstantPoolValue::Const(Value::Long(
+                    ((li.high_bytes as i64) << 32) | li.low_bytes as i64,
+                )),
+                CPEntry::Double(di) => ConstantPoolValue::Const(Value::Double(f64::from_bits(
+                    ((di.high_bytes as u64) << 32) | di.low_bytes as u64,
+                ))),
                 _ => ConstantPoolValue::Skip,
             };
             program.constant_pool.push(cpv);
@@ -141,14 +162,38 @@ impl ClassLoader {
             .to_utf8()
             .to_string();
         let super_class_idx = self.load_class_into(&super_class_name, vm, program);
+        let mut interfaces = vec![];
+        for interface in &class_file.interfaces {
+            let interface_name = pool
+                .index(pool.index(*interface).unwrap().name_index)
+                .unwrap()
+                .data
+                .to_utf8()
+                .to_string();
+            //library interfaces may be absent, they are only matched by name
+            if self.class_exists(&interface_name) {
+                self.load_class_into(&interface_name, vm, program);
+            }
+            interfaces.push(interface_name);
+        }
+        //superclass should be loaded first, so it takes index before us
+        let class_idx: ClassIdx = program.classes.len();
 
         let mut class = Class {
             name: name.clone(),
-            super_class_idx: super_class_idx,
+            super_class_idx,
             vmt: Default::default(),
             constant_pool_idx: cpidx - 1, //because start with [1]
             fields: vec![],
             methods: vec![],
+            source_file: class_file.attributes.get::<SourceFile>(pool).map(|x| {
+                pool.index(x.sourcefile_index)
+                    .unwrap()
+                    .data
+                    .to_utf8()
+                    .to_string()
+            }),
+            ..Default::default()
         };
 
         let super_class = &program.classes[class.super_class_idx];
@@ -176,20 +221,59 @@ impl ClassLoader {
                     .to_utf8()
                     .deref(),
             );
+            let flags =
+                AccessFlags::from_bits_truncate((field.access_flags & JVM_FLAGS_MASK).into());
+            let mut our_field = Field {
+                name: name.clone(),
+                flags,
+                value_type: our_type.clone(),
+                annotations: runtime_annotations(pool, &field.attributes),
+            };
+            for annotation in our_field.annotations.clone() {
+                if let Some(handler) = program.annotation_handlers.get(&annotation.name) {
+                    handler.on_field(&class, &mut our_field, &annotation);
+                }
+            }
+            if flags.contains(AccessFlags::STATIC) {
+                let findex = class.static_fields.len();
+                program
+                    .static_field_names_to_idxs
+                    .insert((class_idx, name.clone()), findex);
+
+                let initial_value = match field.attributes.get::<ConstantValue>(pool) {
+                    Some(cv) => match pool.index(cv.constantvalue_index).unwrap() {
+                        ConstantValueInfo::Integer(ii) => Value::Int(ii.bytes as i32),
+                        ConstantValueInfo::Float(fi) => Value::Float(f32::from_bits(fi.bytes)),
+                        ConstantValueInfo::Long(li) => {
+                            Value::Long(((li.high_bytes as i64) << 32) | li.low_bytes as i64)
+                        }
+                        ConstantValueInfo::Double(di) => Value::Double(f64::from_bits(
+                            ((di.high_bytes as u64) << 32) | di.low_bytes as u64,
+                        )),
+                        ConstantValueInfo::String(si) => {
+                            let string_value = pool.index(si.string_index).unwrap().data.to_utf8();
+                            let ptr = vm.new_string(&string_value);
+                            vm.heap.promote(ptr); //class may be loaded in AutoFree method or arena
+                            Value::Reference(ptr)
+                        }
+                    },
+                    None => our_type.default_value(),
+                };
+                class.static_values.push(initial_value);
+                class.static_fields.push(our_field);
+                continue;
+            }
+
             let findex = class.fields.len();
             program
                 .field_names_to_idxs
                 .insert((class_idx, name.clone()), findex);
 
-            class.fields.push(Field {
-                name: name.clone(),
-                flags: AccessFlags::empty(),
-                value_type: our_type,
-            })
+            class.fields.push(our_field)
         }
         //3. read methods, put code to vm
 
-        //todo: interfaces
+        class.interfaces = interfaces;
 
         class.vmt = super_class.vmt.clone();
 
@@ -208,45 +292,94 @@ impl ClassLoader {
                     .to_utf8()
                     .to_string(),
                 signature: parse_signature(&signature),
-                flags: AccessFlags::from_bits(method_info.access_flags).unwrap(),
+                flags: AccessFlags::from_bits_truncate(
+                    (method_info.access_flags & JVM_FLAGS_MASK).into(),
+                ),
                 code_ptr: 0,
                 max_stack: 0,
                 max_locals: 0,
-                annotation_names: vec![],
-                mem_entry_ptr: 0,
+                exception_table: vec![],
+                line_numbers: vec![],
+                annotations: vec![],
+                parameter_annotations: vec![],
+                mem_table: MemTable::default(),
+                contract: Contract::default(),
+                tail_calls: vec![],
             };
             if let Some(code) = method_info.attributes.get::<Code>(pool) {
                 method.code_ptr = program.code.len();
                 program.code.extend_from_slice(code.code);
                 method.max_locals = code.max_locals;
                 method.max_stack = code.max_stack;
+                for entry in &code.exception_table {
+                    method.exception_table.push(ExceptionHandler {
+                        start_pc: method.code_ptr + entry.start_pc as usize,
+                        end_pc: method.code_ptr + entry.end_pc as usize,
+                        handler_pc: method.code_ptr + entry.handler_pc as usize,
+                        catch_type: if entry.catch_type.index == 0 {
+                            0
+                        } else {
+                            class.constant_pool_idx + entry.catch_type.index as usize
+                        },
+                    })
+                }
+                if let Some(table) = code.attributes.get::<LineNumberTable>(pool) {
+                    method.line_numbers = table
+                        .data
+                        .iter()
+                        .map(|x| (method.code_ptr + x.start_pc as usize, x.line_number))
+                        .collect();
+                    method.line_numbers.sort_by_key(|x| x.0);
+                }
                 trace!("{} {}: {:?}", class.name, method.name, code.code)
             }
-            if let Some(annotations) = method_info
+            method.annotations = runtime_annotations(pool, &method_info.attributes);
+            if let Some(parameters) = method_info
                 .attributes
-                .get::<RuntimeVisibleAnnotations>(pool)
+                .get::<RuntimeVisibleParameterAnnotations>(pool)
             {
-                for ann in &annotations.data {
-                    let annotation_name = pool
-                        .index(ann.type_index)
-                        .unwrap()
-                        .data
-                        .to_utf8()
-                        .to_string();
-                    match annotation_name.as_str() {
-                        "Lio/github/rvm/RVM$TailRecursion;" => {
-                            method.flags.insert(AccessFlags::TAIL_RECURSION)
-                        }
-                        "Lio/github/rvm/RVM$AutoFree;" => {
-                            method.flags.insert(AccessFlags::AUTO_FREE)
-                        }
-                        "Lio/github/rvm/RVM$Mem;" => {
-                            method.flags.insert(AccessFlags::MEM);
-                            method.mem_entry_ptr = 0
-                        }
-                        _ => {}
+                method.parameter_annotations = parameters
+                    .data
+                    .iter()
+                    .map(|x| {
+                        x.annotations
+                            .iter()
+                            .map(|a| parse_annotation(pool, a))
+                            .collect()
+                    })
+                    .collect();
+            }
+            let handlers = &program.annotation_handlers;
+            for annotation in method.annotations.clone() {
+                if let Some(handler) = handlers.get(&annotation.name) {
+                    handler.on_method(&class, &mut method, &annotation);
+                }
+            }
+            for (i, annotations) in method.parameter_annotations.clone().iter().enumerate() {
+                for annotation in annotations {
+                    if let Some(handler) = handlers.get(&annotation.name) {
+                        handler.on_parameter(&class, &mut method, i, annotation);
                     }
-                    method.annotation_names.push(annotation_name);
+                }
+            }
+            method.flags |= vm.trace_flags(&class.name, &method.name);
+            if method
+                .flags
+                .intersects(AccessFlags::TAIL_RECURSION | AccessFlags::TAIL_CALL)
+            {
+                if let Some(code) = method_info.attributes.get::<Code>(pool) {
+                    let constant_pool = &program.constant_pool[class.constant_pool_idx..];
+                    method.tail_calls = tail_calls(&method, code.code, constant_pool);
+                }
+                if method.tail_calls.is_empty() {
+                    let annotation = match method.flags.contains(AccessFlags::TAIL_CALL) {
+                        true => "@RVM.TailCall",
+                        false => "@RVM.TailRecursion",
+                    };
+                    warn!(
+                        "{} of {}.{} has no call in tail position to optimize",
+                        annotation, class.name, method.name
+                    );
                 }
             }
             let midx = class.methods.len();
@@ -261,7 +394,7 @@ impl ClassLoader {
                 &method_name,
                 &method_signature,
             ) {
-                class.vmt.mapping.insert((ci, mi), (class_idx, midx));
+                class.vmt.override_method((ci, mi), (class_idx, midx));
             }
 
             program
@@ -269,35 +402,71 @@ impl ClassLoader {
                 .insert((class_idx, method_name, signature.clone()), midx);
         }
 
+        class.annotations = runtime_annotations(pool, &class_file.attributes);
+        for annotation in class.annotations.clone() {
+            if let Some(handler) = program.annotation_handlers.get(&annotation.name) {
+                handler.on_class(&mut class, &annotation);
+            }
+        }
+
         //5. create Class, put to vm
         program.classes.push(class);
         program.class_names_to_idxs.insert(name.clone(), class_idx);
-        return class_idx;
+        class_idx
+    }
+}
+
+/// Long and double constants take two entries in constant pool, but `class_file` parser
+/// counts them as one. Inserts empty utf8 entry after each of them to keep indexes in sync.
+fn pad_wide_constants(data: &[u8]) -> Vec<u8> {
+    const CP_START: usize = 10;
+    let read_u16 = |pos: usize| ((data[pos] as usize) << 8) | data[pos + 1] as usize;
+    let count = read_u16(8);
+    let mut result = data[..CP_START].to_vec();
+    let mut pos = CP_START;
+    let mut idx = 1;
+    while idx < count {
+        let tag = data[pos];
+        let size = match tag {
+            1 => 3 + read_u16(pos + 1), //utf8
+            3 | 4 => 5,                 //integer, float
+            5 | 6 => 9,                 //long, double
+            7 | 8 | 16 | 19 | 20 => 3,  //class, string, method type, module, package
+            9 | 10 | 11 | 12 | 17 | 18 => 5,
+            15 => 4, //method handle
+            _ => panic!("Unknown constant pool tag {}", tag),
+        };
+        result.extend_from_slice(&data[pos..pos + size]);
+        pos += size;
+        idx += 1;
+        if tag == 5 || tag == 6 {
+            result.extend_from_slice(&[1, 0, 0]);
+            idx += 1;
+        }
     }
+    result.extend_from_slice(&data[pos..]);
+    result
 }
 
-fn find_method(
-    program: &mut Program,
+pub(crate) fn find_method(
+    program: &Program,
     class_idx: ClassIdx,
     name: &str,
     signature: &Signature,
 ) -> Option<(ClassIdx, MethodInClassIdx)> {
-    if class_idx == 0 {
-        return None;
-    }
-
     let class = &program.classes[class_idx];
-    let mut i = 0;
-    for m in &class.methods {
+    for (i, m) in class.methods.iter().enumerate() {
         if m.signature == *signature && m.name == name {
             return Some((class_idx, i));
         }
-        i += 1;
     }
-    return find_method(program, class.super_class_idx, name, signature);
+    if class_idx == 0 {
+        return None;
+    }
+    find_method(program, class.super_class_idx, name, signature)
 }
 
-fn parse_type(ftype: &str) -> Type {
+pub(crate) fn parse_type(ftype: &str) -> Type {
     match ftype {
         "B" => Type::Byte,
         "C" => Type::Char,
@@ -308,13 +477,106 @@ fn parse_type(ftype: &str) -> Type {
         "S" => Type::Short,
         "Z" => Type::Boolean,
         "V" => Type::Void,
-        ar if ar.starts_with("[") => Type::Reference, //todo: temp
+        ar if ar.starts_with("[") => Type::Reference,
         rf if rf.starts_with("L") => Type::Reference,
         _ => panic!("'{}' not supported yet", ftype),
     }
 }
 
-fn parse_signature(signature: &String) -> Signature {
+fn runtime_annotations<'a>(
+    pool: &ConstantPool<'a>,
+    attributes: &Attributes<'a>,
+) -> Vec<Annotation> {
+    match attributes.get::<RuntimeVisibleAnnotations>(pool) {
+        Some(annotations) => annotations
+            .data
+            .iter()
+            .map(|x| parse_annotation(pool, x))
+            .collect(),
+        None => vec![],
+    }
+}
+
+fn parse_annotation(pool: &ConstantPool, annotation: &attr::Annotation) -> Annotation {
+    Annotation {
+        name: pool
+            .index(annotation.type_index)
+            .unwrap()
+            .data
+            .to_utf8()
+            .to_string(),
+        elements: annotation
+            .element_value_pairs
+            .iter()
+            .map(|x| {
+                let name = pool.index(x.element_name_index).unwrap().data.to_utf8();
+                (
+                    name.to_string(),
+                    parse_element_value(pool, &x.element_value),
+                )
+            })
+            .collect(),
+    }
+}
+
+fn parse_element_value(pool: &ConstantPool, value: &ElementValue) -> AnnotationValue {
+    match value {
+        ElementValue::Byte { const_value_index }
+        | ElementValue::Char { const_value_index }
+        | ElementValue::Short { const_value_index }
+        | ElementValue::Boolean { const_value_index }
+        | ElementValue::Integer { const_value_index } => {
+            AnnotationValue::Int(pool.index(*const_value_index).unwrap().bytes as i32)
+        }
+        ElementValue::Long { const_value_index } => {
+            let info = pool.index(*const_value_index).unwrap();
+            AnnotationValue::Long(((info.high_bytes as i64) << 32) | info.low_bytes as i64)
+        }
+        ElementValue::Float { const_value_index } => {
+            let info = pool.index(*const_value_index).unwrap();
+            AnnotationValue::Float(f32::from_bits(info.bytes))
+        }
+        ElementValue::Double { const_value_index } => {
+            let info = pool.index(*const_value_index).unwrap();
+            let bits = ((info.high_bytes as u64) << 32) | info.low_bytes as u64;
+            AnnotationValue::Double(f64::from_bits(bits))
+        }
+        ElementValue::String { const_value_index } => {
+            let text = pool.index(*const_value_index).unwrap().data.to_utf8();
+            AnnotationValue::String(text.to_string())
+        }
+        ElementValue::Enum {
+            type_name_index,
+            const_name_index,
+        } => AnnotationValue::Enum(
+            pool.index(*type_name_index)
+                .unwrap()
+                .data
+                .to_utf8()
+                .to_string(),
+            pool.index(*const_name_index)
+                .unwrap()
+                .data
+                .to_utf8()
+                .to_string(),
+        ),
+        ElementValue::Class { class_info_index } => {
+            let descriptor = pool.index(*class_info_index).unwrap().data.to_utf8();
+            AnnotationValue::Class(descriptor.to_string())
+        }
+        ElementValue::Annotation { annotation_value } => {
+            AnnotationValue::Annotation(parse_annotation(pool, annotation_value))
+        }
+        ElementValue::Array { array_value } => AnnotationValue::Array(
+            array_value
+                .iter()
+                .map(|x| parse_element_value(pool, x))
+                .collect(),
+        ),
+    }
+}
+
+pub(crate) fn parse_signature(signature: &str) -> Signature {
     trace!("{}", signature);
     let mut sign = Signature {
         return_type: Type::Void,
@@ -323,25 +585,21 @@ fn parse_signature(signature: &String) -> Signature {
     let mut i = 1; //skip '('
     let mut is_return = false;
     while i < signature.len() {
-        let mut c: &str = &signature[i..=i];
-        if c == ")" {
+        if &signature[i..=i] == ")" {
             is_return = true;
             i += 1;
             continue;
         }
-        if c == "[" {
-            //todo: temp skip
+        //arrays of any element type are references
+        let start = i;
+        while &signature[i..=i] == "[" {
             i += 1;
-            continue;
         }
-        if c == "L" || c == "[" {
-            //
-            let i2 = signature[i..].find(";").unwrap() + i;
-            c = &signature[i..=i2];
-            i = i2 + 1;
-        } else {
-            i += 1;
+        if &signature[i..=i] == "L" {
+            i += signature[i..].find(';').unwrap();
         }
+        i += 1;
+        let c = &signature[start..i];
 
         if is_return {
             sign.return_type = parse_type(c);
@@ -351,3 +609,63 @@ fn parse_signature(signature: &String) -> Signature {
     }
     sign
 }
+
+#[cfg(test)]
+mod tests {
+    use super::parse_signature;
+    use crate::vm::stack::{Type, Value};
+    use crate::vm::testing::new_vm;
+
+    #[test]
+    fn arrays_are_references() {
+        let signature = parse_signature("([J[[Ljava/lang/String;D[Z)[I");
+        assert_eq!(
+            signature.arguments,
+            vec![
+                Type::Reference,
+                Type::Reference,
+                Type::Double,
+                Type::Reference
+            ]
+        );
+        assert_eq!(signature.return_type, Type::Reference);
+        assert_eq!(parse_signature("(ILjava/lang/Object;)J").arguments.len(), 2);
+    }
+
+    #[test]
+    fn constant_value_strings_outlive_arena() {
+        let source = r#"
+            import io.github.rvm.RVM;
+
+            public class ConstantStatics {
+                @RVM.Arena
+                static int load() {
+                    return new Holder().hashCode() & 1;
+                }
+
+                public static void main(String[] args) {
+                    load();
+                    String text = "";
+                    for (int i = 0; i < 10; i++) {
+                        text = text + i;
+                    }
+                    RVM.gc();
+                    System.out.println(text);
+                }
+            }
+
+            class Holder {
+                static final String NAME = "constant";
+            }
+        "#;
+        let (mut vm, out, _) = new_vm("constant-statics", &[("ConstantStatics", source)]);
+        assert_eq!(vm.start("ConstantStatics"), 0);
+        assert_eq!(out.text(), "0123456789\n");
+        let holder = vm.get_or_load_class_idx(&"Holder".to_string());
+        let name = vm.get_class(holder).static_values[0].clone();
+        match name {
+            Value::Reference(ptr) => assert_eq!(vm.heap.get_string(ptr), "constant"),
+            other => panic!("Unexpected {:?}", other),
+        }
+    }
+}
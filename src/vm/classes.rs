@@ -1,4 +1,6 @@
-use crate::vm::memory::HeapPtr;
+use crate::vm::contract::Contract;
+use crate::vm::memo::MemTable;
+use crate::vm::memory::{HeapPtr, Relocation};
 use crate::vm::stack::{Type, Value};
 use crate::vm::vm::VM;
 use bitflags::bitflags;
@@ -9,6 +11,7 @@ pub struct Field {
     pub name: String,
     pub flags: AccessFlags,
     pub value_type: Type,
+    pub annotations: Vec<Annotation>, //runtime visible
 }
 
 #[derive(Clone, Default)]
@@ -19,25 +22,100 @@ pub struct Method {
     pub code_ptr: CodePtr, //0 means abstract or native
     pub max_locals: u16,
     pub max_stack: u16,
-    pub annotation_names: Vec<String>,
+    pub exception_table: Vec<ExceptionHandler>,
+    pub line_numbers: Vec<(CodePtr, u16)>, //start of code range and its line, ordered by code
+    pub annotations: Vec<Annotation>,      //runtime visible
+    pub parameter_annotations: Vec<Vec<Annotation>>, //by declared parameter, `this` excluded
 
     //extra data
-    pub mem_entry_ptr: HeapPtr,
+    pub mem_table: MemTable,
+    pub contract: Contract,
+    pub tail_calls: Vec<CodePtr>, //pcs right after calls which reuse the frame, sorted
+}
+
+impl Method {
+    /// Source line of instruction at `pc`, if class was compiled with debug info
+    pub fn line_number(&self, pc: CodePtr) -> Option<u16> {
+        let line = self.line_numbers.iter().take_while(|x| x.0 <= pc).last();
+        line.or(self.line_numbers.first()).map(|x| x.1)
+    }
+}
+
+/// Annotation with its element values, defaults are not included
+#[derive(Clone, Debug, PartialEq)]
+pub struct Annotation {
+    pub name: String, //descriptor, e.g. `Lio/github/rvm/RVM$Mem;`
+    pub elements: Vec<(String, AnnotationValue)>,
+}
+
+impl Annotation {
+    pub fn element(&self, name: &str) -> Option<&AnnotationValue> {
+        self.elements.iter().find(|x| x.0 == name).map(|x| &x.1)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum AnnotationValue {
+    Int(i32), //also byte, short, char and boolean
+    Long(i64),
+    Float(f32),
+    Double(f64),
+    String(String),
+    Enum(String, String), //type descriptor and constant name
+    Class(String),        //descriptor
+    Annotation(Annotation),
+    Array(Vec<AnnotationValue>),
+}
+
+/// Behaviour of annotation, registered with `VM::register_annotation`.
+/// Hooks run while class is loaded, `on_class` after its fields and methods
+pub trait AnnotationHandler {
+    fn on_class(&self, _class: &mut Class, _annotation: &Annotation) {}
+
+    fn on_field(&self, _class: &Class, _field: &mut Field, _annotation: &Annotation) {}
+
+    fn on_method(&self, _class: &Class, _method: &mut Method, _annotation: &Annotation) {}
+
+    fn on_parameter(
+        &self,
+        _class: &Class,
+        _method: &mut Method,
+        _parameter: usize,
+        _annotation: &Annotation,
+    ) {
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ExceptionHandler {
+    pub start_pc: CodePtr, //inclusive
+    pub end_pc: CodePtr,   //exclusive
+    pub handler_pc: CodePtr,
+    pub catch_type: ConstantPoolIdx, //0 means any exception (finally)
 }
 
 pub trait NativeMethod {
     fn invoke(
         &self,
         vm: &VM,
-        class_name: &String,
-        name: &String,
+        class_name: &str,
+        name: &str,
+        signature: &Signature,
         arguments: Vec<Value>,
     ) -> Option<Value>;
+
+    /// Heap objects kept by native implementation, so garbage collector does not free them
+    fn roots(&self) -> Vec<HeapPtr> {
+        vec![]
+    }
+
+    /// Updates kept objects after they were moved by heap compaction
+    fn relocate(&self, _relocation: &Relocation) {}
 }
 
 bitflags! {
     #[derive(Default)]
-    pub struct AccessFlags: u16 {
+    pub struct AccessFlags: u32 {
         const PUBLIC = 0x0001;
         const PRIVATE = 0x0002;
         const PROTECTED = 0x0004;
@@ -54,6 +132,13 @@ bitflags! {
         const TAIL_RECURSION = 0x1000;
         const MEM = 0x2000;
         const AUTO_FREE = 0x4000;
+        const ARENA = 0x8000;
+        const TAIL_CALL = 0x1_0000;
+        const TRACE = 0x2_0000;
+        const TRACE_CALLEES = 0x4_0000;
+        const PURE = 0x8_0000;
+        const REQUIRES = 0x10_0000;
+        const ENSURES = 0x20_0000;
     }
 }
 
@@ -68,6 +153,22 @@ pub struct VirtualMethodsTable {
     pub mapping: HashMap<(ClassIdx, MethodInClassIdx), (ClassIdx, MethodInClassIdx)>,
 }
 
+impl VirtualMethodsTable {
+    /// Maps `overridden` method and everything it has overridden itself to `method`
+    pub fn override_method(
+        &mut self,
+        overridden: (ClassIdx, MethodInClassIdx),
+        method: (ClassIdx, MethodInClassIdx),
+    ) {
+        for target in self.mapping.values_mut() {
+            if *target == overridden {
+                *target = method;
+            }
+        }
+        self.mapping.insert(overridden, method);
+    }
+}
+
 #[derive(Clone, Default)]
 pub struct Class {
     pub name: String,
@@ -76,6 +177,16 @@ pub struct Class {
     pub constant_pool_idx: ConstantPoolIdx,
     pub fields: Vec<Field>, //both types and default values.
     pub methods: Vec<Method>,
+
+    pub static_fields: Vec<Field>,
+    pub static_values: Vec<Value>,
+    pub initialized: bool, //<clinit> was already called
+
+    pub interfaces: Vec<String>, //names, as interfaces are not always loaded
+    pub mirror: HeapPtr,         //java/lang/Class instance, 0 until requested
+    pub array_mirror: HeapPtr,   //java/lang/Class of arrays of this class, 0 until requested
+    pub source_file: Option<String>,
+    pub annotations: Vec<Annotation>, //runtime visible
 }
 
 #[derive(Eq, PartialEq, Clone, Default)]
@@ -88,6 +199,7 @@ pub struct Signature {
 pub enum ConstantPoolValue {
     Class(ClassIdx),
     FieldRef(ClassIdx, FieldIdx),
+    StaticFieldRef(ClassIdx, FieldIdx),
     MethodRef(ClassIdx, MethodInClassIdx),
     String(Value),
     Const(Value),
@@ -0,0 +1,404 @@
+use crate::vm::classes::{
+    AccessFlags, Annotation, AnnotationHandler, AnnotationValue, Class, ClassIdx, Method,
+    MethodInClassIdx,
+};
+use crate::vm::java_lang::{new_throwable, ILLEGAL_STATE_EXCEPTION};
+use crate::vm::stack::{FrameModifiers, Type, Value};
+use crate::vm::vm::VM;
+use log::warn;
+
+const ILLEGAL_ARGUMENT_EXCEPTION: &str = "java/lang/IllegalArgumentException";
+
+/// Helpers named by `@RVM.Requires` and `@RVM.Ensures`, looked up on the first call of method
+#[derive(Clone, Default, Debug)]
+pub struct Contract {
+    pub requires: Option<ContractHelper>,
+    pub ensures: Option<ContractHelper>,
+}
+
+#[derive(Clone, Debug)]
+pub struct ContractHelper {
+    pub name: String,
+    pub method: Option<(ClassIdx, MethodInClassIdx)>, //None until found
+}
+
+/// `@RVM.Requires` or `@RVM.Ensures`, by flag
+pub(crate) struct ContractAnnotation(pub AccessFlags);
+
+impl AnnotationHandler for ContractAnnotation {
+    fn on_method(&self, class: &Class, method: &mut Method, annotation: &Annotation) {
+        let kind = annotation_name(self.0);
+        if method
+            .flags
+            .intersects(AccessFlags::ABTRACT | AccessFlags::NATIVE)
+        {
+            warn!(
+                "{} is ignored on {}.{}: method has no code",
+                kind, class.name, method.name
+            );
+            return;
+        }
+        let name = match annotation.element("value") {
+            Some(AnnotationValue::String(name)) => name.clone(),
+            _ => return,
+        };
+        let helper = Some(ContractHelper { name, method: None });
+        if self.0 == AccessFlags::REQUIRES {
+            method.contract.requires = helper;
+        } else {
+            method.contract.ensures = helper;
+        }
+        method.flags.insert(self.0);
+    }
+}
+
+fn annotation_name(flag: AccessFlags) -> &'static str {
+    if flag == AccessFlags::REQUIRES {
+        "@RVM.Requires"
+    } else {
+        "@RVM.Ensures"
+    }
+}
+
+impl VM {
+    /// Finds helpers of method on its first call, when its class is loaded completely
+    pub(crate) fn resolve_contract(&self, class_idx: ClassIdx, method_idx: MethodInClassIdx) {
+        for flag in [AccessFlags::REQUIRES, AccessFlags::ENSURES] {
+            let (name, arguments, is_static) = {
+                let method = self.get_method(class_idx, method_idx);
+                let helper = match flag {
+                    AccessFlags::REQUIRES => &method.contract.requires,
+                    _ => &method.contract.ensures,
+                };
+                let name = match helper {
+                    Some(helper) if method.flags.contains(flag) && helper.method.is_none() => {
+                        helper.name.clone()
+                    }
+                    _ => continue,
+                };
+                //postcondition gets the result after arguments
+                let mut arguments = method.signature.arguments.clone();
+                if flag == AccessFlags::ENSURES && method.signature.return_type != Type::Void {
+                    arguments.push(method.signature.return_type.clone());
+                }
+                (name, arguments, method.flags.contains(AccessFlags::STATIC))
+            };
+            let found = self.find_contract_helper(class_idx, &name, &arguments, is_static);
+            let mut method = self.get_method_mut(class_idx, method_idx);
+            let helper = match flag {
+                AccessFlags::REQUIRES => method.contract.requires.as_mut(),
+                _ => method.contract.ensures.as_mut(),
+            };
+            if let Some(helper) = helper {
+                helper.method = found;
+            }
+            if found.is_none() {
+                method.flags.remove(flag);
+                drop(method);
+                warn!(
+                    "{} is ignored on {}: no {}boolean method {} with {}",
+                    annotation_name(flag),
+                    self.method_name(class_idx, method_idx),
+                    if is_static { "static " } else { "" },
+                    name,
+                    match flag {
+                        AccessFlags::REQUIRES => "the same parameters",
+                        _ => "the same parameters and the result",
+                    }
+                );
+            }
+        }
+    }
+
+    //looks in class and its superclasses
+    fn find_contract_helper(
+        &self,
+        class_idx: ClassIdx,
+        name: &str,
+        arguments: &[Type],
+        is_static: bool,
+    ) -> Option<(ClassIdx, MethodInClassIdx)> {
+        let mut class_idx = class_idx;
+        loop {
+            let class = self.get_class(class_idx);
+            let method_idx = class.methods.iter().position(|m| {
+                m.name == name
+                    && m.signature.arguments == arguments
+                    && m.signature.return_type == Type::Boolean
+                    && m.flags.contains(AccessFlags::STATIC) == is_static
+            });
+            if let Some(method_idx) = method_idx {
+                return Some((class_idx, method_idx));
+            }
+            if class_idx == 0 {
+                return None;
+            }
+            class_idx = class.super_class_idx;
+        }
+    }
+
+    /// Runs precondition of method before its frame is pushed; `false` if it failed or threw
+    pub(crate) fn check_requires(
+        &self,
+        class_idx: ClassIdx,
+        method_idx: MethodInClassIdx,
+        method: &Method,
+        args: &[Value],
+    ) -> bool {
+        let helper = match &method.contract.requires {
+            Some(ContractHelper {
+                name,
+                method: Some(helper),
+            }) => (name, *helper),
+            _ => return true,
+        };
+        let message = || {
+            format!(
+                "@RVM.Requires {} failed for {}({})",
+                helper.0,
+                self.method_name(class_idx, method_idx),
+                self.trace_arguments(method, args)
+            )
+        };
+        self.check_contract(helper.1, args.to_vec(), ILLEGAL_ARGUMENT_EXCEPTION, message)
+    }
+
+    /// Runs postcondition of method returning from the top frame, with arguments it was called with
+    pub(crate) fn check_ensures(&self, value: Option<&Value>) -> bool {
+        let (class_idx, method_idx, args) = {
+            let frame = self.stack.top_frame();
+            if !frame.modifiers.contains(FrameModifiers::ENSURES) {
+                return true;
+            }
+            let (class_idx, method_idx) = frame.class_method_idxs;
+            let method = self.get_method(class_idx, method_idx);
+            let base = self.contract_locals(&method, frame.modifiers);
+            let count = method.signature.arguments.len()
+                + !method.flags.contains(AccessFlags::STATIC) as usize;
+            let args: Vec<_> = (base..base + count)
+                .map(|x| frame.get_local(x as u8))
+                .collect();
+            (class_idx, method_idx, args)
+        };
+        let method = self.get_method(class_idx, method_idx).clone();
+        let helper = match &method.contract.ensures {
+            Some(ContractHelper {
+                name,
+                method: Some(helper),
+            }) => (name, *helper),
+            _ => return true,
+        };
+        let message = || {
+            let result = match value {
+                Some(value) => {
+                    format!(
+                        " = {}",
+                        self.trace_value(value, &method.signature.return_type)
+                    )
+                }
+                None => String::new(),
+            };
+            format!(
+                "@RVM.Ensures {} failed for {}({}){}",
+                helper.0,
+                self.method_name(class_idx, method_idx),
+                self.trace_arguments(&method, &args),
+                result
+            )
+        };
+        let mut helper_args = args.clone();
+        helper_args.extend(value.cloned());
+        self.check_contract(helper.1, helper_args, ILLEGAL_STATE_EXCEPTION, message)
+    }
+
+    /// First of extra locals keeping arguments for postcondition, they go after `@RVM.Mem` entry
+    pub(crate) fn contract_locals(&self, method: &Method, modifiers: FrameModifiers) -> usize {
+        method.max_locals as usize + modifiers.contains(FrameModifiers::MEM_SAVE) as usize
+    }
+
+    fn check_contract(
+        &self,
+        (class_idx, method_idx): (ClassIdx, MethodInClassIdx),
+        args: Vec<Value>,
+        exception: &str,
+        message: impl Fn() -> String,
+    ) -> bool {
+        let passed = self.invoke(class_idx, method_idx, args);
+        if self.pending_exception() != 0 {
+            return false; //thrown by helper
+        }
+        if let Value::Int(0) | Value::Boolean(0) = passed {
+            self.throw(new_throwable(self, exception, &message()));
+            return false;
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::vm::testing::run;
+
+    #[test]
+    fn failed_conditions_are_thrown() {
+        let source = r#"
+            import io.github.rvm.RVM;
+
+            public class Contracts {
+                static boolean positive(int n) {
+                    return n > 0;
+                }
+
+                static boolean isRoot(int n, int r) {
+                    return r * r <= n && (r + 1) * (r + 1) > n;
+                }
+
+                @RVM.Requires("positive")
+                @RVM.Ensures("isRoot")
+                static int sqrt(int n) {
+                    int r = 0;
+                    while ((r + 1) * (r + 1) <= n) {
+                        r++;
+                    }
+                    //arguments are checked as the method got them
+                    n = 0;
+                    return r;
+                }
+
+                @RVM.Ensures("isRoot")
+                static int badSqrt(int n) {
+                    return n / 2;
+                }
+
+                public static void main(String[] args) {
+                    System.out.println(sqrt(17));
+                    try {
+                        sqrt(0);
+                    } catch (IllegalArgumentException e) {
+                        System.out.println(e.getMessage());
+                    }
+                    badSqrt(16);
+                }
+            }
+        "#;
+        let (status, output) = run("contracts", &[("Contracts", source)], &[]);
+        //postcondition fails in the method, precondition in its caller
+        assert_eq!(
+            output,
+            "4\n\
+             @RVM.Requires positive failed for Contracts.sqrt(0)\n\
+             Exception in thread \"main\" java.lang.IllegalStateException: \
+             @RVM.Ensures isRoot failed for Contracts.badSqrt(16) = 8\n\
+             \tat Contracts.badSqrt(Contracts.java:27)\n\
+             \tat Contracts.main(Contracts.java:37)\n"
+        );
+        assert_eq!(status, 1);
+    }
+
+    #[test]
+    fn helpers_are_found_on_this_and_counted_per_call() {
+        let source = r#"
+            import io.github.rvm.RVM;
+
+            public class Accounts {
+                static int requires;
+                static int ensures;
+
+                static class Base {
+                    int balance;
+
+                    boolean canTake(int amount) {
+                        requires++;
+                        if (amount < 0) {
+                            throw new ArithmeticException("negative " + amount);
+                        }
+                        return amount <= balance;
+                    }
+
+                    boolean notNegative(int amount) {
+                        ensures++;
+                        return balance >= 0;
+                    }
+                }
+
+                static class Account extends Base {
+                    @RVM.Requires("canTake")
+                    @RVM.Ensures("notNegative")
+                    void take(int amount) {
+                        balance -= amount;
+                    }
+                }
+
+                static boolean counted(int n) {
+                    requires++;
+                    return true;
+                }
+
+                static boolean countedResult(int n, int result) {
+                    ensures++;
+                    return true;
+                }
+
+                @RVM.Mem
+                @RVM.Requires("counted")
+                @RVM.Ensures("countedResult")
+                static int cached(int n) {
+                    return n * 2;
+                }
+
+                @RVM.TailRecursion
+                @RVM.Requires("counted")
+                static int down(int n) {
+                    return n == 0 ? 0 : down(n - 1);
+                }
+
+                @RVM.Requires("missing")
+                static int unchecked(int n) {
+                    return n;
+                }
+
+                static void report(String what) {
+                    System.out.println(what + " " + requires + " " + ensures);
+                    requires = 0;
+                    ensures = 0;
+                }
+
+                public static void main(String[] args) {
+                    Account account = new Account();
+                    account.balance = 10;
+                    account.take(4);
+                    try {
+                        account.take(7);
+                    } catch (IllegalArgumentException e) {
+                        System.out.println("rejected");
+                    }
+                    try {
+                        account.take(-1);
+                    } catch (ArithmeticException e) {
+                        System.out.println(e.getMessage());
+                    }
+                    System.out.println(account.balance);
+                    report("account");
+                    cached(3);
+                    cached(3);
+                    report("cached");
+                    down(3);
+                    report("down");
+                    System.out.println(unchecked(5));
+                    report("unchecked");
+                }
+            }
+        "#;
+        let (status, output) = run("contracts-this", &[("Accounts", source)], &[]);
+        //preconditions run on cache hits and each tail recursive call, postconditions with the body
+        assert_eq!(
+            output,
+            "rejected\nnegative -1\n6\n\
+             account 3 1\n\
+             cached 2 1\n\
+             down 4 0\n\
+             5\nunchecked 0 0\n"
+        );
+        assert_eq!(status, 0);
+    }
+}
@@ -0,0 +1,239 @@
+use crate::vm::classes::ConstantPoolValue;
+use crate::vm::memory::{HeapPtr, Relocation};
+use crate::vm::stack::Value;
+use crate::vm::vm::VM;
+use log::debug;
+
+impl VM {
+    /// Collects garbage and compacts heap right away, returns amount of freed bytes.
+    /// Pointers kept only in rust variables are neither roots nor relocated,
+    /// so natives should use `HeapMemory::request_gc` instead.
+    pub fn gc(&self) -> usize {
+        self.collect_garbage(true)
+    }
+
+    //called by interpreter when no native is in progress. heap is compacted on explicit request or when fragmented
+    pub(crate) fn gc_safepoint(&self) {
+        if self.heap.needs_gc() {
+            self.collect_garbage(self.heap.is_gc_requested());
+        }
+    }
+
+    pub(crate) fn collect_garbage(&self, compact: bool) -> usize {
+        let roots = self.gc_roots();
+        let freed = self.heap.collect(&roots);
+        debug!("GC freed {} bytes, {} roots", freed, roots.len());
+        //arena is released by truncating heap, so objects could not be moved under it
+        if (compact || self.heap.is_fragmented()) && !self.heap.in_arena() {
+            let relocation = self.heap.compact();
+            self.relocate_roots(&relocation);
+            debug!("Heap compacted to {} bytes", self.heap.size());
+        }
+        freed
+    }
+
+    pub(crate) fn relocate_roots(&self, relocation: &Relocation) {
+        self.stack.relocate(relocation);
+        self.relocate_pending_exception(relocation);
+        {
+            let mut program = self.program.borrow_mut();
+            for class in &mut program.classes {
+                class
+                    .static_values
+                    .iter_mut()
+                    .for_each(|x| relocation.relocate(x));
+                class.mirror = relocation.forward(class.mirror);
+                class.array_mirror = relocation.forward(class.array_mirror);
+                for method in &mut class.methods {
+                    method.mem_table.relocate(relocation);
+                }
+            }
+            for value in &mut program.constant_pool {
+                match value {
+                    ConstantPoolValue::String(value) | ConstantPoolValue::Const(value) => {
+                        relocation.relocate(value)
+                    }
+                    _ => {}
+                }
+            }
+        }
+        for native in &self.native_methods {
+            native.relocate(relocation);
+        }
+    }
+
+    fn gc_roots(&self) -> Vec<HeapPtr> {
+        let mut roots = self.vm_roots();
+        let mut add = |value: &Value| {
+            if let Value::Reference(ptr) = value {
+                roots.push(*ptr)
+            }
+        };
+        for frame in self.stack.inspect() {
+            frame.inspect_stack().iter().for_each(&mut add);
+            frame.inspect_locals().iter().for_each(&mut add);
+            //freed by AutoFree on return, so should not be reused before
+            for ptr in frame.get_instantiated() {
+                add(&Value::Reference(ptr));
+            }
+        }
+        roots
+    }
+
+    /// Roots outside of java stack: statics, constants, caches of natives and pending exception
+    pub(crate) fn vm_roots(&self) -> Vec<HeapPtr> {
+        let mut roots = vec![self.pending_exception()];
+        let mut add = |value: &Value| {
+            if let Value::Reference(ptr) = value {
+                roots.push(*ptr)
+            }
+        };
+        {
+            let program = self.program.borrow();
+            for class in &program.classes {
+                class.static_values.iter().for_each(&mut add);
+                add(&Value::Reference(class.mirror));
+                add(&Value::Reference(class.array_mirror));
+                for method in &class.methods {
+                    add(&Value::Reference(method.mem_table.buckets));
+                    add(&Value::Reference(method.mem_table.order));
+                }
+            }
+            for value in &program.constant_pool {
+                match value {
+                    ConstantPoolValue::String(value) | ConstantPoolValue::Const(value) => {
+                        add(value)
+                    }
+                    _ => {}
+                }
+            }
+        }
+        for native in &self.native_methods {
+            roots.extend(native.roots());
+        }
+        roots
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::vm::interpreter::HEAP_MAX_PROPERTY;
+    use crate::vm::java_lang::STRING_CLASS_IDX;
+    use crate::vm::memory::{HeapObject, HeapPtr};
+    use crate::vm::stack::Value;
+    use crate::vm::testing::run;
+    use crate::vm::vm::VM;
+    use std::convert::TryInto;
+
+    fn strings(vm: &VM) -> Vec<String> {
+        let objects = vm.heap.objects().into_iter();
+        objects
+            .filter(|ptr| matches!(vm.heap.get_object(*ptr), Some(HeapObject::String)))
+            .map(|ptr| vm.heap.get_string(ptr))
+            .collect()
+    }
+
+    #[test]
+    fn stack_and_pending_exception_are_roots() {
+        let vm = VM::new(".");
+        vm.new_string("garbage");
+        let kept = vm.new_string("kept");
+        vm.stack
+            .push_frame(1, 1)
+            .set_local(0, Value::Reference(kept));
+        vm.throw(vm.new_string("pending"));
+        assert!(vm.gc() > 0);
+        let strings = strings(&vm);
+        assert!(strings.contains(&"kept".to_string()), "{:?}", strings);
+        assert!(strings.contains(&"pending".to_string()), "{:?}", strings);
+        assert!(!strings.contains(&"garbage".to_string()), "{:?}", strings);
+    }
+
+    #[test]
+    fn compaction_forwards_roots_and_references() {
+        let vm = VM::new(".");
+        let garbage = vm.new_string("garbage");
+        let array = vm.heap.new_object_array(STRING_CLASS_IDX, 2);
+        let element = vm.new_string("element");
+        vm.heap
+            .set_array_element(array, 1, Value::Reference(element));
+        vm.stack
+            .push_frame(1, 1)
+            .set_local(0, Value::Reference(array));
+        vm.throw(vm.new_string("pending"));
+        vm.gc();
+        let moved: HeapPtr = vm.stack.top_frame().get_local(0).try_into().unwrap();
+        assert_eq!(moved, garbage);
+        let element: HeapPtr = vm.heap.get_array_element(moved, 1).try_into().unwrap();
+        assert_eq!(vm.heap.get_string(element), "element");
+        assert_eq!(vm.heap.get_string(vm.pending_exception()), "pending");
+        assert_eq!(vm.heap.used_space(), vm.heap.size());
+    }
+
+    #[test]
+    fn statics_and_memo_tables_are_roots() {
+        let source = r#"
+            import io.github.rvm.RVM;
+            import java.util.ArrayList;
+
+            public class Roots {
+                static ArrayList<String> kept = new ArrayList<>();
+
+                @RVM.Mem
+                static String name(Integer id) {
+                    return "name " + id;
+                }
+
+                public static void main(String[] args) {
+                    String first = name(1);
+                    for (int i = 0; i < 100; i++) {
+                        String garbage = "garbage " + i;
+                        if (i % 10 == 0) {
+                            kept.add(garbage);
+                        }
+                    }
+                    first = null;
+                    int before = RVM.heapUsed();
+                    RVM.gc();
+                    System.out.println(RVM.heapUsed() < before);
+                    System.out.println(kept);
+                    System.out.println(name(1) == name(1));
+                    System.out.println(name(1));
+                }
+            }
+        "#;
+        let (status, output) = run("roots", &[("Roots", source)], &[]);
+        let expected = "true\n[garbage 0, garbage 10, garbage 20, garbage 30, garbage 40, \
+            garbage 50, garbage 60, garbage 70, garbage 80, garbage 90]\ntrue\nname 1\n";
+        assert_eq!(output, expected);
+        assert_eq!(status, 0);
+    }
+
+    #[test]
+    fn native_allocations_respect_heap_limit() {
+        let source = r#"
+            import java.util.ArrayList;
+            public class NativeOom {
+                public static void main(String[] args) {
+                    ArrayList<Object> list = new ArrayList<>();
+                    try {
+                        while (true) {
+                            list.add(list);
+                        }
+                    } catch (OutOfMemoryError e) {
+                        list = null;
+                        System.out.println("caught");
+                    }
+                    System.out.println(new ArrayList<Object>(1000).size());
+                }
+            }
+        "#;
+        let (status, output) = run(
+            "native-oom",
+            &[("NativeOom", source)],
+            &[(HEAP_MAX_PROPERTY, "1048576")],
+        );
+        assert_eq!(output, "caught\n0\n");
+        assert_eq!(status, 0);
+    }
+}
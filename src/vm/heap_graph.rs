@@ -0,0 +1,467 @@
+use crate::vm::java_lang::cut;
+use crate::vm::memory::{HeapObject, HeapPtr};
+use crate::vm::stack::{FrameModifiers, Type, Value};
+use crate::vm::vm::VM;
+use log::info;
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt::Write;
+
+/// Path of DOT file written on exit, see `GraphFilter` for other `rjava.heap.graph.*` properties
+pub const HEAP_GRAPH_PATH_PROPERTY: &str = "rjava.heap.graph.path";
+/// Comma separated class names, `java.util.*` matches package
+pub const HEAP_GRAPH_CLASSES_PROPERTY: &str = "rjava.heap.graph.classes";
+/// Maximum distance from roots
+pub const HEAP_GRAPH_DEPTH_PROPERTY: &str = "rjava.heap.graph.depth";
+
+//named references going out of node
+type References = Vec<(String, HeapPtr)>;
+
+//longer strings and arrays are cut in labels
+const MAX_TEXT: usize = 32;
+
+/// Which objects are drawn. Hidden objects are still walked, so objects behind them are reachable
+#[derive(Default, Clone, Debug)]
+pub struct GraphFilter {
+    pub classes: Vec<String>, //empty shows all classes
+    pub depth: Option<usize>, //roots are at depth 0
+}
+
+impl GraphFilter {
+    /// Parses comma separated class names, with dots or slashes
+    pub fn new(classes: &str, depth: Option<usize>) -> Self {
+        Self {
+            classes: classes
+                .split(',')
+                .map(|x| x.trim().replace('/', "."))
+                .filter(|x| !x.is_empty())
+                .collect(),
+            depth,
+        }
+    }
+
+    fn shows(&self, class_name: &str) -> bool {
+        self.classes.is_empty()
+            || self.classes.iter().any(|x| match x.strip_suffix('*') {
+                Some(prefix) => class_name.starts_with(prefix),
+                None => class_name == x,
+            })
+    }
+}
+
+impl VM {
+    /// Writes objects reachable from stack, statics and memo entries as Graphviz DOT graph
+    pub fn dump_heap_graph(&self, path: &str, filter: &GraphFilter) -> std::io::Result<()> {
+        std::fs::write(path, self.heap_graph(filter))?;
+        info!("Heap graph written to {}", path);
+        Ok(())
+    }
+
+    //graph requested by properties, written when program ends
+    pub(crate) fn dump_heap_graph_on_exit(&self) {
+        if let Some(path) = self.get_property(HEAP_GRAPH_PATH_PROPERTY) {
+            let classes = self
+                .get_property(HEAP_GRAPH_CLASSES_PROPERTY)
+                .unwrap_or_default();
+            //bad depth should not lose the graph, so it is drawn without limit then
+            let depth = self.get_property(HEAP_GRAPH_DEPTH_PROPERTY);
+            let depth = depth.and_then(|x| {
+                x.parse()
+                    .map_err(|_| log::error!("Invalid {} value {}", HEAP_GRAPH_DEPTH_PROPERTY, x))
+                    .ok()
+            });
+            if let Err(error) = self.dump_heap_graph(&path, &GraphFilter::new(&classes, depth)) {
+                log::error!("Cannot write heap graph to {}: {}", path, error);
+            }
+        }
+    }
+
+    /// Objects are walked breadth first, hidden ones are skipped with dashed edges labelled by path
+    pub fn heap_graph(&self, filter: &GraphFilter) -> String {
+        let max_depth = filter.depth.unwrap_or(usize::MAX);
+        //nearest shown node on the way from roots, with path from it
+        let mut anchors: HashMap<HeapPtr, (String, String)> = HashMap::new();
+        let mut depths: HashMap<HeapPtr, usize> = HashMap::new();
+        let mut pending = VecDeque::new();
+        let mut edges: Vec<(String, HeapPtr, String, bool)> = vec![];
+        let mut nodes = String::new();
+        let mut roots = vec![];
+
+        for (root, label, references) in self.graph_roots() {
+            if max_depth == 0 {
+                break;
+            }
+            for (name, ptr) in references {
+                if self.is_shown(ptr, filter) {
+                    edges.push((root.clone(), ptr, name.clone(), false));
+                }
+                if let Entry::Vacant(entry) = depths.entry(ptr) {
+                    entry.insert(1);
+                    anchors.insert(ptr, (root.clone(), name));
+                    pending.push_back(ptr);
+                }
+            }
+            roots.push((root, label));
+        }
+
+        while let Some(ptr) = pending.pop_front() {
+            let depth = depths[&ptr];
+            let (label, references) = self.graph_node(ptr);
+            let shown = self.is_shown(ptr, filter);
+            if shown {
+                let _ = writeln!(nodes, "  \"@{}\" [label=\"{}\"];", ptr, escape(&label));
+            }
+            if depth >= max_depth {
+                continue;
+            }
+            for (name, reference) in references {
+                let (from, path) = match shown {
+                    true => (format!("@{}", ptr), name),
+                    false => {
+                        let (from, path) = &anchors[&ptr];
+                        (
+                            from.clone(),
+                            format!("{}.{}", path, name).replace(".[", "["),
+                        )
+                    }
+                };
+                if self.is_shown(reference, filter) {
+                    edges.push((from.clone(), reference, path.clone(), !shown));
+                }
+                if let Entry::Vacant(entry) = depths.entry(reference) {
+                    entry.insert(depth + 1);
+                    anchors.insert(reference, (from, path));
+                    pending.push_back(reference);
+                }
+            }
+        }
+
+        let mut dot = String::from(
+            "digraph heap {\n  rankdir=LR;\n  node [shape=box, fontname=\"monospace\"];\n",
+        );
+        for (root, label) in roots {
+            if edges.iter().any(|x| x.0 == root) {
+                let _ = writeln!(
+                    dot,
+                    "  \"{}\" [label=\"{}\", shape=ellipse];",
+                    root,
+                    escape(&label)
+                );
+            }
+        }
+        dot += &nodes;
+        let mut drawn = HashSet::new();
+        for (from, to, name, hidden) in edges {
+            if drawn.insert((from.clone(), to, name.clone())) {
+                let style = if hidden { ", style=dashed" } else { "" };
+                let _ = writeln!(
+                    dot,
+                    "  \"{}\" -> \"@{}\" [label=\"{}\"{}];",
+                    from,
+                    to,
+                    escape(&name),
+                    style
+                );
+            }
+        }
+        dot += "}\n";
+        dot
+    }
+
+    fn is_shown(&self, ptr: HeapPtr, filter: &GraphFilter) -> bool {
+        filter.shows(&self.graph_class_name(ptr))
+    }
+
+    fn graph_class_name(&self, ptr: HeapPtr) -> String {
+        match self.heap.get_object(ptr) {
+            Some(HeapObject::Array(element_type, class_idx)) => match element_type {
+                Type::Reference => {
+                    format!("{}[]", self.get_class(class_idx).name.replace('/', "."))
+                }
+                element_type => format!("{}[]", format!("{:?}", element_type).to_lowercase()),
+            },
+            _ => self.heap_class_name(ptr),
+        }
+    }
+
+    //roots as (node id, label, named references)
+    fn graph_roots(&self) -> Vec<(String, String, References)> {
+        let mut roots = vec![];
+        let frames = self.stack.inspect();
+        for (i, frame) in frames.iter().enumerate() {
+            if frame.modifiers.contains(FrameModifiers::NATIVE_BRIDGE) {
+                continue;
+            }
+            let (class_idx, method_idx) = frame.class_method_idxs;
+            let class = self.get_class(class_idx);
+            let mut label = format!(
+                "{}.{}",
+                class.name.replace('/', "."),
+                class.methods[method_idx].name
+            );
+            if frame.modifiers.contains(FrameModifiers::AUTO_FREE) {
+                label += " @AutoFree";
+            }
+            if frame.modifiers.contains(FrameModifiers::ARENA) {
+                label += " @Arena";
+            }
+            let locals = frame.inspect_locals().into_iter().enumerate();
+            let stack = frame.inspect_stack().into_iter().enumerate();
+            let references = locals
+                .map(|(i, x)| (format!("local {}", i), x))
+                .chain(stack.map(|(i, x)| (format!("stack {}", i), x)))
+                .filter_map(|(name, value)| match value {
+                    Value::Reference(ptr) if ptr != 0 => Some((name, ptr)),
+                    _ => None,
+                })
+                .collect();
+            roots.push((format!("frame {}", i), label, references));
+        }
+        let program = self.program.borrow();
+        for (class_idx, class) in program.classes.iter().enumerate() {
+            let name = class.name.replace('/', ".");
+            let statics = class
+                .static_fields
+                .iter()
+                .zip(&class.static_values)
+                .filter_map(|(field, value)| match value {
+                    Value::Reference(ptr) if *ptr != 0 => Some((field.name.clone(), *ptr)),
+                    _ => None,
+                })
+                .collect();
+            roots.push((
+                format!("statics {}", class_idx),
+                format!("{} statics", name),
+                statics,
+            ));
+            for method in &class.methods {
+                if method.mem_table.buckets != 0 {
+                    roots.push((
+                        format!("memo {}.{}", class_idx, method.name),
+                        format!("{}.{} memo", name, method.name),
+                        vec![
+                            ("table".to_string(), method.mem_table.buckets),
+                            ("order".to_string(), method.mem_table.order),
+                        ],
+                    ));
+                }
+            }
+        }
+        drop(program);
+        let mut vm_references: References = self
+            .native_methods
+            .iter()
+            .flat_map(|x| x.roots())
+            .map(|ptr| ("native".to_string(), ptr))
+            .collect();
+        if self.pending_exception() != 0 {
+            vm_references.push(("exception".to_string(), self.pending_exception()));
+        }
+        roots.push(("vm".to_string(), "vm".to_string(), vm_references));
+        roots
+    }
+
+    //label and references of object, array or string
+    fn graph_node(&self, ptr: HeapPtr) -> (String, References) {
+        let heap = &self.heap;
+        let mut label = format!("{}@{}", self.graph_class_name(ptr), ptr);
+        let region = heap.region_of(ptr);
+        if region != 0 {
+            let _ = write!(label, " (AutoFree region {})", region);
+        }
+        let mut references = vec![];
+        match heap.get_object(ptr) {
+            Some(HeapObject::Object(class_idx)) => {
+                let fields: Vec<(String, Type)> = self
+                    .get_class(class_idx)
+                    .fields
+                    .iter()
+                    .map(|x| (x.name.clone(), x.value_type.clone()))
+                    .collect();
+                for (i, (name, value_type)) in fields.into_iter().enumerate() {
+                    match (value_type, heap.get_field(ptr, i)) {
+                        (Type::Reference, Value::Reference(0)) => {
+                            let _ = write!(label, "\n{} = null", name);
+                        }
+                        (Type::Reference, Value::Reference(reference)) => {
+                            references.push((name, reference))
+                        }
+                        (_, value) => {
+                            let _ = write!(label, "\n{} = {}", name, show(&value));
+                        }
+                    }
+                }
+            }
+            Some(HeapObject::String) => {
+                let _ = write!(label, "\n\"{}\"", cut(&heap.get_string(ptr), MAX_TEXT));
+            }
+            Some(HeapObject::Array(element_type, _)) => {
+                let length = heap.array_length(ptr);
+                let _ = write!(label, "\nlength = {}", length);
+                for i in 0..length {
+                    match heap.get_array_element(ptr, i) {
+                        Value::Reference(0) => {}
+                        Value::Reference(reference) => {
+                            references.push((format!("[{}]", i), reference))
+                        }
+                        value if i < MAX_TEXT && element_type != Type::Reference => {
+                            let _ = write!(label, "\n[{}] = {}", i, show(&value));
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            None => label += "\nfreed",
+        }
+        (label, references)
+    }
+}
+
+fn show(value: &Value) -> String {
+    match value {
+        Value::Byte(x) => x.to_string(),
+        Value::Short(x) => x.to_string(),
+        Value::Int(x) => x.to_string(),
+        Value::Long(x) => x.to_string(),
+        Value::Char(x) => format!("'{}'", char::from_u32(*x as u32).unwrap_or('?')),
+        Value::Float(x) => x.to_string(),
+        Value::Double(x) => x.to_string(),
+        Value::Boolean(x) => (*x != 0).to_string(),
+        other => format!("{:?}", other),
+    }
+}
+
+//dot string literal, new lines become left justified line breaks
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\l")
+        + if text.contains('\n') { "\\l" } else { "" }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{GraphFilter, HEAP_GRAPH_DEPTH_PROPERTY, HEAP_GRAPH_PATH_PROPERTY};
+    use crate::vm::testing::{new_vm, run};
+
+    const CHAIN: &str = r#"
+        public class GraphChain {
+            static GraphChain head = new GraphChain("first", new Link(new GraphChain("second", null)));
+
+            String name;
+            Link next;
+            int size = 7;
+
+            GraphChain(String name, Link next) {
+                this.name = name;
+                this.next = next;
+            }
+
+            static class Link {
+                GraphChain target;
+
+                Link(GraphChain target) {
+                    this.target = target;
+                }
+            }
+
+            public static void main(String[] args) {}
+        }
+    "#;
+
+    //graph with heap addresses removed from node ids and labels
+    fn graph(test_name: &str, filter: &GraphFilter) -> String {
+        let (mut vm, _, _) = new_vm(test_name, &[("GraphChain", CHAIN)]);
+        assert_eq!(vm.start("GraphChain"), 0);
+        let mut graph = String::new();
+        let mut address = false;
+        for c in vm.heap_graph(filter).chars() {
+            address = c == '@' || address && c.is_ascii_digit();
+            if !address || c == '@' {
+                graph.push(c);
+            }
+        }
+        graph
+    }
+
+    #[test]
+    fn hidden_objects_are_skipped_with_dashed_edges() {
+        let graph = graph("graph-hidden", &GraphFilter::new("GraphChain", None));
+        assert!(
+            graph.contains("\"@\" [label=\"GraphChain@\\lsize = 7\\l\"];"),
+            "{}",
+            graph
+        );
+        assert!(
+            graph.contains("\"@\" [label=\"GraphChain@\\lnext = null\\lsize = 7\\l\"];"),
+            "{}",
+            graph
+        );
+        assert!(graph.contains("\" -> \"@\" [label=\"head\"];"), "{}", graph);
+        assert!(
+            graph.contains("\"@\" -> \"@\" [label=\"next.target\", style=dashed];"),
+            "{}",
+            graph
+        );
+        assert!(!graph.contains("Link"), "{}", graph);
+        assert!(!graph.contains("java.lang.String"), "{}", graph);
+    }
+
+    #[test]
+    fn depth_and_package_filter_limit_graph() {
+        let graph = graph(
+            "graph-filter",
+            &GraphFilter::new("GraphChain, java.lang.*", Some(2)),
+        );
+        assert!(
+            graph.contains("\"@\" [label=\"java.lang.String@\\l\\\"first\\\"\\l\"];"),
+            "{}",
+            graph
+        );
+        assert!(
+            graph.contains("\"@\" -> \"@\" [label=\"name\"];"),
+            "{}",
+            graph
+        );
+        //second node is behind hidden link at depth 2
+        assert!(!graph.contains("second"), "{}", graph);
+        assert!(!graph.contains("next = null"), "{}", graph);
+        assert!(!graph.contains("dashed"), "{}", graph);
+    }
+
+    #[test]
+    fn filter_parses_class_names() {
+        let filter = GraphFilter::new(" java/util/*, GraphChain ,", Some(1));
+        assert_eq!(filter.classes, ["java.util.*", "GraphChain"]);
+        assert!(filter.shows("java.util.ArrayList"));
+        assert!(filter.shows("GraphChain"));
+        assert!(!filter.shows("GraphChain$Link"));
+        assert!(!filter.shows("java.lang.String"));
+        assert!(GraphFilter::new("", None).shows("java.lang.String"));
+    }
+
+    #[test]
+    fn invalid_depth_draws_whole_graph() {
+        let source = r#"
+            public class GraphDepth {
+                static Object root = new GraphDepth();
+
+                public static void main(String[] args) {}
+            }
+        "#;
+        let path = std::env::temp_dir().join(format!("rjava-graph-{}.dot", std::process::id()));
+        let (status, output) = run(
+            "graph-depth",
+            &[("GraphDepth", source)],
+            &[
+                (HEAP_GRAPH_PATH_PROPERTY, path.to_str().unwrap()),
+                (HEAP_GRAPH_DEPTH_PROPERTY, "abc"),
+            ],
+        );
+        assert_eq!((status, output.as_str()), (0, ""));
+        let graph = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(graph.starts_with("digraph"), "{}", graph);
+        assert!(graph.contains("GraphDepth"), "{}", graph);
+    }
+}
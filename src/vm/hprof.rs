@@ -0,0 +1,604 @@
+use crate::vm::classes::{ClassIdx, Signature};
+use crate::vm::java_lang::STRING_CLASS_IDX;
+use crate::vm::memory::{type_size, HeapObject, HeapPtr};
+use crate::vm::stack::{FrameModifiers, Type, Value};
+use crate::vm::vm::VM;
+use log::info;
+use std::collections::HashMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Where heap is dumped on exit or on `OutOfMemoryError`, `rjava.hprof` by default
+pub const HEAP_DUMP_PATH_PROPERTY: &str = "rjava.heap.dump.path";
+/// `true` dumps heap when program ends
+pub const HEAP_DUMP_ON_EXIT_PROPERTY: &str = "rjava.heap.dump.exit";
+/// `true` dumps heap on the first `OutOfMemoryError`
+pub const HEAP_DUMP_ON_OOM_PROPERTY: &str = "rjava.heap.dump.oom";
+
+const DEFAULT_DUMP_PATH: &str = "rjava.hprof";
+
+//records
+const TAG_STRING: u8 = 0x01;
+const TAG_LOAD_CLASS: u8 = 0x02;
+const TAG_STACK_FRAME: u8 = 0x04;
+const TAG_STACK_TRACE: u8 = 0x05;
+const TAG_HEAP_DUMP_SEGMENT: u8 = 0x1C;
+const TAG_HEAP_DUMP_END: u8 = 0x2C;
+
+//heap dump sub-records
+const ROOT_UNKNOWN: u8 = 0xFF;
+const ROOT_JAVA_FRAME: u8 = 0x03;
+const ROOT_NATIVE_STACK: u8 = 0x04;
+const ROOT_STICKY_CLASS: u8 = 0x05;
+const CLASS_DUMP: u8 = 0x20;
+const INSTANCE_DUMP: u8 = 0x21;
+const OBJECT_ARRAY_DUMP: u8 = 0x22;
+const PRIMITIVE_ARRAY_DUMP: u8 = 0x23;
+
+//basic types
+const HPROF_OBJECT: u8 = 2;
+const HPROF_CHAR: u8 = 5;
+
+const ID_SIZE: u32 = 8;
+const THREAD_SERIAL: u32 = 1;
+const EMPTY_TRACE_SERIAL: u32 = 1; //objects have no allocation traces
+const THREAD_TRACE_SERIAL: u32 = 2;
+const SEGMENT_SIZE: usize = 1 << 20;
+
+//heap pointers fit into 32 bits and are aligned, so ids of everything else are above them
+const CLASS_ID_BASE: u64 = 1 << 32;
+const ARRAY_CLASS_ID_BASE: u64 = 2 << 32;
+const NAME_ID_BASE: u64 = 3 << 32;
+const FRAME_ID_BASE: u64 = 4 << 32;
+const STRING_VALUE_OFFSET: u64 = 4; //chars of string are dumped as separate array
+
+impl VM {
+    /// Writes heap in HPROF format, as `jmap` does, so it could be analysed with Eclipse MAT or VisualVM
+    pub fn dump_heap(&self, path: &str) -> std::io::Result<()> {
+        let bytes = HprofWriter::new(self).write();
+        std::fs::write(path, bytes)?;
+        info!("Heap dumped to {}", path);
+        Ok(())
+    }
+
+    //dump requested by property, failures are only logged as program is already ending
+    pub(crate) fn dump_heap_if(&self, property: &str) {
+        if self.get_property(property).as_deref() != Some("true") {
+            return;
+        }
+        if property == HEAP_DUMP_ON_OOM_PROPERTY {
+            self.set_property(property, "false"); //only the first error is dumped
+        }
+        let path = self
+            .get_property(HEAP_DUMP_PATH_PROPERTY)
+            .unwrap_or_else(|| DEFAULT_DUMP_PATH.to_string());
+        if let Err(error) = self.dump_heap(&path) {
+            log::error!("Cannot dump heap to {}: {}", path, error);
+        }
+    }
+}
+
+struct HprofWriter<'a> {
+    vm: &'a VM,
+    out: Vec<u8>,
+    segment: Vec<u8>,
+    names: HashMap<String, u64>,
+    array_classes: Vec<ClassIdx>, //element classes of object arrays
+}
+
+impl<'a> HprofWriter<'a> {
+    fn new(vm: &'a VM) -> Self {
+        Self {
+            vm,
+            out: vec![],
+            segment: vec![],
+            names: HashMap::new(),
+            array_classes: vec![],
+        }
+    }
+
+    fn write(mut self) -> Vec<u8> {
+        self.out.extend_from_slice(b"JAVA PROFILE 1.0.2\0");
+        put_u4(&mut self.out, ID_SIZE);
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|x| x.as_millis() as u64)
+            .unwrap_or(0);
+        put_u8(&mut self.out, millis);
+
+        self.write_classes();
+        self.write_stack_trace();
+        self.write_roots();
+        self.write_objects();
+        self.write_array_classes();
+        self.flush_segment();
+        self.record(TAG_HEAP_DUMP_END, &[]);
+        self.out
+    }
+
+    fn record(&mut self, tag: u8, body: &[u8]) {
+        self.out.push(tag);
+        put_u4(&mut self.out, 0); //time since header
+        put_u4(&mut self.out, body.len() as u32);
+        self.out.extend_from_slice(body);
+    }
+
+    //utf-8 name, written once
+    fn name(&mut self, name: &str) -> u64 {
+        if let Some(id) = self.names.get(name) {
+            return *id;
+        }
+        let id = NAME_ID_BASE + self.names.len() as u64 * 8;
+        self.names.insert(name.to_string(), id);
+        let mut body = vec![];
+        put_u8(&mut body, id);
+        body.extend_from_slice(name.as_bytes());
+        self.record(TAG_STRING, &body);
+        id
+    }
+
+    fn sub_record(&mut self, body: Vec<u8>) {
+        self.segment.extend(body);
+        if self.segment.len() >= SEGMENT_SIZE {
+            self.flush_segment();
+        }
+    }
+
+    fn flush_segment(&mut self) {
+        if !self.segment.is_empty() {
+            let segment = std::mem::take(&mut self.segment);
+            self.record(TAG_HEAP_DUMP_SEGMENT, &segment);
+        }
+    }
+
+    fn write_classes(&mut self) {
+        let classes_count = self.vm.program.borrow().classes.len();
+        for class_idx in 0..classes_count {
+            let class = self.vm.get_class(class_idx);
+            let name = class.name.clone();
+            let super_class_idx = class.super_class_idx;
+            let statics: Vec<(String, Type, Value)> = class
+                .static_fields
+                .iter()
+                .zip(&class.static_values)
+                .map(|(field, value)| (field.name.clone(), field.value_type.clone(), value.clone()))
+                .collect();
+            drop(class);
+            let fields = self.declared_fields(class_idx);
+            let instance_size = self.vm.instance_size(class_idx);
+
+            let name_id = self.name(&name);
+            let mut body = vec![];
+            put_u4(&mut body, class_idx as u32 + 1); //serial
+            put_u8(&mut body, class_id(class_idx));
+            put_u4(&mut body, EMPTY_TRACE_SERIAL);
+            put_u8(&mut body, name_id);
+            self.record(TAG_LOAD_CLASS, &body);
+
+            let mut dump = vec![CLASS_DUMP];
+            put_u8(&mut dump, class_id(class_idx));
+            put_u4(&mut dump, EMPTY_TRACE_SERIAL);
+            put_u8(
+                &mut dump,
+                if class_idx == 0 {
+                    0
+                } else {
+                    class_id(super_class_idx)
+                },
+            );
+            put_u8(&mut dump, 0); //class loader
+            put_u8(&mut dump, 0); //signers
+            put_u8(&mut dump, 0); //protection domain
+            put_u8(&mut dump, 0); //reserved
+            put_u8(&mut dump, 0);
+            put_u4(&mut dump, instance_size as u32);
+            put_u2(&mut dump, 0); //constant pool
+            put_u2(&mut dump, statics.len() as u16);
+            for (name, value_type, value) in &statics {
+                put_u8(&mut dump, self.name(name));
+                dump.push(hprof_type(value_type));
+                put_value(&mut dump, value_type, value);
+            }
+            put_u2(&mut dump, fields.len() as u16);
+            for (name, value_type) in &fields {
+                put_u8(&mut dump, self.name(name));
+                dump.push(hprof_type(value_type));
+            }
+            self.sub_record(dump);
+
+            let mut root = vec![ROOT_STICKY_CLASS];
+            put_u8(&mut root, class_id(class_idx));
+            self.sub_record(root);
+        }
+    }
+
+    //fields declared by class itself, inherited ones are dumped with superclass.
+    //strings keep their text inline, so they get `value` field referring to synthetic char array
+    fn declared_fields(&self, class_idx: ClassIdx) -> Vec<(String, Type)> {
+        if class_idx == STRING_CLASS_IDX {
+            return vec![("value".to_string(), Type::Reference)];
+        }
+        let class = self.vm.get_class(class_idx);
+        let inherited = match class_idx {
+            0 => 0,
+            _ => self.vm.get_class(class.super_class_idx).fields.len(),
+        };
+        class.fields[inherited..]
+            .iter()
+            .map(|field| (field.name.clone(), field.value_type.clone()))
+            .collect()
+    }
+
+    //frames of the only thread, innermost first
+    fn write_stack_trace(&mut self) {
+        let mut body = vec![];
+        put_u4(&mut body, EMPTY_TRACE_SERIAL);
+        put_u4(&mut body, THREAD_SERIAL);
+        put_u4(&mut body, 0);
+        self.record(TAG_STACK_TRACE, &body);
+
+        let mut frame_ids = vec![];
+        for frame in self.java_frames().iter().rev() {
+            let (class_idx, method_idx) = frame.class_method_idxs;
+            let class = self.vm.get_class(class_idx);
+            let method = &class.methods[method_idx];
+            let method_name = method.name.clone();
+            let descriptor = method_descriptor(&method.signature);
+            let source_file = class.source_file.clone().unwrap_or_default();
+            let line = method
+                .line_number(frame.pc.get().saturating_sub(1))
+                .map_or(-1, |x| x as i32);
+            drop(class);
+
+            let frame_id = FRAME_ID_BASE + frame_ids.len() as u64 * 8;
+            let mut body = vec![];
+            put_u8(&mut body, frame_id);
+            put_u8(&mut body, self.name(&method_name));
+            put_u8(&mut body, self.name(&descriptor));
+            put_u8(&mut body, self.name(&source_file));
+            put_u4(&mut body, class_idx as u32 + 1);
+            put_u4(&mut body, line as u32);
+            self.record(TAG_STACK_FRAME, &body);
+            frame_ids.push(frame_id);
+        }
+        let mut body = vec![];
+        put_u4(&mut body, THREAD_TRACE_SERIAL);
+        put_u4(&mut body, THREAD_SERIAL);
+        put_u4(&mut body, frame_ids.len() as u32);
+        for id in frame_ids {
+            put_u8(&mut body, id);
+        }
+        self.record(TAG_STACK_TRACE, &body);
+    }
+
+    fn java_frames(&self) -> Vec<crate::vm::stack::StackFrame> {
+        self.vm
+            .stack
+            .inspect()
+            .into_iter()
+            .filter(|frame| !frame.modifiers.contains(FrameModifiers::NATIVE_BRIDGE))
+            .collect()
+    }
+
+    fn write_roots(&mut self) {
+        let frames = self.vm.stack.inspect();
+        let mut frame_number = frames
+            .iter()
+            .filter(|frame| !frame.modifiers.contains(FrameModifiers::NATIVE_BRIDGE))
+            .count() as u32;
+        for frame in &frames {
+            let is_bridge = frame.modifiers.contains(FrameModifiers::NATIVE_BRIDGE);
+            if !is_bridge {
+                frame_number -= 1;
+            }
+            let values = frame
+                .inspect_locals()
+                .into_iter()
+                .chain(frame.inspect_stack());
+            for value in values {
+                if let Value::Reference(ptr) = value {
+                    if ptr == 0 {
+                        continue;
+                    }
+                    //values passed to or returned from natives
+                    let mut root = vec![if is_bridge {
+                        ROOT_NATIVE_STACK
+                    } else {
+                        ROOT_JAVA_FRAME
+                    }];
+                    put_u8(&mut root, ptr as u64);
+                    put_u4(&mut root, THREAD_SERIAL);
+                    if !is_bridge {
+                        put_u4(&mut root, frame_number);
+                    }
+                    self.sub_record(root);
+                }
+            }
+        }
+        for ptr in self.vm.vm_roots() {
+            if ptr != 0 {
+                let mut root = vec![ROOT_UNKNOWN];
+                put_u8(&mut root, ptr as u64);
+                self.sub_record(root);
+            }
+        }
+    }
+
+    fn write_objects(&mut self) {
+        let heap = &self.vm.heap;
+        for ptr in heap.objects() {
+            match heap.get_object(ptr) {
+                Some(HeapObject::Object(class_idx)) => self.write_instance(ptr, class_idx),
+                Some(HeapObject::String) => self.write_string(ptr),
+                Some(HeapObject::Array(Type::Reference, class_idx)) => {
+                    if !self.array_classes.contains(&class_idx) {
+                        self.array_classes.push(class_idx);
+                    }
+                    let length = heap.array_length(ptr);
+                    let mut dump = vec![OBJECT_ARRAY_DUMP];
+                    put_u8(&mut dump, ptr as u64);
+                    put_u4(&mut dump, EMPTY_TRACE_SERIAL);
+                    put_u4(&mut dump, length as u32);
+                    put_u8(&mut dump, ARRAY_CLASS_ID_BASE + class_idx as u64 * 8);
+                    for i in 0..length {
+                        put_value(&mut dump, &Type::Reference, &heap.get_array_element(ptr, i));
+                    }
+                    self.sub_record(dump);
+                }
+                Some(HeapObject::Array(element_type, _)) => {
+                    let length = heap.array_length(ptr);
+                    let mut dump = vec![PRIMITIVE_ARRAY_DUMP];
+                    put_u8(&mut dump, ptr as u64);
+                    put_u4(&mut dump, EMPTY_TRACE_SERIAL);
+                    put_u4(&mut dump, length as u32);
+                    dump.push(hprof_type(&element_type));
+                    for i in 0..length {
+                        put_value(&mut dump, &element_type, &heap.get_array_element(ptr, i));
+                    }
+                    self.sub_record(dump);
+                }
+                None => {}
+            }
+        }
+    }
+
+    //field values of class, then of its superclasses
+    fn write_instance(&mut self, ptr: HeapPtr, class_idx: ClassIdx) {
+        let mut values = vec![];
+        let mut idx = class_idx;
+        loop {
+            let fields = self.declared_fields(idx);
+            let first = self.vm.get_class(idx).fields.len() - fields.len();
+            for (i, (_, value_type)) in fields.iter().enumerate() {
+                put_value(
+                    &mut values,
+                    value_type,
+                    &self.vm.heap.get_field(ptr, first + i),
+                );
+            }
+            if idx == 0 {
+                break;
+            }
+            idx = self.vm.get_class(idx).super_class_idx;
+        }
+        let mut dump = vec![INSTANCE_DUMP];
+        put_u8(&mut dump, ptr as u64);
+        put_u4(&mut dump, EMPTY_TRACE_SERIAL);
+        put_u8(&mut dump, class_id(class_idx));
+        put_u4(&mut dump, values.len() as u32);
+        dump.extend(values);
+        self.sub_record(dump);
+    }
+
+    fn write_string(&mut self, ptr: HeapPtr) {
+        let value_id = ptr as u64 + STRING_VALUE_OFFSET;
+        let mut dump = vec![INSTANCE_DUMP];
+        put_u8(&mut dump, ptr as u64);
+        put_u4(&mut dump, EMPTY_TRACE_SERIAL);
+        put_u8(&mut dump, class_id(STRING_CLASS_IDX));
+        put_u4(&mut dump, ID_SIZE);
+        put_u8(&mut dump, value_id);
+        self.sub_record(dump);
+
+        let chars: Vec<u16> = self.vm.heap.get_string(ptr).encode_utf16().collect();
+        let mut dump = vec![PRIMITIVE_ARRAY_DUMP];
+        put_u8(&mut dump, value_id);
+        put_u4(&mut dump, EMPTY_TRACE_SERIAL);
+        put_u4(&mut dump, chars.len() as u32);
+        dump.push(HPROF_CHAR);
+        for char in chars {
+            put_u2(&mut dump, char);
+        }
+        self.sub_record(dump);
+    }
+
+    //object array classes do not exist in vm, so they are made up for arrays found in heap
+    fn write_array_classes(&mut self) {
+        for class_idx in std::mem::take(&mut self.array_classes) {
+            let name = format!("[L{};", self.vm.get_class(class_idx).name);
+            let id = ARRAY_CLASS_ID_BASE + class_idx as u64 * 8;
+            let name_id = self.name(&name);
+            let mut body = vec![];
+            put_u4(&mut body, (id >> 3) as u32); //serial, unique among classes
+            put_u8(&mut body, id);
+            put_u4(&mut body, EMPTY_TRACE_SERIAL);
+            put_u8(&mut body, name_id);
+            self.record(TAG_LOAD_CLASS, &body);
+
+            let mut dump = vec![CLASS_DUMP];
+            put_u8(&mut dump, id);
+            put_u4(&mut dump, EMPTY_TRACE_SERIAL);
+            put_u8(&mut dump, class_id(0));
+            for _ in 0..5 {
+                put_u8(&mut dump, 0); //loader, signers, protection domain, reserved
+            }
+            put_u4(&mut dump, 0);
+            put_u2(&mut dump, 0);
+            put_u2(&mut dump, 0);
+            put_u2(&mut dump, 0);
+            self.sub_record(dump);
+        }
+    }
+}
+
+fn class_id(class_idx: ClassIdx) -> u64 {
+    CLASS_ID_BASE + class_idx as u64 * 8
+}
+
+fn hprof_type(value_type: &Type) -> u8 {
+    match value_type {
+        Type::Reference => HPROF_OBJECT,
+        Type::Boolean => 4,
+        Type::Char => HPROF_CHAR,
+        Type::Float => 6,
+        Type::Double => 7,
+        Type::Byte => 8,
+        Type::Short => 9,
+        Type::Int => 10,
+        Type::Long => 11,
+        Type::Void => panic!("Void has no hprof type"),
+    }
+}
+
+//big-endian, as hprof is
+fn put_value(out: &mut Vec<u8>, value_type: &Type, value: &Value) {
+    let int = match value {
+        Value::Int(v) | Value::Boolean(v) => *v,
+        Value::Byte(v) => *v as i32,
+        Value::Short(v) => *v as i32,
+        Value::Char(v) => *v as i32,
+        _ => 0,
+    };
+    match (value_type, value) {
+        (Type::Reference, Value::Reference(ptr)) => put_u8(out, *ptr as u64),
+        (Type::Reference, _) => put_u8(out, 0),
+        (Type::Long, Value::Long(v)) => put_u8(out, *v as u64),
+        (Type::Double, Value::Double(v)) => put_u8(out, v.to_bits()),
+        (Type::Float, Value::Float(v)) => put_u4(out, v.to_bits()),
+        (value_type, _) => match type_size(value_type) {
+            1 => out.push(int as u8),
+            2 => put_u2(out, int as u16),
+            4 => put_u4(out, int as u32),
+            _ => put_u8(out, 0),
+        },
+    }
+}
+
+fn method_descriptor(signature: &Signature) -> String {
+    //class names are not kept in signature
+    let arguments: String = signature.arguments.iter().map(Type::descriptor).collect();
+    format!("({}){}", arguments, signature.return_type.descriptor())
+}
+
+fn put_u2(out: &mut Vec<u8>, value: u16) {
+    out.extend_from_slice(&value.to_be_bytes())
+}
+
+fn put_u4(out: &mut Vec<u8>, value: u32) {
+    out.extend_from_slice(&value.to_be_bytes())
+}
+
+fn put_u8(out: &mut Vec<u8>, value: u64) {
+    out.extend_from_slice(&value.to_be_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::interpreter::HEAP_MAX_PROPERTY;
+    use crate::vm::testing::run;
+    use std::convert::TryInto;
+
+    const HEADER: &[u8] = b"JAVA PROFILE 1.0.2\0";
+
+    //top level records as tag and body, checking that lengths add up to the whole dump
+    fn records(bytes: &[u8]) -> Vec<(u8, &[u8])> {
+        assert!(bytes.starts_with(HEADER));
+        assert_eq!(
+            &bytes[HEADER.len()..HEADER.len() + 4],
+            &ID_SIZE.to_be_bytes()
+        );
+        let mut records = vec![];
+        let mut i = HEADER.len() + 12;
+        while i < bytes.len() {
+            let length = u32::from_be_bytes(bytes[i + 5..i + 9].try_into().unwrap()) as usize;
+            records.push((bytes[i], &bytes[i + 9..i + 9 + length]));
+            i += 9 + length;
+        }
+        assert_eq!(i, bytes.len());
+        assert_eq!(records.last().map(|x| x.0), Some(TAG_HEAP_DUMP_END));
+        records
+    }
+
+    fn contains(bytes: &[u8], part: &[u8]) -> bool {
+        bytes.windows(part.len()).any(|x| x == part)
+    }
+
+    #[test]
+    fn strings_and_stack_roots_are_dumped() {
+        let vm = VM::new(".");
+        let text = vm.new_string("dumped text");
+        vm.stack
+            .push_frame(1, 1)
+            .set_local(0, Value::Reference(text));
+        let bytes = HprofWriter::new(&vm).write();
+        let records = records(&bytes);
+
+        let names: Vec<&[u8]> = records
+            .iter()
+            .filter(|(tag, _)| *tag == TAG_STRING)
+            .map(|(_, body)| &body[ID_SIZE as usize..])
+            .collect();
+        assert!(names.contains(&&b"java/lang/String"[..]));
+        assert!(names.contains(&&b"value"[..]));
+
+        let segments: Vec<u8> = records
+            .iter()
+            .filter(|(tag, _)| *tag == TAG_HEAP_DUMP_SEGMENT)
+            .flat_map(|(_, body)| body.to_vec())
+            .collect();
+        let mut root = vec![ROOT_JAVA_FRAME];
+        put_u8(&mut root, text as u64);
+        assert!(contains(&segments, &root));
+        let mut instance = vec![INSTANCE_DUMP];
+        put_u8(&mut instance, text as u64);
+        assert!(contains(&segments, &instance));
+        let chars: Vec<u8> = "dumped text"
+            .encode_utf16()
+            .flat_map(|x| x.to_be_bytes())
+            .collect();
+        assert!(contains(&segments, &chars));
+    }
+
+    #[test]
+    fn heap_is_dumped_on_out_of_memory() {
+        let source = r#"
+            public class DumpOnOom {
+                public static void main(String[] args) {
+                    try {
+                        Object[] huge = new Object[1000000];
+                    } catch (OutOfMemoryError e) {
+                        System.out.println(e.getMessage());
+                    }
+                }
+            }
+        "#;
+        let path = std::env::temp_dir().join(format!("rjava-oom-{}.hprof", std::process::id()));
+        let (status, output) = run(
+            "dump-on-oom",
+            &[("DumpOnOom", source)],
+            &[
+                (HEAP_MAX_PROPERTY, "1048576"),
+                (HEAP_DUMP_ON_OOM_PROPERTY, "true"),
+                (HEAP_DUMP_PATH_PROPERTY, path.to_str().unwrap()),
+            ],
+        );
+        assert_eq!((status, output.as_str()), (0, "Java heap space\n"));
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let frames = records(&bytes)
+            .iter()
+            .filter(|(tag, _)| *tag == TAG_STACK_FRAME)
+            .count();
+        assert_eq!(frames, 1);
+    }
+}
@@ -1,15 +1,119 @@
+use crate::vm::auto_free::{EscapePolicy, AUTO_FREE_ESCAPES_PROPERTY};
+use crate::vm::class_loader::parse_signature;
 use crate::vm::classes::{
-    AccessFlags, ClassIdx, ConstantPoolIdx, ConstantPoolValue, FieldIdx, Method, MethodInClassIdx,
+    AccessFlags, ClassIdx, CodePtr, ConstantPoolIdx, ConstantPoolValue, FieldIdx, Method,
+    MethodInClassIdx,
 };
-use crate::vm::memory::HeapPtr;
+use crate::vm::hprof::{HEAP_DUMP_ON_EXIT_PROPERTY, HEAP_DUMP_ON_OOM_PROPERTY};
+use crate::vm::java_io::STDERR;
+use crate::vm::java_lang::{
+    class_mirror, new_throwable, throwable_stack_trace, ARITHMETIC_EXCEPTION, CLASS_CAST_EXCEPTION,
+    NEGATIVE_ARRAY_SIZE_EXCEPTION, NULL_POINTER_EXCEPTION, OBJECT_CLASS, OBJECT_CLASS_IDX,
+    OUT_OF_MEMORY_ERROR,
+};
+use crate::vm::memory::{AllocationSite, HeapMemory, HeapObject, HeapPtr, InvalidAccess};
+use crate::vm::purity::{SideEffectPolicy, PURE_CHECK_PROPERTY};
 use crate::vm::stack::{FrameModifiers, StackFrame, Type, Value};
 use crate::vm::vm::VM;
-use log::{debug, info, trace};
+use log::{debug, trace};
+use std::any::Any;
 use std::cell::RefMut;
 use std::convert::TryInto;
+use std::panic::{self, AssertUnwindSafe};
+
+/// Overrides `DEFAULT_GC_THRESHOLD`, 0 disables automatic collection
+pub const GC_THRESHOLD_PROPERTY: &str = "rjava.gc.threshold";
+/// Maximum heap size in bytes, as set by `-Xmx`. 0 means no limit
+pub const HEAP_MAX_PROPERTY: &str = "rjava.heap.max";
+/// `true` turns on debug heap, which reports access to freed objects
+pub const HEAP_DEBUG_PROPERTY: &str = "rjava.heap.debug";
 
 impl VM {
-    pub fn start(&mut self, class_name: &str) {
+    /// Runs `main` of the given class, returns exit status
+    pub fn start(&mut self, class_name: &str) -> i32 {
+        let result = panic::catch_unwind(AssertUnwindSafe(|| self.run_main(class_name)));
+        if let Err(reason) = result {
+            self.abort(reason);
+        }
+        self.dump_heap_if(HEAP_DUMP_ON_EXIT_PROPERTY);
+        self.dump_heap_graph_on_exit();
+        self.streams.flush();
+        self.exit_status().unwrap_or(0)
+    }
+
+    //internal failure is reported with java stack, as it helps to find the cause in java code
+    fn abort(&self, reason: Box<dyn Any + Send>) {
+        let message = match (
+            reason.downcast_ref::<String>(),
+            reason.downcast_ref::<&str>(),
+            reason.downcast_ref::<InvalidAccess>(),
+        ) {
+            (Some(message), _, _) => message.clone(),
+            (None, Some(message), _) => message.to_string(),
+            (None, None, Some(access)) => self.describe_invalid_access(access),
+            _ => "VM aborted".to_string(),
+        };
+        let mut trace = format!(
+            "Exception in thread \"main\" java.lang.InternalError: {}\n",
+            message
+        );
+        for frame in self.stack_trace() {
+            trace += &format!("\tat {}\n", frame);
+        }
+        self.streams.write(STDERR, trace.as_bytes());
+        self.exit(1);
+    }
+
+    fn describe_invalid_access(&self, access: &InvalidAccess) -> String {
+        let site_name = |site: Option<AllocationSite>| match site {
+            Some((class_idx, method_idx)) => {
+                let class = self.get_class(class_idx);
+                format!(
+                    "{}.{}",
+                    class.name.replace('/', "."),
+                    class.methods[method_idx].name
+                )
+            }
+            None => "vm".to_string(),
+        };
+        let mut message = format!("Invalid access to {} at @{}", access.problem, access.ptr);
+        if self.heap.is_debug() && access.ptr != 0 {
+            message += &format!(", allocated in {}", site_name(access.allocated));
+            if access.freed.is_some() {
+                message += &format!(", freed in {}", site_name(access.freed));
+            }
+        }
+        message
+    }
+
+    fn run_main(&self, class_name: &str) {
+        if let Some(threshold) = self.get_property(GC_THRESHOLD_PROPERTY) {
+            match threshold.parse() {
+                Ok(threshold) => self.heap.set_gc_threshold(threshold),
+                Err(_) => panic!("Invalid {} value {}", GC_THRESHOLD_PROPERTY, threshold),
+            }
+        }
+        if let Some(max_size) = self.get_property(HEAP_MAX_PROPERTY) {
+            match max_size.parse() {
+                Ok(max_size) => self.heap.set_max_size(max_size),
+                Err(_) => panic!("Invalid {} value {}", HEAP_MAX_PROPERTY, max_size),
+            }
+        }
+        if let Some(debug) = self.get_property(HEAP_DEBUG_PROPERTY) {
+            self.heap.set_debug(debug == "true");
+        }
+        if let Some(policy) = self.get_property(AUTO_FREE_ESCAPES_PROPERTY) {
+            match EscapePolicy::parse(&policy) {
+                Some(policy) => self.set_escape_policy(policy),
+                None => panic!("Unknown {} value {}", AUTO_FREE_ESCAPES_PROPERTY, policy),
+            }
+        }
+        if let Some(policy) = self.get_property(PURE_CHECK_PROPERTY) {
+            match SideEffectPolicy::parse(&policy) {
+                Some(policy) => self.set_side_effect_policy(policy),
+                None => panic!("Unknown {} value {}", PURE_CHECK_PROPERTY, policy),
+            }
+        }
         //lookup for main method
         {
             let class_idx = self.get_or_load_class_idx(&class_name.to_string());
@@ -22,6 +126,26 @@ impl VM {
             frame.pc.set(main_method.code_ptr);
             frame.cp_offset = class.constant_pool_idx;
             frame.class_method_idxs = (class_idx, method_idx);
+            if main_method.flags.contains(AccessFlags::TRACE) {
+                frame.modifiers.insert(FrameModifiers::TRACED);
+            }
+            if main_method.flags.contains(AccessFlags::TRACE_CALLEES) {
+                frame.modifiers.insert(FrameModifiers::TRACE_CALLEES);
+            }
+            let args = [frame.get_local(0)];
+            drop(frame);
+            if main_method.flags.contains(AccessFlags::TRACE) {
+                self.trace_enter(&class.name, main_method, &args);
+            }
+        }
+        //static initializers of main class run before main, superclasses first
+        let main_class_idx = self.get_or_load_class_idx(&class_name.to_string());
+        let mut initializers = vec![];
+        while let Some(initializer) = self.next_class_initializer(main_class_idx) {
+            initializers.push(initializer);
+        }
+        for (class_idx, method_idx) in initializers.into_iter().rev() {
+            self.call(class_idx, method_idx);
         }
 
         self.do_loop();
@@ -33,6 +157,22 @@ impl VM {
         let method_name: String;
         let method: Method;
 
+        //checked on the first call, when classes it refers to could be loaded
+        if self
+            .get_method(class_idx, method_idx)
+            .flags
+            .contains(AccessFlags::PURE)
+        {
+            self.verify_pure(class_idx, method_idx);
+        }
+        if self
+            .get_method(class_idx, method_idx)
+            .flags
+            .intersects(AccessFlags::REQUIRES | AccessFlags::ENSURES)
+        {
+            self.resolve_contract(class_idx, method_idx);
+        }
+
         {
             let class = self.get_class(class_idx);
             class_name = class.name.clone();
@@ -52,6 +192,13 @@ impl VM {
             }
         }
 
+        //thrown in caller, before the method starts
+        if method_flags.contains(AccessFlags::REQUIRES)
+            && !self.check_requires(class_idx, method_idx, &method, &args)
+        {
+            return;
+        }
+
         let is_tail_rec_optimization_requested = method_flags.contains(AccessFlags::TAIL_RECURSION);
 
         if is_tail_rec_optimization_requested {
@@ -59,7 +206,8 @@ impl VM {
 
             let not_native = !method_flags.contains(AccessFlags::NATIVE);
             let same_method = frame.class_method_idxs == (class_idx, method_idx);
-            let last_in_current_method = is_return(frame.pick_u8(self));
+            //found by class loader, possibly with `goto` to return
+            let last_in_current_method = method.tail_calls.binary_search(&frame.pc.get()).is_ok();
             let is_tail_rec_optimization_available =
                 not_native && same_method && last_in_current_method;
 
@@ -69,10 +217,25 @@ impl VM {
             }
         }
 
+        if self.is_tail_call(&method) {
+            debug!("Tail call {}#{}({:?})", class_name, method_name, &args);
+            let mut frame = self.stack.top_frame_mut();
+            frame.resize(method.max_stack, method.max_locals);
+            self.perform_call(class_idx, method_idx, &args, &mut frame);
+            return;
+        }
+
         debug!("Call {}#{}({:?})", class_name, method_name, &args);
         if method_flags.contains(AccessFlags::NATIVE) {
-            for nm in &self.program.borrow().native_methods {
-                let value = nm.invoke(&self, &class_name, &method_name, args.clone());
+            self.check_native_side_effect(&class_name, &method_name);
+            for nm in &self.native_methods {
+                let value = nm.invoke(
+                    self,
+                    &class_name,
+                    &method_name,
+                    &method.signature,
+                    args.clone(),
+                );
                 if let Some(value) = value {
                     if value != Value::Void {
                         self.stack.top_frame().push(value);
@@ -92,72 +255,126 @@ impl VM {
                 class_name, method_name
             );
 
-            {
-                let prev_frame_modifiers = self.stack.top_frame().modifiers;
-                let mut frame = self.stack.push_frame(method.max_stack, method.max_locals);
-                if method_flags.contains(AccessFlags::AUTO_FREE)
-                    || prev_frame_modifiers.contains(FrameModifiers::AUTO_FREE)
-                {
-                    frame.modifiers.insert(FrameModifiers::AUTO_FREE)
+            //cached result is returned without a call, on miss the result is saved on return
+            let mut mem_entry = 0;
+            if method_flags.contains(AccessFlags::MEM) {
+                let hash = self.mem_hash(&args);
+                let answer = match self.pending_exception() {
+                    0 => self.find_mem_answer(class_idx, method_idx, &args, hash),
+                    _ => None,
+                };
+                if let Some(answer) = answer {
+                    debug!("  > answer of {}#{} from mem", class_name, method_name);
+                    if self.is_traced(method_flags) {
+                        self.trace_mem_hit(&class_name, &method, &args, &answer);
+                    }
+                    self.stack.top_frame().push(answer);
+                    return;
                 }
-                self.perform_call(class_idx, method_idx, &args, &mut frame);
+                if self.pending_exception() != 0 {
+                    return; //thrown by hashCode or equals of argument
+                }
+                mem_entry = self.new_mem_entry(&args, hash);
             }
 
-            let is_mem_optimization_requested = method.flags.contains(AccessFlags::MEM);
-            if is_mem_optimization_requested {
-                let is_mem_optimization_available = method
-                    .signature
-                    .arguments
-                    .iter()
-                    .all(|x| *x == Type::Reference)
-                    && method.signature.return_type == Type::Reference;
-
-                if is_mem_optimization_available {
-                    //add call to RVM.getAnswer.
-                    let rvm_class_idx =
-                        self.get_or_load_class_idx(&"io/github/rvm/RVM".to_string());
-                    let mut args_count = method.signature.arguments.len();
-                    if !method.flags.contains(AccessFlags::STATIC) {
-                        args_count += 1;
-                    }
-                    let get_answer_method_idx = match args_count {
-                        1 => self.get_method_idx(rvm_class_idx, "getAnswer".to_string(), "(Lio/github/rvm/MemEntry;Ljava/lang/Object;)Ljava/lang/Object;".to_string()).unwrap(),
-                        2 => self.get_method_idx(rvm_class_idx, "getAnswer".to_string(), "(Lio/github/rvm/MemEntry;Ljava/lang/Object;Ljava/lang/Object;)Ljava/lang/Object;".to_string()).unwrap(),
-                        _ => panic!("Unsupported amount of arguments")
-                    };
-                    //check mem entry associated with method
-
-                    {
-                        self.stack
-                            .top_frame_mut()
-                            .modifiers
-                            .insert(FrameModifiers::MEM_LOAD);
-                    }
-                    debug!(
-                        "  > redirect to RVM.getAnswer {}#{}({:?})",
-                        class_name, method_name, &args
-                    );
-                    let mut frame = self
-                        .stack
-                        .push_frame(method.max_stack, method.max_locals + 1);
-                    let mut get_answer_args = args.clone();
-                    get_answer_args.insert(0, Value::Reference(method.mem_entry_ptr));
-                    self.perform_call(
-                        rvm_class_idx,
-                        get_answer_method_idx,
-                        &get_answer_args,
-                        &mut frame,
-                    );
+            let prev_frame_modifiers = self.stack.top_frame().modifiers;
+            let traced = self.is_traced(method_flags);
+            //entry waiting for the result and arguments for postcondition are kept in extra locals
+            let ensures = method_flags.contains(AccessFlags::ENSURES);
+            let locals_count = method.max_locals
+                + (mem_entry != 0) as u16
+                + if ensures { args.len() as u16 } else { 0 };
+            let mut frame = self.stack.push_frame(method.max_stack, locals_count);
+            if method_flags.contains(AccessFlags::AUTO_FREE)
+                || prev_frame_modifiers.contains(FrameModifiers::AUTO_FREE)
+            {
+                frame.modifiers.insert(FrameModifiers::AUTO_FREE)
+            }
+            if method_flags.contains(AccessFlags::ARENA) {
+                frame.modifiers.insert(FrameModifiers::ARENA);
+                self.heap.enter_arena();
+            }
+            if traced {
+                frame.modifiers.insert(FrameModifiers::TRACED);
+            }
+            if method_flags.contains(AccessFlags::TRACE_CALLEES)
+                || prev_frame_modifiers.contains(FrameModifiers::TRACE_CALLEES)
+            {
+                frame.modifiers.insert(FrameModifiers::TRACE_CALLEES);
+            }
+            let pure_modifiers = self.pure_modifiers(&method, prev_frame_modifiers);
+            frame.modifiers.insert(pure_modifiers);
+            self.perform_call(class_idx, method_idx, &args, &mut frame);
+            if mem_entry != 0 {
+                frame.modifiers.insert(FrameModifiers::MEM_SAVE);
+                frame.set_local(method.max_locals as u8, Value::Reference(mem_entry));
+            }
+            if ensures {
+                frame.modifiers.insert(FrameModifiers::ENSURES);
+                let base = self.contract_locals(&method, frame.modifiers);
+                for (i, arg) in args.iter().enumerate() {
+                    frame.set_local((base + i) as u8, arg.clone());
                 }
             }
+            drop(frame);
+            if pure_modifiers.contains(FrameModifiers::PURE) {
+                self.enter_pure_call();
+            }
+            if traced {
+                self.trace_enter(&class_name, &method, &args);
+            }
+        }
+    }
+
+    //method is annotated with `@RVM.Trace` or called from method tracing its callees
+    fn is_traced(&self, method_flags: AccessFlags) -> bool {
+        method_flags.contains(AccessFlags::TRACE)
+            || self
+                .stack
+                .top_frame()
+                .modifiers
+                .contains(FrameModifiers::TRACE_CALLEES)
+    }
+
+    //call made by `@RVM.TailCall` method right before return may replace its frame
+    fn is_tail_call(&self, callee: &Method) -> bool {
+        let frame = self.stack.top_frame();
+        if frame.modifiers.intersects(
+            FrameModifiers::MEM_SAVE | FrameModifiers::NATIVE_BRIDGE | FrameModifiers::ENSURES,
+        ) {
+            return false; //result of caller is still awaited in its frame
+        }
+        if frame.modifiers.contains(FrameModifiers::TRACE_CALLEES) {
+            return false; //callee is traced in its own frame
         }
+        let (class_idx, method_idx) = frame.class_method_idxs;
+        let caller = self.get_method(class_idx, method_idx);
+        if !caller.flags.contains(AccessFlags::TAIL_CALL) {
+            return false;
+        }
+        //callee needs own frame to look up its cache, enter arena, free or check its objects,
+        //or keep arguments for postcondition
+        let own_frame = AccessFlags::NATIVE
+            | AccessFlags::MEM
+            | AccessFlags::ARENA
+            | AccessFlags::TRACE
+            | AccessFlags::PURE
+            | AccessFlags::ENSURES;
+        if callee.flags.intersects(own_frame)
+            || (callee.flags.contains(AccessFlags::AUTO_FREE)
+                && !frame.modifiers.contains(FrameModifiers::AUTO_FREE))
+        {
+            return false;
+        }
+        //tail positions are found by class loader
+        caller.tail_calls.binary_search(&frame.pc.get()).is_ok()
     }
 
     fn perform_call(
         &self,
         class_idx: ClassIdx,
         method_idx: MethodInClassIdx,
-        args: &Vec<Value>,
+        args: &[Value],
         frame: &mut RefMut<StackFrame>,
     ) {
         let class = self.get_class(class_idx);
@@ -165,52 +382,61 @@ impl VM {
         frame.pc.set(method.code_ptr);
         frame.class_method_idxs = (class_idx, method_idx);
         frame.cp_offset = class.constant_pool_idx;
-        for i in 0..(method.max_locals as usize) {
-            if i >= args.len() {
+        let mut local_idx = 0;
+        for arg in args {
+            if local_idx >= method.max_locals as usize {
                 break;
             }
-            frame.set_local(i as u8, args[i].clone());
+            frame.set_local(local_idx as u8, arg.clone());
+            local_idx += if arg.is_wide() { 2 } else { 1 };
         }
     }
 
     fn return_call(&self) {
         debug!("Return");
+        if !self.check_ensures(None) {
+            return;
+        }
+        if self
+            .stack
+            .top_frame()
+            .modifiers
+            .contains(FrameModifiers::TRACED)
+        {
+            self.trace_return(None);
+        }
+        self.exit_pure_call();
+        self.release_frame_region();
+        let modifiers = self.stack.top_frame().modifiers;
         self.stack.pop_frame();
+        if modifiers.contains(FrameModifiers::ARENA) {
+            self.release_arena();
+        }
     }
 
     fn return_call_with_value(&self, value: Value) {
         debug!("Return {:?}", value);
+        if !self.check_return_escape(&value) || !self.check_ensures(Some(&value)) {
+            return;
+        }
+        if self
+            .stack
+            .top_frame()
+            .modifiers
+            .contains(FrameModifiers::TRACED)
+        {
+            self.trace_return(Some(&value));
+        }
+        self.exit_pure_call();
         {
             let frame = self.stack.top_frame();
             if frame.modifiers.contains(FrameModifiers::MEM_SAVE) {
-                //need to save value.
                 debug!("Save to mem");
                 let (class_idx, method_idx) = frame.class_method_idxs;
-
-                let mut args_count: usize;
-                {
-                    let method = self.get_method(class_idx, method_idx);
-                    args_count = method.signature.arguments.len();
-                    if !method.flags.contains(AccessFlags::STATIC) {
-                        args_count += 1;
-                    }
+                let max_locals = self.get_method(class_idx, method_idx).max_locals;
+                if let Value::Reference(entry) = frame.get_local(max_locals as u8) {
+                    self.save_mem_answer(class_idx, method_idx, entry, &value);
                 }
-                let args_ptr = self.heap.new_object_array(0, args_count as i32);
-                for i in 0..args_count {
-                    self.heap
-                        .set_array_element(args_ptr, i, frame.get_local(i as u8));
-                }
-
-                let heap_ptr = self.heap.new_object(
-                    self.get_or_load_class_idx(&"io/github/rvm/MemEntry".to_string()),
-                    3,
-                );
-                self.heap.new_object_field(Value::Reference(args_ptr)); //arguments
-                self.heap.new_object_field(value.clone()); //answer
-                let mut method = self.get_method_mut(class_idx, method_idx);
-                self.heap
-                    .new_object_field(Value::Reference(method.mem_entry_ptr)); //next
-                method.mem_entry_ptr = heap_ptr;
             }
 
             if frame.modifiers.contains(FrameModifiers::AUTO_FREE) {
@@ -220,54 +446,250 @@ impl VM {
                 }
             }
         }
+        self.release_frame_region();
+        let is_arena = self
+            .stack
+            .top_frame()
+            .modifiers
+            .contains(FrameModifiers::ARENA);
+        if let (true, Value::Reference(ptr)) = (is_arena, &value) {
+            self.heap.copy_out(*ptr); //result outlives the arena
+        }
 
         self.stack.pop_frame();
-        let mods = self.stack.top_frame().modifiers.clone();
-
-        if mods.contains(FrameModifiers::MEM_LOAD) {
-            //so we have value to return. check it is not null
-            if value != Value::Reference(0) {
-                //ok, return it
-                debug!("Return from mem");
-                self.stack.pop_frame();
-            } else {
-                //work as usual, but add save modifier
-                self.stack
-                    .top_frame_mut()
-                    .modifiers
-                    .insert(FrameModifiers::MEM_SAVE);
-                self.stack
-                    .top_frame_mut()
-                    .modifiers
-                    .remove(FrameModifiers::MEM_LOAD);
+        self.stack.top_frame().push(value);
+        if is_arena {
+            self.release_arena();
+        }
+    }
+
+    //references to objects copied out of arena are updated, so it is done when frame is already popped
+    fn release_arena(&self) {
+        if let Some(relocation) = self.heap.exit_arena() {
+            self.relocate_roots(&relocation);
+        }
+    }
+
+    /// Calls java method from rust (e.g. from native method) and waits for the result.
+    /// If method throws, exception stays pending and `Value::Void` is returned.
+    pub fn invoke(
+        &self,
+        class_idx: ClassIdx,
+        method_idx: MethodInClassIdx,
+        arguments: Vec<Value>,
+    ) -> Value {
+        {
+            let in_pure = match self.stack.is_empty() {
+                true => FrameModifiers::empty(),
+                false => self.stack.top_frame().modifiers & FrameModifiers::IN_PURE,
+            };
+            let mut bridge = self.stack.push_frame(arguments.len() as u16 + 1, 0);
+            bridge
+                .modifiers
+                .insert(FrameModifiers::NATIVE_BRIDGE | in_pure);
+            for argument in arguments {
+                bridge.push(argument);
             }
         }
+        let depth = self.stack.depth();
+        let pure_mark = self.heap.pure_mark();
+        self.native_calls.set(self.native_calls.get() + 1);
+        self.call(class_idx, method_idx);
+        if self.stack.depth() > depth {
+            self.run_until(depth);
+        }
+        self.native_calls.set(self.native_calls.get() - 1);
+        let result = self.stack.top_frame().inspect_stack().pop();
+        self.stack.pop_frame();
+        self.enter_region(); //native continues in region of its caller
+        self.heap.set_pure_mark(pure_mark);
+        result.unwrap_or(Value::Void)
+    }
 
-        self.stack.top_frame().push(value);
+    /// Same as `invoke`, but method is looked up by name and descriptor in the class of `object`
+    pub fn invoke_virtual(
+        &self,
+        object: HeapPtr,
+        name: &str,
+        descriptor: &str,
+        mut arguments: Vec<Value>,
+    ) -> Value {
+        let (class_idx, method_idx) = self
+            .find_object_method(object, name, descriptor)
+            .unwrap_or_else(|| panic!("Cannot find method {} {}", name, descriptor));
+        arguments.insert(0, Value::Reference(object));
+        self.invoke(class_idx, method_idx, arguments)
+    }
+
+    /// Looks for method in the class of object, arrays have methods of `java/lang/Object` only
+    pub(crate) fn find_object_method(
+        &self,
+        object: HeapPtr,
+        name: &str,
+        descriptor: &str,
+    ) -> Option<(ClassIdx, MethodInClassIdx)> {
+        let class_idx = self.heap.class_of(object).unwrap_or(OBJECT_CLASS_IDX);
+        self.find_virtual_method(class_idx, name, descriptor)
+    }
+
+    /// Looks for method in class and its superclasses
+    pub(crate) fn find_virtual_method(
+        &self,
+        class_idx: ClassIdx,
+        name: &str,
+        descriptor: &str,
+    ) -> Option<(ClassIdx, MethodInClassIdx)> {
+        let mut class_idx = class_idx;
+        loop {
+            let method_idx =
+                self.get_method_idx(class_idx, name.to_string(), descriptor.to_string());
+            if let Some(method_idx) = method_idx {
+                return Some((class_idx, method_idx));
+            }
+            if class_idx == 0 {
+                return None;
+            }
+            class_idx = self.get_class(class_idx).super_class_idx;
+        }
     }
 
     pub fn do_loop(&self) {
+        self.run_until(0)
+    }
+
+    //runs until stack is unwound to the given depth
+    fn run_until(&self, depth: usize) {
         loop {
             match self.do_command() {
                 StackModification::Nop => {}
-                StackModification::Call(class_idx, method_idx) => self.call(class_idx, method_idx),
+                StackModification::Call(class_idx, method_idx) => {
+                    self.call(class_idx, method_idx);
+                }
                 StackModification::Return(value) => {
                     if let Value::Void = value {
                         self.return_call()
                     } else {
                         self.return_call_with_value(value)
                     }
-                    if self.stack.is_empty() {
-                        break;
-                    }
                 }
+                StackModification::Throw(exception) => self.throw(exception),
+            }
+            self.check_store_escape();
+            self.check_side_effect();
+            //natives may keep pointers in rust variables, so only outer loop is a safe point
+            if depth == 0 {
+                self.check_heap_limit();
+            }
+            if let Some(exception) = self.take_pending_exception() {
+                self.handle_exception(exception);
+            }
+            if depth == 0 {
+                self.gc_safepoint();
+            }
+            if self.exit_status().is_some() || self.stack.depth() <= depth {
+                break;
             }
         }
     }
 
+    /// Unwinds stack up to the nearest matching handler. Uncaught exception terminates the program.
+    fn handle_exception(&self, mut exception: HeapPtr) {
+        let exception_class_idx = match self.heap.class_of(exception) {
+            Some(class_idx) => class_idx,
+            None => panic!("Cannot throw {:?}", self.heap.get_object(exception)),
+        };
+        while !self.stack.is_empty() {
+            let handler_pc = {
+                let frame = self.stack.top_frame();
+                if frame.modifiers.contains(FrameModifiers::NATIVE_BRIDGE) {
+                    //rethrown after native method returns
+                    self.throw(exception);
+                    return;
+                }
+                let (class_idx, method_idx) = frame.class_method_idxs;
+                //pc is already moved to the next instruction
+                let pc = frame.pc.get() - 1;
+                let handlers = self
+                    .get_method(class_idx, method_idx)
+                    .exception_table
+                    .clone();
+                handlers
+                    .iter()
+                    .find(|handler| {
+                        handler.start_pc <= pc
+                            && pc < handler.end_pc
+                            && (handler.catch_type == 0
+                                || self.is_subclass_of(
+                                    exception_class_idx,
+                                    self.resolve_class_reference(handler.catch_type),
+                                ))
+                    })
+                    .map(|handler| handler.handler_pc)
+            };
+            if let Some(handler_pc) = handler_pc {
+                debug!("Catch {} at {}", exception, handler_pc);
+                let frame = self.stack.top_frame();
+                frame.clear_stack();
+                frame.push(Value::Reference(exception));
+                frame.pc.set(handler_pc);
+                return;
+            }
+            self.abandon_frame_region();
+            let modifiers = self.stack.top_frame().modifiers;
+            if modifiers.contains(FrameModifiers::TRACED) {
+                self.trace_throw(exception);
+            }
+            self.exit_pure_call();
+            self.stack.pop_frame();
+            if modifiers.contains(FrameModifiers::ARENA) {
+                self.heap.copy_out(exception);
+                if let Some(relocation) = self.heap.exit_arena() {
+                    self.relocate_roots(&relocation);
+                    exception = relocation.forward(exception);
+                }
+            }
+        }
+        let message = format!(
+            "Exception in thread \"main\" {}",
+            throwable_stack_trace(self, exception)
+        );
+        self.streams.write(STDERR, message.as_bytes());
+        self.exit(1);
+    }
+
+    /// Frames of java stack, innermost first, as `Class.method(File.java:42)`
+    pub fn stack_trace(&self) -> Vec<String> {
+        self.stack
+            .inspect()
+            .iter()
+            .rev()
+            .filter(|frame| !frame.modifiers.contains(FrameModifiers::NATIVE_BRIDGE))
+            .map(|frame| {
+                let (class_idx, method_idx) = frame.class_method_idxs;
+                let class = self.get_class(class_idx);
+                let method = &class.methods[method_idx];
+                //pc is already moved to the next instruction
+                let line = method.line_number(frame.pc.get().saturating_sub(1));
+                let location = match (&class.source_file, line) {
+                    (Some(file), Some(line)) => format!("{}:{}", file, line),
+                    (Some(file), None) => file.clone(),
+                    (None, _) => "Unknown Source".to_string(),
+                };
+                let class_name = class.name.replace('/', ".");
+                format!("{}.{}({})", class_name, method.name, location)
+            })
+            .collect()
+    }
+
     fn do_command(&self) -> StackModification {
+        self.enter_region();
+        self.enter_pure_context();
         let frame = &self.stack.top_frame();
         let code = self;
+        let op_pc = frame.pc.get();
+        if self.heap.is_debug() {
+            self.heap.set_site(frame.class_method_idxs);
+        }
         let cmd = frame.read_u8(code);
         trace!("Process cmd [{}] at [{}]", cmd, frame.pc.get() - 1);
         match cmd {
@@ -287,19 +709,40 @@ impl VM {
             7 => frame.push(Value::Int(4)),
             //iconst_5
             8 => frame.push(Value::Int(5)),
+            //lconst_0
+            9 => frame.push(Value::Long(0)),
+            //lconst_1
+            10 => frame.push(Value::Long(1)),
+            //fconst_0, fconst_1, fconst_2
+            11..=13 => frame.push(Value::Float((cmd - 11) as f32)),
+            //dconst_0, dconst_1
+            14 | 15 => frame.push(Value::Double((cmd - 14) as f64)),
             //bipush
             16 => {
-                let byte_value = frame.read_u8(code);
+                let byte_value = frame.read_i8(code);
                 frame.push(Value::Int(byte_value as i32))
             }
-            //ldc
-            18 => {
-                let idx = frame.read_u8(code) as usize;
-                let cp_entry = &self.program.borrow().constant_pool[frame.cp_offset + idx];
-                match cp_entry {
-                    ConstantPoolValue::String(value) => frame.push(value.clone()),
-                    ConstantPoolValue::Const(value) => frame.push(value.clone()),
-                    _ => panic!("ldc {:?} not supported yet", cp_entry),
+            //sipush
+            17 => {
+                let short_value = frame.read_u16(code) as i16;
+                frame.push(Value::Int(short_value as i32))
+            }
+            //ldc, ldc_w, ldc2_w
+            18..=20 => {
+                let idx = if cmd == 18 {
+                    frame.read_u8(code) as usize
+                } else {
+                    frame.read_u16(code) as usize
+                };
+                let cpi = frame.cp_offset + idx;
+                match self.get_constant_pool_value(cpi) {
+                    ConstantPoolValue::String(value) => frame.push(value),
+                    ConstantPoolValue::Const(value) => frame.push(value),
+                    ConstantPoolValue::UnresolvedClassRef { .. } | ConstantPoolValue::Class(_) => {
+                        let class_idx = self.resolve_class_reference(cpi);
+                        frame.push(Value::Reference(class_mirror(self, class_idx)));
+                    }
+                    cp_entry => panic!("ldc {:?} not supported yet", cp_entry),
                 }
             }
             //iload
@@ -307,6 +750,16 @@ impl VM {
                 let idx = frame.read_u8(code);
                 frame.push(frame.get_local(idx).clone())
             }
+            //lload
+            22 => {
+                let idx = frame.read_u8(code);
+                frame.push(frame.get_local(idx).clone())
+            }
+            //fload, dload
+            23 | 24 => {
+                let idx = frame.read_u8(code);
+                frame.push(frame.get_local(idx).clone())
+            }
             //iload_0
             26 => frame.push(frame.get_local(0).clone()),
             //iload_1
@@ -316,6 +769,23 @@ impl VM {
             //iload_3
             29 => frame.push(frame.get_local(3).clone()),
 
+            //lload_0
+            30 => frame.push(frame.get_local(0).clone()),
+            //lload_1
+            31 => frame.push(frame.get_local(1).clone()),
+            //lload_2
+            32 => frame.push(frame.get_local(2).clone()),
+            //lload_3
+            33 => frame.push(frame.get_local(3).clone()),
+
+            //fload_0..3, dload_0..3
+            34..=41 => frame.push(frame.get_local((cmd - 34) % 4).clone()),
+
+            //aload
+            25 => {
+                let idx = frame.read_u8(code);
+                frame.push(frame.get_local(idx).clone())
+            }
             //aload_0
             42 => frame.push(frame.get_local(0).clone()),
             //aload_1
@@ -338,6 +808,18 @@ impl VM {
                 let value = frame.pop();
                 frame.set_local(idx, value);
             }
+            //lstore
+            55 => {
+                let idx = frame.read_u8(code);
+                let value = frame.pop();
+                frame.set_local(idx, value);
+            }
+            //fstore, dstore
+            56 | 57 => {
+                let idx = frame.read_u8(code);
+                let value = frame.pop();
+                frame.set_local(idx, value);
+            }
             //astore
             58 => {
                 let idx = frame.read_u8(code);
@@ -365,6 +847,33 @@ impl VM {
                 frame.set_local(3, value)
             }
 
+            //lstore_0
+            63 => {
+                let value = frame.pop();
+                frame.set_local(0, value)
+            }
+            //lstore_1
+            64 => {
+                let value = frame.pop();
+                frame.set_local(1, value)
+            }
+            //lstore_2
+            65 => {
+                let value = frame.pop();
+                frame.set_local(2, value)
+            }
+            //lstore_3
+            66 => {
+                let value = frame.pop();
+                frame.set_local(3, value)
+            }
+
+            //fstore_0..3, dstore_0..3
+            67..=74 => {
+                let value = frame.pop();
+                frame.set_local((cmd - 67) % 4, value)
+            }
+
             //astore_0
             75 => {
                 let value = frame.pop();
@@ -400,6 +909,13 @@ impl VM {
                 frame.pop();
             }
 
+            //pop2
+            88 => {
+                if !frame.pop().is_wide() {
+                    frame.pop();
+                }
+            }
+
             // dup
             89 => {
                 frame.dup();
@@ -409,17 +925,234 @@ impl VM {
                 frame.dup_x1();
             }
 
+            //dup2
+            92 => {
+                frame.dup2();
+            }
+
             //iadd
             96 => {
                 let i2: i32 = frame.pop().try_into().unwrap();
                 let i1: i32 = frame.pop().try_into().unwrap();
-                frame.push(Value::Int(i1 + i2));
+                frame.push(Value::Int(i1.wrapping_add(i2)));
+            }
+            //ladd
+            97 => {
+                let l2: i64 = frame.pop().try_into().unwrap();
+                let l1: i64 = frame.pop().try_into().unwrap();
+                frame.push(Value::Long(l1.wrapping_add(l2)));
+            }
+            //fadd
+            98 => {
+                let f2: f32 = frame.pop().try_into().unwrap();
+                let f1: f32 = frame.pop().try_into().unwrap();
+                frame.push(Value::Float(f1 + f2));
+            }
+            //dadd
+            99 => {
+                let d2: f64 = frame.pop().try_into().unwrap();
+                let d1: f64 = frame.pop().try_into().unwrap();
+                frame.push(Value::Double(d1 + d2));
             }
             //isub
             100 => {
                 let i2: i32 = frame.pop().try_into().unwrap();
                 let i1: i32 = frame.pop().try_into().unwrap();
-                frame.push(Value::Int(i1 - i2));
+                frame.push(Value::Int(i1.wrapping_sub(i2)));
+            }
+            //lsub
+            101 => {
+                let l2: i64 = frame.pop().try_into().unwrap();
+                let l1: i64 = frame.pop().try_into().unwrap();
+                frame.push(Value::Long(l1.wrapping_sub(l2)));
+            }
+            //fsub
+            102 => {
+                let f2: f32 = frame.pop().try_into().unwrap();
+                let f1: f32 = frame.pop().try_into().unwrap();
+                frame.push(Value::Float(f1 - f2));
+            }
+            //dsub
+            103 => {
+                let d2: f64 = frame.pop().try_into().unwrap();
+                let d1: f64 = frame.pop().try_into().unwrap();
+                frame.push(Value::Double(d1 - d2));
+            }
+            //imul
+            104 => {
+                let i2: i32 = frame.pop().try_into().unwrap();
+                let i1: i32 = frame.pop().try_into().unwrap();
+                frame.push(Value::Int(i1.wrapping_mul(i2)));
+            }
+            //lmul
+            105 => {
+                let l2: i64 = frame.pop().try_into().unwrap();
+                let l1: i64 = frame.pop().try_into().unwrap();
+                frame.push(Value::Long(l1.wrapping_mul(l2)));
+            }
+            //fmul
+            106 => {
+                let f2: f32 = frame.pop().try_into().unwrap();
+                let f1: f32 = frame.pop().try_into().unwrap();
+                frame.push(Value::Float(f1 * f2));
+            }
+            //dmul
+            107 => {
+                let d2: f64 = frame.pop().try_into().unwrap();
+                let d1: f64 = frame.pop().try_into().unwrap();
+                frame.push(Value::Double(d1 * d2));
+            }
+            //idiv
+            108 => {
+                let i2: i32 = frame.pop().try_into().unwrap();
+                let i1: i32 = frame.pop().try_into().unwrap();
+                if i2 == 0 {
+                    return self.throw_new(ARITHMETIC_EXCEPTION, "/ by zero");
+                }
+                frame.push(Value::Int(i1.wrapping_div(i2)));
+            }
+            //ldiv
+            109 => {
+                let l2: i64 = frame.pop().try_into().unwrap();
+                let l1: i64 = frame.pop().try_into().unwrap();
+                if l2 == 0 {
+                    return self.throw_new(ARITHMETIC_EXCEPTION, "/ by zero");
+                }
+                frame.push(Value::Long(l1.wrapping_div(l2)));
+            }
+            //fdiv
+            110 => {
+                let f2: f32 = frame.pop().try_into().unwrap();
+                let f1: f32 = frame.pop().try_into().unwrap();
+                frame.push(Value::Float(f1 / f2));
+            }
+            //ddiv
+            111 => {
+                let d2: f64 = frame.pop().try_into().unwrap();
+                let d1: f64 = frame.pop().try_into().unwrap();
+                frame.push(Value::Double(d1 / d2));
+            }
+            //irem
+            112 => {
+                let i2: i32 = frame.pop().try_into().unwrap();
+                let i1: i32 = frame.pop().try_into().unwrap();
+                if i2 == 0 {
+                    return self.throw_new(ARITHMETIC_EXCEPTION, "/ by zero");
+                }
+                frame.push(Value::Int(i1.wrapping_rem(i2)));
+            }
+            //lrem
+            113 => {
+                let l2: i64 = frame.pop().try_into().unwrap();
+                let l1: i64 = frame.pop().try_into().unwrap();
+                if l2 == 0 {
+                    return self.throw_new(ARITHMETIC_EXCEPTION, "/ by zero");
+                }
+                frame.push(Value::Long(l1.wrapping_rem(l2)));
+            }
+            //frem
+            114 => {
+                let f2: f32 = frame.pop().try_into().unwrap();
+                let f1: f32 = frame.pop().try_into().unwrap();
+                frame.push(Value::Float(f1 % f2));
+            }
+            //drem
+            115 => {
+                let d2: f64 = frame.pop().try_into().unwrap();
+                let d1: f64 = frame.pop().try_into().unwrap();
+                frame.push(Value::Double(d1 % d2));
+            }
+            //ineg
+            116 => {
+                let i1: i32 = frame.pop().try_into().unwrap();
+                frame.push(Value::Int(i1.wrapping_neg()));
+            }
+            //lneg
+            117 => {
+                let l1: i64 = frame.pop().try_into().unwrap();
+                frame.push(Value::Long(l1.wrapping_neg()));
+            }
+            //fneg
+            118 => {
+                let f1: f32 = frame.pop().try_into().unwrap();
+                frame.push(Value::Float(-f1));
+            }
+            //dneg
+            119 => {
+                let d1: f64 = frame.pop().try_into().unwrap();
+                frame.push(Value::Double(-d1));
+            }
+            //ishl
+            120 => {
+                let i2: i32 = frame.pop().try_into().unwrap();
+                let i1: i32 = frame.pop().try_into().unwrap();
+                frame.push(Value::Int(i1.wrapping_shl(i2 as u32)));
+            }
+            //lshl
+            121 => {
+                let i2: i32 = frame.pop().try_into().unwrap();
+                let l1: i64 = frame.pop().try_into().unwrap();
+                frame.push(Value::Long(l1.wrapping_shl(i2 as u32)));
+            }
+            //ishr
+            122 => {
+                let i2: i32 = frame.pop().try_into().unwrap();
+                let i1: i32 = frame.pop().try_into().unwrap();
+                frame.push(Value::Int(i1.wrapping_shr(i2 as u32)));
+            }
+            //lshr
+            123 => {
+                let i2: i32 = frame.pop().try_into().unwrap();
+                let l1: i64 = frame.pop().try_into().unwrap();
+                frame.push(Value::Long(l1.wrapping_shr(i2 as u32)));
+            }
+            //iushr
+            124 => {
+                let i2: i32 = frame.pop().try_into().unwrap();
+                let i1: i32 = frame.pop().try_into().unwrap();
+                frame.push(Value::Int((i1 as u32).wrapping_shr(i2 as u32) as i32));
+            }
+            //lushr
+            125 => {
+                let i2: i32 = frame.pop().try_into().unwrap();
+                let l1: i64 = frame.pop().try_into().unwrap();
+                frame.push(Value::Long((l1 as u64).wrapping_shr(i2 as u32) as i64));
+            }
+            //iand
+            126 => {
+                let i2: i32 = frame.pop().try_into().unwrap();
+                let i1: i32 = frame.pop().try_into().unwrap();
+                frame.push(Value::Int(i1 & i2));
+            }
+            //land
+            127 => {
+                let l2: i64 = frame.pop().try_into().unwrap();
+                let l1: i64 = frame.pop().try_into().unwrap();
+                frame.push(Value::Long(l1 & l2));
+            }
+            //ior
+            128 => {
+                let i2: i32 = frame.pop().try_into().unwrap();
+                let i1: i32 = frame.pop().try_into().unwrap();
+                frame.push(Value::Int(i1 | i2));
+            }
+            //lor
+            129 => {
+                let l2: i64 = frame.pop().try_into().unwrap();
+                let l1: i64 = frame.pop().try_into().unwrap();
+                frame.push(Value::Long(l1 | l2));
+            }
+            //ixor
+            130 => {
+                let i2: i32 = frame.pop().try_into().unwrap();
+                let i1: i32 = frame.pop().try_into().unwrap();
+                frame.push(Value::Int(i1 ^ i2));
+            }
+            //lxor
+            131 => {
+                let l2: i64 = frame.pop().try_into().unwrap();
+                let l1: i64 = frame.pop().try_into().unwrap();
+                frame.push(Value::Long(l1 ^ l2));
             }
 
             //iinc
@@ -427,7 +1160,105 @@ impl VM {
                 let var_idx = frame.read_u8(code);
                 let delta = frame.read_i8(code) as i32;
                 let current_value: i32 = (frame.get_local(var_idx).clone()).try_into().unwrap();
-                frame.set_local(var_idx, Value::Int(current_value + delta))
+                frame.set_local(var_idx, Value::Int(current_value.wrapping_add(delta)))
+            }
+
+            //i2l
+            133 => {
+                let i1: i32 = frame.pop().try_into().unwrap();
+                frame.push(Value::Long(i1 as i64));
+            }
+            //i2f
+            134 => {
+                let i1: i32 = frame.pop().try_into().unwrap();
+                frame.push(Value::Float(i1 as f32));
+            }
+            //i2d
+            135 => {
+                let i1: i32 = frame.pop().try_into().unwrap();
+                frame.push(Value::Double(i1 as f64));
+            }
+            //l2i
+            136 => {
+                let l1: i64 = frame.pop().try_into().unwrap();
+                frame.push(Value::Int(l1 as i32));
+            }
+            //l2f
+            137 => {
+                let l1: i64 = frame.pop().try_into().unwrap();
+                frame.push(Value::Float(l1 as f32));
+            }
+            //l2d
+            138 => {
+                let l1: i64 = frame.pop().try_into().unwrap();
+                frame.push(Value::Double(l1 as f64));
+            }
+            //f2i
+            139 => {
+                let f1: f32 = frame.pop().try_into().unwrap();
+                frame.push(Value::Int(f1 as i32));
+            }
+            //f2l
+            140 => {
+                let f1: f32 = frame.pop().try_into().unwrap();
+                frame.push(Value::Long(f1 as i64));
+            }
+            //f2d
+            141 => {
+                let f1: f32 = frame.pop().try_into().unwrap();
+                frame.push(Value::Double(f1 as f64));
+            }
+            //d2i
+            142 => {
+                let d1: f64 = frame.pop().try_into().unwrap();
+                frame.push(Value::Int(d1 as i32));
+            }
+            //d2l
+            143 => {
+                let d1: f64 = frame.pop().try_into().unwrap();
+                frame.push(Value::Long(d1 as i64));
+            }
+            //d2f
+            144 => {
+                let d1: f64 = frame.pop().try_into().unwrap();
+                frame.push(Value::Float(d1 as f32));
+            }
+            //i2b
+            145 => {
+                let i1: i32 = frame.pop().try_into().unwrap();
+                frame.push(Value::Int(i1 as i8 as i32));
+            }
+            //i2c
+            146 => {
+                let i1: i32 = frame.pop().try_into().unwrap();
+                frame.push(Value::Int(i1 as u16 as i32));
+            }
+            //i2s
+            147 => {
+                let i1: i32 = frame.pop().try_into().unwrap();
+                frame.push(Value::Int(i1 as i16 as i32));
+            }
+            //lcmp
+            148 => {
+                let l2: i64 = frame.pop().try_into().unwrap();
+                let l1: i64 = frame.pop().try_into().unwrap();
+                frame.push(Value::Int(l1.cmp(&l2) as i32));
+            }
+            //fcmpl, fcmpg: NaN gives -1 or 1 respectively
+            149 | 150 => {
+                let f2: f32 = frame.pop().try_into().unwrap();
+                let f1: f32 = frame.pop().try_into().unwrap();
+                let nan_result = if cmd == 149 { -1 } else { 1 };
+                let result = f1.partial_cmp(&f2).map_or(nan_result, |x| x as i32);
+                frame.push(Value::Int(result));
+            }
+            //dcmpl, dcmpg
+            151 | 152 => {
+                let d2: f64 = frame.pop().try_into().unwrap();
+                let d1: f64 = frame.pop().try_into().unwrap();
+                let nan_result = if cmd == 151 { -1 } else { 1 };
+                let result = d1.partial_cmp(&d2).map_or(nan_result, |x| x as i32);
+                frame.push(Value::Int(result));
             }
 
             //ifeq
@@ -515,6 +1346,24 @@ impl VM {
                     frame.jmp_relative(then_offset)
                 }
             }
+            //if_acmpeq
+            165 => {
+                let then_offset = frame.read_code_offset(code);
+                let ptr2: HeapPtr = frame.pop().try_into().unwrap();
+                let ptr1: HeapPtr = frame.pop().try_into().unwrap();
+                if ptr1 == ptr2 {
+                    frame.jmp_relative(then_offset)
+                }
+            }
+            //if_acmpne
+            166 => {
+                let then_offset = frame.read_code_offset(code);
+                let ptr2: HeapPtr = frame.pop().try_into().unwrap();
+                let ptr1: HeapPtr = frame.pop().try_into().unwrap();
+                if ptr1 != ptr2 {
+                    frame.jmp_relative(then_offset)
+                }
+            }
             //goto
             167 => {
                 let offset = frame.read_code_offset(code);
@@ -526,11 +1375,21 @@ impl VM {
                 let ret_value = frame.pop();
                 return StackModification::Return(ret_value);
             }
+            //lreturn
+            173 => {
+                let ret_value = frame.pop();
+                return StackModification::Return(ret_value);
+            }
             //freturn
             174 => {
                 let ret_value = frame.pop();
                 return StackModification::Return(ret_value);
             }
+            //dreturn
+            175 => {
+                let ret_value = frame.pop();
+                return StackModification::Return(ret_value);
+            }
             //areturn
             176 => {
                 let ret_value = frame.pop();
@@ -543,25 +1402,54 @@ impl VM {
             //invokevirtual
             182 => {
                 let cpi = frame.cp_offset + frame.read_u16(code) as usize;
-                let (mut class_idx, mut method_idx) = self.resolve_method_reference(cpi);
-                //get reference
-                let class = &self.program.borrow().classes[class_idx];
-                let method = &class.methods[method_idx];
-                let args_count = method.signature.arguments.len();
-                if let Value::Reference(heap_ptr) = frame.pick(args_count) {
-                    let value = self.heap.get_value(heap_ptr);
-                    if let Value::ClassIndex(real_class_idx, _) = value {
-                        //check vmt
-                        let real_class = &self.program.borrow().classes[real_class_idx];
-                        (class_idx, method_idx) = *real_class
+                let (class_idx, method_idx) = self.resolve_method_reference(cpi);
+                let (args_count, method_name) = {
+                    let method = self.get_method(class_idx, method_idx);
+                    (method.signature.arguments.len(), method.name.clone())
+                };
+                let receiver: HeapPtr = frame.pick(args_count).try_into().unwrap();
+                if receiver == 0 {
+                    let message = format!("Cannot invoke \"{}()\" on null", method_name);
+                    return self.throw_new(NULL_POINTER_EXCEPTION, &message);
+                }
+                match self.heap.class_of(receiver) {
+                    //check vmt
+                    Some(real_class_idx) => {
+                        let real_class = self.get_class(real_class_idx);
+                        let (class_idx, method_idx) = *real_class
                             .vmt
                             .mapping
                             .get(&(class_idx, method_idx))
                             .unwrap_or(&(class_idx, method_idx));
                         return StackModification::Call(class_idx, method_idx);
                     }
+                    //arrays have methods of java/lang/Object only
+                    None => return StackModification::Call(class_idx, method_idx),
                 }
-                panic!("Cannot find object in stack :(")
+            }
+            //invokeinterface, method is looked up by name in the class of the receiver
+            185 => {
+                let cpi = frame.cp_offset + frame.read_u16(code) as usize;
+                frame.read_u8(code); //count
+                frame.read_u8(code); //0
+                let (method_name, descriptor) = match self.get_constant_pool_value(cpi) {
+                    ConstantPoolValue::UnresolvedMethodRef {
+                        method_name,
+                        signature,
+                        ..
+                    } => (method_name, signature),
+                    value => panic!("Unexpected cp entry {:?}", value),
+                };
+                let args_count = parse_signature(&descriptor).arguments.len();
+                let receiver: HeapPtr = frame.pick(args_count).try_into().unwrap();
+                if receiver == 0 {
+                    let message = format!("Cannot invoke \"{}()\" on null", method_name);
+                    return self.throw_new(NULL_POINTER_EXCEPTION, &message);
+                }
+                let (class_idx, method_idx) = self
+                    .find_object_method(receiver, &method_name, &descriptor)
+                    .unwrap_or_else(|| panic!("Cannot find method {} {}", method_name, descriptor));
+                return StackModification::Call(class_idx, method_idx);
             }
             //invokespecial
             183 => {
@@ -575,8 +1463,35 @@ impl VM {
             184 => {
                 let cpi = frame.cp_offset + frame.read_u16(code) as usize;
                 let (class_idx, method_idx) = self.resolve_method_reference(cpi);
+                if let Some(initializer) = self.next_class_initializer(class_idx) {
+                    frame.pc.set(op_pc);
+                    return StackModification::Call(initializer.0, initializer.1);
+                }
                 return StackModification::Call(class_idx, method_idx);
             }
+            //getstatic
+            178 => {
+                let cpi = frame.cp_offset + frame.read_u16(code) as usize;
+                let (class_idx, field_idx) = self.resolve_static_field_reference(cpi);
+                if let Some(initializer) = self.next_class_initializer(class_idx) {
+                    frame.pc.set(op_pc);
+                    return StackModification::Call(initializer.0, initializer.1);
+                }
+                frame.push(self.get_static(class_idx, field_idx));
+            }
+            //putstatic
+            179 => {
+                let cpi = frame.cp_offset + frame.read_u16(code) as usize;
+                let (class_idx, field_idx) = self.resolve_static_field_reference(cpi);
+                if let Some(initializer) = self.next_class_initializer(class_idx) {
+                    frame.pc.set(op_pc);
+                    return StackModification::Call(initializer.0, initializer.1);
+                }
+                let value = frame.pop();
+                self.check_static_escape(class_idx, field_idx, &value);
+                self.check_static_side_effect(class_idx, field_idx);
+                self.set_static(class_idx, field_idx, value);
+            }
             // getfield
             180 => {
                 let cpi = frame.cp_offset + frame.read_u16(code) as usize;
@@ -598,9 +1513,20 @@ impl VM {
                 let cpi = frame.cp_offset + frame.read_u16(code) as usize;
 
                 let class_idx = self.resolve_class_reference(cpi);
+                if let Some(initializer) = self.next_class_initializer(class_idx) {
+                    frame.pc.set(op_pc);
+                    return StackModification::Call(initializer.0, initializer.1);
+                }
+                let size = self.instance_size(class_idx);
+                if let Some(modification) = self.reserve_heap(frame, op_pc, size) {
+                    return modification;
+                }
                 let ptr = self.new_object(class_idx);
                 frame.push(Value::Reference(ptr));
-                if frame.modifiers.contains(FrameModifiers::AUTO_FREE) {
+                //without escape checks objects are freed by frame, otherwise by their region
+                if frame.modifiers.contains(FrameModifiers::AUTO_FREE)
+                    && !self.heap.is_escape_checked()
+                {
                     frame.on_instantiate(ptr);
                 }
             }
@@ -609,17 +1535,60 @@ impl VM {
                 let cpi = frame.cp_offset + frame.read_u16(code) as usize;
                 let class_idx = self.resolve_class_reference(cpi);
 
+                //elements are instantiated too
+                let count: i32 = frame.pick(0).try_into().unwrap();
+                if count < 0 {
+                    frame.pop();
+                    return self.throw_new(NEGATIVE_ARRAY_SIZE_EXCEPTION, &count.to_string());
+                }
+                let count = count as usize;
+                let size = HeapMemory::array_size(&Type::Reference, count)
+                    + count * self.instance_size(class_idx);
+                if let Some(modification) = self.reserve_heap(frame, op_pc, size) {
+                    return modification;
+                }
                 let count: i32 = frame.pop().try_into().unwrap();
                 let ptr = self.new_object_array(class_idx, count);
                 frame.push(Value::Reference(ptr));
-                if frame.modifiers.contains(FrameModifiers::AUTO_FREE) {
+                //without escape checks objects are freed by frame, otherwise by their region
+                if frame.modifiers.contains(FrameModifiers::AUTO_FREE)
+                    && !self.heap.is_escape_checked()
+                {
                     frame.on_instantiate(ptr);
                 }
             }
             //arraylength
             190 => {
                 let ptr: HeapPtr = frame.pop().try_into().unwrap();
-                frame.push(self.heap.get_field(ptr, 0)); //length is 0th field
+                frame.push(Value::Int(self.heap.array_length(ptr) as i32));
+            }
+            //checkcast
+            192 => {
+                let cpi = frame.cp_offset + frame.read_u16(code) as usize;
+                let ptr: HeapPtr = frame.pick(0).try_into().unwrap();
+                if ptr != 0 && !self.is_instance(ptr, cpi) {
+                    let message = format!(
+                        "class {} cannot be cast to class {}",
+                        self.heap_class_name(ptr),
+                        self.resolve_class_name(cpi).replace('/', ".")
+                    );
+                    return self.throw_new(CLASS_CAST_EXCEPTION, &message);
+                }
+            }
+            //instanceof
+            193 => {
+                let cpi = frame.cp_offset + frame.read_u16(code) as usize;
+                let ptr: HeapPtr = frame.pop().try_into().unwrap();
+                let result = ptr != 0 && self.is_instance(ptr, cpi);
+                frame.push(Value::Int(result as i32));
+            }
+            //athrow
+            191 => {
+                let exception: HeapPtr = frame.pop().try_into().unwrap();
+                if exception == 0 {
+                    return self.throw_new(NULL_POINTER_EXCEPTION, "Cannot throw null");
+                }
+                return StackModification::Throw(exception);
             }
             //ifnull
             198 => {
@@ -640,7 +1609,75 @@ impl VM {
 
             _ => panic!("Unknown code: {}", cmd),
         }
-        return StackModification::Nop;
+        StackModification::Nop
+    }
+
+    fn is_instance(&self, ptr: HeapPtr, cpi: ConstantPoolIdx) -> bool {
+        let target_name = self.resolve_class_name(cpi);
+        match self.heap.class_of(ptr) {
+            Some(class_idx) => self.is_instance_of(class_idx, &target_name),
+            None => target_name == OBJECT_CLASS || target_name.starts_with('['),
+        }
+    }
+
+    pub(crate) fn heap_class_name(&self, ptr: HeapPtr) -> String {
+        match self.heap.get_object(ptr) {
+            Some(HeapObject::Array(Type::Reference, class_idx)) => {
+                format!("[L{};", self.get_class(class_idx).name.replace('/', "."))
+            }
+            Some(HeapObject::Array(element_type, _)) => format!("[{}", element_type.descriptor()),
+            _ => match self.heap.class_of(ptr) {
+                Some(class_idx) => self.get_class(class_idx).name.replace('/', "."),
+                None => format!("{:?}", self.heap.get_object(ptr)),
+            },
+        }
+    }
+
+    //class name without loading the class, so interfaces could be checked without class files
+    fn resolve_class_name(&self, cpi: ConstantPoolIdx) -> String {
+        match self.get_constant_pool_value(cpi) {
+            ConstantPoolValue::UnresolvedClassRef { class_name } => class_name,
+            ConstantPoolValue::Class(class_idx) => self.get_class(class_idx).name.clone(),
+            value => panic!("Unexpected cp entry {:?}", value),
+        }
+    }
+
+    //when heap limit is reached, instruction is retried after collection at the safe point.
+    //natives in progress prevent collection, so error is thrown right away then
+    fn reserve_heap(
+        &self,
+        frame: &StackFrame,
+        op_pc: CodePtr,
+        size: usize,
+    ) -> Option<StackModification> {
+        if self.heap.has_space(size) {
+            return None;
+        }
+        if self.native_calls.get() == 0 && !self.heap.is_collected() {
+            self.heap.request_gc();
+            frame.pc.set(op_pc);
+            return Some(StackModification::Nop);
+        }
+        self.dump_heap_if(HEAP_DUMP_ON_OOM_PROPERTY);
+        Some(self.throw_new(OUT_OF_MEMORY_ERROR, "Java heap space"))
+    }
+
+    //natives allocate without checking the limit, so heap which grew over it is collected
+    //and OutOfMemoryError is thrown if objects still do not fit
+    fn check_heap_limit(&self) {
+        if !self.heap.take_over_limit() {
+            return;
+        }
+        self.collect_garbage(true);
+        if self.heap.used_space() > self.heap.max_size() {
+            self.dump_heap_if(HEAP_DUMP_ON_OOM_PROPERTY);
+            self.throw(new_throwable(self, OUT_OF_MEMORY_ERROR, "Java heap space"));
+            self.heap.take_over_limit(); //error itself does not fit either
+        }
+    }
+
+    fn throw_new(&self, class_name: &str, message: &str) -> StackModification {
+        StackModification::Throw(new_throwable(self, class_name, message))
     }
 
     fn iif_prepare(frame: &StackFrame, code: &VM) -> (i16, i32, i32) {
@@ -664,27 +1701,28 @@ impl VM {
                 method_name,
                 signature,
             } => {
+                //inherited methods are declared in one of superclasses
                 let class_idx = self.get_or_load_class_idx(&class_name);
-                let method_idx = self
-                    .get_method_idx(class_idx, method_name.clone(), signature.clone())
-                    .expect(&format!(
-                        "Cannot find method {} {} {}",
-                        class_name, method_name, signature
-                    ));
+                let (class_idx, method_idx) = self
+                    .find_virtual_method(class_idx, &method_name, &signature)
+                    .unwrap_or_else(|| {
+                        panic!(
+                            "Cannot find method {} {} {}",
+                            class_name, method_name, signature
+                        )
+                    });
                 self.set_constant_pool_value(
                     cpi,
                     ConstantPoolValue::MethodRef(class_idx, method_idx),
                 );
-                return (class_idx, method_idx);
-            }
-            ConstantPoolValue::MethodRef(class_idx, method_idx) => {
-                return (class_idx, method_idx);
+                (class_idx, method_idx)
             }
+            ConstantPoolValue::MethodRef(class_idx, method_idx) => (class_idx, method_idx),
             _ => panic!("Unexpected cp entry {:?}", value),
         }
     }
 
-    fn resolve_field_reference(&self, cpi: ConstantPoolIdx) -> (ClassIdx, FieldIdx) {
+    pub(crate) fn resolve_field_reference(&self, cpi: ConstantPoolIdx) -> (ClassIdx, FieldIdx) {
         let value = self.get_constant_pool_value(cpi);
         match value {
             ConstantPoolValue::UnresolvedFieldRef {
@@ -694,16 +1732,54 @@ impl VM {
                 let class_idx = self.get_or_load_class_idx(&class_name);
                 let field_idx = self
                     .get_field_idx(class_idx, field_name.clone())
-                    .expect(&format!("Cannot find field {} {}", class_name, field_name));
+                    .unwrap_or_else(|| panic!("Cannot find field {} {}", class_name, field_name));
                 self.set_constant_pool_value(
                     cpi,
                     ConstantPoolValue::FieldRef(class_idx, field_idx),
                 );
-                return (class_idx, field_idx);
+                (class_idx, field_idx)
             }
-            ConstantPoolValue::FieldRef(class_idx, field_idx) => {
-                return (class_idx, field_idx);
+            ConstantPoolValue::FieldRef(class_idx, field_idx) => (class_idx, field_idx),
+            _ => panic!("Unexpected cp entry {:?}", value),
+        }
+    }
+
+    //static fields are looked up in superclasses too, result points to declaring class
+    pub(crate) fn resolve_static_field_reference(
+        &self,
+        cpi: ConstantPoolIdx,
+    ) -> (ClassIdx, FieldIdx) {
+        let value = self.get_constant_pool_value(cpi);
+        match value {
+            ConstantPoolValue::UnresolvedFieldRef {
+                class_name,
+                field_name,
+            } => {
+                let mut class_idx = self.get_or_load_class_idx(&class_name);
+                let field_idx = loop {
+                    let field_idx = self
+                        .program
+                        .borrow()
+                        .static_field_names_to_idxs
+                        .get(&(class_idx, field_name.clone()))
+                        .copied();
+                    if let Some(field_idx) = field_idx {
+                        break field_idx;
+                    }
+                    assert_ne!(
+                        class_idx, 0,
+                        "Cannot find static field {} {}",
+                        class_name, field_name
+                    );
+                    class_idx = self.get_class(class_idx).super_class_idx;
+                };
+                self.set_constant_pool_value(
+                    cpi,
+                    ConstantPoolValue::StaticFieldRef(class_idx, field_idx),
+                );
+                (class_idx, field_idx)
             }
+            ConstantPoolValue::StaticFieldRef(class_idx, field_idx) => (class_idx, field_idx),
             _ => panic!("Unexpected cp entry {:?}", value),
         }
     }
@@ -714,11 +1790,9 @@ impl VM {
             ConstantPoolValue::UnresolvedClassRef { class_name } => {
                 let class_idx = self.get_or_load_class_idx(&class_name);
                 self.set_constant_pool_value(cpi, ConstantPoolValue::Class(class_idx));
-                return class_idx;
-            }
-            ConstantPoolValue::Class(class_idx) => {
-                return class_idx;
+                class_idx
             }
+            ConstantPoolValue::Class(class_idx) => class_idx,
             _ => panic!("Unexpected cp entry {:?}", value),
         }
     }
@@ -733,7 +1807,7 @@ impl VM {
         program_ref
             .method_names_to_idxs
             .get(&(class_idx, method_name, method_signature))
-            .map(|x| *x)
+            .copied()
     }
 
     fn get_field_idx(&self, class_idx: ClassIdx, field_name: String) -> Option<FieldIdx> {
@@ -741,16 +1815,117 @@ impl VM {
         program_ref
             .field_names_to_idxs
             .get(&(class_idx, field_name))
-            .map(|x| *x)
+            .copied()
     }
 }
 
-fn is_return(op_code: u8) -> bool {
-    (172..=177).contains(&op_code)
-}
-
 enum StackModification {
     Nop,
     Call(ClassIdx, MethodInClassIdx),
     Return(Value),
+    Throw(HeapPtr),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::HEAP_MAX_PROPERTY;
+    use crate::vm::java_lang::OBJECT_CLASS_IDX;
+    use crate::vm::testing::run;
+    use crate::vm::vm::VM;
+
+    #[test]
+    fn invalid_heap_max_is_reported() {
+        let source = "public class HeapMax { public static void main(String[] args) {} }";
+        let (status, output) = run(
+            "heap-max",
+            &[("HeapMax", source)],
+            &[(HEAP_MAX_PROPERTY, "abc")],
+        );
+        assert!(
+            output.contains("InternalError: Invalid rjava.heap.max value abc"),
+            "{}",
+            output
+        );
+        assert_eq!(status, 1);
+    }
+
+    #[test]
+    fn arrays_dispatch_to_object_methods() {
+        let vm = VM::new(".");
+        let array = vm.heap.new_object_array(OBJECT_CLASS_IDX, 1);
+        let method = vm.find_object_method(array, "hashCode", "()I");
+        assert_eq!(
+            method.map(|(class_idx, _)| class_idx),
+            Some(OBJECT_CLASS_IDX)
+        );
+
+        let source = r#"
+            import java.util.ArrayList;
+            import java.util.List;
+
+            public class ArrayElements {
+                public static void main(String[] args) {
+                    String[] array = new String[1];
+                    List<Object> list = new ArrayList<>();
+                    list.add(array);
+                    System.out.println(list.contains(array) + " " + list.contains(new String[1]));
+                    System.out.println(list.indexOf(new Object[0]));
+                    System.out.println(list.hashCode() == 31 + array.hashCode());
+                    System.out.println(list.toString().equals("[" + array + "]"));
+                }
+            }
+        "#;
+        let (status, output) = run("array-elements", &[("ArrayElements", source)], &[]);
+        assert_eq!(output, "true false\n-1\ntrue\ntrue\n");
+        assert_eq!(status, 0);
+    }
+
+    #[test]
+    fn heap_limit_collects_before_out_of_memory() {
+        let source = r#"
+            public class HeapLimit {
+                public static void main(String[] args) {
+                    Object[] last = null;
+                    for (int i = 0; i < 1000; i++) {
+                        last = new Object[1000];
+                    }
+                    System.out.println(last.length);
+                    try {
+                        last = new Object[1000000];
+                    } catch (OutOfMemoryError e) {
+                        System.out.println(e.getMessage());
+                    }
+                }
+            }
+        "#;
+        let (status, output) = run(
+            "heap-limit",
+            &[("HeapLimit", source)],
+            &[(HEAP_MAX_PROPERTY, "1048576")],
+        );
+        assert_eq!(output, "1000\nJava heap space\n");
+        assert_eq!(status, 0);
+    }
+
+    #[test]
+    fn negative_array_size_is_thrown() {
+        let source = r#"
+            public class NegativeSize {
+                static int size = -3;
+
+                public static void main(String[] args) {
+                    try {
+                        Object[] array = new Object[size];
+                        System.out.println(array);
+                    } catch (NegativeArraySizeException e) {
+                        System.out.println(e.getMessage());
+                    }
+                    System.out.println(new String[0].length);
+                }
+            }
+        "#;
+        let (status, output) = run("negative-size", &[("NegativeSize", source)], &[]);
+        assert_eq!(output, "-3\n0\n");
+        assert_eq!(status, 0);
+    }
 }
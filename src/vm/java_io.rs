@@ -0,0 +1,162 @@
+use crate::vm::classes::{AccessFlags, NativeMethod, Signature};
+use crate::vm::java_lang::to_java_string;
+use crate::vm::memory::HeapPtr;
+use crate::vm::program::Program;
+use crate::vm::stack::Value;
+use crate::VM;
+use std::cell::RefCell;
+use std::convert::TryInto;
+use std::io::{Read, Write};
+
+pub const STDIN: i32 = 0;
+pub const STDOUT: i32 = 1;
+pub const STDERR: i32 = 2;
+
+const PRINT_STREAM_CLASS: &str = "java/io/PrintStream";
+const INPUT_STREAM_CLASS: &str = "java/io/InputStream";
+
+const PRINT: &str = "print";
+const PRINTLN: &str = "println";
+const WRITE: &str = "write";
+const FLUSH: &str = "flush";
+const READ: &str = "read";
+
+/// Streams behind System.in, System.out and System.err. Could be replaced by embedder.
+pub struct StandardStreams {
+    pub(crate) stdin: RefCell<Box<dyn Read>>,
+    pub(crate) stdout: RefCell<Box<dyn Write>>,
+    pub(crate) stderr: RefCell<Box<dyn Write>>,
+}
+
+impl Default for StandardStreams {
+    fn default() -> Self {
+        Self {
+            stdin: RefCell::new(Box::new(std::io::stdin())),
+            stdout: RefCell::new(Box::new(std::io::stdout())),
+            stderr: RefCell::new(Box::new(std::io::stderr())),
+        }
+    }
+}
+
+impl StandardStreams {
+    pub fn write(&self, fd: i32, data: &[u8]) {
+        let result = match fd {
+            STDERR => self.stderr.borrow_mut().write_all(data),
+            _ => self.stdout.borrow_mut().write_all(data),
+        };
+        result.expect("Cannot write to output stream")
+    }
+
+    pub fn flush(&self) {
+        let _ = self.stdout.borrow_mut().flush();
+        let _ = self.stderr.borrow_mut().flush();
+    }
+
+    fn read_byte(&self) -> i32 {
+        let mut buf = [0u8; 1];
+        match self.stdin.borrow_mut().read(&mut buf) {
+            Ok(1) => buf[0] as i32,
+            _ => -1,
+        }
+    }
+}
+
+impl Program {
+    pub(crate) fn init_java_io(&mut self) {
+        let native = AccessFlags::NATIVE;
+        let mut print_stream_methods = vec![
+            (PRINTLN, "()V", native),
+            (WRITE, "(I)V", native),
+            (FLUSH, "()V", native),
+        ];
+        for descriptor in [
+            "(Ljava/lang/String;)V",
+            "(Ljava/lang/Object;)V",
+            "(I)V",
+            "(J)V",
+            "(C)V",
+            "(Z)V",
+            "(F)V",
+            "(D)V",
+        ] {
+            print_stream_methods.push((PRINT, descriptor, native));
+            print_stream_methods.push((PRINTLN, descriptor, native));
+        }
+        self.add_class(
+            PRINT_STREAM_CLASS,
+            0,
+            &[("fd", "I")],
+            &[],
+            &print_stream_methods,
+        );
+        self.add_class(
+            INPUT_STREAM_CLASS,
+            0,
+            &[("fd", "I")],
+            &[],
+            &[(READ, "()I", native)],
+        );
+        self.native_methods.push(Box::new(JavaIo {}));
+    }
+}
+
+/// Creates PrintStream (or InputStream for stdin) bound to the given standard stream.
+pub(crate) fn new_stream(vm: &VM, fd: i32) -> Value {
+    let class_name = if fd == STDIN {
+        INPUT_STREAM_CLASS
+    } else {
+        PRINT_STREAM_CLASS
+    };
+    let class_idx = vm.get_or_load_class_idx(&class_name.to_string());
+    let ptr = vm.new_object(class_idx);
+    vm.heap.set_int(ptr, 0, fd);
+    Value::Reference(ptr)
+}
+
+struct JavaIo;
+
+impl NativeMethod for JavaIo {
+    fn invoke(
+        &self,
+        vm: &VM,
+        class_name: &str,
+        name: &str,
+        signature: &Signature,
+        arguments: Vec<Value>,
+    ) -> Option<Value> {
+        match (class_name, name) {
+            (PRINT_STREAM_CLASS, PRINT) | (PRINT_STREAM_CLASS, PRINTLN) => {
+                let fd = stream_fd(vm, &arguments[0]);
+                let mut str = match arguments.get(1) {
+                    Some(value) => to_java_string(vm, value, &signature.arguments[0]),
+                    None => String::new(),
+                };
+                if vm.pending_exception() != 0 {
+                    return Some(Value::Void); //thrown by toString
+                }
+                if name == PRINTLN {
+                    str += &vm.get_property("line.separator").unwrap_or_default();
+                }
+                vm.streams.write(fd, str.as_bytes());
+                Some(Value::Void)
+            }
+            (PRINT_STREAM_CLASS, WRITE) => {
+                let fd = stream_fd(vm, &arguments[0]);
+                let byte: i32 = arguments[1].clone().try_into().unwrap();
+                vm.streams.write(fd, &[byte as u8]);
+                Some(Value::Void)
+            }
+            (PRINT_STREAM_CLASS, FLUSH) => {
+                vm.streams.flush();
+                Some(Value::Void)
+            }
+            (INPUT_STREAM_CLASS, READ) => Some(Value::Int(vm.streams.read_byte())),
+            _ => None,
+        }
+    }
+}
+
+fn stream_fd(vm: &VM, stream: &Value) -> i32 {
+    let ptr: HeapPtr = stream.clone().try_into().unwrap();
+    vm.heap.get_int(ptr, 0)
+}
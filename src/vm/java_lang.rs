@@ -1,193 +1,300 @@
-use crate::vm::classes::{AccessFlags, Class, ClassIdx, Field, Method, NativeMethod, Signature};
-use crate::vm::memory::HeapPtr;
+use crate::vm::classes::{AccessFlags, ClassIdx, NativeMethod, Signature};
+use crate::vm::java_io::{new_stream, STDERR, STDIN, STDOUT};
+use crate::vm::memory::{HeapObject, HeapPtr, Relocation};
 use crate::vm::program::Program;
-use crate::vm::stack::{Type, Value};
+use crate::vm::stack::{FrameModifiers, Type, Value};
 use crate::VM;
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
 use std::collections::HashMap;
 use std::convert::TryInto;
 use std::ops::BitOr;
+use std::time::{SystemTime, UNIX_EPOCH};
 
-const OBJECT_CLASS_IDX: ClassIdx = 0;
+pub const OBJECT_CLASS_IDX: ClassIdx = 0;
 pub const STRING_CLASS_IDX: ClassIdx = 1;
 const STRING_BUILDER_CLASS_IDX: ClassIdx = 2;
 pub const INTEGER_CLASS_IDX: ClassIdx = 3;
+const SYSTEM_CLASS_IDX: ClassIdx = 4;
+pub const THROWABLE_CLASS_IDX: ClassIdx = 5;
 
 impl Program {
-    //todo: more helpers to create "rust" java classes
     pub(crate) fn init_java_lang(&mut self) {
-        self.classes.push(Class {
-            name: "java/lang/Object".to_string(),
-            methods: vec![
-                Method {
-                    name: INIT_METHOD.to_string(),
-                    signature: Signature {
-                        return_type: Type::Void,
-                        arguments: vec![],
-                    },
-                    code_ptr: 1,
-                    ..Default::default()
-                },
-                Method {
-                    name: EQUALS.to_string(),
-                    signature: Signature {
-                        return_type: Type::Boolean,
-                        arguments: vec![Type::Reference],
-                    },
-                    flags: AccessFlags::NATIVE,
-                    ..Default::default()
-                },
+        let native = AccessFlags::NATIVE;
+        let static_native = AccessFlags::NATIVE.bitor(AccessFlags::STATIC);
+
+        let idx = self.add_class(
+            OBJECT_CLASS,
+            OBJECT_CLASS_IDX,
+            &[],
+            &[],
+            &[
+                (INIT_METHOD, "()V", AccessFlags::empty()),
+                (EQUALS, "(Ljava/lang/Object;)Z", native),
+                (HASH_CODE, "()I", native),
+                (TO_STRING, "()Ljava/lang/String;", native),
+                (GET_CLASS, "()Ljava/lang/Class;", native),
             ],
-            ..Default::default()
-        });
-        self.class_names_to_idxs
-            .insert("java/lang/Object".to_string(), OBJECT_CLASS_IDX);
-        self.method_names_to_idxs.insert(
-            (OBJECT_CLASS_IDX, INIT_METHOD.to_string(), "()V".to_string()),
-            0,
         );
-        self.method_names_to_idxs.insert(
-            (
-                OBJECT_CLASS_IDX,
-                EQUALS.to_string(),
-                "(Ljava/lang/Object;)Z".to_string(),
-            ),
-            1,
+        assert_eq!(idx, OBJECT_CLASS_IDX);
+
+        let idx = self.add_class(
+            STRING_CLASS,
+            OBJECT_CLASS_IDX,
+            &[],
+            &[],
+            &[
+                (EQUALS, "(Ljava/lang/Object;)Z", native),
+                (HASH_CODE, "()I", native),
+                (TO_STRING, "()Ljava/lang/String;", native),
+            ],
         );
+        assert_eq!(idx, STRING_CLASS_IDX);
 
-        self.classes.push(Class {
-            name: "java/lang/String".to_string(),
-            ..Default::default()
-        });
-        self.class_names_to_idxs
-            .insert("java/lang/String".to_string(), STRING_CLASS_IDX);
-
-        self.classes.push(Class {
-            name: STRING_BUILDER_CLASS.to_string(),
-            super_class_idx: 0,
-            vmt: Default::default(), // empty for now
-            constant_pool_idx: 0,
-            fields: vec![Field {
-                name: "buffer".to_string(),
-                flags: AccessFlags::empty(),
-                value_type: Type::Reference,
-            }],
-            methods: vec![
-                Method {
-                    name: INIT_METHOD.to_string(),
-                    signature: Signature {
-                        return_type: Type::Void,
-                        arguments: vec![],
-                    },
-                    flags: AccessFlags::NATIVE,
-                    ..Default::default()
-                },
-                Method {
-                    name: APPEND_METHOD.to_string(),
-                    signature: Signature {
-                        return_type: Type::Reference,
-                        arguments: vec![Type::Reference],
-                    },
-                    flags: AccessFlags::NATIVE,
-                    ..Default::default()
-                },
-                Method {
-                    name: TO_STRING.to_string(),
-                    signature: Signature {
-                        return_type: Type::Reference,
-                        arguments: vec![],
-                    },
-                    flags: AccessFlags::NATIVE,
-                    ..Default::default()
-                },
+        let idx = self.add_class(
+            STRING_BUILDER_CLASS,
+            OBJECT_CLASS_IDX,
+            &[("buffer", "Ljava/lang/String;")],
+            &[],
+            &[
+                (INIT_METHOD, "()V", native),
+                (INIT_METHOD, "(Ljava/lang/String;)V", native),
+                (
+                    APPEND_METHOD,
+                    "(Ljava/lang/String;)Ljava/lang/StringBuilder;",
+                    native,
+                ),
+                (
+                    APPEND_METHOD,
+                    "(Ljava/lang/Object;)Ljava/lang/StringBuilder;",
+                    native,
+                ),
+                (APPEND_METHOD, "(I)Ljava/lang/StringBuilder;", native),
+                (APPEND_METHOD, "(J)Ljava/lang/StringBuilder;", native),
+                (APPEND_METHOD, "(C)Ljava/lang/StringBuilder;", native),
+                (APPEND_METHOD, "(Z)Ljava/lang/StringBuilder;", native),
+                (APPEND_METHOD, "(F)Ljava/lang/StringBuilder;", native),
+                (APPEND_METHOD, "(D)Ljava/lang/StringBuilder;", native),
+                (TO_STRING, "()Ljava/lang/String;", native),
             ],
-        });
-
-        self.class_names_to_idxs
-            .insert(STRING_BUILDER_CLASS.to_string(), STRING_BUILDER_CLASS_IDX);
-        self.method_names_to_idxs.insert(
-            (
-                STRING_BUILDER_CLASS_IDX,
-                INIT_METHOD.to_string(),
-                "()V".to_string(),
-            ),
-            0,
         );
-        self.method_names_to_idxs.insert(
-            (
-                STRING_BUILDER_CLASS_IDX,
-                APPEND_METHOD.to_string(),
-                "(Ljava/lang/String;)Ljava/lang/StringBuilder;".to_string(),
-            ),
-            1,
+        assert_eq!(idx, STRING_BUILDER_CLASS_IDX);
+
+        let idx = self.add_class(
+            INTEGER_CLASS,
+            OBJECT_CLASS_IDX,
+            &[("_", "I")],
+            &[],
+            &[
+                (VALUE_OF, "(I)Ljava/lang/Integer;", static_native),
+                (INT_VALUE, "()I", native),
+                (EQUALS, "(Ljava/lang/Object;)Z", native),
+                (HASH_CODE, "()I", native),
+                (TO_STRING, "()Ljava/lang/String;", native),
+            ],
         );
-        self.method_names_to_idxs.insert(
-            (
-                STRING_BUILDER_CLASS_IDX,
-                TO_STRING.to_string(),
-                "()Ljava/lang/String;".to_string(),
-            ),
-            2,
+        assert_eq!(idx, INTEGER_CLASS_IDX);
+
+        let idx = self.add_class(
+            SYSTEM_CLASS,
+            OBJECT_CLASS_IDX,
+            &[],
+            &[
+                ("in", "Ljava/io/InputStream;"),
+                ("out", "Ljava/io/PrintStream;"),
+                ("err", "Ljava/io/PrintStream;"),
+            ],
+            &[
+                (CLASS_INIT_METHOD, "()V", static_native),
+                (CURRENT_TIME_MILLIS, "()J", static_native),
+                (NANO_TIME, "()J", static_native),
+                (
+                    ARRAYCOPY,
+                    "(Ljava/lang/Object;ILjava/lang/Object;II)V",
+                    static_native,
+                ),
+                (EXIT, "(I)V", static_native),
+                (
+                    GET_PROPERTY,
+                    "(Ljava/lang/String;)Ljava/lang/String;",
+                    static_native,
+                ),
+                (
+                    GET_PROPERTY,
+                    "(Ljava/lang/String;Ljava/lang/String;)Ljava/lang/String;",
+                    static_native,
+                ),
+                (LINE_SEPARATOR, "()Ljava/lang/String;", static_native),
+                (IDENTITY_HASH_CODE, "(Ljava/lang/Object;)I", static_native),
+            ],
         );
-        self.method_names_to_idxs.insert(
-            (
-                STRING_BUILDER_CLASS_IDX,
-                APPEND_METHOD.to_string(),
-                "(I)Ljava/lang/StringBuilder;".to_string(),
-            ),
-            1,
-        ); //map to same
-
-        self.classes.push(Class {
-            name: INTEGER_CLASS.to_string(),
-            super_class_idx: 0,
-            vmt: Default::default(), // empty for now
-            constant_pool_idx: 0,
-            fields: vec![Field {
-                name: "_".to_string(),
-                flags: Default::default(),
-                value_type: Type::Int,
-            }],
-            methods: vec![
-                Method {
-                    name: VALUE_OF.to_string(),
-                    signature: Signature {
-                        return_type: Type::Reference,
-                        arguments: vec![Type::Int],
-                    },
-                    flags: AccessFlags::NATIVE.bitor(AccessFlags::STATIC),
-                    ..Default::default()
-                },
-                Method {
-                    name: INT_VALUE.to_string(),
-                    signature: Signature {
-                        return_type: Type::Int,
-                        arguments: vec![],
-                    },
-                    flags: AccessFlags::NATIVE,
-                    ..Default::default()
-                },
+        assert_eq!(idx, SYSTEM_CLASS_IDX);
+
+        let idx = self.add_class(
+            THROWABLE_CLASS,
+            OBJECT_CLASS_IDX,
+            &[
+                ("message", "Ljava/lang/String;"),
+                ("cause", "Ljava/lang/Throwable;"),
+                ("backtrace", "[Ljava/lang/String;"), //frames at the moment of creation
+            ],
+            &[],
+            &[
+                (INIT_METHOD, "()V", native),
+                (INIT_METHOD, "(Ljava/lang/String;)V", native),
+                (
+                    INIT_METHOD,
+                    "(Ljava/lang/String;Ljava/lang/Throwable;)V",
+                    native,
+                ),
+                (INIT_METHOD, "(Ljava/lang/Throwable;)V", native),
+                (GET_MESSAGE, "()Ljava/lang/String;", native),
+                (GET_LOCALIZED_MESSAGE, "()Ljava/lang/String;", native),
+                (GET_CAUSE, "()Ljava/lang/Throwable;", native),
+                (TO_STRING, "()Ljava/lang/String;", native),
+                (PRINT_STACK_TRACE, "()V", native),
             ],
-        });
-        self.class_names_to_idxs
-            .insert(INTEGER_CLASS.to_string(), INTEGER_CLASS_IDX);
-        self.method_names_to_idxs.insert(
-            (
-                INTEGER_CLASS_IDX,
-                VALUE_OF.to_string(),
-                "(I)Ljava/lang/Integer;".to_string(),
-            ),
-            0,
         );
-        self.method_names_to_idxs.insert(
-            (INTEGER_CLASS_IDX, INT_VALUE.to_string(), "()I".to_string()),
-            1,
+        assert_eq!(idx, THROWABLE_CLASS_IDX);
+        for (name, super_name) in THROWABLE_SUBCLASSES {
+            self.add_exception_class(name, super_name);
+        }
+
+        //mirror of loaded class, `_` is its index, `array` is set for arrays of that class
+        self.add_class(
+            CLASS_CLASS,
+            OBJECT_CLASS_IDX,
+            &[("_", "I"), ("array", "Z")],
+            &[],
+            &[
+                (GET_NAME, "()Ljava/lang/String;", native),
+                (GET_SIMPLE_NAME, "()Ljava/lang/String;", native),
+                (GET_SUPERCLASS, "()Ljava/lang/Class;", native),
+                (IS_INSTANCE, "(Ljava/lang/Object;)Z", native),
+                (TO_STRING, "()Ljava/lang/String;", native),
+            ],
         );
 
+        let mut math_methods = vec![(RANDOM, "()D", static_native)];
+        for (name, descriptor) in MATH_METHODS {
+            math_methods.push((name, descriptor, static_native));
+        }
+        self.add_class(MATH_CLASS, OBJECT_CLASS_IDX, &[], &[], &math_methods);
+        self.add_class(STRICT_MATH_CLASS, OBJECT_CLASS_IDX, &[], &[], &math_methods);
+
         self.native_methods.push(Box::new(JavaLang::default()));
+        self.native_methods.push(Box::new(JavaLangMath::default()));
     }
 }
 
+impl Program {
+    /// Registers subclass of Throwable with the standard constructors
+    pub(crate) fn add_exception_class(&mut self, name: &str, super_name: &str) -> ClassIdx {
+        let native = AccessFlags::NATIVE;
+        let super_class_idx = self.class_names_to_idxs[super_name];
+        self.add_class(
+            name,
+            super_class_idx,
+            &[],
+            &[],
+            &[
+                (INIT_METHOD, "()V", native),
+                (INIT_METHOD, "(Ljava/lang/String;)V", native),
+                (
+                    INIT_METHOD,
+                    "(Ljava/lang/String;Ljava/lang/Throwable;)V",
+                    native,
+                ),
+                (INIT_METHOD, "(Ljava/lang/Throwable;)V", native),
+            ],
+        )
+    }
+}
+
+//exceptions, which could be thrown by vm itself or by natives
+const THROWABLE_SUBCLASSES: &[(&str, &str)] = &[
+    ("java/lang/Exception", THROWABLE_CLASS),
+    ("java/lang/Error", THROWABLE_CLASS),
+    ("java/lang/RuntimeException", "java/lang/Exception"),
+    ("java/io/IOException", "java/lang/Exception"),
+    (ARITHMETIC_EXCEPTION, "java/lang/RuntimeException"),
+    (NULL_POINTER_EXCEPTION, "java/lang/RuntimeException"),
+    (CLASS_CAST_EXCEPTION, "java/lang/RuntimeException"),
+    (
+        "java/lang/IllegalArgumentException",
+        "java/lang/RuntimeException",
+    ),
+    (
+        "java/lang/IllegalStateException",
+        "java/lang/RuntimeException",
+    ),
+    (
+        "java/lang/UnsupportedOperationException",
+        "java/lang/RuntimeException",
+    ),
+    (NEGATIVE_ARRAY_SIZE_EXCEPTION, "java/lang/RuntimeException"),
+    (
+        "java/lang/IndexOutOfBoundsException",
+        "java/lang/RuntimeException",
+    ),
+    (
+        ARRAY_INDEX_OUT_OF_BOUNDS_EXCEPTION,
+        "java/lang/IndexOutOfBoundsException",
+    ),
+    ("java/lang/VirtualMachineError", "java/lang/Error"),
+    (OUT_OF_MEMORY_ERROR, "java/lang/VirtualMachineError"),
+];
+
+const MATH_METHODS: &[(&str, &str)] = &[
+    ("abs", "(I)I"),
+    ("abs", "(J)J"),
+    ("abs", "(F)F"),
+    ("abs", "(D)D"),
+    ("max", "(II)I"),
+    ("max", "(JJ)J"),
+    ("max", "(FF)F"),
+    ("max", "(DD)D"),
+    ("min", "(II)I"),
+    ("min", "(JJ)J"),
+    ("min", "(FF)F"),
+    ("min", "(DD)D"),
+    ("sqrt", "(D)D"),
+    ("cbrt", "(D)D"),
+    ("pow", "(DD)D"),
+    ("hypot", "(DD)D"),
+    ("floor", "(D)D"),
+    ("ceil", "(D)D"),
+    ("rint", "(D)D"),
+    ("round", "(F)I"),
+    ("round", "(D)J"),
+    ("signum", "(F)F"),
+    ("signum", "(D)D"),
+    ("sin", "(D)D"),
+    ("cos", "(D)D"),
+    ("tan", "(D)D"),
+    ("asin", "(D)D"),
+    ("acos", "(D)D"),
+    ("atan", "(D)D"),
+    ("atan2", "(DD)D"),
+    ("toRadians", "(D)D"),
+    ("toDegrees", "(D)D"),
+    ("exp", "(D)D"),
+    ("log", "(D)D"),
+    ("log10", "(D)D"),
+    ("floorDiv", "(II)I"),
+    ("floorDiv", "(JJ)J"),
+    ("floorMod", "(II)I"),
+    ("floorMod", "(JJ)J"),
+    ("addExact", "(II)I"),
+    ("addExact", "(JJ)J"),
+    ("subtractExact", "(II)I"),
+    ("subtractExact", "(JJ)J"),
+    ("multiplyExact", "(II)I"),
+    ("multiplyExact", "(JJ)J"),
+    ("negateExact", "(I)I"),
+    ("negateExact", "(J)J"),
+    ("toIntExact", "(J)I"),
+];
+
 #[derive(Default)]
 struct JavaLang {
     ints: RefCell<Vec<HeapPtr>>,
@@ -195,45 +302,182 @@ struct JavaLang {
     more_ints: RefCell<HashMap<i32, HeapPtr>>,
 }
 
+const STRING_CLASS: &str = "java/lang/String";
 const STRING_BUILDER_CLASS: &str = "java/lang/StringBuilder";
 const INTEGER_CLASS: &str = "java/lang/Integer";
-const OBJECT_CLASS: &str = "java/lang/Object";
+pub const OBJECT_CLASS: &str = "java/lang/Object";
+const SYSTEM_CLASS: &str = "java/lang/System";
+const THROWABLE_CLASS: &str = "java/lang/Throwable";
+const CLASS_CLASS: &str = "java/lang/Class";
+pub const ARITHMETIC_EXCEPTION: &str = "java/lang/ArithmeticException";
+pub const NULL_POINTER_EXCEPTION: &str = "java/lang/NullPointerException";
+pub const CLASS_CAST_EXCEPTION: &str = "java/lang/ClassCastException";
+pub const ARRAY_INDEX_OUT_OF_BOUNDS_EXCEPTION: &str = "java/lang/ArrayIndexOutOfBoundsException";
+pub const NEGATIVE_ARRAY_SIZE_EXCEPTION: &str = "java/lang/NegativeArraySizeException";
+pub const OUT_OF_MEMORY_ERROR: &str = "java/lang/OutOfMemoryError";
+pub const ILLEGAL_STATE_EXCEPTION: &str = "java/lang/IllegalStateException";
+const MATH_CLASS: &str = "java/lang/Math";
+const STRICT_MATH_CLASS: &str = "java/lang/StrictMath";
 
 const INIT_METHOD: &str = "<init>";
+pub const CLASS_INIT_METHOD: &str = "<clinit>";
 const EQUALS: &str = "equals";
 const APPEND_METHOD: &str = "append";
 const TO_STRING: &str = "toString";
+const HASH_CODE: &str = "hashCode";
+const GET_CLASS: &str = "getClass";
+const GET_NAME: &str = "getName";
+const GET_SIMPLE_NAME: &str = "getSimpleName";
+const GET_SUPERCLASS: &str = "getSuperclass";
+const IS_INSTANCE: &str = "isInstance";
 const VALUE_OF: &str = "valueOf";
 const INT_VALUE: &str = "intValue";
+const CURRENT_TIME_MILLIS: &str = "currentTimeMillis";
+const NANO_TIME: &str = "nanoTime";
+const ARRAYCOPY: &str = "arraycopy";
+const EXIT: &str = "exit";
+const GET_PROPERTY: &str = "getProperty";
+const LINE_SEPARATOR: &str = "lineSeparator";
+const IDENTITY_HASH_CODE: &str = "identityHashCode";
+const GET_MESSAGE: &str = "getMessage";
+const GET_LOCALIZED_MESSAGE: &str = "getLocalizedMessage";
+const GET_CAUSE: &str = "getCause";
+const PRINT_STACK_TRACE: &str = "printStackTrace";
+const RANDOM: &str = "random";
 
 impl NativeMethod for JavaLang {
     fn invoke(
         &self,
         vm: &VM,
-        class_name: &String,
-        name: &String,
+        class_name: &str,
+        name: &str,
+        signature: &Signature,
         arguments: Vec<Value>,
     ) -> Option<Value> {
-        match (class_name.as_str(), name.as_str()) {
+        match (class_name, name) {
             (OBJECT_CLASS, EQUALS) => {
                 let value1 = &arguments[0];
                 let value2 = &arguments[1];
-                return Some(Value::Boolean(if value1 == value2 { 1 } else { 0 }));
+                Some(Value::Boolean(if value1 == value2 { 1 } else { 0 }))
+            }
+            (OBJECT_CLASS, HASH_CODE) => {
+                let ptr: HeapPtr = arguments[0].clone().try_into().unwrap();
+                Some(Value::Int(vm.heap.identity_hash(ptr)))
+            }
+            (OBJECT_CLASS, TO_STRING) => {
+                let ptr: HeapPtr = arguments[0].clone().try_into().unwrap();
+                let str = object_to_string(vm, ptr);
+                Some(Value::Reference(vm.new_string(&str)))
+            }
+            (OBJECT_CLASS, GET_CLASS) => {
+                let ptr: HeapPtr = arguments[0].clone().try_into().unwrap();
+                match vm.heap.get_object(ptr) {
+                    Some(HeapObject::Array(Type::Reference, class_idx)) => {
+                        Some(Value::Reference(array_class_mirror(vm, class_idx)))
+                    }
+                    _ => vm
+                        .heap
+                        .class_of(ptr)
+                        .map(|class_idx| Value::Reference(class_mirror(vm, class_idx))),
+                }
+            }
+            (CLASS_CLASS, _) => {
+                let ptr: HeapPtr = arguments[0].clone().try_into().unwrap();
+                let class_idx = vm.heap.get_int(ptr, 0) as ClassIdx;
+                let is_array = vm.heap.get_int(ptr, 1) != 0;
+                let element_name = vm.get_class(class_idx).name.replace('/', ".");
+                let class_name = match is_array {
+                    true => format!("[L{};", element_name),
+                    false => element_name.clone(),
+                };
+                match name {
+                    GET_NAME => Some(Value::Reference(vm.new_string(&class_name))),
+                    GET_SIMPLE_NAME => {
+                        let simple_name = element_name.rsplit(['.', '$']).next().unwrap();
+                        let simple_name = match is_array {
+                            true => format!("{}[]", simple_name),
+                            false => simple_name.to_string(),
+                        };
+                        Some(Value::Reference(vm.new_string(&simple_name)))
+                    }
+                    GET_SUPERCLASS if is_array => {
+                        Some(Value::Reference(class_mirror(vm, OBJECT_CLASS_IDX)))
+                    }
+                    GET_SUPERCLASS if class_idx == OBJECT_CLASS_IDX => Some(Value::Reference(0)),
+                    GET_SUPERCLASS => {
+                        let super_class_idx = vm.get_class(class_idx).super_class_idx;
+                        Some(Value::Reference(class_mirror(vm, super_class_idx)))
+                    }
+                    IS_INSTANCE => match arguments[1] {
+                        Value::Reference(0) => Some(Value::Boolean(0)),
+                        //arrays are covariant
+                        Value::Reference(object) => match (is_array, vm.heap.get_object(object)) {
+                            (true, Some(HeapObject::Array(Type::Reference, element_class_idx))) => {
+                                Some(Value::Boolean(
+                                    vm.is_subclass_of(element_class_idx, class_idx) as i32,
+                                ))
+                            }
+                            (true, _) => Some(Value::Boolean(0)),
+                            (false, _) => match vm.heap.class_of(object) {
+                                Some(object_class_idx) => Some(Value::Boolean(
+                                    vm.is_subclass_of(object_class_idx, class_idx) as i32,
+                                )),
+                                None => {
+                                    Some(Value::Boolean((class_idx == OBJECT_CLASS_IDX) as i32))
+                                }
+                            },
+                        },
+                        _ => None,
+                    },
+                    TO_STRING => {
+                        let str = format!("class {}", class_name);
+                        Some(Value::Reference(vm.new_string(&str)))
+                    }
+                    _ => None,
+                }
+            }
+            (STRING_CLASS, EQUALS) => {
+                let ptr: HeapPtr = arguments[0].clone().try_into().unwrap();
+                let other: HeapPtr = arguments[1].clone().try_into().unwrap();
+                let equal = vm.heap.get_object(other) == Some(HeapObject::String)
+                    && vm.heap.get_string(ptr) == vm.heap.get_string(other);
+                Some(Value::Boolean(equal as i32))
+            }
+            (INTEGER_CLASS, EQUALS) => {
+                let ptr: HeapPtr = arguments[0].clone().try_into().unwrap();
+                let other: HeapPtr = arguments[1].clone().try_into().unwrap();
+                let equal = vm.heap.class_of(other) == Some(INTEGER_CLASS_IDX)
+                    && vm.heap.get_int(ptr, 0) == vm.heap.get_int(other, 0);
+                Some(Value::Boolean(equal as i32))
             }
-            (INTEGER_CLASS, INT_VALUE) => {
+            (STRING_CLASS, HASH_CODE) => {
+                let ptr: HeapPtr = arguments[0].clone().try_into().unwrap();
+                let str = to_java_string(vm, &Value::Reference(ptr), &Type::Reference);
+                //same as java: s[0]*31^(n-1) + ... + s[n-1] over utf-16 code units
+                let hash = str
+                    .encode_utf16()
+                    .fold(0i32, |h, c| h.wrapping_mul(31).wrapping_add(c as i32));
+                Some(Value::Int(hash))
+            }
+            (STRING_CLASS, TO_STRING) => Some(arguments[0].clone()),
+            (INTEGER_CLASS, INT_VALUE) | (INTEGER_CLASS, HASH_CODE) => {
                 let ptr: HeapPtr = arguments[0].clone().try_into().unwrap();
                 Some(vm.heap.get_field(ptr, 0))
             }
+            (INTEGER_CLASS, TO_STRING) => {
+                let str = to_java_string(vm, &arguments[0], &Type::Reference);
+                Some(Value::Reference(vm.new_string(&str)))
+            }
             (INTEGER_CLASS, VALUE_OF) => {
                 let value = &arguments[0];
                 let int: i32 = value.clone().try_into().unwrap();
-                if int >= 0 && int < 50 {
+                if (0..50).contains(&int) {
                     let int = int as usize;
                     let mut ints = self.ints.borrow_mut();
                     while int >= ints.len() {
                         let int_obj_ptr = vm.new_object(INTEGER_CLASS_IDX);
-                        vm.heap
-                            .set_field(int_obj_ptr, 0, Value::Int(ints.len() as i32));
+                        vm.heap.set_int(int_obj_ptr, 0, ints.len() as i32);
+                        vm.heap.promote(int_obj_ptr); //cached
                         ints.push(int_obj_ptr);
                     }
 
@@ -244,7 +488,8 @@ impl NativeMethod for JavaLang {
                         Some(Value::Reference(*ptr))
                     } else {
                         let int_obj_ptr = vm.new_object(INTEGER_CLASS_IDX);
-                        vm.heap.set_field(int_obj_ptr, 0, Value::Int(int));
+                        vm.heap.set_int(int_obj_ptr, 0, int);
+                        vm.heap.promote(int_obj_ptr); //cached
                         more_ints.insert(int, int_obj_ptr);
                         Some(Value::Reference(int_obj_ptr))
                     }
@@ -252,51 +497,710 @@ impl NativeMethod for JavaLang {
             }
             (STRING_BUILDER_CLASS, INIT_METHOD) => {
                 if let Value::Reference(heap_ptr) = arguments[0] {
-                    vm.heap.set_field(heap_ptr, 0, Value::String(String::new()))
+                    let initial = match arguments.get(1) {
+                        Some(value) => to_java_string(vm, value, &Type::Reference),
+                        None => String::new(),
+                    };
+                    if vm.pending_exception() != 0 {
+                        return Some(Value::Void);
+                    }
+                    let buffer = vm.new_string(&initial);
+                    vm.heap.set_reference(heap_ptr, 0, buffer)
                 }
                 Some(Value::Void)
             }
-            (STRING_BUILDER_CLASS, TO_STRING) => {
-                if let Value::Reference(heap_ptr) = arguments[0] {
-                    let str_ptr = vm.heap.new_object(STRING_CLASS_IDX, 1);
-                    let str_value = vm.heap.get_field(heap_ptr, 0);
-                    vm.heap.new_object_field(str_value);
-                    Some(Value::Reference(str_ptr))
-                } else {
-                    None
-                }
-            }
+            //strings are immutable, so buffer is returned as is
+            (STRING_BUILDER_CLASS, TO_STRING) => Some(
+                vm.heap
+                    .get_field(arguments[0].clone().try_into().unwrap(), 0),
+            ),
             (STRING_BUILDER_CLASS, APPEND_METHOD) => {
                 if let Value::Reference(heap_ptr) = arguments[0] {
-                    if let Value::String(mut str) = vm.heap.get_field(heap_ptr, 0) {
-                        match &arguments[1] {
-                            Value::Byte(v) => str.push_str(&*v.to_string()),
-                            Value::Short(v) => str.push_str(&*v.to_string()),
-                            Value::Int(v) => str.push_str(&*v.to_string()),
-                            Value::Long(v) => str.push_str(&*v.to_string()),
-                            Value::Char(v) => str.push_str(&*v.to_string()),
-                            Value::Float(v) => str.push_str(&*v.to_string()),
-                            Value::Double(v) => str.push_str(&*v.to_string()),
-                            Value::Boolean(v) => str.push_str(&*v.to_string()),
-                            Value::Reference(hptr) => {
-                                //todo: support not only strings
-                                if let Value::ClassIndex(STRING_CLASS_IDX, _) =
-                                    vm.heap.get_value(*hptr)
-                                {
-                                    if let Value::String(s) = vm.heap.get_field(*hptr, 0) {
-                                        str.push_str(&s);
-                                    }
-                                }
-                            }
-                            Value::String(s) => str.push_str(&s),
-                            _ => panic!("Not supported"),
-                        }
-                        vm.heap.set_field(heap_ptr, 0, Value::String(str))
+                    let appended = to_java_string(vm, &arguments[1], &signature.arguments[0]);
+                    if vm.pending_exception() != 0 {
+                        return Some(Value::Void);
                     }
+                    let str = vm.heap.get_string(vm.heap.get_reference(heap_ptr, 0)) + &appended;
+                    let buffer = vm.new_string(&str);
+                    vm.heap.set_reference(heap_ptr, 0, buffer)
                 }
                 Some(arguments[0].clone())
             }
+            (SYSTEM_CLASS, CLASS_INIT_METHOD) => {
+                vm.set_static(SYSTEM_CLASS_IDX, 0, new_stream(vm, STDIN));
+                vm.set_static(SYSTEM_CLASS_IDX, 1, new_stream(vm, STDOUT));
+                vm.set_static(SYSTEM_CLASS_IDX, 2, new_stream(vm, STDERR));
+                Some(Value::Void)
+            }
+            (SYSTEM_CLASS, CURRENT_TIME_MILLIS) => Some(Value::Long(
+                SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap()
+                    .as_millis() as i64,
+            )),
+            (SYSTEM_CLASS, NANO_TIME) => Some(Value::Long(vm.nano_time())),
+            (SYSTEM_CLASS, ARRAYCOPY) => {
+                let src: HeapPtr = arguments[0].clone().try_into().unwrap();
+                let src_pos: i32 = arguments[1].clone().try_into().unwrap();
+                let dest: HeapPtr = arguments[2].clone().try_into().unwrap();
+                let dest_pos: i32 = arguments[3].clone().try_into().unwrap();
+                let length: i32 = arguments[4].clone().try_into().unwrap();
+                //nothing is copied unless the whole range fits
+                if let Some((class_name, message)) =
+                    arraycopy_error(vm, src, src_pos, dest, dest_pos, length)
+                {
+                    vm.throw(new_throwable(vm, class_name, &message));
+                    return Some(Value::Void);
+                }
+                let copied: Vec<Value> = (0..length)
+                    .map(|i| vm.heap.get_array_element(src, (src_pos + i) as usize))
+                    .collect();
+                for (i, value) in copied.into_iter().enumerate() {
+                    vm.heap
+                        .set_array_element(dest, dest_pos as usize + i, value);
+                }
+                Some(Value::Void)
+            }
+            (SYSTEM_CLASS, EXIT) => {
+                let status: i32 = arguments[0].clone().try_into().unwrap();
+                vm.exit(status);
+                Some(Value::Void)
+            }
+            (SYSTEM_CLASS, GET_PROPERTY) => {
+                let key = to_java_string(vm, &arguments[0], &Type::Reference);
+                match vm.get_property(&key) {
+                    Some(value) => Some(Value::Reference(vm.new_string(&value))),
+                    None => Some(arguments.get(1).cloned().unwrap_or(Value::Reference(0))),
+                }
+            }
+            (SYSTEM_CLASS, LINE_SEPARATOR) => {
+                let separator = vm.get_property("line.separator").unwrap_or_default();
+                Some(Value::Reference(vm.new_string(&separator)))
+            }
+            (SYSTEM_CLASS, IDENTITY_HASH_CODE) => match arguments[0] {
+                Value::Reference(0) => Some(Value::Int(0)),
+                Value::Reference(ptr) => Some(Value::Int(vm.heap.identity_hash(ptr))),
+                _ => None,
+            },
+            (_, INIT_METHOD) if is_throwable_class(vm, class_name) => {
+                let ptr: HeapPtr = arguments[0].clone().try_into().unwrap();
+                let (message, cause) = match (&signature.arguments[..], &arguments[1..]) {
+                    ([], _) => (Value::Reference(0), Value::Reference(0)),
+                    ([Type::Reference], [Value::Reference(arg)])
+                        if *arg != 0 && is_throwable(vm, *arg) =>
+                    {
+                        let message = throwable_to_string(vm, *arg);
+                        (
+                            Value::Reference(vm.new_string(&message)),
+                            Value::Reference(*arg),
+                        )
+                    }
+                    ([Type::Reference], [message]) => (message.clone(), Value::Reference(0)),
+                    (_, [message, cause]) => (message.clone(), cause.clone()),
+                    _ => return None,
+                };
+                vm.heap.set_field(ptr, 0, message);
+                vm.heap.set_field(ptr, 1, cause);
+                fill_in_stack_trace(vm, ptr);
+                Some(Value::Void)
+            }
+            (THROWABLE_CLASS, GET_MESSAGE) | (THROWABLE_CLASS, GET_LOCALIZED_MESSAGE) => {
+                let ptr: HeapPtr = arguments[0].clone().try_into().unwrap();
+                Some(vm.heap.get_field(ptr, 0))
+            }
+            (THROWABLE_CLASS, GET_CAUSE) => {
+                let ptr: HeapPtr = arguments[0].clone().try_into().unwrap();
+                Some(vm.heap.get_field(ptr, 1))
+            }
+            (THROWABLE_CLASS, TO_STRING) => {
+                let ptr: HeapPtr = arguments[0].clone().try_into().unwrap();
+                let str = throwable_to_string(vm, ptr);
+                Some(Value::Reference(vm.new_string(&str)))
+            }
+            (THROWABLE_CLASS, PRINT_STACK_TRACE) => {
+                let ptr: HeapPtr = arguments[0].clone().try_into().unwrap();
+                let str = throwable_stack_trace(vm, ptr);
+                vm.streams.write(STDERR, str.as_bytes());
+                Some(Value::Void)
+            }
             _ => None,
         }
     }
+
+    fn roots(&self) -> Vec<HeapPtr> {
+        let ints = self.ints.borrow();
+        let more_ints = self.more_ints.borrow();
+        ints.iter().chain(more_ints.values()).copied().collect()
+    }
+
+    fn relocate(&self, relocation: &Relocation) {
+        for ptr in self.ints.borrow_mut().iter_mut() {
+            *ptr = relocation.forward(*ptr);
+        }
+        for ptr in self.more_ints.borrow_mut().values_mut() {
+            *ptr = relocation.forward(*ptr);
+        }
+    }
+}
+
+/// Returns `java/lang/Class` instance of the class. There is one mirror per class, so they could be compared by reference.
+pub(crate) fn class_mirror(vm: &VM, class_idx: ClassIdx) -> HeapPtr {
+    let mirror = vm.get_class(class_idx).mirror;
+    if mirror != 0 {
+        return mirror;
+    }
+    let ptr = new_mirror(vm, class_idx, false);
+    vm.program.borrow_mut().classes[class_idx].mirror = ptr;
+    ptr
+}
+
+/// Same as `class_mirror`, for arrays with elements of the class
+pub(crate) fn array_class_mirror(vm: &VM, class_idx: ClassIdx) -> HeapPtr {
+    let mirror = vm.get_class(class_idx).array_mirror;
+    if mirror != 0 {
+        return mirror;
+    }
+    let ptr = new_mirror(vm, class_idx, true);
+    vm.program.borrow_mut().classes[class_idx].array_mirror = ptr;
+    ptr
+}
+
+fn new_mirror(vm: &VM, class_idx: ClassIdx, is_array: bool) -> HeapPtr {
+    let mirror_class_idx = vm.get_or_load_class_idx(&CLASS_CLASS.to_string());
+    let ptr = vm.new_object(mirror_class_idx);
+    vm.heap.set_int(ptr, 0, class_idx as i32);
+    vm.heap.set_int(ptr, 1, is_array as i32);
+    vm.heap.promote(ptr);
+    ptr
+}
+
+/// Same as `Object.toString`: class name and hex of (possibly overridden) hashCode
+fn object_to_string(vm: &VM, ptr: HeapPtr) -> String {
+    let class_name = match vm.heap.class_of(ptr) {
+        Some(class_idx) => vm.get_class(class_idx).name.replace('/', "."),
+        //arrays do not override hashCode
+        None => {
+            return format!(
+                "{}@{:x}",
+                vm.heap_class_name(ptr),
+                vm.heap.identity_hash(ptr)
+            )
+        }
+    };
+    let hash_code = match vm.invoke_virtual(ptr, HASH_CODE, "()I", vec![]) {
+        Value::Int(hash_code) => hash_code,
+        _ => 0, //exception is pending
+    };
+    format!("{}@{:x}", class_name, hash_code)
+}
+
+fn is_throwable_class(vm: &VM, class_name: &str) -> bool {
+    let class_idx = vm.get_or_load_class_idx(&class_name.to_string());
+    vm.is_subclass_of(class_idx, THROWABLE_CLASS_IDX)
+}
+
+fn is_throwable(vm: &VM, ptr: HeapPtr) -> bool {
+    match vm.heap.class_of(ptr) {
+        Some(class_idx) => vm.is_subclass_of(class_idx, THROWABLE_CLASS_IDX),
+        None => false,
+    }
+}
+
+/// Creates exception of given class with message, as `new X(message)` does.
+pub(crate) fn new_throwable(vm: &VM, class_name: &str, message: &str) -> HeapPtr {
+    let class_idx = vm.get_or_load_class_idx(&class_name.to_string());
+    let ptr = vm.new_object(class_idx);
+    let message_ptr = vm.new_string(message);
+    vm.heap.set_reference(ptr, 0, message_ptr);
+    fill_in_stack_trace(vm, ptr);
+    ptr
+}
+
+//saves current stack into `backtrace`, constructors of the exception itself are skipped as java does
+fn fill_in_stack_trace(vm: &VM, ptr: HeapPtr) {
+    let class_idx = match vm.heap.class_of(ptr) {
+        Some(class_idx) => class_idx,
+        None => return,
+    };
+    let constructors = vm
+        .stack
+        .inspect()
+        .iter()
+        .rev()
+        .take_while(|frame| {
+            let (frame_class_idx, method_idx) = frame.class_method_idxs;
+            !frame.modifiers.contains(FrameModifiers::NATIVE_BRIDGE)
+                && vm.get_method(frame_class_idx, method_idx).name == INIT_METHOD
+                && vm.is_subclass_of(class_idx, frame_class_idx)
+        })
+        .count();
+    let frames = &vm.stack_trace()[constructors..];
+    let backtrace = vm
+        .heap
+        .new_object_array(STRING_CLASS_IDX, frames.len() as i32);
+    for (i, frame) in frames.iter().enumerate() {
+        let frame_ptr = vm.new_string(frame);
+        vm.heap
+            .set_array_element(backtrace, i, Value::Reference(frame_ptr));
+    }
+    vm.heap.set_reference(ptr, 2, backtrace);
+}
+
+fn backtrace(vm: &VM, ptr: HeapPtr) -> Vec<String> {
+    match vm.heap.get_reference(ptr, 2) {
+        0 => vec![],
+        array => (0..vm.heap.array_length(array))
+            .map(|i| to_java_string(vm, &vm.heap.get_array_element(array, i), &Type::Reference))
+            .collect(),
+    }
+}
+
+/// Same as `Throwable.printStackTrace` prints: description, frames and chain of causes
+pub(crate) fn throwable_stack_trace(vm: &VM, ptr: HeapPtr) -> String {
+    let mut str = to_java_string(vm, &Value::Reference(ptr), &Type::Reference) + "\n";
+    let mut frames = backtrace(vm, ptr);
+    for frame in &frames {
+        str += &format!("\tat {}\n", frame);
+    }
+    let mut seen = vec![ptr];
+    let mut cause = vm.heap.get_reference(ptr, 1);
+    while cause != 0 && !seen.contains(&cause) {
+        let cause_frames = backtrace(vm, cause);
+        //frames in common with enclosing trace are not repeated
+        let in_common = cause_frames
+            .iter()
+            .rev()
+            .zip(frames.iter().rev())
+            .take_while(|(a, b)| a == b)
+            .count();
+        str += &format!(
+            "Caused by: {}\n",
+            to_java_string(vm, &Value::Reference(cause), &Type::Reference)
+        );
+        for frame in &cause_frames[..cause_frames.len() - in_common] {
+            str += &format!("\tat {}\n", frame);
+        }
+        if in_common > 0 {
+            str += &format!("\t... {} more\n", in_common);
+        }
+        seen.push(cause);
+        frames = cause_frames;
+        cause = vm.heap.get_reference(cause, 1);
+    }
+    str
+}
+
+/// Same as `Throwable.toString`: class name and message, if any
+pub(crate) fn throwable_to_string(vm: &VM, ptr: HeapPtr) -> String {
+    let class_name = match vm.heap.class_of(ptr) {
+        Some(class_idx) => vm.get_class(class_idx).name.replace('/', "."),
+        None => panic!("Not an object: {}", ptr),
+    };
+    match vm.heap.get_field(ptr, 0) {
+        Value::Reference(0) => class_name,
+        message => format!(
+            "{}: {}",
+            class_name,
+            to_java_string(vm, &message, &Type::Reference)
+        ),
+    }
+}
+
+#[derive(Default)]
+struct JavaLangMath {
+    random_state: Cell<u64>,
+}
+
+impl NativeMethod for JavaLangMath {
+    fn invoke(
+        &self,
+        vm: &VM,
+        class_name: &str,
+        name: &str,
+        _signature: &Signature,
+        arguments: Vec<Value>,
+    ) -> Option<Value> {
+        if class_name != MATH_CLASS && class_name != STRICT_MATH_CLASS {
+            return None;
+        }
+        use Value::{Double, Float, Int, Long};
+        let result = match (name, &arguments[..]) {
+            ("abs", [Int(a)]) => Int(a.wrapping_abs()),
+            ("abs", [Long(a)]) => Long(a.wrapping_abs()),
+            ("abs", [Float(a)]) => Float(a.abs()),
+            ("abs", [Double(a)]) => Double(a.abs()),
+            ("max", [Int(a), Int(b)]) => Int(*a.max(b)),
+            ("max", [Long(a), Long(b)]) => Long(*a.max(b)),
+            ("max", [Float(a), Float(b)]) => Float(java_max(*a as f64, *b as f64) as f32),
+            ("max", [Double(a), Double(b)]) => Double(java_max(*a, *b)),
+            ("min", [Int(a), Int(b)]) => Int(*a.min(b)),
+            ("min", [Long(a), Long(b)]) => Long(*a.min(b)),
+            ("min", [Float(a), Float(b)]) => Float(java_min(*a as f64, *b as f64) as f32),
+            ("min", [Double(a), Double(b)]) => Double(java_min(*a, *b)),
+            ("sqrt", [Double(a)]) => Double(a.sqrt()),
+            ("cbrt", [Double(a)]) => Double(a.cbrt()),
+            ("pow", [Double(a), Double(b)]) => Double(java_pow(*a, *b)),
+            ("hypot", [Double(a), Double(b)]) => Double(a.hypot(*b)),
+            ("floor", [Double(a)]) => Double(a.floor()),
+            ("ceil", [Double(a)]) => Double(a.ceil()),
+            ("rint", [Double(a)]) => Double(a.round_ties_even()),
+            //float casts saturate and give 0 for NaN, as java does
+            ("round", [Float(a)]) => Int((*a as f64 + 0.5).floor() as i32),
+            ("round", [Double(a)]) => Long(java_round(*a) as i64),
+            ("signum", [Float(a)]) => Float(if *a == 0.0 || a.is_nan() {
+                *a
+            } else {
+                a.signum()
+            }),
+            ("signum", [Double(a)]) => Double(if *a == 0.0 || a.is_nan() {
+                *a
+            } else {
+                a.signum()
+            }),
+            ("sin", [Double(a)]) => Double(a.sin()),
+            ("cos", [Double(a)]) => Double(a.cos()),
+            ("tan", [Double(a)]) => Double(a.tan()),
+            ("asin", [Double(a)]) => Double(a.asin()),
+            ("acos", [Double(a)]) => Double(a.acos()),
+            ("atan", [Double(a)]) => Double(a.atan()),
+            ("atan2", [Double(a), Double(b)]) => Double(a.atan2(*b)),
+            ("toRadians", [Double(a)]) => Double(a.to_radians()),
+            ("toDegrees", [Double(a)]) => Double(a.to_degrees()),
+            ("exp", [Double(a)]) => Double(a.exp()),
+            ("log", [Double(a)]) => Double(a.ln()),
+            ("log10", [Double(a)]) => Double(a.log10()),
+            ("floorDiv", [_, Int(0)])
+            | ("floorDiv", [_, Long(0)])
+            | ("floorMod", [_, Int(0)])
+            | ("floorMod", [_, Long(0)]) => throw_arithmetic(vm, "/ by zero"),
+            ("floorDiv", [Int(a), Int(b)]) => Int(floor_div(*a as i64, *b as i64) as i32),
+            ("floorDiv", [Long(a), Long(b)]) => Long(floor_div(*a, *b)),
+            ("floorMod", [Int(a), Int(b)]) => Int(floor_mod(*a as i64, *b as i64) as i32),
+            ("floorMod", [Long(a), Long(b)]) => Long(floor_mod(*a, *b)),
+            ("addExact", [Int(a), Int(b)]) => exact_int(vm, a.checked_add(*b)),
+            ("addExact", [Long(a), Long(b)]) => exact_long(vm, a.checked_add(*b)),
+            ("subtractExact", [Int(a), Int(b)]) => exact_int(vm, a.checked_sub(*b)),
+            ("subtractExact", [Long(a), Long(b)]) => exact_long(vm, a.checked_sub(*b)),
+            ("multiplyExact", [Int(a), Int(b)]) => exact_int(vm, a.checked_mul(*b)),
+            ("multiplyExact", [Long(a), Long(b)]) => exact_long(vm, a.checked_mul(*b)),
+            ("negateExact", [Int(a)]) => exact_int(vm, a.checked_neg()),
+            ("negateExact", [Long(a)]) => exact_long(vm, a.checked_neg()),
+            ("toIntExact", [Long(a)]) => exact_int(vm, (*a).try_into().ok()),
+            (RANDOM, []) => Double(self.next_random()),
+            _ => return None,
+        };
+        Some(result)
+    }
+}
+
+impl JavaLangMath {
+    //xorshift64*, seeded by time of the first call
+    fn next_random(&self) -> f64 {
+        let mut x = self.random_state.get();
+        if x == 0 {
+            x = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_nanos() as u64
+                | 1;
+        }
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        self.random_state.set(x);
+        //53 random bits, as java.util.Random.nextDouble
+        (x.wrapping_mul(0x2545_F491_4F6C_DD1D) >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+//exception thrown by `System.arraycopy` with these arguments, messages as java has them
+fn arraycopy_error(
+    vm: &VM,
+    src: HeapPtr,
+    src_pos: i32,
+    dest: HeapPtr,
+    dest_pos: i32,
+    length: i32,
+) -> Option<(&'static str, String)> {
+    for (what, ptr) in [("source", src), ("destination", dest)] {
+        if ptr == 0 {
+            let message = format!("arraycopy: {} array is null", what);
+            return Some((NULL_POINTER_EXCEPTION, message));
+        }
+    }
+    if length < 0 {
+        let message = format!("arraycopy: length {} is negative", length);
+        return Some((ARRAY_INDEX_OUT_OF_BOUNDS_EXCEPTION, message));
+    }
+    for (what, ptr, pos) in [("source", src, src_pos), ("destination", dest, dest_pos)] {
+        let array_length = vm.heap.array_length(ptr) as i64;
+        let array_name = match vm.heap.get_object(ptr) {
+            Some(HeapObject::Array(element_type, _)) if element_type != Type::Reference => {
+                format!("{:?}", element_type).to_lowercase()
+            }
+            _ => "object array".to_string(),
+        };
+        let (pos, last) = (pos as i64, pos as i64 + length as i64);
+        let message = if pos < 0 {
+            format!(
+                "arraycopy: {} index {} out of bounds for {}[{}]",
+                what, pos, array_name, array_length
+            )
+        } else if last > array_length {
+            format!(
+                "arraycopy: last {} index {} out of bounds for {}[{}]",
+                what, last, array_name, array_length
+            )
+        } else {
+            continue;
+        };
+        return Some((ARRAY_INDEX_OUT_OF_BOUNDS_EXCEPTION, message));
+    }
+    None
+}
+
+fn throw_arithmetic(vm: &VM, message: &str) -> Value {
+    vm.throw(new_throwable(vm, ARITHMETIC_EXCEPTION, message));
+    Value::Void
+}
+
+fn exact_int(vm: &VM, result: Option<i32>) -> Value {
+    match result {
+        Some(int) => Value::Int(int),
+        None => throw_arithmetic(vm, "integer overflow"),
+    }
+}
+
+fn exact_long(vm: &VM, result: Option<i64>) -> Value {
+    match result {
+        Some(long) => Value::Long(long),
+        None => throw_arithmetic(vm, "long overflow"),
+    }
+}
+
+fn floor_div(a: i64, b: i64) -> i64 {
+    let q = a.wrapping_div(b);
+    if (a ^ b) < 0 && q.wrapping_mul(b) != a {
+        q - 1
+    } else {
+        q
+    }
+}
+
+fn floor_mod(a: i64, b: i64) -> i64 {
+    let m = a.wrapping_rem(b);
+    if m != 0 && (m ^ b) < 0 {
+        m + b
+    } else {
+        m
+    }
+}
+
+//unlike f64::max, NaN wins and -0.0 is less than 0.0
+fn java_max(a: f64, b: f64) -> f64 {
+    if a.is_nan() {
+        return a;
+    }
+    if a == 0.0 && b == 0.0 && a.is_sign_negative() {
+        return b;
+    }
+    if a >= b {
+        a
+    } else {
+        b
+    }
+}
+
+fn java_min(a: f64, b: f64) -> f64 {
+    if a.is_nan() {
+        return a;
+    }
+    if a == 0.0 && b == 0.0 && b.is_sign_negative() {
+        return b;
+    }
+    if a <= b {
+        a
+    } else {
+        b
+    }
+}
+
+//differs from C pow only for NaN exponent and 1/-1 base
+fn java_pow(a: f64, b: f64) -> f64 {
+    if b == 0.0 {
+        return 1.0;
+    }
+    if b.is_nan() || (a.abs() == 1.0 && b.is_infinite()) {
+        return f64::NAN;
+    }
+    a.powf(b)
+}
+
+//ties are rounded to positive infinity: round(-0.5) == 0, round(0.49999999999999994) == 0
+fn java_round(a: f64) -> f64 {
+    let floor = a.floor();
+    if a - floor >= 0.5 {
+        floor + 1.0
+    } else {
+        floor
+    }
+}
+
+/// Converts value of given declared type to string the same way as `String.valueOf` does.
+/// Gives empty string if `toString` throws, or without a call if an exception is already pending
+pub(crate) fn to_java_string(vm: &VM, value: &Value, value_type: &Type) -> String {
+    match (value, value_type) {
+        (Value::Int(code), Type::Char) => java_char_to_string(*code as u32),
+        (Value::Char(code), _) => java_char_to_string(*code as u32),
+        (Value::Int(v), Type::Boolean) | (Value::Boolean(v), _) => (*v != 0).to_string(),
+        (Value::Byte(v), _) => v.to_string(),
+        (Value::Short(v), _) => v.to_string(),
+        (Value::Int(v), _) => v.to_string(),
+        (Value::Long(v), _) => v.to_string(),
+        (Value::Float(v), _) => java_float_to_string(*v),
+        (Value::Double(v), _) => java_double_to_string(*v),
+        (Value::Reference(0), _) => "null".to_string(),
+        (Value::Reference(heap_ptr), _) => {
+            match vm.heap.get_object(*heap_ptr) {
+                Some(HeapObject::String) => vm.heap.get_string(*heap_ptr),
+                Some(HeapObject::Object(INTEGER_CLASS_IDX)) => {
+                    vm.heap.get_int(*heap_ptr, 0).to_string()
+                }
+                Some(HeapObject::Object(_)) if vm.pending_exception() != 0 => String::new(),
+                Some(HeapObject::Object(_)) => {
+                    let str =
+                        vm.invoke_virtual(*heap_ptr, TO_STRING, "()Ljava/lang/String;", vec![]);
+                    match str {
+                        Value::Reference(_) => to_java_string(vm, &str, &Type::Reference),
+                        _ => String::new(), //exception is pending
+                    }
+                }
+                Some(HeapObject::Array(..)) => object_to_string(vm, *heap_ptr),
+                object => panic!("Don't know how to serialize to string: {:?}", object),
+            }
+        }
+        _ => panic!("Not supported"),
+    }
+}
+
+/// Text shortened to `max_chars` characters with `...`, for logs and labels
+pub(crate) fn cut(text: &str, max_chars: usize) -> String {
+    match text.char_indices().nth(max_chars) {
+        Some((end, _)) => format!("{}...", &text[..end]),
+        None => text.to_string(),
+    }
+}
+
+fn java_char_to_string(code: u32) -> String {
+    char::from_u32(code)
+        .unwrap_or(char::REPLACEMENT_CHARACTER)
+        .to_string()
+}
+
+/// Formats double as `Double.toString` does: `1.0`, `1.0E10`, `NaN`, `-Infinity`.
+pub(crate) fn java_double_to_string(value: f64) -> String {
+    if value.is_nan() {
+        return "NaN".to_string();
+    }
+    if value.is_infinite() {
+        return if value > 0.0 { "Infinity" } else { "-Infinity" }.to_string();
+    }
+    format_java_decimal(format!("{}", value), format!("{:e}", value), value.abs())
+}
+
+/// Formats float as `Float.toString` does.
+pub(crate) fn java_float_to_string(value: f32) -> String {
+    if !value.is_finite() {
+        return java_double_to_string(value as f64);
+    }
+    format_java_decimal(
+        format!("{}", value),
+        format!("{:e}", value),
+        value.abs() as f64,
+    )
+}
+
+//java uses plain notation for [10^-3, 10^7) and computerized scientific one otherwise
+fn format_java_decimal(plain: String, scientific: String, abs: f64) -> String {
+    if abs == 0.0 || (1e-3..1e7).contains(&abs) {
+        return if plain.contains('.') {
+            plain
+        } else {
+            plain + ".0"
+        };
+    }
+    let (mantissa, exponent) = scientific.split_once('e').unwrap();
+    if mantissa.contains('.') {
+        format!("{}E{}", mantissa, exponent)
+    } else {
+        format!("{}.0E{}", mantissa, exponent)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::class_loader::parse_signature;
+
+    fn math(name: &str, descriptor: &str, arguments: Vec<Value>) -> Value {
+        let vm = VM::new(".");
+        let signature = parse_signature(descriptor);
+        JavaLangMath::default()
+            .invoke(&vm, MATH_CLASS, name, &signature, arguments)
+            .unwrap()
+    }
+
+    #[test]
+    fn round_float_saturates() {
+        let round = |a: f32| math("round", "(F)I", vec![Value::Float(a)]);
+        assert_eq!(round(f32::INFINITY), Value::Int(i32::MAX));
+        assert_eq!(round(f32::NEG_INFINITY), Value::Int(i32::MIN));
+        assert_eq!(round(f32::NAN), Value::Int(0));
+        assert_eq!(round(-0.5), Value::Int(0));
+        assert_eq!(round(-1.5), Value::Int(-1));
+        assert_eq!(round(0.49999997), Value::Int(0));
+        assert_eq!(round(3.0e9), Value::Int(i32::MAX));
+        assert_eq!(round(2147483648.0), Value::Int(i32::MAX));
+        assert_eq!(round(-3.0e9), Value::Int(i32::MIN));
+    }
+
+    #[test]
+    fn round_double_saturates() {
+        let round = |a: f64| math("round", "(D)J", vec![Value::Double(a)]);
+        assert_eq!(round(f64::INFINITY), Value::Long(i64::MAX));
+        assert_eq!(round(f64::NEG_INFINITY), Value::Long(i64::MIN));
+        assert_eq!(round(f64::NAN), Value::Long(0));
+        assert_eq!(round(-0.5), Value::Long(0));
+        assert_eq!(round(0.49999999999999994), Value::Long(0));
+        assert_eq!(round(3.0e9), Value::Long(3_000_000_000));
+        assert_eq!(round(1.0e19), Value::Long(i64::MAX));
+    }
+
+    #[test]
+    fn abs_of_min_value_overflows() {
+        let abs = math("abs", "(I)I", vec![Value::Int(i32::MIN)]);
+        assert_eq!(abs, Value::Int(i32::MIN));
+        let abs = math("abs", "(J)J", vec![Value::Long(i64::MIN)]);
+        assert_eq!(abs, Value::Long(i64::MIN));
+    }
+
+    #[test]
+    fn arrays_are_named_by_descriptor() {
+        let vm = VM::new(".");
+        let ints = vm.heap.new_array(Type::Int, 0, 2);
+        let strings = vm.heap.new_object_array(STRING_CLASS_IDX, 1);
+        let expected = format!("[I@{:x}", vm.heap.identity_hash(ints));
+        assert_eq!(
+            to_java_string(&vm, &Value::Reference(ints), &Type::Reference),
+            expected
+        );
+        let text = to_java_string(&vm, &Value::Reference(strings), &Type::Reference);
+        assert!(text.starts_with("[Ljava.lang.String;@"), "{}", text);
+    }
+
+    #[test]
+    fn cut_keeps_whole_characters() {
+        assert_eq!(cut("short", 5), "short");
+        assert_eq!(cut("żółw idzie", 3), "żół...");
+    }
 }
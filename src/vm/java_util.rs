@@ -0,0 +1,1549 @@
+use crate::vm::classes::{AccessFlags, ClassIdx, FieldIdx, NativeMethod, Signature};
+use crate::vm::java_lang::{
+    new_throwable, to_java_string, ILLEGAL_STATE_EXCEPTION, NULL_POINTER_EXCEPTION,
+};
+use crate::vm::memory::HeapPtr;
+use crate::vm::program::Program;
+use crate::vm::stack::{Type, Value};
+use crate::VM;
+use std::convert::TryInto;
+
+//collections keep their data in heap, so AutoFree (and later gc) see it as usual objects
+const ITERABLE: &str = "java/lang/Iterable";
+const COLLECTION: &str = "java/util/Collection";
+const LIST: &str = "java/util/List";
+const SET: &str = "java/util/Set";
+const QUEUE: &str = "java/util/Queue";
+const DEQUE: &str = "java/util/Deque";
+const MAP: &str = "java/util/Map";
+const MAP_ENTRY: &str = "java/util/Map$Entry";
+const ITERATOR: &str = "java/util/Iterator";
+
+const ARRAY_LIST: &str = "java/util/ArrayList";
+const LINKED_LIST: &str = "java/util/LinkedList";
+const ARRAY_DEQUE: &str = "java/util/ArrayDeque";
+const HASH_MAP: &str = "java/util/HashMap";
+const HASH_MAP_NODE: &str = "java/util/HashMap$Node";
+const HASH_MAP_KEY_SET: &str = "java/util/HashMap$KeySet";
+const HASH_MAP_VALUES: &str = "java/util/HashMap$Values";
+const HASH_MAP_ENTRY_SET: &str = "java/util/HashMap$EntrySet";
+const HASH_SET: &str = "java/util/HashSet";
+const LIST_ITERATOR: &str = "java/util/ArrayList$Itr";
+const HASH_ITERATOR: &str = "java/util/HashMap$HashIterator";
+
+const CONCURRENT_MODIFICATION_EXCEPTION: &str = "java/util/ConcurrentModificationException";
+const NO_SUCH_ELEMENT_EXCEPTION: &str = "java/util/NoSuchElementException";
+const INDEX_OUT_OF_BOUNDS_EXCEPTION: &str = "java/lang/IndexOutOfBoundsException";
+
+//ArrayList, LinkedList and ArrayDeque share array based layout,
+//elements form a ring starting at head, so both ends are O(1)
+const LIST_ELEMENTS: FieldIdx = 0;
+const LIST_SIZE: FieldIdx = 1;
+const LIST_MOD_COUNT: FieldIdx = 2;
+const LIST_HEAD: FieldIdx = 3;
+
+const MAP_TABLE: FieldIdx = 0;
+const MAP_SIZE: FieldIdx = 1;
+const MAP_MOD_COUNT: FieldIdx = 2;
+const MAP_THRESHOLD: FieldIdx = 3; //initial capacity until table is allocated
+
+const NODE_HASH: FieldIdx = 0;
+const NODE_KEY: FieldIdx = 1;
+const NODE_VALUE: FieldIdx = 2;
+const NODE_NEXT: FieldIdx = 3;
+
+//HashSet and views of HashMap refer to the map
+const BACKING_MAP: FieldIdx = 0;
+
+const LIST_ITERATOR_LIST: FieldIdx = 0;
+const LIST_ITERATOR_CURSOR: FieldIdx = 1;
+const LIST_ITERATOR_LAST_RETURNED: FieldIdx = 2;
+const LIST_ITERATOR_EXPECTED_MOD_COUNT: FieldIdx = 3;
+
+const HASH_ITERATOR_MAP: FieldIdx = 0;
+const HASH_ITERATOR_KIND: FieldIdx = 1;
+const HASH_ITERATOR_BUCKET: FieldIdx = 2;
+const HASH_ITERATOR_NEXT: FieldIdx = 3;
+const HASH_ITERATOR_CURRENT: FieldIdx = 4;
+const HASH_ITERATOR_EXPECTED_MOD_COUNT: FieldIdx = 5;
+
+const ITERATE_KEYS: i32 = 0;
+const ITERATE_VALUES: i32 = 1;
+const ITERATE_ENTRIES: i32 = 2;
+
+const DEFAULT_LIST_CAPACITY: usize = 10;
+const DEFAULT_MAP_CAPACITY: i32 = 16;
+const MAXIMUM_MAP_CAPACITY: i32 = 1 << 30;
+
+const OBJECT: &str = "Ljava/lang/Object;";
+
+impl Program {
+    pub(crate) fn init_java_util(&mut self) {
+        let native = AccessFlags::NATIVE;
+
+        for (name, super_interfaces) in [
+            (ITERABLE, vec![]),
+            (COLLECTION, vec![ITERABLE]),
+            (LIST, vec![COLLECTION]),
+            (SET, vec![COLLECTION]),
+            (QUEUE, vec![COLLECTION]),
+            (DEQUE, vec![QUEUE]),
+            (MAP, vec![]),
+            (MAP_ENTRY, vec![]),
+            (ITERATOR, vec![]),
+        ] {
+            let idx = self.add_class(name, 0, &[], &[], &[]);
+            self.set_interfaces(idx, &super_interfaces);
+        }
+
+        let collection_methods = [
+            ("size", "()I"),
+            ("isEmpty", "()Z"),
+            ("contains", "(Ljava/lang/Object;)Z"),
+            ("add", "(Ljava/lang/Object;)Z"),
+            ("remove", "(Ljava/lang/Object;)Z"),
+            ("clear", "()V"),
+            ("iterator", "()Ljava/util/Iterator;"),
+            ("addAll", "(Ljava/util/Collection;)Z"),
+            ("containsAll", "(Ljava/util/Collection;)Z"),
+            ("toArray", "()[Ljava/lang/Object;"),
+            ("equals", "(Ljava/lang/Object;)Z"),
+            ("hashCode", "()I"),
+            ("toString", "()Ljava/lang/String;"),
+            ("<init>", "()V"),
+            ("<init>", "(Ljava/util/Collection;)V"),
+        ];
+        let list_methods = [
+            ("<init>", "(I)V"),
+            ("get", "(I)Ljava/lang/Object;"),
+            ("set", "(ILjava/lang/Object;)Ljava/lang/Object;"),
+            ("add", "(ILjava/lang/Object;)V"),
+            ("remove", "(I)Ljava/lang/Object;"),
+            ("indexOf", "(Ljava/lang/Object;)I"),
+            ("lastIndexOf", "(Ljava/lang/Object;)I"),
+        ];
+        let deque_methods = [
+            ("addFirst", "(Ljava/lang/Object;)V"),
+            ("addLast", "(Ljava/lang/Object;)V"),
+            ("offerFirst", "(Ljava/lang/Object;)Z"),
+            ("offerLast", "(Ljava/lang/Object;)Z"),
+            ("offer", "(Ljava/lang/Object;)Z"),
+            ("push", "(Ljava/lang/Object;)V"),
+            ("removeFirst", "()Ljava/lang/Object;"),
+            ("removeLast", "()Ljava/lang/Object;"),
+            ("remove", "()Ljava/lang/Object;"),
+            ("pop", "()Ljava/lang/Object;"),
+            ("pollFirst", "()Ljava/lang/Object;"),
+            ("pollLast", "()Ljava/lang/Object;"),
+            ("poll", "()Ljava/lang/Object;"),
+            ("getFirst", "()Ljava/lang/Object;"),
+            ("getLast", "()Ljava/lang/Object;"),
+            ("element", "()Ljava/lang/Object;"),
+            ("peekFirst", "()Ljava/lang/Object;"),
+            ("peekLast", "()Ljava/lang/Object;"),
+            ("peek", "()Ljava/lang/Object;"),
+        ];
+        let list_fields = [
+            ("elementData", "[Ljava/lang/Object;"),
+            ("size", "I"),
+            ("modCount", "I"),
+            ("head", "I"),
+        ];
+
+        let with_flags = |methods: &[&[(&'static str, &'static str)]]| {
+            methods
+                .iter()
+                .flat_map(|x| x.iter())
+                .map(|(name, descriptor)| (*name, *descriptor, native))
+                .collect::<Vec<_>>()
+        };
+
+        let idx = self.add_class(
+            ARRAY_LIST,
+            0,
+            &list_fields,
+            &[],
+            &with_flags(&[&collection_methods, &list_methods]),
+        );
+        self.set_interfaces(idx, &[LIST]);
+        let idx = self.add_class(
+            LINKED_LIST,
+            0,
+            &list_fields,
+            &[],
+            &with_flags(&[&collection_methods, &list_methods, &deque_methods]),
+        );
+        self.set_interfaces(idx, &[LIST, DEQUE]);
+        let idx = self.add_class(
+            ARRAY_DEQUE,
+            0,
+            &list_fields,
+            &[],
+            &with_flags(&[&collection_methods, &[("<init>", "(I)V")], &deque_methods]),
+        );
+        self.set_interfaces(idx, &[DEQUE]);
+
+        let idx = self.add_class(
+            HASH_MAP,
+            0,
+            &[
+                ("table", "[Ljava/util/HashMap$Node;"),
+                ("size", "I"),
+                ("modCount", "I"),
+                ("threshold", "I"),
+            ],
+            &[],
+            &with_flags(&[&[
+                ("<init>", "()V"),
+                ("<init>", "(I)V"),
+                ("<init>", "(Ljava/util/Map;)V"),
+                ("size", "()I"),
+                ("isEmpty", "()Z"),
+                ("get", "(Ljava/lang/Object;)Ljava/lang/Object;"),
+                (
+                    "getOrDefault",
+                    "(Ljava/lang/Object;Ljava/lang/Object;)Ljava/lang/Object;",
+                ),
+                ("containsKey", "(Ljava/lang/Object;)Z"),
+                ("containsValue", "(Ljava/lang/Object;)Z"),
+                (
+                    "put",
+                    "(Ljava/lang/Object;Ljava/lang/Object;)Ljava/lang/Object;",
+                ),
+                (
+                    "putIfAbsent",
+                    "(Ljava/lang/Object;Ljava/lang/Object;)Ljava/lang/Object;",
+                ),
+                ("putAll", "(Ljava/util/Map;)V"),
+                ("remove", "(Ljava/lang/Object;)Ljava/lang/Object;"),
+                ("clear", "()V"),
+                ("keySet", "()Ljava/util/Set;"),
+                ("values", "()Ljava/util/Collection;"),
+                ("entrySet", "()Ljava/util/Set;"),
+                ("equals", "(Ljava/lang/Object;)Z"),
+                ("hashCode", "()I"),
+                ("toString", "()Ljava/lang/String;"),
+            ]]),
+        );
+        self.set_interfaces(idx, &[MAP]);
+
+        let idx = self.add_class(
+            HASH_MAP_NODE,
+            0,
+            &[
+                ("hash", "I"),
+                ("key", OBJECT),
+                ("value", OBJECT),
+                ("next", "Ljava/util/HashMap$Node;"),
+            ],
+            &[],
+            &with_flags(&[&[
+                ("getKey", "()Ljava/lang/Object;"),
+                ("getValue", "()Ljava/lang/Object;"),
+                ("setValue", "(Ljava/lang/Object;)Ljava/lang/Object;"),
+                ("equals", "(Ljava/lang/Object;)Z"),
+                ("hashCode", "()I"),
+                ("toString", "()Ljava/lang/String;"),
+            ]]),
+        );
+        self.set_interfaces(idx, &[MAP_ENTRY]);
+
+        let view_methods = [
+            ("size", "()I"),
+            ("isEmpty", "()Z"),
+            ("contains", "(Ljava/lang/Object;)Z"),
+            ("remove", "(Ljava/lang/Object;)Z"),
+            ("iterator", "()Ljava/util/Iterator;"),
+            ("toString", "()Ljava/lang/String;"),
+        ];
+        for (name, interface) in [
+            (HASH_MAP_KEY_SET, SET),
+            (HASH_MAP_VALUES, COLLECTION),
+            (HASH_MAP_ENTRY_SET, SET),
+        ] {
+            let idx = self.add_class(
+                name,
+                0,
+                &[("map", "Ljava/util/HashMap;")],
+                &[],
+                &with_flags(&[&view_methods]),
+            );
+            self.set_interfaces(idx, &[interface]);
+        }
+
+        let idx = self.add_class(
+            HASH_SET,
+            0,
+            &[("map", "Ljava/util/HashMap;")],
+            &[],
+            &with_flags(&[&collection_methods, &[("<init>", "(I)V")]]),
+        );
+        self.set_interfaces(idx, &[SET]);
+
+        let iterator_methods = [
+            ("hasNext", "()Z"),
+            ("next", "()Ljava/lang/Object;"),
+            ("remove", "()V"),
+        ];
+        let idx = self.add_class(
+            LIST_ITERATOR,
+            0,
+            &[
+                ("list", OBJECT),
+                ("cursor", "I"),
+                ("lastRet", "I"),
+                ("expectedModCount", "I"),
+            ],
+            &[],
+            &with_flags(&[&iterator_methods]),
+        );
+        self.set_interfaces(idx, &[ITERATOR]);
+        let idx = self.add_class(
+            HASH_ITERATOR,
+            0,
+            &[
+                ("map", "Ljava/util/HashMap;"),
+                ("kind", "I"),
+                ("index", "I"),
+                ("next", "Ljava/util/HashMap$Node;"),
+                ("current", "Ljava/util/HashMap$Node;"),
+                ("expectedModCount", "I"),
+            ],
+            &[],
+            &with_flags(&[&iterator_methods]),
+        );
+        self.set_interfaces(idx, &[ITERATOR]);
+
+        self.add_exception_class(
+            CONCURRENT_MODIFICATION_EXCEPTION,
+            "java/lang/RuntimeException",
+        );
+        self.add_exception_class(NO_SUCH_ELEMENT_EXCEPTION, "java/lang/RuntimeException");
+
+        self.native_methods.push(Box::new(JavaUtil {}));
+    }
+
+    fn set_interfaces(&mut self, class_idx: ClassIdx, interfaces: &[&str]) {
+        self.classes[class_idx].interfaces = interfaces.iter().map(|x| x.to_string()).collect();
+    }
+}
+
+struct JavaUtil;
+
+impl NativeMethod for JavaUtil {
+    fn invoke(
+        &self,
+        vm: &VM,
+        class_name: &str,
+        name: &str,
+        signature: &Signature,
+        arguments: Vec<Value>,
+    ) -> Option<Value> {
+        let this: HeapPtr = match arguments.first() {
+            Some(Value::Reference(ptr)) => *ptr,
+            _ => return None,
+        };
+        let args = &arguments[1..];
+        match class_name {
+            ARRAY_LIST | LINKED_LIST | ARRAY_DEQUE => {
+                list_method(vm, class_name, this, name, signature, args)
+            }
+            HASH_MAP => map_method(vm, this, name, args),
+            HASH_SET => set_method(vm, this, name, args),
+            HASH_MAP_KEY_SET | HASH_MAP_VALUES | HASH_MAP_ENTRY_SET => {
+                view_method(vm, class_name, this, name, args)
+            }
+            HASH_MAP_NODE => entry_method(vm, this, name, args),
+            LIST_ITERATOR => list_iterator_method(vm, this, name),
+            HASH_ITERATOR => hash_iterator_method(vm, this, name),
+            _ => None,
+        }
+    }
+}
+
+fn list_method(
+    vm: &VM,
+    class_name: &str,
+    this: HeapPtr,
+    name: &str,
+    signature: &Signature,
+    args: &[Value],
+) -> Option<Value> {
+    let size = int_field(vm, this, LIST_SIZE) as usize;
+    //ArrayDeque rejects null elements, though looking for null just finds nothing
+    let inserts = matches!(
+        name,
+        "add" | "addFirst" | "addLast" | "offer" | "offerFirst" | "offerLast" | "push"
+    );
+    if class_name == ARRAY_DEQUE && inserts && args.first() == Some(&Value::Reference(0)) {
+        return throw(vm, NULL_POINTER_EXCEPTION, "");
+    }
+    let index_arg = match (signature.arguments.first(), args.first()) {
+        (Some(Type::Int), Some(Value::Int(index))) => Some(*index),
+        _ => None,
+    };
+    let result = match (name, index_arg) {
+        ("<init>", _) => {
+            if let Some(Value::Reference(collection)) = args.first() {
+                for value in iterate(vm, *collection) {
+                    list_insert(vm, this, list_size(vm, this), value);
+                }
+            }
+            Value::Void
+        }
+        ("size", _) => Value::Int(size as i32),
+        ("isEmpty", _) => Value::Boolean((size == 0) as i32),
+        ("contains", _) => Value::Boolean(list_index_of(vm, this, &args[0]).is_some() as i32),
+        ("indexOf", _) => Value::Int(list_index_of(vm, this, &args[0]).map_or(-1, |x| x as i32)),
+        ("lastIndexOf", _) => {
+            let elements = list_elements(vm, this);
+            let index = elements.iter().rposition(|x| java_equals(vm, &args[0], x));
+            Value::Int(index.map_or(-1, |x| x as i32))
+        }
+        ("get", Some(index)) | ("set", Some(index)) | ("remove", Some(index))
+            if index < 0 || index as usize >= size =>
+        {
+            return throw_index_out_of_bounds(vm, index, size);
+        }
+        ("add", Some(index)) if index < 0 || index as usize > size => {
+            return throw_index_out_of_bounds(vm, index, size);
+        }
+        ("get", Some(index)) => list_get(vm, this, index as usize),
+        ("set", Some(index)) => {
+            let old = list_get(vm, this, index as usize);
+            list_set(vm, this, index as usize, args[1].clone());
+            old
+        }
+        ("add", Some(index)) => {
+            list_insert(vm, this, index as usize, args[1].clone());
+            Value::Void
+        }
+        ("remove", Some(index)) => list_remove_at(vm, this, index as usize),
+        ("add", None) | ("offer", _) | ("offerLast", _) => {
+            list_insert(vm, this, size, args[0].clone());
+            Value::Boolean(1)
+        }
+        ("addLast", _) => {
+            list_insert(vm, this, size, args[0].clone());
+            Value::Void
+        }
+        ("addFirst", _) | ("push", _) => {
+            list_insert(vm, this, 0, args[0].clone());
+            Value::Void
+        }
+        ("offerFirst", _) => {
+            list_insert(vm, this, 0, args[0].clone());
+            Value::Boolean(1)
+        }
+        ("remove", None) if args.is_empty() => {
+            if size == 0 {
+                return throw(vm, NO_SUCH_ELEMENT_EXCEPTION, "");
+            }
+            list_remove_at(vm, this, 0)
+        }
+        ("remove", None) => match list_index_of(vm, this, &args[0]) {
+            Some(index) => {
+                list_remove_at(vm, this, index);
+                Value::Boolean(1)
+            }
+            None => Value::Boolean(0),
+        },
+        ("removeFirst", _)
+        | ("pop", _)
+        | ("getFirst", _)
+        | ("element", _)
+        | ("removeLast", _)
+        | ("getLast", _)
+            if size == 0 =>
+        {
+            return throw(vm, NO_SUCH_ELEMENT_EXCEPTION, "");
+        }
+        ("pollFirst", _)
+        | ("poll", _)
+        | ("pollLast", _)
+        | ("peekFirst", _)
+        | ("peek", _)
+        | ("peekLast", _)
+            if size == 0 =>
+        {
+            Value::Reference(0)
+        }
+        ("removeFirst", _) | ("pop", _) | ("pollFirst", _) | ("poll", _) => {
+            list_remove_at(vm, this, 0)
+        }
+        ("removeLast", _) | ("pollLast", _) => list_remove_at(vm, this, size - 1),
+        ("getFirst", _) | ("element", _) | ("peekFirst", _) | ("peek", _) => list_get(vm, this, 0),
+        ("getLast", _) | ("peekLast", _) => list_get(vm, this, size - 1),
+        ("clear", _) => {
+            for i in 0..size {
+                list_set(vm, this, i, Value::Reference(0));
+            }
+            set_int_field(vm, this, LIST_SIZE, 0);
+            set_int_field(vm, this, LIST_HEAD, 0);
+            increment_int_field(vm, this, LIST_MOD_COUNT);
+            Value::Void
+        }
+        ("iterator", _) => {
+            let iterator = new_object(vm, LIST_ITERATOR);
+            vm.heap
+                .set_field(iterator, LIST_ITERATOR_LIST, Value::Reference(this));
+            vm.heap
+                .set_field(iterator, LIST_ITERATOR_LAST_RETURNED, Value::Int(-1));
+            vm.heap.set_field(
+                iterator,
+                LIST_ITERATOR_EXPECTED_MOD_COUNT,
+                vm.heap.get_field(this, LIST_MOD_COUNT),
+            );
+            Value::Reference(iterator)
+        }
+        _ => return collection_method(vm, this, name, args),
+    };
+    Some(result)
+}
+
+fn map_method(vm: &VM, this: HeapPtr, name: &str, args: &[Value]) -> Option<Value> {
+    let result = match name {
+        "<init>" => {
+            match args.first() {
+                Some(Value::Int(capacity)) => {
+                    let capacity = table_size_for(*capacity);
+                    set_int_field(vm, this, MAP_THRESHOLD, capacity);
+                }
+                Some(Value::Reference(other)) => {
+                    let entries = map_entries(vm, *other);
+                    let capacity = (entries.len() as f32 / 0.75) as i32 + 1;
+                    set_int_field(vm, this, MAP_THRESHOLD, table_size_for(capacity));
+                    for (key, value) in entries {
+                        map_put(vm, this, key, value, false);
+                    }
+                }
+                _ => {}
+            }
+            Value::Void
+        }
+        "size" => vm.heap.get_field(this, MAP_SIZE),
+        "isEmpty" => Value::Boolean((int_field(vm, this, MAP_SIZE) == 0) as i32),
+        "get" => match map_find(vm, this, &args[0]) {
+            Some(node) => vm.heap.get_field(node, NODE_VALUE),
+            None => Value::Reference(0),
+        },
+        "getOrDefault" => match map_find(vm, this, &args[0]) {
+            Some(node) => vm.heap.get_field(node, NODE_VALUE),
+            None => args[1].clone(),
+        },
+        "containsKey" => Value::Boolean(map_find(vm, this, &args[0]).is_some() as i32),
+        "containsValue" => {
+            let found = map_nodes(vm, this)
+                .iter()
+                .any(|node| java_equals(vm, &args[0], &vm.heap.get_field(*node, NODE_VALUE)));
+            Value::Boolean(found as i32)
+        }
+        "put" => map_put(vm, this, args[0].clone(), args[1].clone(), false)
+            .unwrap_or(Value::Reference(0)),
+        "putIfAbsent" => {
+            map_put(vm, this, args[0].clone(), args[1].clone(), true).unwrap_or(Value::Reference(0))
+        }
+        "putAll" => {
+            if let Value::Reference(other) = args[0] {
+                for (key, value) in map_entries(vm, other) {
+                    map_put(vm, this, key, value, false);
+                }
+            }
+            Value::Void
+        }
+        "remove" => match map_remove(vm, this, &args[0]) {
+            Some(value) => value,
+            None => Value::Reference(0),
+        },
+        "clear" => {
+            map_clear(vm, this);
+            Value::Void
+        }
+        "keySet" => new_view(vm, HASH_MAP_KEY_SET, this),
+        "values" => new_view(vm, HASH_MAP_VALUES, this),
+        "entrySet" => new_view(vm, HASH_MAP_ENTRY_SET, this),
+        "equals" => {
+            let equal = match args[0] {
+                Value::Reference(other) if other == this => true,
+                Value::Reference(other) if is_instance(vm, other, MAP) => {
+                    let other_size = vm.invoke_virtual(other, "size", "()I", vec![]);
+                    other_size == vm.heap.get_field(this, MAP_SIZE)
+                        && map_entries(vm, this).iter().all(|(key, value)| {
+                            let other_value = vm.invoke_virtual(
+                                other,
+                                "get",
+                                "(Ljava/lang/Object;)Ljava/lang/Object;",
+                                vec![key.clone()],
+                            );
+                            java_equals(vm, value, &other_value)
+                                && (*value != Value::Reference(0)
+                                    || is_true(vm.invoke_virtual(
+                                        other,
+                                        "containsKey",
+                                        "(Ljava/lang/Object;)Z",
+                                        vec![key.clone()],
+                                    )))
+                        })
+                }
+                _ => false,
+            };
+            Value::Boolean(equal as i32)
+        }
+        "hashCode" => {
+            let hash = map_nodes(vm, this).iter().fold(0i32, |hash, node| {
+                hash.wrapping_add(entry_hash_code(vm, *node))
+            });
+            Value::Int(hash)
+        }
+        "toString" => {
+            let mut items = vec![];
+            for (key, value) in map_entries(vm, this) {
+                let key = element_to_string(vm, &key, this, "(this Map)");
+                let value = element_to_string(vm, &value, this, "(this Map)");
+                match (key, value) {
+                    (Some(key), Some(value)) => items.push(format!("{}={}", key, value)),
+                    _ => return Some(Value::Void), //thrown by toString
+                }
+            }
+            new_string(vm, format!("{{{}}}", items.join(", ")))
+        }
+        _ => return None,
+    };
+    Some(result)
+}
+
+fn set_method(vm: &VM, this: HeapPtr, name: &str, args: &[Value]) -> Option<Value> {
+    let result = match name {
+        "<init>" => {
+            let map = new_object(vm, HASH_MAP);
+            vm.heap.set_field(this, BACKING_MAP, Value::Reference(map));
+            match args.first() {
+                Some(Value::Int(capacity)) => {
+                    set_int_field(vm, map, MAP_THRESHOLD, table_size_for(*capacity));
+                }
+                Some(Value::Reference(collection)) => {
+                    let values = iterate(vm, *collection);
+                    let capacity = ((values.len() as f32 / 0.75) as i32 + 1).max(16);
+                    set_int_field(vm, map, MAP_THRESHOLD, table_size_for(capacity));
+                    for value in values {
+                        map_put(vm, map, value, Value::Reference(0), false);
+                    }
+                }
+                _ => {}
+            }
+            Value::Void
+        }
+        "add" => {
+            let map = ptr_field(vm, this, BACKING_MAP);
+            let existed = map_put(vm, map, args[0].clone(), Value::Reference(0), true).is_some();
+            Value::Boolean(!existed as i32)
+        }
+        "remove" => {
+            let map = ptr_field(vm, this, BACKING_MAP);
+            Value::Boolean(map_remove(vm, map, &args[0]).is_some() as i32)
+        }
+        "clear" => {
+            map_clear(vm, ptr_field(vm, this, BACKING_MAP));
+            Value::Void
+        }
+        "size" | "isEmpty" | "contains" | "iterator" => {
+            return view_method(vm, HASH_MAP_KEY_SET, this, name, args)
+        }
+        _ => return collection_method(vm, this, name, args),
+    };
+    Some(result)
+}
+
+//key set, values and entry set of HashMap, HashSet works as key set of its map
+fn view_method(
+    vm: &VM,
+    class_name: &str,
+    this: HeapPtr,
+    name: &str,
+    args: &[Value],
+) -> Option<Value> {
+    let map = ptr_field(vm, this, BACKING_MAP);
+    let result = match name {
+        "size" => vm.heap.get_field(map, MAP_SIZE),
+        "isEmpty" => Value::Boolean((int_field(vm, map, MAP_SIZE) == 0) as i32),
+        "contains" => Value::Boolean(view_node(vm, class_name, map, &args[0]).is_some() as i32),
+        "remove" => match view_node(vm, class_name, map, &args[0]) {
+            Some(node) => {
+                map_remove_node(vm, map, node);
+                Value::Boolean(1)
+            }
+            None => Value::Boolean(0),
+        },
+        "iterator" => {
+            let kind = match class_name {
+                HASH_MAP_VALUES => ITERATE_VALUES,
+                HASH_MAP_ENTRY_SET => ITERATE_ENTRIES,
+                _ => ITERATE_KEYS,
+            };
+            let iterator = new_object(vm, HASH_ITERATOR);
+            vm.heap
+                .set_field(iterator, HASH_ITERATOR_MAP, Value::Reference(map));
+            vm.heap
+                .set_field(iterator, HASH_ITERATOR_KIND, Value::Int(kind));
+            vm.heap.set_field(
+                iterator,
+                HASH_ITERATOR_EXPECTED_MOD_COUNT,
+                vm.heap.get_field(map, MAP_MOD_COUNT),
+            );
+            hash_iterator_advance(vm, iterator, 0);
+            Value::Reference(iterator)
+        }
+        _ => return collection_method(vm, this, name, args),
+    };
+    Some(result)
+}
+
+//node of the map which is the given element of view
+fn view_node(vm: &VM, class_name: &str, map: HeapPtr, element: &Value) -> Option<HeapPtr> {
+    match class_name {
+        HASH_MAP_KEY_SET => map_find(vm, map, element),
+        HASH_MAP_VALUES => map_nodes(vm, map)
+            .into_iter()
+            .find(|node| java_equals(vm, element, &vm.heap.get_field(*node, NODE_VALUE))),
+        _ => match *element {
+            Value::Reference(entry) if entry != 0 && is_instance(vm, entry, MAP_ENTRY) => {
+                let key = vm.invoke_virtual(entry, "getKey", "()Ljava/lang/Object;", vec![]);
+                let value = vm.invoke_virtual(entry, "getValue", "()Ljava/lang/Object;", vec![]);
+                map_find(vm, map, &key)
+                    .filter(|node| java_equals(vm, &value, &vm.heap.get_field(*node, NODE_VALUE)))
+            }
+            _ => None,
+        },
+    }
+}
+
+//methods implemented on top of iteration, shared by all collections
+fn collection_method(vm: &VM, this: HeapPtr, name: &str, args: &[Value]) -> Option<Value> {
+    let result = match name {
+        "addAll" => {
+            let values = match args[0] {
+                Value::Reference(collection) => iterate(vm, collection),
+                _ => return None,
+            };
+            let mut changed = false;
+            for value in values {
+                let added = vm.invoke_virtual(this, "add", "(Ljava/lang/Object;)Z", vec![value]);
+                if vm.pending_exception() != 0 {
+                    break;
+                }
+                changed |= is_true(added);
+            }
+            Value::Boolean(changed as i32)
+        }
+        "containsAll" => {
+            let values = match args[0] {
+                Value::Reference(collection) => iterate(vm, collection),
+                _ => return None,
+            };
+            let all = values.into_iter().all(|value| {
+                is_true(vm.invoke_virtual(this, "contains", "(Ljava/lang/Object;)Z", vec![value]))
+            });
+            Value::Boolean(all as i32)
+        }
+        "toArray" => {
+            let values = iterate(vm, this);
+            let array = vm.heap.new_object_array(0, values.len() as i32);
+            for (i, value) in values.into_iter().enumerate() {
+                vm.heap.set_array_element(array, i, value);
+            }
+            Value::Reference(array)
+        }
+        "equals" => {
+            let equal = match args[0] {
+                Value::Reference(other) if other == this => true,
+                Value::Reference(other) if is_instance(vm, this, LIST) => {
+                    other != 0 && is_instance(vm, other, LIST) && {
+                        let values = iterate(vm, this);
+                        let other_values = iterate(vm, other);
+                        values.len() == other_values.len()
+                            && values
+                                .iter()
+                                .zip(other_values.iter())
+                                .all(|(a, b)| java_equals(vm, a, b))
+                    }
+                }
+                Value::Reference(other) if is_instance(vm, this, SET) => {
+                    other != 0 && is_instance(vm, other, SET) && {
+                        let other_values = iterate(vm, other);
+                        let size = vm.invoke_virtual(this, "size", "()I", vec![]);
+                        size == Value::Int(other_values.len() as i32)
+                            && other_values.into_iter().all(|value| {
+                                is_true(vm.invoke_virtual(
+                                    this,
+                                    "contains",
+                                    "(Ljava/lang/Object;)Z",
+                                    vec![value],
+                                ))
+                            })
+                    }
+                }
+                _ => false,
+            };
+            Value::Boolean(equal as i32)
+        }
+        "hashCode" => {
+            let values = iterate(vm, this);
+            let hash = if is_instance(vm, this, LIST) {
+                values.iter().fold(1i32, |hash, value| {
+                    hash.wrapping_mul(31)
+                        .wrapping_add(java_hash_code(vm, value))
+                })
+            } else {
+                values.iter().fold(0i32, |hash, value| {
+                    hash.wrapping_add(java_hash_code(vm, value))
+                })
+            };
+            Value::Int(hash)
+        }
+        "toString" => {
+            let mut items = vec![];
+            for value in iterate(vm, this) {
+                match element_to_string(vm, &value, this, "(this Collection)") {
+                    Some(item) => items.push(item),
+                    None => return Some(Value::Void), //thrown by toString
+                }
+            }
+            new_string(vm, format!("[{}]", items.join(", ")))
+        }
+        _ => return None,
+    };
+    Some(result)
+}
+
+fn entry_method(vm: &VM, this: HeapPtr, name: &str, args: &[Value]) -> Option<Value> {
+    let key = vm.heap.get_field(this, NODE_KEY);
+    let value = vm.heap.get_field(this, NODE_VALUE);
+    let result = match name {
+        "getKey" => key,
+        "getValue" => value,
+        "setValue" => {
+            vm.heap.set_field(this, NODE_VALUE, args[0].clone());
+            value
+        }
+        "equals" => {
+            let equal = match args[0] {
+                Value::Reference(other) if other != 0 && is_instance(vm, other, MAP_ENTRY) => {
+                    let other_key =
+                        vm.invoke_virtual(other, "getKey", "()Ljava/lang/Object;", vec![]);
+                    let other_value =
+                        vm.invoke_virtual(other, "getValue", "()Ljava/lang/Object;", vec![]);
+                    java_equals(vm, &key, &other_key) && java_equals(vm, &value, &other_value)
+                }
+                _ => false,
+            };
+            Value::Boolean(equal as i32)
+        }
+        "hashCode" => Value::Int(entry_hash_code(vm, this)),
+        "toString" => {
+            let str = format!(
+                "{}={}",
+                to_java_string(vm, &key, &Type::Reference),
+                to_java_string(vm, &value, &Type::Reference)
+            );
+            if vm.pending_exception() != 0 {
+                return Some(Value::Void); //thrown by toString
+            }
+            new_string(vm, str)
+        }
+        _ => return None,
+    };
+    Some(result)
+}
+
+fn list_iterator_method(vm: &VM, this: HeapPtr, name: &str) -> Option<Value> {
+    let list = ptr_field(vm, this, LIST_ITERATOR_LIST);
+    let cursor = int_field(vm, this, LIST_ITERATOR_CURSOR);
+    let result = match name {
+        "hasNext" => Value::Boolean((cursor != int_field(vm, list, LIST_SIZE)) as i32),
+        "next" => {
+            if !check_mod_count(
+                vm,
+                this,
+                LIST_ITERATOR_EXPECTED_MOD_COUNT,
+                list,
+                LIST_MOD_COUNT,
+            ) {
+                return throw(vm, CONCURRENT_MODIFICATION_EXCEPTION, "");
+            }
+            if cursor >= int_field(vm, list, LIST_SIZE) {
+                return throw(vm, NO_SUCH_ELEMENT_EXCEPTION, "");
+            }
+            set_int_field(vm, this, LIST_ITERATOR_CURSOR, cursor + 1);
+            set_int_field(vm, this, LIST_ITERATOR_LAST_RETURNED, cursor);
+            list_get(vm, list, cursor as usize)
+        }
+        "remove" => {
+            let last_returned = int_field(vm, this, LIST_ITERATOR_LAST_RETURNED);
+            if last_returned < 0 {
+                return throw(vm, ILLEGAL_STATE_EXCEPTION, "");
+            }
+            if !check_mod_count(
+                vm,
+                this,
+                LIST_ITERATOR_EXPECTED_MOD_COUNT,
+                list,
+                LIST_MOD_COUNT,
+            ) {
+                return throw(vm, CONCURRENT_MODIFICATION_EXCEPTION, "");
+            }
+            list_remove_at(vm, list, last_returned as usize);
+            set_int_field(vm, this, LIST_ITERATOR_CURSOR, last_returned);
+            set_int_field(vm, this, LIST_ITERATOR_LAST_RETURNED, -1);
+            vm.heap.set_field(
+                this,
+                LIST_ITERATOR_EXPECTED_MOD_COUNT,
+                vm.heap.get_field(list, LIST_MOD_COUNT),
+            );
+            Value::Void
+        }
+        _ => return None,
+    };
+    Some(result)
+}
+
+fn hash_iterator_method(vm: &VM, this: HeapPtr, name: &str) -> Option<Value> {
+    let map = ptr_field(vm, this, HASH_ITERATOR_MAP);
+    let result = match name {
+        "hasNext" => Value::Boolean((ptr_field(vm, this, HASH_ITERATOR_NEXT) != 0) as i32),
+        "next" => {
+            if !check_mod_count(
+                vm,
+                this,
+                HASH_ITERATOR_EXPECTED_MOD_COUNT,
+                map,
+                MAP_MOD_COUNT,
+            ) {
+                return throw(vm, CONCURRENT_MODIFICATION_EXCEPTION, "");
+            }
+            let node = ptr_field(vm, this, HASH_ITERATOR_NEXT);
+            if node == 0 {
+                return throw(vm, NO_SUCH_ELEMENT_EXCEPTION, "");
+            }
+            vm.heap
+                .set_field(this, HASH_ITERATOR_CURRENT, Value::Reference(node));
+            let next = ptr_field(vm, node, NODE_NEXT);
+            if next != 0 {
+                vm.heap
+                    .set_field(this, HASH_ITERATOR_NEXT, Value::Reference(next));
+            } else {
+                let bucket = int_field(vm, this, HASH_ITERATOR_BUCKET) as usize;
+                hash_iterator_advance(vm, this, bucket);
+            }
+            match int_field(vm, this, HASH_ITERATOR_KIND) {
+                ITERATE_KEYS => vm.heap.get_field(node, NODE_KEY),
+                ITERATE_VALUES => vm.heap.get_field(node, NODE_VALUE),
+                _ => Value::Reference(node),
+            }
+        }
+        "remove" => {
+            let current = ptr_field(vm, this, HASH_ITERATOR_CURRENT);
+            if current == 0 {
+                return throw(vm, ILLEGAL_STATE_EXCEPTION, "");
+            }
+            if !check_mod_count(
+                vm,
+                this,
+                HASH_ITERATOR_EXPECTED_MOD_COUNT,
+                map,
+                MAP_MOD_COUNT,
+            ) {
+                return throw(vm, CONCURRENT_MODIFICATION_EXCEPTION, "");
+            }
+            let key = vm.heap.get_field(current, NODE_KEY);
+            vm.heap
+                .set_field(this, HASH_ITERATOR_CURRENT, Value::Reference(0));
+            map_remove(vm, map, &key);
+            vm.heap.set_field(
+                this,
+                HASH_ITERATOR_EXPECTED_MOD_COUNT,
+                vm.heap.get_field(map, MAP_MOD_COUNT),
+            );
+            Value::Void
+        }
+        _ => return None,
+    };
+    Some(result)
+}
+
+//finds first node in buckets starting from given one
+fn hash_iterator_advance(vm: &VM, iterator: HeapPtr, from_bucket: usize) {
+    let map = ptr_field(vm, iterator, HASH_ITERATOR_MAP);
+    let table = ptr_field(vm, map, MAP_TABLE);
+    let capacity = array_length(vm, table);
+    let mut bucket = from_bucket;
+    let mut next = 0;
+    while bucket < capacity && next == 0 {
+        next = vm.heap.get_array_element(table, bucket).try_into().unwrap();
+        bucket += 1;
+    }
+    vm.heap
+        .set_field(iterator, HASH_ITERATOR_NEXT, Value::Reference(next));
+    vm.heap
+        .set_field(iterator, HASH_ITERATOR_BUCKET, Value::Int(bucket as i32));
+}
+
+fn check_mod_count(
+    vm: &VM,
+    iterator: HeapPtr,
+    expected_field: FieldIdx,
+    collection: HeapPtr,
+    mod_count_field: FieldIdx,
+) -> bool {
+    vm.heap.get_field(iterator, expected_field) == vm.heap.get_field(collection, mod_count_field)
+}
+
+fn list_size(vm: &VM, list: HeapPtr) -> usize {
+    int_field(vm, list, LIST_SIZE) as usize
+}
+
+//array and its slot holding element at index
+fn list_slot(vm: &VM, list: HeapPtr, index: usize) -> (HeapPtr, usize) {
+    let elements = ptr_field(vm, list, LIST_ELEMENTS);
+    let head = int_field(vm, list, LIST_HEAD) as usize;
+    (elements, (head + index) % array_length(vm, elements))
+}
+
+fn list_get(vm: &VM, list: HeapPtr, index: usize) -> Value {
+    let (elements, slot) = list_slot(vm, list, index);
+    vm.heap.get_array_element(elements, slot)
+}
+
+fn list_set(vm: &VM, list: HeapPtr, index: usize, value: Value) {
+    let (elements, slot) = list_slot(vm, list, index);
+    vm.heap.set_array_element(elements, slot, value);
+}
+
+fn list_elements(vm: &VM, list: HeapPtr) -> Vec<Value> {
+    (0..list_size(vm, list))
+        .map(|i| list_get(vm, list, i))
+        .collect()
+}
+
+fn list_index_of(vm: &VM, list: HeapPtr, value: &Value) -> Option<usize> {
+    list_elements(vm, list)
+        .iter()
+        .position(|x| java_equals(vm, value, x))
+}
+
+fn list_insert(vm: &VM, list: HeapPtr, index: usize, value: Value) {
+    let size = list_size(vm, list);
+    let elements = ptr_field(vm, list, LIST_ELEMENTS);
+    let mut capacity = array_length(vm, elements);
+    if size == capacity {
+        //grow by half, as java does; ring is unrolled to start at 0
+        capacity = (capacity + (capacity >> 1)).max(DEFAULT_LIST_CAPACITY);
+        let new_elements = vm.heap.new_object_array(0, capacity as i32);
+        for i in 0..size {
+            vm.heap
+                .set_array_element(new_elements, i, list_get(vm, list, i));
+        }
+        if elements != 0 {
+            vm.heap.release(elements);
+        }
+        vm.heap
+            .set_field(list, LIST_ELEMENTS, Value::Reference(new_elements));
+        set_int_field(vm, list, LIST_HEAD, 0);
+    }
+    //shifts the shorter side
+    if index < size - index {
+        let head = int_field(vm, list, LIST_HEAD) as usize;
+        set_int_field(
+            vm,
+            list,
+            LIST_HEAD,
+            ((head + capacity - 1) % capacity) as i32,
+        );
+        for i in 0..index {
+            list_set(vm, list, i, list_get(vm, list, i + 1));
+        }
+    } else {
+        for i in (index..size).rev() {
+            list_set(vm, list, i + 1, list_get(vm, list, i));
+        }
+    }
+    list_set(vm, list, index, value);
+    set_int_field(vm, list, LIST_SIZE, size as i32 + 1);
+    increment_int_field(vm, list, LIST_MOD_COUNT);
+}
+
+fn list_remove_at(vm: &VM, list: HeapPtr, index: usize) -> Value {
+    let size = list_size(vm, list);
+    let removed = list_get(vm, list, index);
+    //shifts the shorter side
+    if index < size - 1 - index {
+        for i in (0..index).rev() {
+            list_set(vm, list, i + 1, list_get(vm, list, i));
+        }
+        list_set(vm, list, 0, Value::Reference(0));
+        let capacity = array_length(vm, ptr_field(vm, list, LIST_ELEMENTS));
+        let head = int_field(vm, list, LIST_HEAD) as usize;
+        set_int_field(vm, list, LIST_HEAD, ((head + 1) % capacity) as i32);
+    } else {
+        for i in index..size - 1 {
+            list_set(vm, list, i, list_get(vm, list, i + 1));
+        }
+        list_set(vm, list, size - 1, Value::Reference(0));
+    }
+    set_int_field(vm, list, LIST_SIZE, size as i32 - 1);
+    increment_int_field(vm, list, LIST_MOD_COUNT);
+    removed
+}
+
+//same as HashMap.hash: high bits are spread to lower ones; None if hashCode threw
+fn map_hash(vm: &VM, key: &Value) -> Option<i32> {
+    let h = java_hash_code(vm, key);
+    if vm.pending_exception() != 0 {
+        return None;
+    }
+    Some(h ^ ((h as u32) >> 16) as i32)
+}
+
+//None also if hashCode or equals of key threw
+fn map_find(vm: &VM, map: HeapPtr, key: &Value) -> Option<HeapPtr> {
+    let hash = map_hash(vm, key)?;
+    map_find_hashed(vm, map, key, hash)
+}
+
+fn map_find_hashed(vm: &VM, map: HeapPtr, key: &Value, hash: i32) -> Option<HeapPtr> {
+    let table = ptr_field(vm, map, MAP_TABLE);
+    if table == 0 {
+        return None;
+    }
+    let bucket = (hash as usize) & (array_length(vm, table) - 1);
+    let mut node: HeapPtr = vm.heap.get_array_element(table, bucket).try_into().unwrap();
+    while node != 0 {
+        if int_field(vm, node, NODE_HASH) == hash
+            && java_equals(vm, key, &vm.heap.get_field(node, NODE_KEY))
+        {
+            return Some(node);
+        }
+        if vm.pending_exception() != 0 {
+            return None;
+        }
+        node = ptr_field(vm, node, NODE_NEXT);
+    }
+    None
+}
+
+//returns previous value if key was present; map is not changed if hashCode or equals threw
+fn map_put(vm: &VM, map: HeapPtr, key: Value, value: Value, only_if_absent: bool) -> Option<Value> {
+    let hash = map_hash(vm, &key)?;
+    if let Some(node) = map_find_hashed(vm, map, &key, hash) {
+        let old = vm.heap.get_field(node, NODE_VALUE);
+        if !only_if_absent || old == Value::Reference(0) {
+            vm.heap.set_field(node, NODE_VALUE, value);
+        }
+        return Some(old);
+    }
+    if vm.pending_exception() != 0 {
+        return None;
+    }
+    if ptr_field(vm, map, MAP_TABLE) == 0 {
+        map_resize(vm, map);
+    }
+    let table = ptr_field(vm, map, MAP_TABLE);
+    let node = new_object(vm, HASH_MAP_NODE);
+    vm.heap.set_field(node, NODE_HASH, Value::Int(hash));
+    vm.heap.set_field(node, NODE_KEY, key);
+    vm.heap.set_field(node, NODE_VALUE, value);
+
+    //new nodes are added to the end of bucket
+    let bucket = (hash as usize) & (array_length(vm, table) - 1);
+    let mut last: HeapPtr = vm.heap.get_array_element(table, bucket).try_into().unwrap();
+    if last == 0 {
+        vm.heap
+            .set_array_element(table, bucket, Value::Reference(node));
+    } else {
+        while ptr_field(vm, last, NODE_NEXT) != 0 {
+            last = ptr_field(vm, last, NODE_NEXT);
+        }
+        vm.heap.set_field(last, NODE_NEXT, Value::Reference(node));
+    }
+    increment_int_field(vm, map, MAP_MOD_COUNT);
+    let size = int_field(vm, map, MAP_SIZE) + 1;
+    set_int_field(vm, map, MAP_SIZE, size);
+    if size > int_field(vm, map, MAP_THRESHOLD) {
+        map_resize(vm, map);
+    }
+    None
+}
+
+//returns value of removed entry
+fn map_remove(vm: &VM, map: HeapPtr, key: &Value) -> Option<Value> {
+    let node = map_find(vm, map, key)?;
+    Some(map_remove_node(vm, map, node))
+}
+
+fn map_remove_node(vm: &VM, map: HeapPtr, node: HeapPtr) -> Value {
+    let table = ptr_field(vm, map, MAP_TABLE);
+    let bucket = (int_field(vm, node, NODE_HASH) as usize) & (array_length(vm, table) - 1);
+    let next = vm.heap.get_field(node, NODE_NEXT);
+    let first: HeapPtr = vm.heap.get_array_element(table, bucket).try_into().unwrap();
+    if first == node {
+        vm.heap.set_array_element(table, bucket, next);
+    } else {
+        let mut prev = first;
+        while ptr_field(vm, prev, NODE_NEXT) != node {
+            prev = ptr_field(vm, prev, NODE_NEXT);
+        }
+        vm.heap.set_field(prev, NODE_NEXT, next);
+    }
+    let value = vm.heap.get_field(node, NODE_VALUE);
+    increment_int_field(vm, map, MAP_MOD_COUNT);
+    set_int_field(vm, map, MAP_SIZE, int_field(vm, map, MAP_SIZE) - 1);
+    value
+}
+
+fn map_clear(vm: &VM, map: HeapPtr) {
+    let table = ptr_field(vm, map, MAP_TABLE);
+    for i in 0..array_length(vm, table) {
+        vm.heap.set_array_element(table, i, Value::Reference(0));
+    }
+    set_int_field(vm, map, MAP_SIZE, 0);
+    increment_int_field(vm, map, MAP_MOD_COUNT);
+}
+
+//doubles the table, nodes of each bucket are split preserving their order, as in java
+fn map_resize(vm: &VM, map: HeapPtr) {
+    let old_table = ptr_field(vm, map, MAP_TABLE);
+    let old_capacity = array_length(vm, old_table) as i32;
+    let new_capacity = if old_capacity > 0 {
+        (old_capacity * 2).min(MAXIMUM_MAP_CAPACITY)
+    } else if int_field(vm, map, MAP_THRESHOLD) > 0 {
+        int_field(vm, map, MAP_THRESHOLD)
+    } else {
+        DEFAULT_MAP_CAPACITY
+    };
+    let threshold = if new_capacity == MAXIMUM_MAP_CAPACITY {
+        i32::MAX
+    } else {
+        (new_capacity as f32 * 0.75) as i32
+    };
+    set_int_field(vm, map, MAP_THRESHOLD, threshold);
+    if new_capacity == old_capacity {
+        return;
+    }
+    let new_table = vm.heap.new_object_array(0, new_capacity);
+    for bucket in 0..old_capacity as usize {
+        let mut node: HeapPtr = vm
+            .heap
+            .get_array_element(old_table, bucket)
+            .try_into()
+            .unwrap();
+        let mut tails: [HeapPtr; 2] = [0, 0];
+        while node != 0 {
+            let next = ptr_field(vm, node, NODE_NEXT);
+            vm.heap.set_field(node, NODE_NEXT, Value::Reference(0));
+            let high = (int_field(vm, node, NODE_HASH) & old_capacity) != 0;
+            let new_bucket = bucket + if high { old_capacity as usize } else { 0 };
+            let tail = &mut tails[high as usize];
+            if *tail == 0 {
+                vm.heap
+                    .set_array_element(new_table, new_bucket, Value::Reference(node));
+            } else {
+                vm.heap.set_field(*tail, NODE_NEXT, Value::Reference(node));
+            }
+            *tail = node;
+            node = next;
+        }
+    }
+    if old_table != 0 {
+        vm.heap.release(old_table);
+    }
+    vm.heap
+        .set_field(map, MAP_TABLE, Value::Reference(new_table));
+}
+
+//nodes in iteration order
+fn map_nodes(vm: &VM, map: HeapPtr) -> Vec<HeapPtr> {
+    let table = ptr_field(vm, map, MAP_TABLE);
+    let mut nodes = vec![];
+    for bucket in 0..array_length(vm, table) {
+        let mut node: HeapPtr = vm.heap.get_array_element(table, bucket).try_into().unwrap();
+        while node != 0 {
+            nodes.push(node);
+            node = ptr_field(vm, node, NODE_NEXT);
+        }
+    }
+    nodes
+}
+
+//entries of any map, through entrySet if it is not a HashMap
+fn map_entries(vm: &VM, map: HeapPtr) -> Vec<(Value, Value)> {
+    if is_instance(vm, map, HASH_MAP) {
+        return map_nodes(vm, map)
+            .into_iter()
+            .map(|node| {
+                (
+                    vm.heap.get_field(node, NODE_KEY),
+                    vm.heap.get_field(node, NODE_VALUE),
+                )
+            })
+            .collect();
+    }
+    let entry_set = vm.invoke_virtual(map, "entrySet", "()Ljava/util/Set;", vec![]);
+    let entry_set: HeapPtr = match entry_set {
+        Value::Reference(ptr) => ptr,
+        _ => return vec![],
+    };
+    iterate(vm, entry_set)
+        .into_iter()
+        .filter_map(|entry| match entry {
+            Value::Reference(entry) => Some((
+                vm.invoke_virtual(entry, "getKey", "()Ljava/lang/Object;", vec![]),
+                vm.invoke_virtual(entry, "getValue", "()Ljava/lang/Object;", vec![]),
+            )),
+            _ => None,
+        })
+        .collect()
+}
+
+fn entry_hash_code(vm: &VM, node: HeapPtr) -> i32 {
+    java_hash_code(vm, &vm.heap.get_field(node, NODE_KEY))
+        ^ java_hash_code(vm, &vm.heap.get_field(node, NODE_VALUE))
+}
+
+fn table_size_for(capacity: i32) -> i32 {
+    (capacity.max(1) as u32)
+        .next_power_of_two()
+        .min(MAXIMUM_MAP_CAPACITY as u32) as i32
+}
+
+/// Collects elements of any `Iterable` via its iterator
+pub(crate) fn iterate(vm: &VM, iterable: HeapPtr) -> Vec<Value> {
+    let mut values = vec![];
+    let iterator = match vm.invoke_virtual(iterable, "iterator", "()Ljava/util/Iterator;", vec![]) {
+        Value::Reference(ptr) if ptr != 0 => ptr,
+        _ => return values, //exception is pending
+    };
+    while is_true(vm.invoke_virtual(iterator, "hasNext", "()Z", vec![])) {
+        match vm.invoke_virtual(iterator, "next", "()Ljava/lang/Object;", vec![]) {
+            Value::Void => break,
+            value => values.push(value),
+        }
+    }
+    values
+}
+
+/// Null-safe `a.equals(b)`, dispatched virtually; `false` without a call if an exception is pending
+pub(crate) fn java_equals(vm: &VM, a: &Value, b: &Value) -> bool {
+    match (a, b) {
+        _ if vm.pending_exception() != 0 => false,
+        (Value::Reference(x), Value::Reference(y)) if x == y => true,
+        (Value::Reference(0), _) | (_, Value::Reference(0)) => false,
+        (Value::Reference(x), _) => {
+            is_true(vm.invoke_virtual(*x, "equals", "(Ljava/lang/Object;)Z", vec![b.clone()]))
+        }
+        _ => a == b,
+    }
+}
+
+/// Null-safe `value.hashCode()`, dispatched virtually; 0 without a call if an exception is pending
+pub(crate) fn java_hash_code(vm: &VM, value: &Value) -> i32 {
+    match value {
+        _ if vm.pending_exception() != 0 => 0,
+        Value::Reference(0) => 0,
+        Value::Reference(ptr) => match vm.invoke_virtual(*ptr, "hashCode", "()I", vec![]) {
+            Value::Int(hash) => hash,
+            _ => 0, //exception is pending
+        },
+        _ => 0,
+    }
+}
+
+//None leaves exception thrown by toString pending
+fn element_to_string(vm: &VM, value: &Value, this: HeapPtr, self_name: &str) -> Option<String> {
+    if *value == Value::Reference(this) {
+        return Some(self_name.to_string());
+    }
+    let str = to_java_string(vm, value, &Type::Reference);
+    match vm.pending_exception() {
+        0 => Some(str),
+        _ => None,
+    }
+}
+
+fn is_true(value: Value) -> bool {
+    let value: Result<i32, _> = value.try_into();
+    value.is_ok_and(|x| x != 0)
+}
+
+fn is_instance(vm: &VM, ptr: HeapPtr, class_name: &str) -> bool {
+    match vm.heap.class_of(ptr) {
+        Some(class_idx) => vm.is_instance_of(class_idx, class_name),
+        None => false,
+    }
+}
+
+fn new_object(vm: &VM, class_name: &str) -> HeapPtr {
+    let class_idx = vm.get_or_load_class_idx(&class_name.to_string());
+    vm.new_object(class_idx)
+}
+
+fn new_view(vm: &VM, class_name: &str, map: HeapPtr) -> Value {
+    let view = new_object(vm, class_name);
+    vm.heap.set_field(view, BACKING_MAP, Value::Reference(map));
+    Value::Reference(view)
+}
+
+fn new_string(vm: &VM, str: String) -> Value {
+    Value::Reference(vm.new_string(&str))
+}
+
+fn array_length(vm: &VM, array: HeapPtr) -> usize {
+    if array == 0 {
+        return 0;
+    }
+    vm.heap.array_length(array)
+}
+
+fn int_field(vm: &VM, ptr: HeapPtr, field_idx: FieldIdx) -> i32 {
+    vm.heap.get_int(ptr, field_idx)
+}
+
+fn ptr_field(vm: &VM, ptr: HeapPtr, field_idx: FieldIdx) -> HeapPtr {
+    vm.heap.get_reference(ptr, field_idx)
+}
+
+fn set_int_field(vm: &VM, ptr: HeapPtr, field_idx: FieldIdx, value: i32) {
+    vm.heap.set_int(ptr, field_idx, value)
+}
+
+fn increment_int_field(vm: &VM, ptr: HeapPtr, field_idx: FieldIdx) {
+    set_int_field(vm, ptr, field_idx, int_field(vm, ptr, field_idx) + 1)
+}
+
+fn throw(vm: &VM, class_name: &str, message: &str) -> Option<Value> {
+    let exception = new_throwable(vm, class_name, message);
+    if message.is_empty() {
+        vm.heap.set_field(exception, 0, Value::Reference(0));
+    }
+    vm.throw(exception);
+    Some(Value::Void)
+}
+
+fn throw_index_out_of_bounds(vm: &VM, index: i32, size: usize) -> Option<Value> {
+    let message = format!("Index {} out of bounds for length {}", index, size);
+    throw(vm, INDEX_OUT_OF_BOUNDS_EXCEPTION, &message)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::vm::testing::run;
+
+    #[test]
+    fn deque_lookups_of_null_and_view_removal() {
+        let source = r#"
+        import java.util.ArrayDeque;
+        import java.util.HashMap;
+        import java.util.Map;
+
+        public class Views {
+            public static void main(String[] args) {
+                ArrayDeque<String> deque = new ArrayDeque<>();
+                deque.add("a");
+                System.out.println(deque.contains(null));
+                System.out.println(deque.remove(null));
+                try {
+                    deque.push(null);
+                } catch (NullPointerException e) {
+                    System.out.println("npe " + deque);
+                }
+                HashMap<String, Integer> map = new HashMap<>();
+                map.put("a", 1);
+                map.put("b", 2);
+                map.put("c", 3);
+                map.put("d", 4);
+                System.out.println(map.keySet().remove("a") + " " + map.keySet().remove("x"));
+                System.out.println(map.values().remove(2) + " " + map.values().remove(5));
+                HashMap<String, Integer> other = new HashMap<>();
+                other.put("c", 5);
+                Map.Entry<String, Integer> foreign = other.entrySet().iterator().next();
+                Map.Entry<String, Integer> own = map.entrySet().iterator().next();
+                System.out.println(map.entrySet().remove(foreign) + " " + map.entrySet().remove(own));
+                System.out.println(map);
+            }
+        }
+        "#;
+        let (status, output) = run("views", &[("Views", source)], &[]);
+        assert_eq!(
+            output,
+            "false\nfalse\nnpe [a]\ntrue false\ntrue false\nfalse true\n{d=4}\n"
+        );
+        assert_eq!(status, 0);
+    }
+
+    #[test]
+    fn user_equals_and_hash_code_with_concurrent_modification() {
+        let source = r#"
+        import java.util.ArrayList;
+        import java.util.ConcurrentModificationException;
+        import java.util.HashMap;
+        import java.util.HashSet;
+
+        public class Dispatch {
+            static int equalsCalls;
+            static int hashCalls;
+
+            int id;
+
+            Dispatch(int id) {
+                this.id = id;
+            }
+
+            @Override
+            public boolean equals(Object other) {
+                equalsCalls++;
+                return other instanceof Dispatch && ((Dispatch) other).id == id;
+            }
+
+            @Override
+            public int hashCode() {
+                hashCalls++;
+                return id % 2;
+            }
+
+            public static void main(String[] args) {
+                HashMap<Dispatch, String> map = new HashMap<>();
+                map.put(new Dispatch(1), "one");
+                map.put(new Dispatch(3), "three");
+                map.put(new Dispatch(3), "three again");
+                System.out.println(map.size() + " " + map.get(new Dispatch(3)) + " " + map.get(new Dispatch(5)));
+                HashSet<Dispatch> set = new HashSet<>();
+                set.add(new Dispatch(2));
+                System.out.println(set.add(new Dispatch(2)) + " " + set.contains(new Dispatch(2)));
+                ArrayList<Dispatch> list = new ArrayList<>();
+                list.add(new Dispatch(7));
+                System.out.println(list.contains(new Dispatch(7)) + " " + list.indexOf(new Dispatch(8)));
+                System.out.println((equalsCalls > 0) + " " + (hashCalls > 0));
+
+                try {
+                    for (Dispatch item : list) {
+                        list.add(item);
+                    }
+                } catch (ConcurrentModificationException e) {
+                    System.out.println("list cme " + list.size());
+                }
+                try {
+                    for (Dispatch key : map.keySet()) {
+                        map.remove(key);
+                    }
+                } catch (ConcurrentModificationException e) {
+                    System.out.println("map cme " + map.size());
+                }
+            }
+        }
+        "#;
+        let (status, output) = run("dispatch", &[("Dispatch", source)], &[]);
+        assert_eq!(
+            output,
+            "2 three again null\nfalse true\ntrue -1\ntrue true\nlist cme 2\nmap cme 1\n"
+        );
+        assert_eq!(status, 0);
+    }
+}
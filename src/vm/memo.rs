@@ -0,0 +1,882 @@
+use crate::vm::classes::{
+    AccessFlags, Annotation, AnnotationHandler, AnnotationValue, Class, ClassIdx, FieldIdx, Method,
+    MethodInClassIdx,
+};
+use crate::vm::java_util::{java_equals, java_hash_code};
+use crate::vm::memory::{HeapPtr, Relocation};
+use crate::vm::stack::{Type, Value};
+use crate::vm::vm::VM;
+use log::warn;
+use std::convert::TryInto;
+
+/// `true` to print statistics of `@RVM.Mem` methods when program ends
+pub const MEM_STATS_PROPERTY: &str = "rjava.mem.stats";
+
+const MEM_ENTRY_CLASS: &str = "io/github/rvm/MemEntry";
+
+//fields of MemEntry
+const ENTRY_ARGUMENTS: FieldIdx = 0;
+const ENTRY_ANSWER: FieldIdx = 1;
+const ENTRY_NEXT: FieldIdx = 2;
+const ENTRY_PRIMITIVES: FieldIdx = 3;
+const ENTRY_PRIMITIVE_ANSWER: FieldIdx = 4;
+const ENTRY_HASH: FieldIdx = 5;
+const ENTRY_CREATED: FieldIdx = 6;
+const ENTRY_LAST_USED: FieldIdx = 7;
+const ENTRY_HITS: FieldIdx = 8;
+const ENTRY_COST: FieldIdx = 9;
+const ENTRY_SLOT: FieldIdx = 10;
+
+pub(crate) const MEM_STATS_CLASS: &str = "io/github/rvm/MemStats";
+
+//fields of MemStats
+const STATS_METHOD: FieldIdx = 0;
+const STATS_HITS: FieldIdx = 1;
+const STATS_MISSES: FieldIdx = 2;
+const STATS_EVICTIONS: FieldIdx = 3;
+const STATS_ENTRIES: FieldIdx = 4;
+const STATS_SAVED_NANOS: FieldIdx = 5;
+
+const INITIAL_CAPACITY: usize = 16;
+
+/// Results of `@RVM.Mem` method: hash table of `MemEntry` chains, kept in heap as `MemEntry[]`
+#[derive(Clone, Default, Debug)]
+pub struct MemTable {
+    pub buckets: HeapPtr, //0 until the first result is saved
+    pub size: usize,
+    //binary min-heap of entries by eviction priority, as long as buckets. entries keep their slots
+    pub order: HeapPtr,
+
+    //set by annotation elements
+    pub max_entries: usize, //0 is unbounded
+    pub eviction: Eviction,
+    pub ttl: i32,           //in ticks, 0 means results do not expire
+    pub require_pure: bool, //caching is dropped if method fails `@RVM.Pure` check
+
+    clock: u64, //counts hits and saves, orders entries by their last use
+    pub stats: MemStats,
+}
+
+/// Counters of `@RVM.Mem` method, kept when its table is cleared
+#[derive(Clone, Copy, Default, Debug)]
+pub struct MemStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,   //by `maxEntries` or `ttl`
+    pub saved_nanos: u64, //sum of computation times of hit results
+}
+
+impl MemStats {
+    pub fn hit_ratio(&self) -> f64 {
+        match self.hits + self.misses {
+            0 => 0.0,
+            calls => self.hits as f64 / calls as f64,
+        }
+    }
+}
+
+/// Which entry is dropped from a full table, `RVM.Eviction`
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Eviction {
+    #[default]
+    Lru,
+    Lfu,
+    Ttl, //oldest saved
+}
+
+impl MemTable {
+    /// Empty table bounded by elements of `@RVM.Mem` annotation
+    pub(crate) fn configured(annotation: &Annotation) -> Result<MemTable, String> {
+        let mut table = MemTable::default();
+        for (name, value) in &annotation.elements {
+            match (name.as_str(), value) {
+                ("maxEntries", AnnotationValue::Int(max)) if *max >= 0 => {
+                    table.max_entries = *max as usize
+                }
+                ("ttl", AnnotationValue::Int(ttl)) if *ttl >= 0 => table.ttl = *ttl,
+                ("requirePure", AnnotationValue::Int(pure)) => table.require_pure = *pure != 0,
+                ("policy", AnnotationValue::Enum(_, policy)) => {
+                    table.eviction = match policy.as_str() {
+                        "LRU" => Eviction::Lru,
+                        "LFU" => Eviction::Lfu,
+                        "TTL" => Eviction::Ttl,
+                        other => return Err(format!("unknown policy {}", other)),
+                    }
+                }
+                (name, value) => return Err(format!("invalid {} = {:?}", name, value)),
+            }
+        }
+        Ok(table)
+    }
+
+    /// Entries in heap, for inspection
+    pub fn entries(&self, vm: &VM) -> Vec<HeapPtr> {
+        let mut entries = vec![];
+        if self.buckets == 0 {
+            return entries;
+        }
+        for i in 0..vm.heap.array_length(self.buckets) {
+            let mut entry = reference_element(vm, self.buckets, i);
+            while entry != 0 {
+                entries.push(entry);
+                entry = vm.heap.get_reference(entry, ENTRY_NEXT);
+            }
+        }
+        entries
+    }
+
+    pub(crate) fn relocate(&mut self, relocation: &Relocation) {
+        self.buckets = relocation.forward(self.buckets);
+        self.order = relocation.forward(self.order);
+    }
+
+    fn bucket(&self, vm: &VM, hash: i32) -> usize {
+        bucket(vm, self.buckets, hash)
+    }
+
+    fn is_full(&self, vm: &VM) -> bool {
+        self.buckets != 0 && self.size >= vm.heap.array_length(self.buckets) * 3 / 4
+    }
+
+    fn is_expired(&self, vm: &VM, entry: HeapPtr, now: i32) -> bool {
+        self.ttl > 0 && now.wrapping_sub(vm.heap.get_int(entry, ENTRY_CREATED)) >= self.ttl
+    }
+
+    fn touch(&mut self, vm: &VM, entry: HeapPtr) {
+        self.clock += 1;
+        vm.heap
+            .set_field(entry, ENTRY_LAST_USED, Value::Long(self.clock as i64));
+    }
+
+    //counts use of entry in the table, its priority only grows
+    fn hit(&mut self, vm: &VM, entry: HeapPtr) {
+        let hits = vm.heap.get_int(entry, ENTRY_HITS);
+        vm.heap.set_int(entry, ENTRY_HITS, hits.saturating_add(1));
+        self.touch(vm, entry);
+        self.sift_down(vm, vm.heap.get_int(entry, ENTRY_SLOT) as usize);
+    }
+
+    //adds entry to its bucket chain and eviction order
+    fn insert(&mut self, vm: &VM, entry: HeapPtr) {
+        let bucket = self.bucket(vm, vm.heap.get_int(entry, ENTRY_HASH));
+        let next = reference_element(vm, self.buckets, bucket);
+        vm.heap.set_reference(entry, ENTRY_NEXT, next);
+        vm.heap
+            .set_array_element(self.buckets, bucket, Value::Reference(entry));
+        self.set_slot(vm, self.size, entry);
+        self.sift_up(vm, self.size);
+        self.size += 1;
+    }
+
+    //unlinks entry from its bucket chain and eviction order
+    fn remove(&mut self, vm: &VM, entry: HeapPtr) {
+        let bucket = self.bucket(vm, vm.heap.get_int(entry, ENTRY_HASH));
+        let next = vm.heap.get_reference(entry, ENTRY_NEXT);
+        let mut current = reference_element(vm, self.buckets, bucket);
+        if current == entry {
+            vm.heap
+                .set_array_element(self.buckets, bucket, Value::Reference(next));
+        } else {
+            while vm.heap.get_reference(current, ENTRY_NEXT) != entry {
+                current = vm.heap.get_reference(current, ENTRY_NEXT);
+            }
+            vm.heap.set_reference(current, ENTRY_NEXT, next);
+        }
+        //last entry takes the freed slot
+        self.size -= 1;
+        let slot = vm.heap.get_int(entry, ENTRY_SLOT) as usize;
+        let last = reference_element(vm, self.order, self.size);
+        vm.heap
+            .set_array_element(self.order, self.size, Value::Reference(0));
+        if slot < self.size {
+            self.set_slot(vm, slot, last);
+            self.sift_down(vm, slot);
+            self.sift_up(vm, vm.heap.get_int(last, ENTRY_SLOT) as usize);
+        }
+    }
+
+    fn evict(&mut self, vm: &VM, entry: HeapPtr) {
+        self.remove(vm, entry);
+        self.stats.evictions += 1;
+    }
+
+    //entry to drop by eviction policy
+    fn victim(&self, vm: &VM) -> Option<HeapPtr> {
+        match self.size {
+            0 => None,
+            _ => Some(reference_element(vm, self.order, 0)),
+        }
+    }
+
+    //the least one is evicted first
+    fn priority(&self, vm: &VM, entry: HeapPtr) -> (i64, i64) {
+        let last_used: i64 = vm
+            .heap
+            .get_field(entry, ENTRY_LAST_USED)
+            .try_into()
+            .unwrap();
+        match self.eviction {
+            Eviction::Lru => (last_used, 0),
+            Eviction::Lfu => (vm.heap.get_int(entry, ENTRY_HITS) as i64, last_used),
+            Eviction::Ttl => (vm.heap.get_int(entry, ENTRY_CREATED) as i64, 0),
+        }
+    }
+
+    fn set_slot(&self, vm: &VM, slot: usize, entry: HeapPtr) {
+        vm.heap
+            .set_array_element(self.order, slot, Value::Reference(entry));
+        vm.heap.set_int(entry, ENTRY_SLOT, slot as i32);
+    }
+
+    fn sift_up(&self, vm: &VM, mut slot: usize) {
+        let entry = reference_element(vm, self.order, slot);
+        let priority = self.priority(vm, entry);
+        while slot > 0 {
+            let parent = reference_element(vm, self.order, (slot - 1) / 2);
+            if self.priority(vm, parent) <= priority {
+                break;
+            }
+            self.set_slot(vm, slot, parent);
+            slot = (slot - 1) / 2;
+        }
+        self.set_slot(vm, slot, entry);
+    }
+
+    fn sift_down(&self, vm: &VM, mut slot: usize) {
+        let entry = reference_element(vm, self.order, slot);
+        let priority = self.priority(vm, entry);
+        loop {
+            let mut child = 2 * slot + 1;
+            if child >= self.size {
+                break;
+            }
+            let mut child_entry = reference_element(vm, self.order, child);
+            if child + 1 < self.size {
+                let right = reference_element(vm, self.order, child + 1);
+                if self.priority(vm, right) < self.priority(vm, child_entry) {
+                    child += 1;
+                    child_entry = right;
+                }
+            }
+            if self.priority(vm, child_entry) >= priority {
+                break;
+            }
+            self.set_slot(vm, slot, child_entry);
+            slot = child;
+        }
+        self.set_slot(vm, slot, entry);
+    }
+}
+
+/// `@RVM.Mem`, which is ignored with a warning on methods that could not be cached
+pub(crate) struct MemAnnotation;
+
+impl AnnotationHandler for MemAnnotation {
+    fn on_method(&self, class: &Class, method: &mut Method, annotation: &Annotation) {
+        if let Err(reason) = check_mem_method(method) {
+            warn!(
+                "@RVM.Mem is ignored on {}.{}: {}",
+                class.name, method.name, reason
+            );
+            return;
+        }
+        method.flags.insert(AccessFlags::MEM);
+        match MemTable::configured(annotation) {
+            Ok(table) if table.require_pure => {
+                method.flags.insert(AccessFlags::PURE);
+                method.mem_table = table
+            }
+            Ok(table) => method.mem_table = table,
+            Err(reason) => warn!(
+                "@RVM.Mem of {}.{} is not bounded: {}",
+                class.name, method.name, reason
+            ),
+        }
+    }
+}
+
+/// Reason why `@RVM.Mem` could not be applied to the method
+fn check_mem_method(method: &Method) -> Result<(), &'static str> {
+    if method.signature.return_type == Type::Void {
+        Err("void method has no result to cache")
+    } else if method
+        .flags
+        .intersects(AccessFlags::ABTRACT | AccessFlags::NATIVE)
+    {
+        Err("method has no code")
+    } else if method.name.starts_with('<') {
+        Err("constructors and initializers are not cached")
+    } else {
+        Ok(())
+    }
+}
+
+impl VM {
+    /// Hash of arguments as in `Arrays.hashCode`, with boxed primitives.
+    /// Reference arguments are hashed with `hashCode`, which may leave an exception pending
+    pub(crate) fn mem_hash(&self, args: &[Value]) -> i32 {
+        let mut hash = 1i32;
+        for arg in args {
+            let arg_hash = match arg {
+                Value::Reference(_) => java_hash_code(self, arg),
+                primitive => {
+                    let bits: i64 = to_bits(primitive).try_into().unwrap();
+                    (bits ^ (bits >> 32)) as i32
+                }
+            };
+            hash = hash.wrapping_mul(31).wrapping_add(arg_hash);
+        }
+        hash
+    }
+
+    /// Cached result of `@RVM.Mem` method for the arguments (`this` included).
+    /// Reference arguments are compared with `equals`, which may leave an exception pending
+    pub(crate) fn find_mem_answer(
+        &self,
+        class_idx: ClassIdx,
+        method_idx: MethodInClassIdx,
+        args: &[Value],
+        hash: i32,
+    ) -> Option<Value> {
+        //cache bookkeeping is not a side effect of the caller
+        let pure_mark = self.heap.pure_mark();
+        self.heap.set_pure_mark(0);
+        let answer = self.lookup_mem_answer(class_idx, method_idx, args, hash);
+        self.heap.set_pure_mark(pure_mark);
+        if answer.is_none() && self.pending_exception() == 0 {
+            self.get_method_mut(class_idx, method_idx)
+                .mem_table
+                .stats
+                .misses += 1;
+        }
+        answer
+    }
+
+    fn lookup_mem_answer(
+        &self,
+        class_idx: ClassIdx,
+        method_idx: MethodInClassIdx,
+        args: &[Value],
+        hash: i32,
+    ) -> Option<Value> {
+        let (buckets, return_type) = {
+            let method = self.get_method(class_idx, method_idx);
+            (
+                method.mem_table.buckets,
+                method.signature.return_type.clone(),
+            )
+        };
+        if buckets == 0 {
+            return None;
+        }
+        let mut entry = reference_element(self, buckets, bucket(self, buckets, hash));
+        while entry != 0 {
+            if self.heap.get_int(entry, ENTRY_HASH) == hash && self.mem_entry_matches(entry, args) {
+                //`equals` may have run memoized code, so table is read again
+                let mut method = self.get_method_mut(class_idx, method_idx);
+                let table = &mut method.mem_table;
+                if table.is_expired(self, entry, self.tick()) {
+                    table.evict(self, entry);
+                    return None;
+                }
+                table.hit(self, entry);
+                let cost: i64 = self.heap.get_field(entry, ENTRY_COST).try_into().unwrap();
+                table.stats.hits += 1;
+                table.stats.saved_nanos += cost as u64;
+                return Some(match return_type {
+                    Type::Reference => self.heap.get_field(entry, ENTRY_ANSWER),
+                    _ => from_bits(
+                        self.heap.get_field(entry, ENTRY_PRIMITIVE_ANSWER),
+                        &return_type,
+                    ),
+                });
+            }
+            if self.pending_exception() != 0 {
+                return None;
+            }
+            entry = self.heap.get_reference(entry, ENTRY_NEXT);
+        }
+        None
+    }
+
+    fn mem_entry_matches(&self, entry: HeapPtr, args: &[Value]) -> bool {
+        let arguments = self.heap.get_reference(entry, ENTRY_ARGUMENTS);
+        let primitives = self.heap.get_reference(entry, ENTRY_PRIMITIVES);
+        let (mut reference_idx, mut primitive_idx) = (0, 0);
+        for arg in args {
+            let matches = match arg {
+                Value::Reference(_) => {
+                    reference_idx += 1;
+                    let cached = self.heap.get_array_element(arguments, reference_idx - 1);
+                    java_equals(self, &cached, arg)
+                }
+                primitive => {
+                    primitive_idx += 1;
+                    let cached = self.heap.get_array_element(primitives, primitive_idx - 1);
+                    cached == to_bits(primitive)
+                }
+            };
+            if !matches {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Entry keyed by arguments of the call, its answer is set by `save_mem_answer`
+    pub(crate) fn new_mem_entry(&self, args: &[Value], hash: i32) -> HeapPtr {
+        let (references, primitives): (Vec<&Value>, Vec<&Value>) =
+            args.iter().partition(|x| matches!(x, Value::Reference(_)));
+        let entry = self.new_object(self.get_or_load_class_idx(&MEM_ENTRY_CLASS.to_string()));
+        let arguments = self.heap.new_object_array(0, references.len() as i32);
+        for (i, value) in references.into_iter().enumerate() {
+            self.heap.set_array_element(arguments, i, value.clone());
+        }
+        self.heap.set_reference(entry, ENTRY_ARGUMENTS, arguments);
+        let bits = self.heap.new_array(Type::Long, 0, primitives.len() as i32);
+        for (i, value) in primitives.into_iter().enumerate() {
+            self.heap.set_array_element(bits, i, to_bits(value));
+        }
+        self.heap.set_reference(entry, ENTRY_PRIMITIVES, bits);
+        self.heap.set_int(entry, ENTRY_HASH, hash);
+        //start of computation until the answer is saved
+        self.heap
+            .set_field(entry, ENTRY_COST, Value::Long(self.nano_time()));
+        entry
+    }
+
+    /// Adds entry with the result to cache of method
+    pub(crate) fn save_mem_answer(
+        &self,
+        class_idx: ClassIdx,
+        method_idx: MethodInClassIdx,
+        entry: HeapPtr,
+        answer: &Value,
+    ) {
+        //cache outlives regions and arenas of both caller and method
+        self.heap.promote(entry);
+        self.heap.set_pure_mark(0); //until the next instruction, bookkeeping is not a side effect
+        let start: i64 = self.heap.get_field(entry, ENTRY_COST).try_into().unwrap();
+        let cost = Value::Long(self.nano_time() - start);
+        self.heap.set_field(entry, ENTRY_COST, cost);
+        //answer is read back by the declared type, so it is stored by it too
+        let return_type = self
+            .get_method(class_idx, method_idx)
+            .signature
+            .return_type
+            .clone();
+        match return_type {
+            Type::Reference => {
+                self.heap.promote(answer.clone().try_into().unwrap());
+                self.heap.set_field(entry, ENTRY_ANSWER, answer.clone());
+            }
+            _ => self
+                .heap
+                .set_field(entry, ENTRY_PRIMITIVE_ANSWER, to_bits(answer)),
+        }
+        //taken out of method, as loading of MemEntry class borrows program
+        let mut table = std::mem::take(&mut self.get_method_mut(class_idx, method_idx).mem_table);
+        let now = self.tick();
+        self.heap.set_int(entry, ENTRY_CREATED, now);
+        table.touch(self, entry);
+        if table.ttl > 0 && table.is_full(self) {
+            for expired in table.entries(self) {
+                if table.is_expired(self, expired, now) {
+                    table.evict(self, expired);
+                }
+            }
+        }
+        if table.max_entries > 0 && table.size >= table.max_entries {
+            if let Some(victim) = table.victim(self) {
+                table.evict(self, victim);
+            }
+        }
+        if table.buckets == 0 || table.is_full(self) {
+            self.grow_mem_table(&mut table);
+        }
+        table.insert(self, entry);
+        self.get_method_mut(class_idx, method_idx).mem_table = table;
+    }
+
+    /// Empties tables of `@RVM.Mem` methods named `method` (or `pkg.Class.method`), all when `None`.
+    /// Returns number of dropped results
+    pub fn clear_mem(&self, method: Option<&str>) -> usize {
+        let mut cleared = 0;
+        for (class_idx, method_idx, _) in self.mem_methods(method) {
+            let table = &mut self.get_method_mut(class_idx, method_idx).mem_table;
+            cleared += table.size;
+            table.buckets = 0;
+            table.order = 0;
+            table.size = 0;
+        }
+        cleared
+    }
+
+    /// Tables of `@RVM.Mem` methods selected as in `clear_mem`, by `pkg.Class.method` names
+    pub fn mem_stats(&self, method: Option<&str>) -> Vec<(String, MemTable)> {
+        self.mem_methods(method)
+            .into_iter()
+            .map(|(class_idx, method_idx, name)| {
+                let table = self.get_method(class_idx, method_idx).mem_table.clone();
+                (name, table)
+            })
+            .collect()
+    }
+
+    /// Table of statistics for end of run, methods saving the most time first
+    pub fn mem_report(&self) -> String {
+        let mut stats = self.mem_stats(None);
+        stats.sort_by_key(|(_, table)| std::cmp::Reverse(table.stats.saved_nanos));
+        let mut report = format!(
+            "{:<40} {:>10} {:>10} {:>6} {:>10} {:>8} {:>12}\n",
+            "@RVM.Mem method", "hits", "misses", "ratio", "evictions", "entries", "saved ms"
+        );
+        for (name, table) in stats {
+            report += &format!(
+                "{:<40} {:>10} {:>10} {:>5.0}% {:>10} {:>8} {:>12.3}\n",
+                name,
+                table.stats.hits,
+                table.stats.misses,
+                table.stats.hit_ratio() * 100.0,
+                table.stats.evictions,
+                table.size,
+                table.stats.saved_nanos as f64 / 1e6
+            );
+        }
+        report
+    }
+
+    /// `io.github.rvm.MemStats` of method
+    pub(crate) fn new_mem_stats(&self, name: &str, table: &MemTable) -> HeapPtr {
+        let stats = self.new_object(self.get_or_load_class_idx(&MEM_STATS_CLASS.to_string()));
+        let name = self.new_string(name);
+        self.heap.set_reference(stats, STATS_METHOD, name);
+        let counters = [
+            (STATS_HITS, table.stats.hits),
+            (STATS_MISSES, table.stats.misses),
+            (STATS_EVICTIONS, table.stats.evictions),
+            (STATS_SAVED_NANOS, table.stats.saved_nanos),
+        ];
+        for (field, value) in counters {
+            self.heap.set_field(stats, field, Value::Long(value as i64));
+        }
+        self.heap.set_int(stats, STATS_ENTRIES, table.size as i32);
+        stats
+    }
+
+    //memoized methods matching name, with their `pkg.Class.method` names
+    fn mem_methods(&self, method: Option<&str>) -> Vec<(ClassIdx, MethodInClassIdx, String)> {
+        let program = self.program.borrow();
+        let mut selected = vec![];
+        for (class_idx, class) in program.classes.iter().enumerate() {
+            for (method_idx, m) in class.methods.iter().enumerate() {
+                let full_name = format!("{}.{}", class.name.replace('/', "."), m.name);
+                let matches = match method {
+                    None => true,
+                    Some(name) => m.name == name || full_name == name,
+                };
+                if matches && m.flags.contains(AccessFlags::MEM) {
+                    selected.push((class_idx, method_idx, full_name));
+                }
+            }
+        }
+        selected
+    }
+
+    //doubles buckets, entries are moved by their saved hashes and keep their slots in order
+    fn grow_mem_table(&self, table: &mut MemTable) {
+        let entries = table.entries(self);
+        let capacity = match table.buckets {
+            0 => INITIAL_CAPACITY,
+            buckets => self.heap.array_length(buckets) * 2,
+        };
+        let entry_class_idx = self.get_or_load_class_idx(&MEM_ENTRY_CLASS.to_string());
+        table.buckets = self.heap.new_object_array(entry_class_idx, capacity as i32);
+        self.heap.promote(table.buckets);
+        let order = self.heap.new_object_array(entry_class_idx, capacity as i32);
+        self.heap.promote(order);
+        for slot in 0..table.size {
+            let entry = self.heap.get_array_element(table.order, slot);
+            self.heap.set_array_element(order, slot, entry);
+        }
+        table.order = order;
+        for entry in entries {
+            let bucket = table.bucket(self, self.heap.get_int(entry, ENTRY_HASH));
+            let next = reference_element(self, table.buckets, bucket);
+            self.heap.set_reference(entry, ENTRY_NEXT, next);
+            self.heap
+                .set_array_element(table.buckets, bucket, Value::Reference(entry));
+        }
+    }
+}
+
+fn bucket(vm: &VM, buckets: HeapPtr, hash: i32) -> usize {
+    hash as u32 as usize & (vm.heap.array_length(buckets) - 1)
+}
+
+fn reference_element(vm: &VM, array: HeapPtr, idx: usize) -> HeapPtr {
+    vm.heap.get_array_element(array, idx).try_into().unwrap()
+}
+
+//primitives are kept as long bits, so floats are equal as in `Double.equals`
+fn to_bits(value: &Value) -> Value {
+    Value::Long(match value {
+        Value::Byte(x) => *x as i64,
+        Value::Short(x) => *x as i64,
+        Value::Int(x) | Value::Boolean(x) => *x as i64,
+        Value::Char(x) => *x as i64,
+        Value::Long(x) => *x,
+        Value::Float(x) => x.to_bits() as i64,
+        Value::Double(x) => x.to_bits() as i64,
+        other => panic!("Cannot memoize {:?}", other),
+    })
+}
+
+fn from_bits(bits: Value, value_type: &Type) -> Value {
+    let bits: i64 = bits.try_into().unwrap();
+    match value_type {
+        //narrow types are ints on operand stack
+        Type::Byte | Type::Short | Type::Int | Type::Boolean | Type::Char => {
+            Value::Int(bits as i32)
+        }
+        Type::Long => Value::Long(bits),
+        Type::Float => Value::Float(f32::from_bits(bits as u32)),
+        Type::Double => Value::Double(f64::from_bits(bits as u64)),
+        other => panic!("Cannot memoize {:?}", other),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MemStats;
+    use crate::vm::testing::{new_vm, run};
+
+    #[test]
+    fn array_answers_and_arguments_are_references() {
+        let source = r#"
+            import io.github.rvm.RVM;
+
+            public class MemArrays {
+                static int calls;
+
+                @RVM.Mem
+                static int[] empty(int n) {
+                    calls++;
+                    return null;
+                }
+
+                @RVM.Mem
+                static int length(long[] values, int n) {
+                    calls++;
+                    return values == null ? n : -1;
+                }
+
+                public static void main(String[] args) {
+                    for (int i = 0; i < 2; i++) {
+                        System.out.println(empty(1) == null);
+                        System.out.println(length(null, 5));
+                    }
+                    System.out.println(calls);
+                }
+            }
+        "#;
+        let (status, output) = run("mem-arrays", &[("MemArrays", source)], &[]);
+        assert_eq!(output, "true\n5\ntrue\n5\n2\n");
+        assert_eq!(status, 0);
+    }
+
+    #[test]
+    fn full_caches_evict_by_policy() {
+        let source = r#"
+            import io.github.rvm.RVM;
+
+            public class MemEviction {
+                static int calls;
+
+                @RVM.Mem(maxEntries = 2)
+                static int lru(int x) {
+                    calls++;
+                    return x;
+                }
+
+                @RVM.Mem(maxEntries = 2, policy = RVM.Eviction.LFU)
+                static int lfu(int x) {
+                    calls++;
+                    return x;
+                }
+
+                @RVM.Mem(maxEntries = 2, policy = RVM.Eviction.TTL)
+                static int oldest(int x) {
+                    calls++;
+                    return x;
+                }
+
+                //prints whether the result was computed again
+                static void check(int policy, int x) {
+                    int before = calls;
+                    if (policy == 0) {
+                        lru(x);
+                    } else if (policy == 1) {
+                        lfu(x);
+                    } else {
+                        //results saved in one tick are equally old
+                        int start = RVM.tick();
+                        while (RVM.tick() == start) {}
+                        oldest(x);
+                    }
+                    System.out.print(calls == before ? "hit " : "miss ");
+                }
+
+                public static void main(String[] args) {
+                    String[] names = {"lru", "lfu", "oldest"};
+                    for (int i = 0; i < names.length; i++) {
+                        check(i, 1);
+                        check(i, 2);
+                        check(i, 1);
+                        check(i, 3);
+                        check(i, 2);
+                        check(i, 1);
+                        System.out.println(names[i]);
+                    }
+                }
+            }
+        "#;
+        let (status, output) = run("mem-eviction", &[("MemEviction", source)], &[]);
+        assert_eq!(
+            output,
+            "miss miss hit miss miss miss lru\n\
+             miss miss hit miss miss hit lfu\n\
+             miss miss hit miss hit miss oldest\n"
+        );
+        assert_eq!(status, 0);
+    }
+
+    #[test]
+    fn results_expire_and_caches_are_cleared() {
+        let source = r#"
+            import io.github.rvm.RVM;
+
+            public class MemExpiry {
+                static int calls;
+
+                @RVM.Mem(ttl = 20)
+                static int expiring(int x) {
+                    calls++;
+                    return x;
+                }
+
+                @RVM.Mem
+                static int kept(int x) {
+                    calls++;
+                    return x;
+                }
+
+                public static void main(String[] args) {
+                    expiring(1);
+                    expiring(1);
+                    kept(1);
+                    kept(2);
+                    System.out.println(calls);
+                    int start = RVM.tick();
+                    while (RVM.tick() - start < 20) {}
+                    expiring(1);
+                    kept(1);
+                    System.out.println(calls);
+                    System.out.println(RVM.memStats("expiring").evictions);
+                    System.out.println(RVM.clearMem("MemExpiry.kept") + " " + RVM.clearMem());
+                    kept(1);
+                    System.out.println(calls);
+                }
+            }
+        "#;
+        let (status, output) = run("mem-expiry", &[("MemExpiry", source)], &[]);
+        assert_eq!(output, "3\n4\n1\n2 1\n5\n");
+        assert_eq!(status, 0);
+    }
+
+    const STATS: &str = r#"
+        import io.github.rvm.MemStats;
+        import io.github.rvm.RVM;
+
+        public class MemCounters {
+            @RVM.Mem
+            static long slow(int n) {
+                long sum = 0;
+                for (int i = 0; i < n; i++) {
+                    sum += i;
+                }
+                return sum;
+            }
+
+            @RVM.Mem(maxEntries = 1)
+            static int bounded(int x) {
+                return x;
+            }
+
+            public static void main(String[] args) {
+                for (int i = 0; i < 3; i++) {
+                    slow(20000);
+                }
+                bounded(1);
+                bounded(2);
+                bounded(2);
+                RVM.clearMem("bounded");
+                MemStats slow = RVM.memStats("MemCounters.slow");
+                System.out.println(slow.method + " " + slow.hits + " " + slow.misses + " "
+                        + slow.evictions + " " + slow.entries + " " + (slow.savedNanos > 0));
+                MemStats[] all = RVM.memStats();
+                for (int i = 0; i < all.length; i++) {
+                    MemStats stats = all[i];
+                    System.out.println(stats.method + " " + stats.hits + " " + stats.misses + " "
+                            + stats.evictions + " " + stats.entries);
+                }
+                System.out.println(RVM.memStats("missing") == null);
+            }
+        }
+    "#;
+
+    #[test]
+    fn stats_count_calls_and_survive_clear() {
+        let (status, output) = run("mem-stats", &[("MemCounters", STATS)], &[]);
+        assert_eq!(
+            output,
+            "MemCounters.slow 2 1 0 1 true\n\
+             MemCounters.slow 2 1 0 1\n\
+             MemCounters.bounded 1 2 1 0\n\
+             true\n"
+        );
+        assert_eq!(status, 0);
+    }
+
+    #[test]
+    fn report_lists_methods_saving_most_time_first() {
+        let (mut vm, _, _) = new_vm("mem-report", &[("MemCounters", STATS)]);
+        assert_eq!(vm.start("MemCounters"), 0);
+        let report = vm.mem_report();
+        let lines: Vec<Vec<&str>> = report
+            .lines()
+            .map(|x| x.split_whitespace().collect())
+            .collect();
+        assert_eq!(lines.len(), 3, "{}", report);
+        assert_eq!(lines[0][..2], ["@RVM.Mem", "method"]);
+        assert_eq!(
+            lines[1][..6],
+            ["MemCounters.slow", "2", "1", "67%", "0", "1"]
+        );
+        assert_eq!(
+            lines[2][..6],
+            ["MemCounters.bounded", "1", "2", "33%", "1", "0"]
+        );
+    }
+
+    #[test]
+    fn hit_ratio_of_unused_method_is_zero() {
+        assert_eq!(MemStats::default().hit_ratio(), 0.0);
+        let stats = MemStats {
+            hits: 3,
+            misses: 1,
+            ..MemStats::default()
+        };
+        assert_eq!(stats.hit_ratio(), 0.75);
+    }
+}
@@ -1,110 +1,1311 @@
-use crate::vm::classes::{ClassIdx, FieldIdx};
+use crate::vm::classes::{ClassIdx, FieldIdx, MethodInClassIdx};
 use crate::vm::stack::{Type, Value};
-use std::cell::{RefCell, RefMut};
+use std::cell::{Cell, Ref, RefCell};
+use std::collections::HashMap;
 use std::convert::TryInto;
 
-//is very simplified heap
+/// Bytes allocated between automatic garbage collections, by default
+pub const DEFAULT_GC_THRESHOLD: usize = 8 << 20;
+
+//every block starts at multiple of it, so side tables could be indexed by ptr / ALIGN
+const ALIGN: usize = 8;
+//kind, element type, AutoFree region and class index (or size of free block)
+const HEADER_SIZE: usize = 8;
+//header and length, elements follow
+const ARRAY_HEADER_SIZE: usize = 12;
+const REFERENCE_SIZE: usize = 4;
+//references are 4 bytes, so heap could not grow over it whatever the limit is
+const MAX_HEAP_SIZE: usize = u32::MAX as usize;
+
+const KIND_FREE: u8 = 0;
+const KIND_OBJECT: u8 = 1;
+const KIND_ARRAY: u8 = 2;
+const KIND_STRING: u8 = 3;
+//freed in debug mode: keeps size like free block and former kind as element type
+const KIND_POISONED: u8 = 4;
+
+//fills payload of poisoned blocks
+const POISON: u8 = 0xDD;
+
+/// What is stored at heap pointer
+#[derive(Clone, Debug, PartialEq)]
+pub enum HeapObject {
+    Object(ClassIdx),
+    Array(Type, ClassIdx), //element type and class of reference elements
+    String,
+}
+
+/// Placement of class fields inside its objects: widest first, so they are packed without padding
+#[derive(Clone, Debug)]
+pub struct Layout {
+    pub types: Vec<Type>,
+    pub offsets: Vec<usize>, //by field index, from object start
+    pub size: usize,         //with header
+    references: Vec<usize>,  //offsets of reference fields, walked by collector
+}
+
+impl Layout {
+    pub fn new(types: &[Type]) -> Self {
+        let mut order: Vec<FieldIdx> = (0..types.len()).collect();
+        order.sort_by_key(|i| std::cmp::Reverse(type_size(&types[*i])));
+        let mut offsets = vec![0; types.len()];
+        let mut offset = HEADER_SIZE;
+        for i in order {
+            offsets[i] = offset;
+            offset += type_size(&types[i]);
+        }
+        let references = (0..types.len())
+            .filter(|i| types[*i] == Type::Reference)
+            .map(|i| offsets[i])
+            .collect();
+        Self {
+            types: types.to_vec(),
+            offsets,
+            size: align(offset),
+            references,
+        }
+    }
+}
+
+/// Bytes taken by value of given type inside object or array
+pub fn type_size(value_type: &Type) -> usize {
+    match value_type {
+        Type::Byte | Type::Boolean => 1,
+        Type::Short | Type::Char => 2,
+        Type::Int | Type::Float | Type::Reference => 4,
+        Type::Long | Type::Double => 8,
+        Type::Void => panic!("Void has no size"),
+    }
+}
+
+fn align(size: usize) -> usize {
+    size.div_ceil(ALIGN) * ALIGN
+}
+
+//is simplified heap: objects are placed one after another, freed blocks are reused
 #[derive(Default)]
 pub struct HeapMemory {
-    values: RefCell<Vec<Value>>,
+    values: RefCell<Vec<u8>>,
+    layouts: RefCell<Vec<Option<Layout>>>, //by class index
+
+    //freed ranges (start, length), ordered and merged. first block of range keeps whole length
+    free_list: RefCell<Vec<(HeapPtr, usize)>>,
+    allocated: Cell<usize>, //since last collection
+    gc_threshold: Cell<usize>,
+    gc_requested: Cell<bool>,
+    collected: Cell<bool>,  //nothing was allocated since last collection
+    max_size: Cell<usize>,  //0 means no limit
+    over_limit: Cell<bool>, //heap grew over the limit since last check
+
+    //assigned lazily, on first request
+    identity_hashes: RefCell<HashMap<HeapPtr, i32>>,
+    hash_seed: Cell<u32>,
+
+    //AutoFree regions are tracked only when escapes are checked, see `set_region`
+    escape_checks: Cell<bool>,
+    region: Cell<usize>,
+    regions: RefCell<Vec<Vec<HeapPtr>>>, //objects allocated in region, by region
+    escape: Cell<Option<Escape>>,
+
+    //debug mode: freed blocks are poisoned and not reused while referenced, accesses are validated
+    debug: Cell<bool>,
+    site: Cell<Option<AllocationSite>>,
+    sites: RefCell<HashMap<HeapPtr, ObjectSites>>,
+
+    //active arenas, innermost last. everything is allocated at heap end while any is active
+    arenas: RefCell<Vec<Arena>>,
+
+    //@RVM.Pure checks: stores into objects allocated before the running pure call are reported
+    pure_mark: Cell<u64>, //serial of the first object of the call, 0 when stores are not checked
+    serials: RefCell<HashMap<HeapPtr, u64>>, //allocation order of objects made while checked
+    last_serial: Cell<u64>,
+    side_effect: Cell<Option<HeapPtr>>,
+}
+
+//objects allocated since `start` are dropped at once when arena is released
+#[derive(Default)]
+struct Arena {
+    start: HeapPtr,
+    kept: Vec<HeapPtr>,   //copied out to enclosing heap
+    pinned: Vec<HeapPtr>, //kept by vm itself, so copied out of enclosing arenas too
+}
+
+/// Method running when object was allocated or freed
+pub type AllocationSite = (ClassIdx, MethodInClassIdx);
+
+/// Where object was allocated and freed, unknown for objects made by vm itself
+pub type ObjectSites = (Option<AllocationSite>, Option<AllocationSite>);
+
+/// Access to null, freed or wrong kind of object found in debug mode, raised as panic payload
+#[derive(Debug)]
+pub struct InvalidAccess {
+    pub ptr: HeapPtr,
+    pub problem: String,
+    pub allocated: Option<AllocationSite>,
+    pub freed: Option<AllocationSite>,
 }
 
+/// Region object stored into object outside of any region: (object, its region, target)
+pub type Escape = (HeapPtr, usize, HeapPtr);
+
 pub type HeapPtr = usize;
 
 impl HeapMemory {
     pub fn new() -> Self {
         let heap: HeapMemory = Default::default();
-        heap.values.borrow_mut().push(Value::Void);
+        heap.values.borrow_mut().resize(ALIGN, 0); //null
+        heap.gc_threshold.set(DEFAULT_GC_THRESHOLD);
         heap
     }
 
-    pub fn append(&self, data: &String) {
-        self.values.borrow_mut().push(Value::String(data.clone()))
+    /// Sets placement of fields for objects of the class, should be done before the first one is allocated
+    pub fn set_layout(&self, class_idx: ClassIdx, layout: Layout) {
+        let mut layouts = self.layouts.borrow_mut();
+        if layouts.len() <= class_idx {
+            layouts.resize(class_idx + 1, None);
+        }
+        layouts[class_idx] = Some(layout);
+    }
+
+    pub fn has_layout(&self, class_idx: ClassIdx) -> bool {
+        matches!(self.layouts.borrow().get(class_idx), Some(Some(_)))
     }
 
-    pub fn new_object(&self, class_index: ClassIdx, fields_count: u16) -> HeapPtr {
-        //todo: look for empty spaces first
-        //todo: allocate size for all fields
+    /// Bytes taken by object of the class
+    pub fn object_size(&self, class_idx: ClassIdx) -> usize {
+        self.layout(class_idx).size
+    }
+
+    /// Bytes taken by array of given length
+    pub fn array_size(element_type: &Type, length: usize) -> usize {
+        align(ARRAY_HEADER_SIZE + type_size(element_type) * length)
+    }
+
+    fn layout(&self, class_idx: ClassIdx) -> Ref<'_, Layout> {
+        Ref::map(self.layouts.borrow(), |x| match x.get(class_idx) {
+            Some(Some(layout)) => layout,
+            _ => panic!("No layout for class {}", class_idx),
+        })
+    }
+
+    /// Allocates object with all fields set to zero (null), layout of the class should be set
+    pub fn new_object(&self, class_idx: ClassIdx) -> HeapPtr {
+        let size = self.object_size(class_idx);
         let mut values = self.values.borrow_mut();
-        values.push(Value::ClassIndex(class_index, fields_count));
-        return values.len() - 1;
+        let ptr = self.allocate(&mut values, size);
+        write_header(&mut values, ptr, KIND_OBJECT, 0, class_idx as u32);
+        ptr
     }
 
-    pub fn free(&self, ptr: HeapPtr) {
+    /// Allocates array with all elements set to zero (null). Length should not be negative
+    pub fn new_array(&self, element_type: Type, class_idx: ClassIdx, length: i32) -> HeapPtr {
+        assert!(length >= 0, "Negative array length {}", length);
+        let size = Self::array_size(&element_type, length as usize);
+        let mut values = self.values.borrow_mut();
+        let ptr = self.allocate(&mut values, size);
+        write_header(
+            &mut values,
+            ptr,
+            KIND_ARRAY,
+            type_code(&element_type),
+            class_idx as u32,
+        );
+        write_u32(&mut values, ptr + HEADER_SIZE, length as u32);
+        ptr
+    }
+
+    pub fn new_object_array(&self, class_idx: ClassIdx, length: i32) -> HeapPtr {
+        self.new_array(Type::Reference, class_idx, length)
+    }
+
+    /// Allocates `java/lang/String` instance, text is kept as utf-8
+    pub fn new_string(&self, class_idx: ClassIdx, str: &str) -> HeapPtr {
+        let size = align(ARRAY_HEADER_SIZE + str.len());
         let mut values = self.values.borrow_mut();
-        Self::_free(ptr, &mut values);
-        if let Some(first_not_empty_from_end) = values.iter().rposition(|x| *x != Value::Void) {
-            values.truncate(first_not_empty_from_end)
+        let ptr = self.allocate(&mut values, size);
+        write_header(&mut values, ptr, KIND_STRING, 0, class_idx as u32);
+        write_u32(&mut values, ptr + HEADER_SIZE, str.len() as u32);
+        let start = ptr + ARRAY_HEADER_SIZE;
+        values[start..start + str.len()].copy_from_slice(str.as_bytes());
+        ptr
+    }
+
+    //takes the first free range which is big enough, or grows heap.
+    //natives could not handle failure, so growing over the limit is only recorded; see `take_over_limit`
+    fn allocate(&self, values: &mut Vec<u8>, size: usize) -> HeapPtr {
+        self.allocated.set(self.allocated.get() + size);
+        self.collected.set(false);
+        let mut free_list = self.free_list.borrow_mut();
+        let position = match self.in_arena() {
+            true => None, //bump allocation, so arena could be dropped by truncating heap
+            false => free_list.iter().position(|(_, len)| *len >= size),
+        };
+        if let Some(i) = position {
+            let (ptr, len) = free_list[i];
+            if len == size {
+                free_list.remove(i);
+            } else {
+                free_list[i] = (ptr + size, len - size);
+                write_free_header(values, ptr + size, len - size);
+            }
+            values[ptr..ptr + size].fill(0);
+            self.add_to_region(values, ptr);
+            self.add_site(ptr);
+            self.add_serial(ptr);
+            return ptr;
         }
+        let ptr = values.len();
+        assert!(
+            ptr + size <= MAX_HEAP_SIZE,
+            "Heap could not grow over {} bytes",
+            MAX_HEAP_SIZE
+        );
+        if self.max_size.get() != 0 && ptr + size > self.max_size.get() {
+            self.over_limit.set(true);
+        }
+        values.resize(ptr + size, 0);
+        self.add_to_region(values, ptr);
+        self.add_site(ptr);
+        self.add_serial(ptr);
+        ptr
     }
 
-    fn _free(ptr: HeapPtr, values: &mut RefMut<Vec<Value>>) {
-        if ptr == 0 || ptr >= values.len() {
+    fn add_serial(&self, ptr: HeapPtr) {
+        if self.pure_mark.get() != 0 {
+            self.last_serial.set(self.last_serial.get() + 1);
+            self.serials
+                .borrow_mut()
+                .insert(ptr, self.last_serial.get());
+        } else if !self.serials.borrow().is_empty() {
+            self.serials.borrow_mut().remove(&ptr); //block of freed object is reused
+        }
+    }
+
+    /// Mark of pure call starting now: objects allocated from now on belong to it
+    pub fn next_pure_mark(&self) -> u64 {
+        self.last_serial.get() + 1
+    }
+
+    /// Makes stores into objects allocated before `mark` reported by `take_side_effect`, 0 stops checks
+    pub fn set_pure_mark(&self, mark: u64) {
+        self.pure_mark.set(mark)
+    }
+
+    pub fn pure_mark(&self) -> u64 {
+        self.pure_mark.get()
+    }
+
+    /// Forgets allocation order, when no pure call is running
+    pub fn end_pure_calls(&self) {
+        self.pure_mark.set(0);
+        self.serials.borrow_mut().clear();
+    }
+
+    /// Object allocated before the pure call which was changed first, since the last call
+    pub fn take_side_effect(&self) -> Option<HeapPtr> {
+        self.side_effect.take()
+    }
+
+    fn add_site(&self, ptr: HeapPtr) {
+        if self.debug.get() {
+            self.sites.borrow_mut().insert(ptr, (self.site.get(), None));
+        }
+    }
+
+    /// Turns on debug mode: freed objects are poisoned and kept while referenced,
+    /// so access through stale reference is reported instead of reading reused memory
+    pub fn set_debug(&self, enabled: bool) {
+        self.debug.set(enabled)
+    }
+
+    pub fn is_debug(&self) -> bool {
+        self.debug.get()
+    }
+
+    /// Method which allocates and frees objects from now on, tracked in debug mode
+    pub fn set_site(&self, site: AllocationSite) {
+        self.site.set(Some(site))
+    }
+
+    /// Where object was allocated and freed, known in debug mode only
+    pub fn sites_of(&self, ptr: HeapPtr) -> ObjectSites {
+        self.sites.borrow().get(&ptr).copied().unwrap_or_default()
+    }
+
+    //checks that pointer leads to live object of given kind
+    fn validate(&self, values: &[u8], ptr: HeapPtr, kind: u8) {
+        if !self.debug.get() {
             return;
         }
-        let value = values.get(ptr).unwrap().clone();
-        values[ptr] = Value::Void;
-        match value {
-            Value::Reference(reference) => {
-                Self::_free(reference, values);
+        let problem = if ptr == 0 {
+            "null".to_string()
+        } else if ptr >= values.len() || !ptr.is_multiple_of(ALIGN) {
+            "outside of heap".to_string()
+        } else if values[ptr] == KIND_POISONED {
+            format!("freed {}", kind_name(values[ptr + 1]))
+        } else if values[ptr] == KIND_FREE {
+            "free memory".to_string()
+        } else if values[ptr] != kind {
+            format!(
+                "{} where {} expected",
+                kind_name(values[ptr]),
+                kind_name(kind)
+            )
+        } else {
+            return;
+        };
+        let (allocated, freed) = self.sites_of(ptr);
+        std::panic::panic_any(InvalidAccess {
+            ptr,
+            problem,
+            allocated,
+            freed,
+        })
+    }
+
+    //in debug mode block is not reused while referenced, see `collect`
+    fn poison(&self, ptr: HeapPtr, values: &mut [u8]) {
+        if values[ptr] == KIND_POISONED {
+            let (allocated, freed) = self.sites_of(ptr);
+            std::panic::panic_any(InvalidAccess {
+                ptr,
+                problem: "freed twice".to_string(),
+                allocated,
+                freed,
+            })
+        }
+        let size = self.block_size(values, ptr);
+        let kind = values[ptr];
+        values[ptr..ptr + size].fill(POISON);
+        write_header(values, ptr, KIND_POISONED, kind, size as u32);
+        write_region(values, ptr, 0);
+        if let Some(sites) = self.sites.borrow_mut().get_mut(&ptr) {
+            sites.1 = self.site.get();
+        }
+    }
+
+    fn add_to_region(&self, values: &mut [u8], ptr: HeapPtr) {
+        let region = self.region.get();
+        if region != 0 && !self.in_arena() {
+            write_region(values, ptr, region);
+            let mut regions = self.regions.borrow_mut();
+            if regions.len() <= region {
+                regions.resize(region + 1, vec![]);
+            }
+            regions[region].push(ptr);
+        }
+    }
+
+    /// Makes heap track which AutoFree region owns objects and check that they do not escape it
+    pub fn set_escape_checks(&self, enabled: bool) {
+        self.escape_checks.set(enabled)
+    }
+
+    pub fn is_escape_checked(&self) -> bool {
+        self.escape_checks.get()
+    }
+
+    /// Region new objects belong to: depth of AutoFree frame, 0 when objects are owned by collector.
+    /// Regions are nested, deeper ones are released first.
+    pub fn set_region(&self, region: usize) {
+        if self.escape_checks.get() && region <= u16::MAX as usize {
+            self.region.set(region)
+        }
+    }
+
+    /// Region of the object, 0 for objects owned by collector
+    pub fn region_of(&self, ptr: HeapPtr) -> usize {
+        if ptr == 0 {
+            return 0;
+        }
+        read_region(&self.values.borrow(), ptr)
+    }
+
+    /// Moves object and everything it references to the given region, if it is deeper.
+    /// Returns region of object when it escapes all regions (`region` is 0).
+    pub fn escape(&self, ptr: HeapPtr, region: usize) -> Option<usize> {
+        let own_region = self.region_of(ptr);
+        if own_region <= region || (region != 0 && own_region == 0) {
+            return None;
+        }
+        let mut values = self.values.borrow_mut();
+        let mut pending = vec![ptr];
+        while let Some(ptr) = pending.pop() {
+            if ptr == 0 || values[ptr] == KIND_FREE || read_region(&values, ptr) <= region {
+                continue;
+            }
+            write_region(&mut values, ptr, region);
+            for offset in self.reference_offsets(&values, ptr) {
+                pending.push(read_u32(&values, ptr + offset) as HeapPtr)
+            }
+        }
+        (region == 0).then_some(own_region)
+    }
+
+    /// Gives object to collector, for objects kept by vm itself (e.g. constants or caches)
+    pub fn promote(&self, ptr: HeapPtr) {
+        self.escape(ptr, 0);
+        if let Some(arena) = self.arenas.borrow_mut().last_mut() {
+            if ptr >= arena.start {
+                arena.pinned.push(ptr);
+            }
+        }
+    }
+
+    /// Starts arena: objects allocated until `exit_arena` are released together
+    pub fn enter_arena(&self) {
+        let start = self.values.borrow().len();
+        self.arenas.borrow_mut().push(Arena {
+            start,
+            ..Default::default()
+        });
+    }
+
+    pub fn in_arena(&self) -> bool {
+        !self.arenas.borrow().is_empty()
+    }
+
+    /// Keeps object of the innermost arena (and arena objects it references) after the arena is released
+    pub fn copy_out(&self, ptr: HeapPtr) {
+        if let Some(arena) = self.arenas.borrow_mut().last_mut() {
+            if ptr >= arena.start {
+                arena.kept.push(ptr);
+            }
+        }
+    }
+
+    /// Drops all objects of the innermost arena by truncating heap. When objects are copied out,
+    /// they are moved to the arena start and returned table should be applied to references outside of heap,
+    /// references to other arena objects become null.
+    pub fn exit_arena(&self) -> Option<Relocation> {
+        let arena = self.arenas.borrow_mut().pop().expect("No arena to exit");
+        let mut values = self.values.borrow_mut();
+        let start = arena.start.min(values.len());
+        self.cut_free_list(&mut values, start);
+        if arena.kept.is_empty() && arena.pinned.is_empty() {
+            values.truncate(start);
+            return None;
+        }
+
+        //arena objects reachable from kept ones
+        let mut marked = vec![false; values.len() / ALIGN];
+        let mut pending: Vec<HeapPtr> = arena.kept.iter().chain(&arena.pinned).copied().collect();
+        while let Some(ptr) = pending.pop() {
+            if ptr < start || ptr >= values.len() || marked[ptr / ALIGN] {
+                continue;
+            }
+            if values[ptr] == KIND_FREE || values[ptr] == KIND_POISONED {
+                continue;
+            }
+            marked[ptr / ALIGN] = true;
+            for offset in self.reference_offsets(&values, ptr) {
+                pending.push(read_u32(&values, ptr + offset) as HeapPtr)
+            }
+        }
+        let mut new_ptrs: Vec<HeapPtr> = (0..start / ALIGN).map(|i| i * ALIGN).collect();
+        new_ptrs.resize(values.len() / ALIGN, 0);
+        let mut new_ptr = start;
+        let mut ptr = start;
+        while ptr < values.len() {
+            let size = self.block_size(&values, ptr);
+            if marked[ptr / ALIGN] {
+                new_ptrs[ptr / ALIGN] = new_ptr;
+                new_ptr += size;
             }
-            Value::ClassIndex(_, fields) => {
-                for p in 0..=(fields as usize) {
-                    Self::_free(ptr + p, values);
+            ptr += size;
+        }
+        let relocation = Relocation { new_ptrs };
+
+        ptr = ALIGN;
+        while ptr < values.len() {
+            let size = self.block_size(&values, ptr);
+            if ptr < start || marked[ptr / ALIGN] {
+                for offset in self.reference_offsets(&values, ptr) {
+                    let reference = read_u32(&values, ptr + offset) as HeapPtr;
+                    write_u32(
+                        &mut values,
+                        ptr + offset,
+                        relocation.forward(reference) as u32,
+                    );
                 }
             }
-            Value::ArrayOf(_, _) => {
-                let len: i32 = values[ptr + 1].clone().try_into().unwrap();
-                for p in 0..=(1 + len as usize) {
-                    Self::_free(ptr + p, values);
+            if ptr >= start && marked[ptr / ALIGN] {
+                values.copy_within(ptr..ptr + size, relocation.forward(ptr));
+            }
+            ptr += size;
+        }
+        values.truncate(new_ptr);
+        self.relocate_tables(&relocation);
+        if let Some(outer) = self.arenas.borrow_mut().last_mut() {
+            outer
+                .pinned
+                .extend(arena.pinned.iter().map(|ptr| relocation.forward(*ptr)));
+        }
+        Some(relocation)
+    }
+
+    //free ranges above arena start are dropped with the arena
+    fn cut_free_list(&self, values: &mut [u8], start: HeapPtr) {
+        let mut free_list = self.free_list.borrow_mut();
+        while let Some((ptr, len)) = free_list.last().copied() {
+            if ptr >= start {
+                free_list.pop();
+            } else {
+                if ptr + len > start {
+                    free_list.last_mut().unwrap().1 = start - ptr;
+                    write_free_header(values, ptr, start - ptr);
+                    write_free_header(values, start, ptr + len - start);
                 }
+                break;
             }
-            _ => {}
         }
     }
 
-    pub fn new_object_array(&self, class_index: ClassIdx, count: i32) -> HeapPtr {
+    //stored object should live at least as long as object it is stored into
+    fn check_store(&self, target: HeapPtr, value: &Value) {
+        let mark = self.pure_mark.get();
+        if mark != 0 && self.side_effect.get().is_none() {
+            let serial = self.serials.borrow().get(&target).copied().unwrap_or(0);
+            if serial < mark {
+                self.side_effect.set(Some(target));
+            }
+        }
+        if let (Some(arena), Value::Reference(ptr)) = (self.arenas.borrow_mut().last_mut(), value) {
+            //objects made before arena (e.g. collections growing their storage) keep arena objects
+            if target < arena.start && *ptr >= arena.start {
+                arena.kept.push(*ptr);
+            }
+        }
+        if let (true, Value::Reference(ptr)) = (self.escape_checks.get(), value) {
+            if let Some(region) = self.escape(*ptr, self.region_of(target)) {
+                if self.escape.get().is_none() {
+                    self.escape.set(Some((*ptr, region, target)));
+                }
+            }
+        }
+    }
+
+    /// Last store which moved object out of AutoFree regions
+    pub fn take_escape(&self) -> Option<Escape> {
+        self.escape.take()
+    }
+
+    /// Frees objects owned by the region, objects moved to outer regions are handed to them.
+    /// Returns amount of freed objects.
+    pub fn release_region(&self, region: usize) -> usize {
+        let objects = match self.regions.borrow_mut().get_mut(region) {
+            Some(objects) => std::mem::take(objects),
+            None => return 0,
+        };
+        let mut freed = 0;
+        for ptr in objects {
+            match self.region_of(ptr) {
+                0 => {}
+                owner if owner == region => {
+                    self.release(ptr);
+                    freed += 1;
+                }
+                owner if owner < region => self.regions.borrow_mut()[owner].push(ptr),
+                _ => {}
+            }
+        }
+        freed
+    }
+
+    /// Gives all objects of the region to collector, e.g. when its frame is unwound by exception
+    pub fn abandon_region(&self, region: usize) {
+        let objects = match self.regions.borrow_mut().get_mut(region) {
+            Some(objects) => std::mem::take(objects),
+            None => return,
+        };
         let mut values = self.values.borrow_mut();
-        let ptr = values.len();
-        values.push(Value::ArrayOf(Type::Reference, class_index));
-        values.push(Value::Int(count));
-        for _ in 0..count {
-            values.push(Value::Reference(0));
+        for ptr in objects {
+            if values[ptr] != KIND_FREE && read_region(&values, ptr) == region {
+                write_region(&mut values, ptr, 0);
+            }
+        }
+    }
+
+    /// Amount of bytes allocated since the last collection which triggers the next one, 0 disables automatic collection
+    pub fn set_gc_threshold(&self, threshold: usize) {
+        self.gc_threshold.set(threshold)
+    }
+
+    /// Limits heap to given amount of bytes, 0 removes the limit
+    pub fn set_max_size(&self, bytes: usize) {
+        self.max_size.set(bytes)
+    }
+
+    pub fn max_size(&self) -> usize {
+        self.max_size.get()
+    }
+
+    /// Heap size in bytes, including free space between objects
+    pub fn size(&self) -> usize {
+        self.values.borrow().len()
+    }
+
+    /// Bytes taken by objects, either alive or not collected yet
+    pub fn used_space(&self) -> usize {
+        self.size() - self.free_list_space()
+    }
+
+    /// Bytes which could be allocated without growing over the limit.
+    /// Without limit, only free space inside heap is counted.
+    pub fn free_space(&self) -> usize {
+        let free = self.free_list_space();
+        match self.max_size.get() {
+            0 => free,
+            max_size => free + max_size.saturating_sub(self.size()),
+        }
+    }
+
+    fn free_list_space(&self) -> usize {
+        self.free_list.borrow().iter().map(|(_, len)| len).sum()
+    }
+
+    /// True if object of given size fits into free space or heap could grow for it
+    pub fn has_space(&self, size: usize) -> bool {
+        let max_size = match self.max_size.get() {
+            0 => MAX_HEAP_SIZE,
+            max_size => max_size.min(MAX_HEAP_SIZE),
+        };
+        self.size() + size <= max_size
+            || (!self.in_arena() && self.free_list.borrow().iter().any(|(_, len)| *len >= size))
+    }
+
+    /// True if heap grew over the limit since last call
+    pub(crate) fn take_over_limit(&self) -> bool {
+        self.over_limit.replace(false)
+    }
+
+    pub(crate) fn is_collected(&self) -> bool {
+        self.collected.get()
+    }
+
+    /// Asks for collection at the next safe point
+    pub fn request_gc(&self) {
+        self.gc_requested.set(true)
+    }
+
+    pub(crate) fn is_gc_requested(&self) -> bool {
+        self.gc_requested.get()
+    }
+
+    pub(crate) fn needs_gc(&self) -> bool {
+        let threshold = self.gc_threshold.get();
+        self.gc_requested.get() || (threshold > 0 && self.allocated.get() >= threshold)
+    }
+
+    /// Mark and sweep: frees everything not reachable from `roots`, returns amount of freed bytes
+    pub fn collect(&self, roots: &[HeapPtr]) -> usize {
+        let mut values = self.values.borrow_mut();
+        let mut roots = roots.to_vec();
+        roots.extend(self.regions.borrow().iter().flatten());
+        let marked = self.mark(&values, &roots);
+        let mut freed = 0;
+        let mut free_list: Vec<(HeapPtr, usize)> = vec![];
+        {
+            let mut identity_hashes = self.identity_hashes.borrow_mut();
+            let mut ptr = ALIGN;
+            while ptr < values.len() {
+                let size = self.block_size(&values, ptr);
+                if values[ptr] != KIND_FREE && !marked[ptr / ALIGN] {
+                    values[ptr] = KIND_FREE;
+                    identity_hashes.remove(&ptr);
+                    self.sites.borrow_mut().remove(&ptr);
+                    self.serials.borrow_mut().remove(&ptr);
+                    freed += size;
+                }
+                if values[ptr] == KIND_FREE {
+                    match free_list.last_mut() {
+                        Some((start, len)) if *start + *len == ptr => *len += size,
+                        _ => free_list.push((ptr, size)),
+                    }
+                }
+                ptr += size;
+            }
+        }
+        for (start, len) in &free_list {
+            write_free_header(&mut values, *start, *len);
+        }
+        *self.free_list.borrow_mut() = free_list;
+        self.allocated.set(0);
+        self.gc_requested.set(false);
+        self.collected.set(true);
+        self.trim(&mut values);
+        freed
+    }
+
+    /// True when free space between objects takes more than a quarter of heap
+    pub(crate) fn is_fragmented(&self) -> bool {
+        self.free_list_space() * 4 > self.size()
+    }
+
+    /// Slides live objects to the start of heap, so there is no free space between them.
+    /// References inside heap are updated; returned table gives new location of each object,
+    /// which should be applied to references kept outside of heap (see `relocate`).
+    pub fn compact(&self) -> Relocation {
+        let mut values = self.values.borrow_mut();
+        let mut new_ptrs = vec![0; values.len() / ALIGN];
+        let mut new_ptr = ALIGN;
+        let mut ptr = ALIGN;
+        while ptr < values.len() {
+            let size = self.block_size(&values, ptr);
+            if values[ptr] != KIND_FREE {
+                new_ptrs[ptr / ALIGN] = new_ptr;
+                new_ptr += size;
+            }
+            ptr += size;
+        }
+        let relocation = Relocation { new_ptrs };
+
+        ptr = ALIGN;
+        while ptr < values.len() {
+            let size = self.block_size(&values, ptr);
+            if values[ptr] != KIND_FREE {
+                for offset in self.reference_offsets(&values, ptr) {
+                    let reference = read_u32(&values, ptr + offset) as HeapPtr;
+                    write_u32(
+                        &mut values,
+                        ptr + offset,
+                        relocation.forward(reference) as u32,
+                    );
+                }
+                //objects keep their order, so destination never overlaps objects not moved yet
+                values.copy_within(ptr..ptr + size, relocation.forward(ptr));
+            }
+            ptr += size;
         }
-        return ptr;
+        values.truncate(new_ptr);
+        self.free_list.borrow_mut().clear();
+        self.relocate_tables(&relocation);
+        relocation
+    }
+
+    //side tables are keyed by object location
+    fn relocate_tables(&self, relocation: &Relocation) {
+        for objects in self.regions.borrow_mut().iter_mut() {
+            *objects = objects
+                .iter()
+                .map(|ptr| relocation.forward(*ptr))
+                .filter(|ptr| *ptr != 0)
+                .collect();
+        }
+
+        let mut identity_hashes = self.identity_hashes.borrow_mut();
+        *identity_hashes = identity_hashes
+            .iter()
+            .map(|(ptr, hash)| (relocation.forward(*ptr), *hash))
+            .filter(|(ptr, _)| *ptr != 0)
+            .collect();
+        let mut sites = self.sites.borrow_mut();
+        *sites = sites
+            .iter()
+            .map(|(ptr, sites)| (relocation.forward(*ptr), *sites))
+            .filter(|(ptr, _)| *ptr != 0)
+            .collect();
+        let mut serials = self.serials.borrow_mut();
+        *serials = serials
+            .iter()
+            .map(|(ptr, serial)| (relocation.forward(*ptr), *serial))
+            .filter(|(ptr, _)| *ptr != 0)
+            .collect();
+    }
+
+    fn mark(&self, values: &[u8], roots: &[HeapPtr]) -> Vec<bool> {
+        let mut marked = vec![false; values.len() / ALIGN];
+        let mut pending = roots.to_vec();
+        while let Some(ptr) = pending.pop() {
+            if ptr == 0 || ptr >= values.len() || marked[ptr / ALIGN] {
+                continue;
+            }
+            if values[ptr] == KIND_FREE {
+                continue; //already freed, e.g. by AutoFree
+            }
+            marked[ptr / ALIGN] = true; //poisoned block is kept while referenced
+            for offset in self.reference_offsets(values, ptr) {
+                pending.push(read_u32(values, ptr + offset) as HeapPtr)
+            }
+        }
+        marked
+    }
+
+    //offsets of references kept by object or array, from its start
+    fn reference_offsets(&self, values: &[u8], ptr: HeapPtr) -> Vec<usize> {
+        match values[ptr] {
+            KIND_OBJECT => self
+                .layout(read_u32(values, ptr + 4) as ClassIdx)
+                .references
+                .clone(),
+            KIND_ARRAY if type_from_code(values[ptr + 1]) == Type::Reference => {
+                let length = read_u32(values, ptr + HEADER_SIZE) as usize;
+                (0..length)
+                    .map(|i| ARRAY_HEADER_SIZE + i * REFERENCE_SIZE)
+                    .collect()
+            }
+            _ => vec![],
+        }
+    }
+
+    //bytes taken by object, array, string or free block
+    fn block_size(&self, values: &[u8], ptr: HeapPtr) -> usize {
+        match values[ptr] {
+            KIND_OBJECT => self.object_size(read_u32(values, ptr + 4) as ClassIdx),
+            KIND_ARRAY => Self::array_size(
+                &type_from_code(values[ptr + 1]),
+                read_u32(values, ptr + HEADER_SIZE) as usize,
+            ),
+            KIND_STRING => align(ARRAY_HEADER_SIZE + read_u32(values, ptr + HEADER_SIZE) as usize),
+            _ => read_u32(values, ptr + 4) as usize,
+        }
+    }
+
+    /// Bytes taken by object, array or string
+    pub fn size_of(&self, ptr: HeapPtr) -> usize {
+        self.block_size(&self.values.borrow(), ptr)
+    }
+
+    /// Objects which are alive or not collected yet, in order of their location
+    pub fn objects(&self) -> Vec<HeapPtr> {
+        let values = self.values.borrow();
+        let mut objects = vec![];
+        let mut ptr = ALIGN;
+        while ptr < values.len() {
+            if values[ptr] != KIND_FREE && values[ptr] != KIND_POISONED {
+                objects.push(ptr);
+            }
+            ptr += self.block_size(&values, ptr);
+        }
+        objects
+    }
+
+    /// Frees object and everything it references, as AutoFree does
+    pub fn free(&self, ptr: HeapPtr) {
+        let mut values = self.values.borrow_mut();
+        self._free(ptr, &mut values);
+        self.trim(&mut values);
+    }
+
+    /// Frees object or array itself, but not values it references
+    pub fn release(&self, ptr: HeapPtr) {
+        let mut values = self.values.borrow_mut();
+        self.free_block(ptr, &mut values);
+        self.trim(&mut values);
+    }
+
+    fn _free(&self, ptr: HeapPtr, values: &mut [u8]) {
+        if ptr == 0
+            || ptr >= values.len()
+            || values[ptr] == KIND_FREE
+            || values[ptr] == KIND_POISONED
+        {
+            return;
+        }
+        let references: Vec<HeapPtr> = self
+            .reference_offsets(values, ptr)
+            .iter()
+            .map(|offset| read_u32(values, ptr + offset) as HeapPtr)
+            .collect();
+        self.free_block(ptr, values);
+        for reference in references {
+            self._free(reference, values);
+        }
+    }
+
+    //adds block to free list, merging it with neighbour free ranges
+    fn free_block(&self, ptr: HeapPtr, values: &mut [u8]) {
+        let size = self.block_size(values, ptr);
+        self.identity_hashes.borrow_mut().remove(&ptr);
+        let region = read_region(values, ptr);
+        if region != 0 {
+            //region keeps only live objects, so freed block could be reused by any other
+            if let Some(objects) = self.regions.borrow_mut().get_mut(region) {
+                if let Some(idx) = objects.iter().rposition(|x| *x == ptr) {
+                    objects.swap_remove(idx);
+                }
+            }
+        }
+        if self.debug.get() {
+            return self.poison(ptr, values);
+        }
+        values[ptr] = KIND_FREE;
+        let mut free_list = self.free_list.borrow_mut();
+        let mut i = free_list.partition_point(|(start, _)| *start < ptr);
+        free_list.insert(i, (ptr, size));
+        if i + 1 < free_list.len() && ptr + size == free_list[i + 1].0 {
+            free_list[i].1 += free_list.remove(i + 1).1;
+        }
+        if i > 0 && free_list[i - 1].0 + free_list[i - 1].1 == ptr {
+            free_list[i - 1].1 += free_list.remove(i).1;
+            i -= 1;
+        }
+        write_free_header(values, free_list[i].0, free_list[i].1);
+    }
+
+    //drops free range from the end of heap, but not below the innermost arena start
+    fn trim(&self, values: &mut Vec<u8>) {
+        let floor = self.arenas.borrow().last().map_or(0, |arena| arena.start);
+        let mut free_list = self.free_list.borrow_mut();
+        if let Some((start, len)) = free_list.last().copied() {
+            if start + len != values.len() {
+                return;
+            }
+            if start >= floor {
+                values.truncate(start);
+                free_list.pop();
+            } else if floor < values.len() {
+                values.truncate(floor);
+                free_list.last_mut().unwrap().1 = floor - start;
+                write_free_header(values, start, floor - start);
+            }
+        }
+    }
+
+    /// Returns hash code which stays the same for object during all its life.
+    pub fn identity_hash(&self, ptr: HeapPtr) -> i32 {
+        *self
+            .identity_hashes
+            .borrow_mut()
+            .entry(ptr)
+            .or_insert_with(|| {
+                //xorshift, as hotspot does
+                let mut x = self.hash_seed.get().wrapping_add(0x9E37_79B9);
+                x ^= x << 13;
+                x ^= x >> 17;
+                x ^= x << 5;
+                self.hash_seed.set(x);
+                (x & 0x7FFF_FFFF) as i32
+            })
+    }
+
+    /// Kind of object at pointer, `None` for null or freed memory
+    pub fn get_object(&self, ptr: HeapPtr) -> Option<HeapObject> {
+        let values = self.values.borrow();
+        if ptr == 0 || ptr >= values.len() {
+            return None;
+        }
+        let class_idx = read_u32(&values, ptr + 4) as ClassIdx;
+        match values[ptr] {
+            KIND_OBJECT => Some(HeapObject::Object(class_idx)),
+            KIND_ARRAY => Some(HeapObject::Array(
+                type_from_code(values[ptr + 1]),
+                class_idx,
+            )),
+            KIND_STRING => Some(HeapObject::String),
+            _ => None,
+        }
+    }
+
+    /// Class of object or string, `None` for arrays and null
+    pub fn class_of(&self, ptr: HeapPtr) -> Option<ClassIdx> {
+        let values = self.values.borrow();
+        match values.get(ptr) {
+            Some(&KIND_OBJECT) | Some(&KIND_STRING) if ptr != 0 => {
+                Some(read_u32(&values, ptr + 4) as ClassIdx)
+            }
+            _ => None,
+        }
+    }
+
+    pub fn array_length(&self, ptr: HeapPtr) -> usize {
+        let values = self.values.borrow();
+        self.validate(&values, ptr, KIND_ARRAY);
+        read_u32(&values, ptr + HEADER_SIZE) as usize
+    }
+
+    pub fn get_string(&self, ptr: HeapPtr) -> String {
+        let values = self.values.borrow();
+        self.validate(&values, ptr, KIND_STRING);
+        let start = ptr + ARRAY_HEADER_SIZE;
+        let length = read_u32(&values, ptr + HEADER_SIZE) as usize;
+        String::from_utf8_lossy(&values[start..start + length]).into_owned()
     }
 
     pub fn get_array_element(&self, ptr: HeapPtr, idx: usize) -> Value {
-        self.values.borrow()[ptr + 2 + idx].clone()
+        let values = self.values.borrow();
+        self.validate(&values, ptr, KIND_ARRAY);
+        let element_type = type_from_code(values[ptr + 1]);
+        let offset = ARRAY_HEADER_SIZE + idx * type_size(&element_type);
+        read_value(&values, ptr + offset, &element_type)
     }
 
     pub fn set_array_element(&self, ptr: HeapPtr, idx: usize, value: Value) {
-        self.values.borrow_mut()[ptr + 2 + idx] = value
+        self.validate(&self.values.borrow(), ptr, KIND_ARRAY);
+        self.check_store(ptr, &value);
+        let mut values = self.values.borrow_mut();
+        let element_type = type_from_code(values[ptr + 1]);
+        let offset = ARRAY_HEADER_SIZE + idx * type_size(&element_type);
+        write_value(&mut values, ptr + offset, &element_type, &value)
+    }
+
+    /// Value of field by its index in class fields (inherited ones go first)
+    pub fn get_field(&self, ptr: HeapPtr, field_idx: FieldIdx) -> Value {
+        let values = self.values.borrow();
+        self.validate(&values, ptr, KIND_OBJECT);
+        let layout = self.layout(read_u32(&values, ptr + 4) as ClassIdx);
+        read_value(
+            &values,
+            ptr + layout.offsets[field_idx],
+            &layout.types[field_idx],
+        )
+    }
+
+    pub fn set_field(&self, ptr: HeapPtr, field_idx: FieldIdx, value: Value) {
+        self.validate(&self.values.borrow(), ptr, KIND_OBJECT);
+        self.check_store(ptr, &value);
+        let mut values = self.values.borrow_mut();
+        let layout = self.layout(read_u32(&values, ptr + 4) as ClassIdx);
+        write_value(
+            &mut values,
+            ptr + layout.offsets[field_idx],
+            &layout.types[field_idx],
+            &value,
+        )
+    }
+
+    pub fn get_int(&self, ptr: HeapPtr, field_idx: FieldIdx) -> i32 {
+        self.get_field(ptr, field_idx).try_into().unwrap()
+    }
+
+    pub fn set_int(&self, ptr: HeapPtr, field_idx: FieldIdx, value: i32) {
+        self.set_field(ptr, field_idx, Value::Int(value))
+    }
+
+    pub fn get_reference(&self, ptr: HeapPtr, field_idx: FieldIdx) -> HeapPtr {
+        self.get_field(ptr, field_idx).try_into().unwrap()
+    }
+
+    pub fn set_reference(&self, ptr: HeapPtr, field_idx: FieldIdx, reference: HeapPtr) {
+        self.set_field(ptr, field_idx, Value::Reference(reference))
+    }
+}
+
+fn write_header(values: &mut [u8], ptr: HeapPtr, kind: u8, element_type: u8, class_idx: u32) {
+    values[ptr] = kind;
+    values[ptr + 1] = element_type;
+    write_u32(values, ptr + 4, class_idx);
+}
+
+fn kind_name(kind: u8) -> &'static str {
+    match kind {
+        KIND_OBJECT => "object",
+        KIND_ARRAY => "array",
+        KIND_STRING => "string",
+        _ => "free block",
+    }
+}
+
+fn read_region(values: &[u8], ptr: HeapPtr) -> usize {
+    u16::from_le_bytes([values[ptr + 2], values[ptr + 3]]) as usize
+}
+
+fn write_region(values: &mut [u8], ptr: HeapPtr, region: usize) {
+    values[ptr + 2..ptr + 4].copy_from_slice(&(region as u16).to_le_bytes())
+}
+
+fn write_free_header(values: &mut [u8], ptr: HeapPtr, size: usize) {
+    write_header(values, ptr, KIND_FREE, 0, size as u32);
+}
+
+fn read_u32(values: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(values[at..at + 4].try_into().unwrap())
+}
+
+fn write_u32(values: &mut [u8], at: usize, value: u32) {
+    values[at..at + 4].copy_from_slice(&value.to_le_bytes())
+}
+
+fn type_code(value_type: &Type) -> u8 {
+    match value_type {
+        Type::Void => 0,
+        Type::Byte => 1,
+        Type::Short => 2,
+        Type::Int => 3,
+        Type::Long => 4,
+        Type::Char => 5,
+        Type::Float => 6,
+        Type::Double => 7,
+        Type::Boolean => 8,
+        Type::Reference => 9,
+    }
+}
+
+fn type_from_code(code: u8) -> Type {
+    match code {
+        1 => Type::Byte,
+        2 => Type::Short,
+        3 => Type::Int,
+        4 => Type::Long,
+        5 => Type::Char,
+        6 => Type::Float,
+        7 => Type::Double,
+        8 => Type::Boolean,
+        9 => Type::Reference,
+        _ => Type::Void,
+    }
+}
+
+//values narrower than int are loaded as int, as jvm does
+fn read_value(values: &[u8], at: usize, value_type: &Type) -> Value {
+    let bytes = &values[at..at + type_size(value_type)];
+    match value_type {
+        Type::Byte => Value::Int(bytes[0] as i8 as i32),
+        Type::Boolean => Value::Int(bytes[0] as i32),
+        Type::Short => Value::Int(i16::from_le_bytes(bytes.try_into().unwrap()) as i32),
+        Type::Char => Value::Int(u16::from_le_bytes(bytes.try_into().unwrap()) as i32),
+        Type::Int => Value::Int(i32::from_le_bytes(bytes.try_into().unwrap())),
+        Type::Float => Value::Float(f32::from_le_bytes(bytes.try_into().unwrap())),
+        Type::Reference => {
+            Value::Reference(u32::from_le_bytes(bytes.try_into().unwrap()) as HeapPtr)
+        }
+        Type::Long => Value::Long(i64::from_le_bytes(bytes.try_into().unwrap())),
+        Type::Double => Value::Double(f64::from_le_bytes(bytes.try_into().unwrap())),
+        Type::Void => panic!("Void has no value"),
+    }
+}
+
+//narrow values are truncated, as putfield and *astore do
+fn write_value(values: &mut [u8], at: usize, value_type: &Type, value: &Value) {
+    let int = match value {
+        Value::Int(v) | Value::Boolean(v) => Some(*v),
+        Value::Byte(v) => Some(*v as i32),
+        Value::Short(v) => Some(*v as i32),
+        Value::Char(v) => Some(*v as i32),
+        _ => None,
+    };
+    match (value_type, int, value) {
+        (Type::Byte | Type::Boolean, Some(v), _) => values[at] = v as u8,
+        (Type::Short | Type::Char, Some(v), _) => {
+            values[at..at + 2].copy_from_slice(&(v as u16).to_le_bytes())
+        }
+        (Type::Int, Some(v), _) => values[at..at + 4].copy_from_slice(&v.to_le_bytes()),
+        (Type::Float, _, Value::Float(v)) => values[at..at + 4].copy_from_slice(&v.to_le_bytes()),
+        (Type::Reference, _, Value::Reference(ptr)) => write_u32(values, at, *ptr as u32),
+        (Type::Long, _, Value::Long(v)) => values[at..at + 8].copy_from_slice(&v.to_le_bytes()),
+        (Type::Double, _, Value::Double(v)) => values[at..at + 8].copy_from_slice(&v.to_le_bytes()),
+        _ => panic!("Cannot store {:?} as {:?}", value, value_type),
+    }
+}
+
+/// New locations of objects moved by `HeapMemory::compact`
+pub struct Relocation {
+    new_ptrs: Vec<HeapPtr>, //by old location / ALIGN, 0 for objects which are not alive
+}
+
+impl Relocation {
+    /// New location of object, null if it was freed
+    pub fn forward(&self, ptr: HeapPtr) -> HeapPtr {
+        self.new_ptrs.get(ptr / ALIGN).copied().unwrap_or(0)
+    }
+
+    /// Updates value if it is a reference
+    pub fn relocate(&self, value: &mut Value) {
+        if let Value::Reference(ptr) = value {
+            *ptr = self.forward(*ptr)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //objects of class 0 take 16 bytes: header, reference and int
+    fn heap() -> HeapMemory {
+        let heap = HeapMemory::new();
+        heap.set_layout(0, Layout::new(&[Type::Reference, Type::Int]));
+        heap
     }
 
-    pub fn inspect(&self) -> Vec<Value> {
-        return self.values.borrow().clone();
+    #[test]
+    fn layout_places_widest_fields_first() {
+        let layout = Layout::new(&[
+            Type::Byte,
+            Type::Long,
+            Type::Reference,
+            Type::Short,
+            Type::Int,
+        ]);
+        assert_eq!(layout.offsets, vec![26, 8, 16, 24, 20]);
+        assert_eq!(layout.references, vec![16]);
+        assert_eq!(layout.size, 32);
     }
 
-    pub fn new_object_field(&self, value: Value) {
-        self.values.borrow_mut().push(value);
+    #[test]
+    fn free_block_merges_neighbours() {
+        let heap = heap();
+        let objects: Vec<_> = (0..4).map(|_| heap.new_object(0)).collect();
+        heap.release(objects[0]);
+        heap.release(objects[2]);
+        assert_eq!(
+            *heap.free_list.borrow(),
+            vec![(objects[0], 16), (objects[2], 16)]
+        );
+        heap.release(objects[1]);
+        assert_eq!(*heap.free_list.borrow(), vec![(objects[0], 48)]);
+        assert_eq!(heap.size_of(objects[0]), 48);
+        assert_eq!(heap.new_object(0), objects[0]);
     }
 
-    pub fn put_value(&self, value: Value) -> HeapPtr {
-        let mut values_mut = self.values.borrow_mut();
-        values_mut.push(value);
-        values_mut.len() - 1
+    #[test]
+    fn trim_drops_free_range_at_heap_end() {
+        let heap = heap();
+        let a = heap.new_object(0);
+        let b = heap.new_object(0);
+        heap.release(a);
+        assert_eq!(heap.size(), ALIGN + 32);
+        heap.release(b);
+        assert_eq!(heap.size(), ALIGN);
+        assert!(heap.free_list.borrow().is_empty());
     }
 
-    pub fn get_value(&self, reference: HeapPtr) -> Value {
-        return self.values.borrow()[reference].clone();
+    #[test]
+    fn compact_slides_live_objects_and_forwards_references() {
+        let heap = heap();
+        let a = heap.new_object(0);
+        let b = heap.new_object(0);
+        let c = heap.new_object(0);
+        heap.set_reference(a, 0, c);
+        heap.set_int(c, 1, 42);
+        heap.collect(&[a]);
+        let relocation = heap.compact();
+        assert_eq!(relocation.forward(a), a);
+        assert_eq!(relocation.forward(b), 0);
+        assert_eq!(relocation.forward(c), b);
+        assert_eq!(heap.get_reference(a, 0), b);
+        assert_eq!(heap.get_int(b, 1), 42);
+        assert_eq!(heap.size(), ALIGN + 32);
+        assert!(heap.free_list.borrow().is_empty());
     }
 
-    pub fn get_field(&self, reference: HeapPtr, field_idx: FieldIdx) -> Value {
-        return self.values.borrow()[reference + 1 + field_idx].clone();
+    #[test]
+    fn arena_exit_moves_copied_out_objects_to_arena_start() {
+        let heap = heap();
+        heap.new_object(0);
+        heap.enter_arena();
+        let start = heap.size();
+        heap.new_object(0);
+        let inner = heap.new_object(0);
+        let kept = heap.new_object(0);
+        heap.set_reference(kept, 0, inner);
+        heap.copy_out(kept);
+        let relocation = heap.exit_arena().unwrap();
+        let (inner, kept) = (relocation.forward(inner), relocation.forward(kept));
+        assert_eq!((inner, kept), (start, start + 16));
+        assert_eq!(heap.get_reference(kept, 0), inner);
+        assert_eq!(heap.size(), start + 32);
     }
 
-    pub fn set_field(&self, reference: HeapPtr, field_idx: FieldIdx, value: Value) {
-        self.values.borrow_mut()[reference + 1 + field_idx] = value;
+    #[test]
+    fn arena_exit_without_copies_truncates_heap() {
+        let heap = heap();
+        let outside = heap.new_object(0);
+        heap.enter_arena();
+        heap.new_object(0);
+        heap.new_object(0);
+        assert!(heap.exit_arena().is_none());
+        assert_eq!(heap.size(), outside + 16);
+        assert!(!heap.in_arena());
     }
 }
@@ -1,9 +1,24 @@
+pub mod auto_free;
 mod class_loader;
-mod classes;
+pub mod classes;
+pub mod contract;
+mod gc;
+pub mod heap_graph;
+pub mod hprof;
 pub mod interpreter;
+mod java_io;
 mod java_lang;
+mod java_util;
+pub mod memo;
 pub mod memory;
 mod program;
+pub mod purity;
 pub mod rvm_class;
 pub mod stack;
+pub mod state_dump;
+mod tail_call;
+#[cfg(test)]
+mod testing;
+pub mod trace;
+#[allow(clippy::module_inception)]
 pub mod vm;
@@ -1,5 +1,7 @@
+use crate::vm::class_loader::{find_method, parse_signature, parse_type};
 use crate::vm::classes::{
-    Class, ClassIdx, ConstantPoolValue, FieldIdx, MethodInClassIdx, NativeMethod,
+    AccessFlags, AnnotationHandler, Class, ClassIdx, ConstantPoolValue, Field, FieldIdx, Method,
+    MethodInClassIdx, NativeMethod,
 };
 use crate::vm::rvm_class::RvmClass;
 use std::collections::HashMap;
@@ -12,9 +14,11 @@ pub struct Program {
 
     pub class_names_to_idxs: HashMap<String, ClassIdx>,
     pub field_names_to_idxs: HashMap<(ClassIdx, String), FieldIdx>,
+    pub static_field_names_to_idxs: HashMap<(ClassIdx, String), FieldIdx>,
     pub method_names_to_idxs: HashMap<(ClassIdx, String, String), MethodInClassIdx>,
 
     pub native_methods: Vec<Box<dyn NativeMethod>>,
+    pub annotation_handlers: HashMap<String, Box<dyn AnnotationHandler>>, //by descriptor
 }
 
 impl Program {
@@ -22,7 +26,87 @@ impl Program {
         self.code.push(0x00);
         self.code.push(177); //1 - return for empty methods
         self.init_java_lang();
+        self.init_java_io();
+        self.init_java_util();
         self.native_methods.push(Box::new(RvmClass {}));
+        self.init_rvm_annotations();
         self.constant_pool.push(ConstantPoolValue::Skip); //skip 0 element, as starts from 1
     }
+
+    /// Registers class implemented in rust.
+    /// Methods are given as (name, descriptor, flags); methods without `NATIVE` flag get empty body.
+    pub(crate) fn add_class(
+        &mut self,
+        name: &str,
+        super_class_idx: ClassIdx,
+        fields: &[(&str, &str)],
+        static_fields: &[(&str, &str)],
+        methods: &[(&str, &str, AccessFlags)],
+    ) -> ClassIdx {
+        let class_idx = self.classes.len();
+        let mut class = Class {
+            name: name.to_string(),
+            super_class_idx,
+            ..Default::default()
+        };
+        if class_idx != super_class_idx {
+            let super_class = &self.classes[super_class_idx];
+            class.vmt = super_class.vmt.clone();
+            class.fields = super_class.fields.clone();
+        }
+        for (field_name, descriptor) in fields {
+            self.field_names_to_idxs
+                .insert((class_idx, field_name.to_string()), class.fields.len());
+            class.fields.push(Field {
+                name: field_name.to_string(),
+                flags: AccessFlags::empty(),
+                value_type: parse_type(descriptor),
+                annotations: vec![],
+            });
+        }
+        for (field_name, descriptor) in static_fields {
+            self.static_field_names_to_idxs.insert(
+                (class_idx, field_name.to_string()),
+                class.static_fields.len(),
+            );
+            let value_type = parse_type(descriptor);
+            class.static_values.push(value_type.default_value());
+            class.static_fields.push(Field {
+                name: field_name.to_string(),
+                //built-in statics are constants or streams, final as in the JDK
+                flags: AccessFlags::STATIC | AccessFlags::FINAL,
+                value_type,
+                annotations: vec![],
+            });
+        }
+        for (method_name, descriptor, flags) in methods {
+            let signature = parse_signature(descriptor);
+            let midx = class.methods.len();
+            if class_idx != super_class_idx {
+                if let Some(overridden) =
+                    find_method(self, super_class_idx, method_name, &signature)
+                {
+                    class.vmt.override_method(overridden, (class_idx, midx));
+                }
+            }
+            class.methods.push(Method {
+                name: method_name.to_string(),
+                signature,
+                flags: *flags,
+                code_ptr: if flags.contains(AccessFlags::NATIVE) {
+                    0
+                } else {
+                    1
+                },
+                ..Default::default()
+            });
+            self.method_names_to_idxs.insert(
+                (class_idx, method_name.to_string(), descriptor.to_string()),
+                midx,
+            );
+        }
+        self.classes.push(class);
+        self.class_names_to_idxs.insert(name.to_string(), class_idx);
+        class_idx
+    }
 }
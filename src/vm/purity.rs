@@ -0,0 +1,1027 @@
+use crate::vm::class_loader::parse_signature;
+use crate::vm::classes::{
+    AccessFlags, Annotation, AnnotationHandler, Class, ClassIdx, ConstantPoolIdx,
+    ConstantPoolValue, Method, MethodInClassIdx, Signature,
+};
+use crate::vm::java_lang::{new_throwable, CLASS_INIT_METHOD, ILLEGAL_STATE_EXCEPTION};
+use crate::vm::memory::HeapObject;
+use crate::vm::stack::{FrameModifiers, Type};
+use crate::vm::tail_call::instruction_length;
+use crate::vm::vm::VM;
+use log::warn;
+use std::collections::{HashMap, HashSet};
+use std::convert::TryInto;
+
+/// How side effects of running `@RVM.Pure` methods are reported: `ignore`, `warn` or `error`
+pub const PURE_CHECK_PROPERTY: &str = "rjava.pure.check";
+
+//built-in classes whose methods depend on their arguments only
+const PURE_CLASSES: [&str; 7] = [
+    "java/lang/Object",
+    "java/lang/String",
+    "java/lang/Integer",
+    "java/lang/Math",
+    "java/lang/StrictMath",
+    "java/lang/Class",
+    "java/lang/Throwable",
+];
+
+//methods of built-in collections and StringBuilder which do not change the receiver
+const QUERIES: [&str; 26] = [
+    "size",
+    "isEmpty",
+    "contains",
+    "containsKey",
+    "containsValue",
+    "containsAll",
+    "get",
+    "getOrDefault",
+    "indexOf",
+    "lastIndexOf",
+    "getFirst",
+    "getLast",
+    "element",
+    "peek",
+    "peekFirst",
+    "peekLast",
+    "toArray",
+    "equals",
+    "hashCode",
+    "toString",
+    "keySet",
+    "values",
+    "entrySet",
+    "hasNext",
+    "getKey",
+    "getValue",
+];
+
+//StringBuilder methods which return the receiver
+const BUILDER_CHAINS: [&str; 6] = [
+    "append",
+    "insert",
+    "reverse",
+    "delete",
+    "deleteCharAt",
+    "replace",
+];
+
+/// What happens when `@RVM.Pure` method changes object or static it did not make, or does output
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum SideEffectPolicy {
+    /// Only bytecode is checked, on the first call
+    #[default]
+    Ignore,
+    /// Each kind of side effect is logged once
+    Warn,
+    /// `IllegalStateException` is thrown at the side effect
+    Error,
+}
+
+impl SideEffectPolicy {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "ignore" => Some(SideEffectPolicy::Ignore),
+            "warn" => Some(SideEffectPolicy::Warn),
+            "error" => Some(SideEffectPolicy::Error),
+            _ => None,
+        }
+    }
+}
+
+/// `@RVM.Pure`, its bytecode is checked when it is called first
+pub(crate) struct PureAnnotation;
+
+impl AnnotationHandler for PureAnnotation {
+    fn on_method(&self, class: &Class, method: &mut Method, _annotation: &Annotation) {
+        if method
+            .flags
+            .intersects(AccessFlags::ABTRACT | AccessFlags::NATIVE)
+        {
+            warn!(
+                "@RVM.Pure is ignored on {}.{}: method has no code",
+                class.name, method.name
+            );
+            return;
+        }
+        method.flags.insert(AccessFlags::PURE);
+    }
+}
+
+//value of operand stack or local variable slot, as seen by the check
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Slot {
+    Fresh, //object allocated by the checked call
+    Other,
+}
+
+#[derive(Clone, PartialEq)]
+struct SlotFrame {
+    stack: Vec<Slot>,
+    locals: Vec<Slot>,
+}
+
+impl SlotFrame {
+    //slot stays fresh only if it is fresh on every path, returns true if frame changed
+    fn merge(&mut self, other: &SlotFrame) -> bool {
+        let mut changed = false;
+        let pairs = self
+            .stack
+            .iter_mut()
+            .zip(&other.stack)
+            .chain(self.locals.iter_mut().zip(&other.locals));
+        for (slot, other) in pairs {
+            if *slot == Slot::Fresh && *other == Slot::Other {
+                *slot = Slot::Other;
+                changed = true;
+            }
+        }
+        changed
+    }
+
+    fn pop(&mut self, slots: usize) {
+        self.stack.truncate(self.stack.len() - slots);
+    }
+
+    fn push(&mut self, slot: Slot, slots: usize) {
+        self.stack.extend(std::iter::repeat_n(slot, slots));
+    }
+
+    //value which is `below` slots under the top
+    fn peek(&self, below: usize) -> Slot {
+        self.stack[self.stack.len() - 1 - below]
+    }
+}
+
+//method referenced by invoke instruction
+struct Callee {
+    class_name: String,
+    name: String,
+    signature: Signature,
+    resolved: Option<(ClassIdx, MethodInClassIdx)>, //none for built-in classes, checked by name
+}
+
+impl VM {
+    pub fn set_side_effect_policy(&self, policy: SideEffectPolicy) {
+        self.side_effect_policy.set(policy);
+    }
+
+    pub fn side_effect_policy(&self) -> SideEffectPolicy {
+        self.side_effect_policy.get()
+    }
+
+    /// Checks bytecode of `@RVM.Pure` method and everything it calls, once.
+    /// Impure method loses the annotation with a warning, as does `@RVM.Mem(requirePure = true)`
+    pub(crate) fn verify_pure(&self, class_idx: ClassIdx, method_idx: MethodInClassIdx) {
+        let key = (class_idx, method_idx, 0);
+        let checked = self.purity.borrow().get(&key).cloned();
+        let reason = match checked {
+            Some(reason) => reason,
+            None => {
+                let known: HashSet<_> = self.purity.borrow().keys().copied().collect();
+                let reason = self.method_impurity(class_idx, method_idx, 0);
+                if reason.is_some() {
+                    //callees were assumed pure while the method was checked
+                    self.purity
+                        .borrow_mut()
+                        .retain(|key, reason| reason.is_some() || known.contains(key));
+                }
+                reason
+            }
+        };
+        let reason = match reason {
+            Some(reason) => reason,
+            None => return,
+        };
+        let name = self.method_name(class_idx, method_idx);
+        warn!("@RVM.Pure is ignored on {}: {}", name, reason);
+        let mut method = self.get_method_mut(class_idx, method_idx);
+        method.flags.remove(AccessFlags::PURE);
+        if method.mem_table.require_pure && method.flags.contains(AccessFlags::MEM) {
+            method.flags.remove(AccessFlags::MEM);
+            warn!("@RVM.Mem is ignored on {}: method is not pure", name);
+        }
+    }
+
+    /// Reason why method is not pure, found by bytecode check. `fresh` has bit of each argument
+    /// (`this` first) which is an object made by the caller within the checked call
+    pub(crate) fn method_impurity(
+        &self,
+        class_idx: ClassIdx,
+        method_idx: MethodInClassIdx,
+        fresh: u64,
+    ) -> Option<String> {
+        let key = (class_idx, method_idx, fresh);
+        if let Some(reason) = self.purity.borrow().get(&key) {
+            return reason.clone();
+        }
+        //recursive calls are assumed pure until the check ends
+        self.purity.borrow_mut().insert(key, None);
+        let reason = self.find_impurity(class_idx, method_idx, fresh);
+        self.purity.borrow_mut().insert(key, reason.clone());
+        reason
+    }
+
+    //follows every path through the method, tracking which slots hold objects it made
+    fn find_impurity(
+        &self,
+        class_idx: ClassIdx,
+        method_idx: MethodInClassIdx,
+        fresh: u64,
+    ) -> Option<String> {
+        let (code_ptr, handlers, entry) = {
+            let method = self.get_method(class_idx, method_idx);
+            let mut locals = vec![Slot::Other; method.max_locals as usize];
+            let this = !method.flags.contains(AccessFlags::STATIC) as usize;
+            let types =
+                std::iter::repeat_n(&Type::Reference, this).chain(&method.signature.arguments);
+            let mut slot = 0;
+            for (i, value_type) in types.enumerate() {
+                if slot < locals.len() && i < 64 && fresh & (1 << i) != 0 {
+                    locals[slot] = Slot::Fresh;
+                }
+                slot += slots(value_type);
+            }
+            let handlers: Vec<_> = method
+                .exception_table
+                .iter()
+                .map(|x| {
+                    (
+                        x.start_pc - method.code_ptr,
+                        x.end_pc - method.code_ptr,
+                        x.handler_pc - method.code_ptr,
+                    )
+                })
+                .collect();
+            let entry = SlotFrame {
+                stack: vec![],
+                locals,
+            };
+            (method.code_ptr, handlers, entry)
+        };
+        let cp_offset = self.get_class(class_idx).constant_pool_idx;
+        let mut frames: HashMap<usize, SlotFrame> = HashMap::from([(0, entry)]);
+        let mut pending = vec![0];
+        while let Some(pc) = pending.pop() {
+            let mut frame = frames[&pc].clone();
+            let before = frame.clone();
+            let bytes = {
+                let program = self.program.borrow();
+                let code = &program.code[code_ptr..];
+                code[pc..pc + instruction_length(code, pc)].to_vec()
+            };
+            let next = match self.step(&bytes, pc, cp_offset, &mut frame) {
+                Ok(next) => next,
+                Err(what) => {
+                    //the location of a call goes before the reason found in the callee
+                    let location = self.code_location(class_idx, method_idx, pc);
+                    return Some(match what.split_once(", which ") {
+                        Some((call, reason)) => {
+                            format!("{} at {}, which {}", call, location, reason)
+                        }
+                        None => format!("{} at {}", what, location),
+                    });
+                }
+            };
+            let mut successors: Vec<(usize, SlotFrame)> =
+                next.into_iter().map(|x| (x, frame.clone())).collect();
+            for (start, end, handler) in &handlers {
+                if *start <= pc && pc < *end {
+                    for locals in [&before.locals, &frame.locals] {
+                        let caught = SlotFrame {
+                            stack: vec![Slot::Other],
+                            locals: locals.clone(),
+                        };
+                        successors.push((*handler, caught));
+                    }
+                }
+            }
+            for (target, state) in successors {
+                let changed = match frames.get_mut(&target) {
+                    Some(known) => known.merge(&state),
+                    None => {
+                        frames.insert(target, state);
+                        true
+                    }
+                };
+                if changed {
+                    pending.push(target);
+                }
+            }
+        }
+        None
+    }
+
+    //applies instruction to the frame, returns pcs it may continue at
+    fn step(
+        &self,
+        bytes: &[u8],
+        pc: usize,
+        cp_offset: ConstantPoolIdx,
+        frame: &mut SlotFrame,
+    ) -> Result<Vec<usize>, String> {
+        let u16_at = |i: usize| u16::from_be_bytes([bytes[i], bytes[i + 1]]) as usize;
+        let i32_at = |i: usize| i32::from_be_bytes(bytes[i..i + 4].try_into().unwrap());
+        let jump = |offset: i32| (pc as i64 + offset as i64) as usize;
+        let next = pc + bytes.len();
+        let (op, local) = match bytes[0] {
+            196 => (bytes[1], u16_at(2)), //wide
+            op @ (21..=25 | 54..=58) => (op, bytes[1] as usize),
+            op => (op, 0),
+        };
+        match op {
+            0 | 132 | 192 => {} //nop, iinc, checkcast
+            1..=8 | 11..=13 | 16..=19 | 21 | 23 | 26..=29 | 34..=37 => frame.push(Slot::Other, 1),
+            9 | 10 | 14 | 15 | 20 | 22 | 24 | 30..=33 | 38..=41 => frame.push(Slot::Other, 2),
+            25 => frame.push(frame.locals[local], 1),
+            42..=45 => frame.push(frame.locals[op as usize - 42], 1),
+            //array loads, element of fresh array could be stored there by anyone
+            46..=53 => {
+                frame.pop(2);
+                frame.push(Slot::Other, if op == 47 || op == 49 { 2 } else { 1 });
+            }
+            54 | 56 => {
+                frame.pop(1);
+                frame.locals[local] = Slot::Other;
+            }
+            55 | 57 => {
+                frame.pop(2);
+                frame.locals[local] = Slot::Other;
+                frame.locals[local + 1] = Slot::Other;
+            }
+            58 => {
+                frame.locals[local] = frame.peek(0);
+                frame.pop(1);
+            }
+            59..=62 | 67..=70 => {
+                frame.pop(1);
+                frame.locals[(op as usize - 59) % 4] = Slot::Other;
+            }
+            63..=66 | 71..=74 => {
+                frame.pop(2);
+                let local = (op as usize - 63) % 4;
+                frame.locals[local] = Slot::Other;
+                frame.locals[local + 1] = Slot::Other;
+            }
+            75..=78 => {
+                frame.locals[op as usize - 75] = frame.peek(0);
+                frame.pop(1);
+            }
+            //array stores: array, index and value
+            79..=86 => {
+                let value = if op == 80 || op == 82 { 2 } else { 1 };
+                if frame.peek(value + 1) != Slot::Fresh {
+                    return Err("stores into array made before the call".to_string());
+                }
+                frame.pop(value + 2);
+            }
+            87 => frame.pop(1),
+            88 => frame.pop(2),
+            //dup, dup_x1, dup_x2
+            89..=91 => {
+                let top = frame.peek(0);
+                let at = frame.stack.len() - (op as usize - 88);
+                frame.stack.insert(at, top);
+            }
+            //dup2, dup2_x1, dup2_x2
+            92..=94 => {
+                let len = frame.stack.len();
+                let top = frame.stack[len - 2..].to_vec();
+                let at = len - (op as usize - 90);
+                frame.stack.splice(at..at, top);
+            }
+            95 => {
+                let len = frame.stack.len();
+                frame.stack.swap(len - 1, len - 2);
+            }
+            //arithmetic of int, long, float and double, in this order
+            96..=115 => match (op - 96) % 4 {
+                1 | 3 => {
+                    frame.pop(4);
+                    frame.push(Slot::Other, 2)
+                }
+                _ => {
+                    frame.pop(2);
+                    frame.push(Slot::Other, 1)
+                }
+            },
+            116..=119 => {}            //negation keeps size
+            120..=125 => frame.pop(1), //shift distance is int
+            126..=131 => frame.pop(if op % 2 == 1 { 2 } else { 1 }),
+            //conversions
+            133..=147 => {
+                let (pops, pushes) = match op {
+                    133 | 135 | 140 | 141 => (1, 2),
+                    136 | 137 | 142 | 144 => (2, 1),
+                    138 | 143 => (2, 2),
+                    _ => (1, 1),
+                };
+                frame.pop(pops);
+                frame.push(Slot::Other, pushes);
+            }
+            148 | 151 | 152 => {
+                frame.pop(4);
+                frame.push(Slot::Other, 1);
+            }
+            149 | 150 => {
+                frame.pop(2);
+                frame.push(Slot::Other, 1);
+            }
+            153..=158 | 198 | 199 => {
+                frame.pop(1);
+                return Ok(vec![next, jump(u16_at(1) as i16 as i32)]);
+            }
+            159..=166 => {
+                frame.pop(2);
+                return Ok(vec![next, jump(u16_at(1) as i16 as i32)]);
+            }
+            167 => return Ok(vec![jump(u16_at(1) as i16 as i32)]),
+            200 => return Ok(vec![jump(i32_at(1))]),
+            //switches are aligned to 4 bytes from method start
+            170 | 171 => {
+                frame.pop(1);
+                let at = 1 + (4 - (pc + 1) % 4) % 4;
+                let mut targets = vec![jump(i32_at(at))];
+                if op == 170 {
+                    let count = i32_at(at + 8) - i32_at(at + 4) + 1;
+                    for i in 0..count as usize {
+                        targets.push(jump(i32_at(at + 12 + i * 4)));
+                    }
+                } else {
+                    for i in 0..i32_at(at + 4) as usize {
+                        targets.push(jump(i32_at(at + 12 + i * 8)));
+                    }
+                }
+                return Ok(targets);
+            }
+            172..=177 | 191 => return Ok(vec![]), //returns and athrow
+            178 => {
+                let (name, field_type, flags) = self.field_ref(cp_offset + u16_at(1), true)?;
+                if !flags.contains(AccessFlags::FINAL) {
+                    return Err(format!("reads mutable static field {}", name));
+                }
+                frame.push(Slot::Other, slots(&field_type));
+            }
+            179 => {
+                let (name, _, _) = self.field_ref(cp_offset + u16_at(1), true)?;
+                return Err(format!("changes static field {}", name));
+            }
+            180 => {
+                let (_, field_type, _) = self.field_ref(cp_offset + u16_at(1), false)?;
+                frame.pop(1);
+                frame.push(Slot::Other, slots(&field_type));
+            }
+            181 => {
+                let (name, field_type, _) = self.field_ref(cp_offset + u16_at(1), false)?;
+                let value = slots(&field_type);
+                if frame.peek(value) != Slot::Fresh {
+                    return Err(format!(
+                        "changes field {} of object made before the call",
+                        name
+                    ));
+                }
+                frame.pop(value + 1);
+            }
+            182..=185 => {
+                let callee = self.callee(cp_offset + u16_at(1))?;
+                //first slot of each argument, `this` is not passed to static methods
+                let types: Vec<_> = std::iter::repeat_n(&Type::Reference, (op != 184) as usize)
+                    .chain(&callee.signature.arguments)
+                    .collect();
+                let total: usize = types.iter().map(|x| slots(x)).sum();
+                let mut at = frame.stack.len() - total;
+                let mut args = vec![];
+                for value_type in types {
+                    args.push(frame.stack[at]);
+                    at += slots(value_type);
+                }
+                let result = self.call_impurity(&callee, &args)?;
+                frame.pop(total);
+                frame.push(result, slots(&callee.signature.return_type));
+            }
+            186 => return Err("uses invokedynamic, which is not supported".to_string()),
+            187 => frame.push(Slot::Fresh, 1),
+            188 | 189 => {
+                frame.pop(1);
+                frame.push(Slot::Fresh, 1);
+            }
+            190 | 193 => {
+                frame.pop(1);
+                frame.push(Slot::Other, 1);
+            }
+            194 | 195 => frame.pop(1),
+            197 => {
+                frame.pop(bytes[3] as usize);
+                frame.push(Slot::Fresh, 1);
+            }
+            _ => return Err(format!("uses unsupported instruction {}", op)),
+        }
+        Ok(vec![next])
+    }
+
+    //`Class.field`, its type and flags; class is loaded if needed
+    fn field_ref(
+        &self,
+        cpi: ConstantPoolIdx,
+        is_static: bool,
+    ) -> Result<(String, Type, AccessFlags), String> {
+        if let ConstantPoolValue::UnresolvedFieldRef { class_name, .. } =
+            self.get_constant_pool_value(cpi)
+        {
+            if !self.class_exists(&class_name) {
+                return Err(format!(
+                    "uses missing class {}",
+                    class_name.replace('/', ".")
+                ));
+            }
+        }
+        let (class_idx, field_idx) = match is_static {
+            true => self.resolve_static_field_reference(cpi),
+            false => self.resolve_field_reference(cpi),
+        };
+        let class = self.get_class(class_idx);
+        let field = match is_static {
+            true => &class.static_fields[field_idx],
+            false => &class.fields[field_idx],
+        };
+        let name = format!("{}.{}", class.name.replace('/', "."), field.name);
+        Ok((name, field.value_type.clone(), field.flags))
+    }
+
+    //called method, without resolving constant pool entry: interface calls are resolved by receiver
+    fn callee(&self, cpi: ConstantPoolIdx) -> Result<Callee, String> {
+        match self.get_constant_pool_value(cpi) {
+            ConstantPoolValue::UnresolvedMethodRef {
+                class_name,
+                method_name,
+                signature,
+            } => {
+                if class_name.starts_with("java/") {
+                    return Ok(Callee {
+                        class_name,
+                        name: method_name,
+                        signature: parse_signature(&signature),
+                        resolved: None,
+                    });
+                }
+                if !self.class_exists(&class_name) {
+                    return Err(format!(
+                        "uses missing class {}",
+                        class_name.replace('/', ".")
+                    ));
+                }
+                let class_idx = self.get_or_load_class_idx(&class_name);
+                match self.find_virtual_method(class_idx, &method_name, &signature) {
+                    Some((class_idx, method_idx)) => {
+                        Ok(self.resolved_callee(class_idx, method_idx))
+                    }
+                    None => {
+                        let name = format!("{}.{}", class_name.replace('/', "."), method_name);
+                        Err(format!("calls missing method {}", name))
+                    }
+                }
+            }
+            ConstantPoolValue::MethodRef(class_idx, method_idx) => {
+                Ok(self.resolved_callee(class_idx, method_idx))
+            }
+            value => Err(format!("calls unexpected {:?}", value)),
+        }
+    }
+
+    //inherited methods of built-in classes are checked by name too
+    fn resolved_callee(&self, class_idx: ClassIdx, method_idx: MethodInClassIdx) -> Callee {
+        let class = self.get_class(class_idx);
+        let method = &class.methods[method_idx];
+        Callee {
+            class_name: class.name.clone(),
+            name: method.name.clone(),
+            signature: method.signature.clone(),
+            resolved: match class.name.starts_with("java/") {
+                true => None,
+                false => Some((class_idx, method_idx)),
+            },
+        }
+    }
+
+    //slot of the result, `args` has one slot per argument, `this` first.
+    //virtual calls are checked by the declared method, overriding ones are not seen here
+    fn call_impurity(&self, callee: &Callee, args: &[Slot]) -> Result<Slot, String> {
+        let name = format!("{}.{}", callee.class_name.replace('/', "."), callee.name);
+        let (class_idx, method_idx) = match callee.resolved {
+            Some(method) => method,
+            None => return library_call(callee, &name, args),
+        };
+        let flags = self.get_method(class_idx, method_idx).flags;
+        if flags.contains(AccessFlags::NATIVE) {
+            return Err(format!("calls native {}", name));
+        }
+        if self.get_method(class_idx, method_idx).code_ptr == 0 {
+            return Err(format!(
+                "calls abstract {}, its implementation is not known",
+                name
+            ));
+        }
+        let fresh = args
+            .iter()
+            .take(64)
+            .enumerate()
+            .filter(|(_, x)| **x == Slot::Fresh)
+            .fold(0u64, |mask, (i, _)| mask | 1 << i);
+        match self.method_impurity(class_idx, method_idx, fresh) {
+            Some(reason) => Err(format!("calls {}, which {}", name, reason)),
+            None => Ok(Slot::Other),
+        }
+    }
+
+    fn code_location(
+        &self,
+        class_idx: ClassIdx,
+        method_idx: MethodInClassIdx,
+        pc: usize,
+    ) -> String {
+        let method = self.get_method(class_idx, method_idx);
+        match method.line_number(method.code_ptr + pc) {
+            Some(line) => format!("{} line {}", self.method_name(class_idx, method_idx), line),
+            None => self.method_name(class_idx, method_idx),
+        }
+    }
+
+    pub(crate) fn method_name(&self, class_idx: ClassIdx, method_idx: MethodInClassIdx) -> String {
+        let class = self.get_class(class_idx);
+        format!(
+            "{}.{}",
+            class.name.replace('/', "."),
+            class.methods[method_idx].name
+        )
+    }
+
+    /// Starts checks of stores for frame of `@RVM.Pure` method, which was just pushed
+    pub(crate) fn enter_pure_call(&self) {
+        self.pure_marks
+            .borrow_mut()
+            .push(self.heap.next_pure_mark());
+    }
+
+    /// Ends checks for the top frame, if it is frame of `@RVM.Pure` method
+    pub(crate) fn exit_pure_call(&self) {
+        let modifiers = self.stack.top_frame().modifiers;
+        if !modifiers.contains(FrameModifiers::PURE) {
+            return;
+        }
+        let mut marks = self.pure_marks.borrow_mut();
+        marks.pop();
+        if marks.is_empty() {
+            self.heap.end_pure_calls();
+        }
+    }
+
+    //stores are checked while top frame runs within `@RVM.Pure` call, against its innermost one
+    pub(crate) fn enter_pure_context(&self) {
+        if self.side_effect_policy() == SideEffectPolicy::Ignore || self.stack.is_empty() {
+            return;
+        }
+        let in_pure = self
+            .stack
+            .top_frame()
+            .modifiers
+            .contains(FrameModifiers::IN_PURE);
+        let mark = match in_pure {
+            true => self.pure_marks.borrow().last().copied().unwrap_or(0),
+            false => 0,
+        };
+        self.heap.set_pure_mark(mark);
+    }
+
+    /// Frame modifiers of `method` called from frame with `caller` modifiers
+    pub(crate) fn pure_modifiers(&self, method: &Method, caller: FrameModifiers) -> FrameModifiers {
+        if self.side_effect_policy() == SideEffectPolicy::Ignore {
+            FrameModifiers::empty()
+        } else if method.flags.contains(AccessFlags::PURE) {
+            FrameModifiers::PURE | FrameModifiers::IN_PURE
+        } else if caller.contains(FrameModifiers::IN_PURE) && method.name != CLASS_INIT_METHOD {
+            FrameModifiers::IN_PURE //class initialization is not a side effect of the call
+        } else {
+            FrameModifiers::empty()
+        }
+    }
+
+    //stores into heap are checked by heap itself
+    pub(crate) fn check_side_effect(&self) {
+        if let Some(target) = self.heap.take_side_effect() {
+            let what = match self.heap.get_object(target) {
+                Some(HeapObject::Array(..)) => {
+                    format!("changes array {}", self.heap_class_name(target))
+                }
+                _ => format!("changes {} object", self.heap_class_name(target)),
+            };
+            self.report_side_effect(&format!("{} made before the call", what));
+        }
+    }
+
+    pub(crate) fn check_static_side_effect(&self, class_idx: ClassIdx, field_idx: usize) {
+        if self.heap.pure_mark() != 0 {
+            let class = self.get_class(class_idx);
+            let field = format!(
+                "{}.{}",
+                class.name.replace('/', "."),
+                class.static_fields[field_idx].name
+            );
+            drop(class);
+            self.report_side_effect(&format!("changes static field {}", field));
+        }
+    }
+
+    /// Output done by native method called within `@RVM.Pure` call
+    pub(crate) fn check_native_side_effect(&self, class_name: &str, method_name: &str) {
+        if self.heap.pure_mark() != 0 && class_name.starts_with("java/io/") {
+            let name = format!("{}.{}", class_name.replace('/', "."), method_name);
+            self.report_side_effect(&format!("does output with {}", name));
+        }
+    }
+
+    fn report_side_effect(&self, what: &str) {
+        let depth = (1..=self.stack.depth())
+            .rev()
+            .find(|x| {
+                self.stack
+                    .frame_at(*x)
+                    .modifiers
+                    .contains(FrameModifiers::PURE)
+            })
+            .unwrap_or(self.stack.depth());
+        let (class_idx, method_idx) = self.stack.frame_at(depth).class_method_idxs;
+        let message = format!(
+            "@RVM.Pure method {} {}",
+            self.method_name(class_idx, method_idx),
+            what
+        );
+        match self.side_effect_policy() {
+            SideEffectPolicy::Ignore => {}
+            SideEffectPolicy::Warn => {
+                if self
+                    .reported_side_effects
+                    .borrow_mut()
+                    .insert(message.clone())
+                {
+                    warn!("{}", message);
+                }
+            }
+            SideEffectPolicy::Error => {
+                self.throw(new_throwable(self, ILLEGAL_STATE_EXCEPTION, &message))
+            }
+        }
+    }
+}
+
+//built-in classes have no bytecode, so their methods are known by name
+fn library_call(callee: &Callee, name: &str, args: &[Slot]) -> Result<Slot, String> {
+    let class_name = callee.class_name.as_str();
+    let method_name = callee.name.as_str();
+    if class_name.starts_with("java/io/") {
+        return Err(format!("does input or output with {}", name));
+    }
+    if method_name == "random" || method_name == "printStackTrace" {
+        return Err(format!("calls {}", name));
+    }
+    //built-in constructors set up the new object only
+    if method_name == "<init>" && args.first() == Some(&Slot::Fresh) {
+        return Ok(Slot::Other);
+    }
+    if PURE_CLASSES.contains(&class_name) {
+        return Ok(Slot::Other);
+    }
+    if class_name == "java/lang/System" {
+        return match method_name {
+            "arraycopy" if args[2] == Slot::Fresh => Ok(Slot::Other),
+            "arraycopy" => Err("copies into array made before the call".to_string()),
+            "identityHashCode" | "lineSeparator" | "getProperty" => Ok(Slot::Other),
+            _ => Err(format!("calls {}", name)),
+        };
+    }
+    //collections and StringBuilder change their receiver only
+    if class_name.starts_with("java/util/") || class_name == "java/lang/StringBuilder" {
+        if method_name == "iterator" {
+            return Ok(Slot::Fresh);
+        }
+        if method_name == "remove"
+            && callee.signature.arguments.is_empty()
+            && class_name.ends_with("Iterator")
+        {
+            return Err(format!("removes through {}", name));
+        }
+        if args.first() == Some(&Slot::Fresh) {
+            //builder methods return their receiver
+            if class_name == "java/lang/StringBuilder" && BUILDER_CHAINS.contains(&method_name) {
+                return Ok(Slot::Fresh);
+            }
+            return Ok(Slot::Other);
+        }
+        if QUERIES.contains(&method_name) {
+            return Ok(Slot::Other);
+        }
+        return Err(format!("calls {} on object made before the call", name));
+    }
+    Err(format!("calls {}, its effects are not known", name))
+}
+
+fn slots(value_type: &Type) -> usize {
+    match value_type {
+        Type::Long | Type::Double => 2,
+        Type::Void => 0,
+        _ => 1,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PURE_CHECK_PROPERTY;
+    use crate::vm::testing::{new_vm, run};
+
+    //override of pure method's callee has effects the bytecode check does not see
+    const SHAPES: &str = r#"
+        import io.github.rvm.RVM;
+
+        public class PureShapes {
+            static int calls;
+
+            int side = 2;
+
+            int area() {
+                return side * side;
+            }
+
+            static class Counted extends PureShapes {
+                int area() {
+                    calls++;
+                    return super.area();
+                }
+            }
+
+            static class Growing extends PureShapes {
+                int area() {
+                    side++;
+                    return super.area();
+                }
+            }
+
+            @RVM.Pure
+            static int area(PureShapes shape) {
+                return shape.area();
+            }
+
+            @RVM.Mem(requirePure = true)
+            static int cached(int x) {
+                calls++;
+                return x;
+            }
+
+            public static void main(String[] args) {
+                System.out.println(area(new PureShapes()));
+                try {
+                    System.out.println(area(new Counted()));
+                } catch (IllegalStateException e) {
+                    System.out.println(e.getMessage());
+                }
+                try {
+                    System.out.println(area(new Growing()));
+                } catch (IllegalStateException e) {
+                    System.out.println(e.getMessage());
+                }
+                calls = 0;
+                cached(1);
+                cached(1);
+                System.out.println(calls);
+            }
+        }
+    "#;
+
+    #[test]
+    fn side_effects_are_thrown_at_runtime_with_error_policy() {
+        let (status, output) = run(
+            "pure-error",
+            &[("PureShapes", SHAPES)],
+            &[(PURE_CHECK_PROPERTY, "error")],
+        );
+        assert_eq!(
+            output,
+            "4\n\
+             @RVM.Pure method PureShapes.area changes static field PureShapes.calls\n\
+             @RVM.Pure method PureShapes.area changes PureShapes$Growing object made before the call\n\
+             2\n"
+        );
+        assert_eq!(status, 0);
+    }
+
+    #[test]
+    fn side_effects_are_allowed_by_default() {
+        let (status, output) = run("pure-ignore", &[("PureShapes", SHAPES)], &[]);
+        assert_eq!(output, "4\n4\n9\n2\n");
+        assert_eq!(status, 0);
+    }
+
+    #[test]
+    fn bytecode_check_finds_side_effects() {
+        let source = r#"
+            import java.util.ArrayList;
+            import java.util.List;
+
+            public class PureCheck {
+                static int calls;
+                static final int LIMIT = 10;
+                int x;
+
+                static int add(int a, int b) {
+                    return a + b + LIMIT;
+                }
+
+                static int count(int a) {
+                    calls = a;
+                    return a;
+                }
+
+                static int readCalls(int a) {
+                    return a + calls;
+                }
+
+                static int viaCount(int a) {
+                    return count(a) + 1;
+                }
+
+                static PureCheck move(PureCheck p) {
+                    p.x++;
+                    return p;
+                }
+
+                static PureCheck make(int x) {
+                    PureCheck p = new PureCheck();
+                    p.x = x;
+                    return move(p);
+                }
+
+                static String text(int a) {
+                    return "value " + a;
+                }
+
+                static int fill(List<Integer> list) {
+                    list.add(1);
+                    return list.size();
+                }
+
+                static int fillOwn(int a) {
+                    List<Integer> list = new ArrayList<>();
+                    list.add(a);
+                    return list.size();
+                }
+
+                static int print(int a) {
+                    System.out.println(a);
+                    return a;
+                }
+            }
+        "#;
+        let (vm, _, _) = new_vm("pure-check", &[("PureCheck", source)]);
+        let class_idx = vm.get_or_load_class_idx(&"PureCheck".to_string());
+        let impurity = |name: &str| {
+            let method_idx = vm
+                .get_class(class_idx)
+                .methods
+                .iter()
+                .position(|x| x.name == name)
+                .unwrap();
+            vm.method_impurity(class_idx, method_idx, 0)
+        };
+        for name in ["add", "make", "text", "fillOwn"] {
+            assert_eq!(impurity(name), None, "{}", name);
+        }
+        let impure = [
+            (
+                "count",
+                "changes static field PureCheck.calls at PureCheck.count line 15",
+            ),
+            (
+                "readCalls",
+                "reads mutable static field PureCheck.calls at PureCheck.readCalls line 20",
+            ),
+            (
+                "viaCount",
+                "calls PureCheck.count at PureCheck.viaCount line 24, \
+                 which changes static field PureCheck.calls at PureCheck.count line 15",
+            ),
+            (
+                "move",
+                "changes field PureCheck.x of object made before the call at PureCheck.move line 28",
+            ),
+            (
+                "fill",
+                "calls java.util.List.add on object made before the call at PureCheck.fill line 43",
+            ),
+            (
+                "print",
+                "does input or output with java.io.PrintStream.println at PureCheck.print line 54",
+            ),
+        ];
+        for (name, reason) in impure {
+            assert_eq!(impurity(name).as_deref(), Some(reason), "{}", name);
+        }
+    }
+}
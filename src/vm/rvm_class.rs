@@ -1,10 +1,20 @@
-use crate::vm::classes::NativeMethod;
-use crate::vm::java_lang::{INTEGER_CLASS_IDX, STRING_CLASS_IDX};
-use crate::vm::memory::HeapMemory;
+use crate::vm::classes::{
+    AccessFlags, Annotation, AnnotationHandler, Class, Method, NativeMethod, Signature,
+};
+use crate::vm::contract::ContractAnnotation;
+use crate::vm::heap_graph::GraphFilter;
+use crate::vm::java_io::STDOUT;
+use crate::vm::java_lang::{new_throwable, to_java_string};
+use crate::vm::memo::{MemAnnotation, MEM_STATS_CLASS};
+use crate::vm::memory::HeapObject;
+use crate::vm::program::Program;
+use crate::vm::purity::PureAnnotation;
 use crate::vm::stack::Value;
+use crate::vm::state_dump::STATE_FORMAT_PROPERTY;
+use crate::vm::trace::TraceAnnotation;
 use crate::VM;
 use log::info;
-use std::time::SystemTime;
+use std::convert::TryInto;
 
 pub struct RvmClass;
 
@@ -14,38 +24,169 @@ const PRINTLN: &str = "println";
 const LOG_STATE: &str = "logState";
 const TICK: &str = "tick";
 const HEAP_SIZE: &str = "heapSize";
+const HEAP_USED: &str = "heapUsed";
+const HEAP_FREE: &str = "heapFree";
+const GC: &str = "gc";
+const COPY_OUT: &str = "copyOut";
+const DUMP_HEAP: &str = "dumpHeap";
+const DUMP_HEAP_GRAPH: &str = "dumpHeapGraph";
+const CLEAR_MEM: &str = "clearMem";
+const MEM_STATS: &str = "memStats";
+const IO_EXCEPTION: &str = "java/io/IOException";
+
+impl Program {
+    pub(crate) fn init_rvm_annotations(&mut self) {
+        let flags = [
+            ("TailRecursion", AccessFlags::TAIL_RECURSION),
+            ("TailCall", AccessFlags::TAIL_CALL),
+            ("AutoFree", AccessFlags::AUTO_FREE),
+            ("Arena", AccessFlags::ARENA),
+        ];
+        for (name, flag) in flags {
+            let handler = Box::new(FlagAnnotation(flag));
+            self.annotation_handlers
+                .insert(format!("Lio/github/rvm/RVM${};", name), handler);
+        }
+        self.annotation_handlers.insert(
+            "Lio/github/rvm/RVM$Mem;".to_string(),
+            Box::new(MemAnnotation),
+        );
+        self.annotation_handlers.insert(
+            "Lio/github/rvm/RVM$Trace;".to_string(),
+            Box::new(TraceAnnotation),
+        );
+        self.annotation_handlers.insert(
+            "Lio/github/rvm/RVM$Pure;".to_string(),
+            Box::new(PureAnnotation),
+        );
+        let contracts = [
+            ("Requires", AccessFlags::REQUIRES),
+            ("Ensures", AccessFlags::ENSURES),
+        ];
+        for (name, flag) in contracts {
+            let handler = Box::new(ContractAnnotation(flag));
+            self.annotation_handlers
+                .insert(format!("Lio/github/rvm/RVM${};", name), handler);
+        }
+    }
+}
+
+/// Annotation which only marks method for interpreter
+struct FlagAnnotation(AccessFlags);
+
+impl AnnotationHandler for FlagAnnotation {
+    fn on_method(&self, _class: &Class, method: &mut Method, _annotation: &Annotation) {
+        method.flags.insert(self.0)
+    }
+}
 
 impl NativeMethod for RvmClass {
     fn invoke(
         &self,
         vm: &VM,
-        class_name: &String,
-        name: &String,
+        class_name: &str,
+        name: &str,
+        signature: &Signature,
         arguments: Vec<Value>,
     ) -> Option<Value> {
-        match (class_name.as_str(), name.as_str()) {
-            (RVM_CLASS_NAME, PRINT) => self.print(&arguments, &vm.heap),
-            (RVM_CLASS_NAME, PRINTLN) => self.println(),
+        match (class_name, name) {
+            (RVM_CLASS_NAME, PRINT) => self.print(vm, signature, &arguments),
+            (RVM_CLASS_NAME, PRINTLN) => self.println(vm),
             (RVM_CLASS_NAME, LOG_STATE) => self.log_state(vm),
-            (RVM_CLASS_NAME, HEAP_SIZE) => Some(Value::Int(vm.heap.inspect().len() as i32)),
-            (RVM_CLASS_NAME, TICK) => Some(Value::Int(
-                SystemTime::now()
-                    .duration_since(vm.start_time)
-                    .unwrap()
-                    .as_millis() as i32,
-            )),
+            (RVM_CLASS_NAME, HEAP_SIZE) => Some(int_bytes(vm.heap.size())),
+            (RVM_CLASS_NAME, HEAP_USED) => Some(int_bytes(vm.heap.used_space())),
+            (RVM_CLASS_NAME, HEAP_FREE) => Some(int_bytes(vm.heap.free_space())),
+            (RVM_CLASS_NAME, GC) => {
+                //native has no pointers to keep, so collection runs right after return
+                vm.heap.request_gc();
+                Some(Value::Void)
+            }
+            (RVM_CLASS_NAME, COPY_OUT) => {
+                if let Value::Reference(ptr) = arguments[0] {
+                    vm.heap.copy_out(ptr);
+                }
+                Some(arguments[0].clone())
+            }
+            (RVM_CLASS_NAME, DUMP_HEAP) => {
+                let path = vm.heap.get_string(arguments[0].clone().try_into().unwrap());
+                if let Err(error) = vm.dump_heap(&path) {
+                    vm.throw(new_throwable(vm, IO_EXCEPTION, &error.to_string()));
+                }
+                Some(Value::Void)
+            }
+            (RVM_CLASS_NAME, DUMP_HEAP_GRAPH) => self.dump_heap_graph(vm, &arguments),
+            (RVM_CLASS_NAME, CLEAR_MEM) => {
+                //null clears all methods as well
+                let method = match arguments.first() {
+                    Some(Value::Reference(ptr)) if *ptr != 0 => Some(vm.heap.get_string(*ptr)),
+                    _ => None,
+                };
+                Some(Value::Int(vm.clear_mem(method.as_deref()) as i32))
+            }
+            (RVM_CLASS_NAME, MEM_STATS) => self.mem_stats(vm, &arguments),
+            (RVM_CLASS_NAME, TICK) => Some(Value::Int(vm.tick())),
             _ => None,
         }
     }
 }
 
 impl RvmClass {
-    fn println(&self) -> Option<Value> {
-        println!();
-        return Some(Value::Void);
+    fn println(&self, vm: &VM) -> Option<Value> {
+        vm.streams.write(STDOUT, b"\n");
+        Some(Value::Void)
+    }
+
+    //optional class names (null for all) and depth (negative for unlimited) follow path
+    fn dump_heap_graph(&self, vm: &VM, arguments: &[Value]) -> Option<Value> {
+        let path = vm.heap.get_string(arguments[0].clone().try_into().unwrap());
+        let filter = match arguments {
+            [_, Value::Reference(classes), Value::Int(depth)] => {
+                let classes = match *classes {
+                    0 => String::new(),
+                    ptr => vm.heap.get_string(ptr),
+                };
+                GraphFilter::new(&classes, (*depth).try_into().ok())
+            }
+            _ => GraphFilter::default(),
+        };
+        if let Err(error) = vm.dump_heap_graph(&path, &filter) {
+            vm.throw(new_throwable(vm, IO_EXCEPTION, &error.to_string()));
+        }
+        Some(Value::Void)
+    }
+
+    //stats of the method given by name, or array of all stats without arguments
+    fn mem_stats(&self, vm: &VM, arguments: &[Value]) -> Option<Value> {
+        match arguments.first() {
+            Some(Value::Reference(0)) => Some(Value::Reference(0)),
+            Some(Value::Reference(ptr)) => {
+                let method = vm.heap.get_string(*ptr);
+                let stats = vm.mem_stats(Some(&method));
+                Some(Value::Reference(match stats.first() {
+                    Some((name, table)) => vm.new_mem_stats(name, table),
+                    None => 0,
+                }))
+            }
+            _ => {
+                let stats = vm.mem_stats(None);
+                let class_idx = vm.get_or_load_class_idx(&MEM_STATS_CLASS.to_string());
+                let array = vm.heap.new_object_array(class_idx, stats.len() as i32);
+                for (i, (name, table)) in stats.iter().enumerate() {
+                    let element = Value::Reference(vm.new_mem_stats(name, table));
+                    vm.heap.set_array_element(array, i, element);
+                }
+                Some(Value::Reference(array))
+            }
+        }
     }
 
     fn log_state(&self, vm: &VM) -> Option<Value> {
+        if vm.get_property(STATE_FORMAT_PROPERTY).as_deref() == Some("json") {
+            if let Err(error) = vm.log_state_json() {
+                vm.throw(new_throwable(vm, IO_EXCEPTION, &error.to_string()));
+            }
+            return Some(Value::Void);
+        }
         info!("--------------- STACK -----------------");
         let frames = vm.stack.inspect();
         for frame in frames.iter().rev() {
@@ -58,74 +199,59 @@ impl RvmClass {
             for item in frame.inspect_stack().iter().rev() {
                 info!("  > {}", item.short())
             }
-            let locals = frame.inspect_locals();
-            for i in 0..locals.len() {
-                info!("  local({}) = {}", i, locals[i].short())
-            }
-        }
-        let heap_values = vm.heap.inspect();
-        info!("------------ HEAP [{:4}] --------------", heap_values.len());
-        let mut str = "".to_string();
-        for i in 0..heap_values.len() {
-            str += " [";
-            str += &heap_values[i].short();
-            str += " ]";
-            if i % 10 == 9 {
-                info!("{}", str);
-                str = "".to_string()
+            for (i, local) in frame.inspect_locals().iter().enumerate() {
+                info!("  local({}) = {}", i, local.short())
             }
         }
-        if str != "" {
-            info!("{}", str);
+        info!("---------- HEAP [{:6} bytes] ----------", vm.heap.size());
+        for ptr in vm.heap.objects() {
+            let (name, values) = match vm.heap.get_object(ptr) {
+                Some(HeapObject::Object(class_idx)) => {
+                    let fields = vm.get_class(class_idx).fields.len();
+                    let values = (0..fields).map(|i| vm.heap.get_field(ptr, i)).collect();
+                    (vm.get_class(class_idx).name.clone(), values)
+                }
+                Some(HeapObject::Array(element_type, _)) => {
+                    let length = vm.heap.array_length(ptr);
+                    let values = (0..length)
+                        .map(|i| vm.heap.get_array_element(ptr, i))
+                        .collect();
+                    (format!("{:?}[{}]", element_type, length), values)
+                }
+                _ => (format!("{:?}", vm.heap.get_string(ptr)), vec![]),
+            };
+            let values: Vec<String> = values.iter().map(|x| x.short()).collect();
+            info!(" @{:<6} {} [{} ]", ptr, name, values.join(" "));
         }
 
         info!("-------------- CLASSES ----------------");
-        let classes = vm.program.borrow().classes.clone();
-        for i in 0..classes.len() {
-            info!("{:4} {}", i, classes[i].name)
+        for (i, class) in vm.program.borrow().classes.iter().enumerate() {
+            info!("{:4} {}", i, class.name)
         }
         info!("--------------- <eof> -----------------");
-        return Some(Value::Void);
+        Some(Value::Void)
     }
 
-    fn print(&self, arguments: &Vec<Value>, heap: &HeapMemory) -> Option<Value> {
-        for arg in arguments {
-            match arg {
-                Value::Byte(v) => print!("{}", v),
-                Value::Short(v) => print!("{}", v),
-                Value::Int(v) => print!("{}", v),
-                Value::Long(v) => print!("{}", v),
-                Value::Char(v) => print!("{}", v),
-                Value::Float(v) => print!("{}", v),
-                Value::Double(v) => print!("{}", v),
-                Value::Boolean(v) => print!("{}", v),
-                Value::String(v) => print!("{}", v),
-                Value::Reference(0) => print!("null"),
-                Value::Reference(heap_ptr) => {
-                    match (heap.get_value(*heap_ptr), heap.get_field(*heap_ptr, 0)) {
-                        (Value::ClassIndex(STRING_CLASS_IDX, _), Value::String(str)) => {
-                            print!("{}", str)
-                        }
-                        (Value::ClassIndex(INTEGER_CLASS_IDX, _), Value::Int(int)) => {
-                            print!("{}", int)
-                        }
-                        _ => panic!(
-                            "Don't know how to serialize to string: {:?} {:?}",
-                            heap.get_value(*heap_ptr),
-                            heap.get_field(*heap_ptr, 0)
-                        ),
-                    }
-                }
-                _ => {}
+    fn print(&self, vm: &VM, signature: &Signature, arguments: &[Value]) -> Option<Value> {
+        for (arg, arg_type) in arguments.iter().zip(&signature.arguments) {
+            let str = to_java_string(vm, arg, arg_type);
+            if vm.pending_exception() != 0 {
+                break; //thrown by toString
             }
+            vm.streams.write(STDOUT, str.as_bytes());
         }
-        return Some(Value::Void);
+        Some(Value::Void)
     }
 }
 
+//heap could be larger than int range, so sizes saturate instead of wrapping to negative
+fn int_bytes(bytes: usize) -> Value {
+    Value::Int(bytes.min(i32::MAX as usize) as i32)
+}
+
 impl Value {
     fn short(&self) -> String {
-        return match self {
+        match self {
             Value::Byte(b) => format!("b{:5}", b),
             Value::Short(s) => format!("s{:5}", s),
             Value::Int(i) => format!("i{:5}", i),
@@ -134,16 +260,64 @@ impl Value {
             Value::Float(f) => format!("f{:5}", f),
             Value::Double(d) => format!("d{:5}", d),
             Value::Boolean(bool) => format!("{:6}", if *bool > 0 { "true" } else { "false" }),
-            Value::ReturnType => format!("retut"),
-            Value::Reference(0) => format!("P null",),
+            Value::ReturnType => "retut".to_string(),
+            Value::Reference(0) => "P null".to_string(),
             Value::Reference(ptr) => format!("P{:5}", ptr),
-            Value::ClassIndex(ci, _) => format!("C{:5}", ci),
-            Value::ArrayOf(ty, _) => format!("A{:?}", ty),
-            Value::String(s) => format!(
-                "{:6}",
-                s.replace("\n", "\\n").chars().take(6).collect::<String>()
-            ),
             Value::Void => " ---- ".to_string(),
-        };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::int_bytes;
+    use crate::vm::stack::Value;
+    use crate::vm::testing::run;
+
+    #[test]
+    fn heap_sizes_saturate() {
+        assert_eq!(int_bytes(1024), Value::Int(1024));
+        assert_eq!(int_bytes(i32::MAX as usize), Value::Int(i32::MAX));
+        assert_eq!(int_bytes(u32::MAX as usize), Value::Int(i32::MAX));
+    }
+
+    #[test]
+    fn arena_copies_out_kept_objects_only() {
+        let source = r#"
+            import io.github.rvm.RVM;
+            import java.util.ArrayList;
+
+            public class Arenas {
+                static String last;
+                static Object[] holder = new Object[1];
+
+                @RVM.Arena
+                static String process(int batch, ArrayList<String> samples) {
+                    for (int i = 0; i < 100; i++) {
+                        String temporary = batch + ":" + i;
+                    }
+                    samples.add(RVM.copyOut("sample " + batch));
+                    last = "static " + batch;
+                    holder[0] = "held " + batch;
+                    return "result " + batch;
+                }
+
+                public static void main(String[] args) {
+                    ArrayList<String> samples = new ArrayList<>();
+                    String result = process(0, samples);
+                    int before = RVM.heapSize();
+                    for (int batch = 1; batch <= 3; batch++) {
+                        result = process(batch, samples);
+                    }
+                    int grown = RVM.heapSize() - before;
+                    System.out.println(result + " " + samples + " " + last + " " + holder[0]);
+                    System.out.println(grown < 1000);
+                }
+            }
+        "#;
+        let (status, output) = run("arenas", &[("Arenas", source)], &[]);
+        let expected = "result 3 [sample 0, sample 1, sample 2, sample 3] static 3 held 3\ntrue\n";
+        assert_eq!(output, expected);
+        assert_eq!(status, 0);
     }
 }
@@ -1,5 +1,5 @@
 use crate::vm::classes::{ClassIdx, CodePtr, ConstantPoolIdx, MethodInClassIdx};
-use crate::vm::memory::HeapPtr;
+use crate::vm::memory::{HeapPtr, Relocation};
 use crate::VM;
 use derive_more::TryInto;
 use std::cell::{Cell, Ref, RefCell, RefMut};
@@ -11,25 +11,31 @@ pub struct Stack {
 
 impl Stack {
     pub fn new() -> Self {
-        return Default::default();
+        Default::default()
     }
 
-    pub fn top_frame(&self) -> Ref<StackFrame> {
+    pub fn top_frame(&self) -> Ref<'_, StackFrame> {
         let frames = self.frames.borrow();
         Ref::map(frames, |x| x.last().unwrap())
     }
 
-    pub fn top_frame_mut(&self) -> RefMut<StackFrame> {
+    pub fn top_frame_mut(&self) -> RefMut<'_, StackFrame> {
         let frames = self.frames.borrow_mut();
         RefMut::map(frames, |x| x.last_mut().unwrap())
     }
 
-    pub fn push_frame(&self, stack_size: u16, locals_count: u16) -> RefMut<StackFrame> {
+    pub fn push_frame(&self, stack_size: u16, locals_count: u16) -> RefMut<'_, StackFrame> {
         let mut frames_mut = self.frames.borrow_mut();
         frames_mut.push(StackFrame::new(stack_size, locals_count));
         RefMut::map(frames_mut, |x| x.last_mut().unwrap())
     }
 
+    /// Frame at the given depth, the outermost frame has depth 1
+    pub fn frame_at(&self, depth: usize) -> Ref<'_, StackFrame> {
+        let frames = self.frames.borrow();
+        Ref::map(frames, |x| &x[depth - 1])
+    }
+
     pub fn pop_frame(&self) {
         self.frames.borrow_mut().pop();
     }
@@ -38,17 +44,49 @@ impl Stack {
         return self.frames.borrow().clone();
     }
 
+    pub fn depth(&self) -> usize {
+        self.frames.borrow().len()
+    }
+
     pub fn is_empty(&self) -> bool {
         self.frames.borrow().is_empty()
     }
+
+    /// Updates references in all frames after heap compaction
+    pub fn relocate(&self, relocation: &Relocation) {
+        for frame in self.frames.borrow().iter() {
+            frame
+                .stack
+                .borrow_mut()
+                .iter_mut()
+                .for_each(|x| relocation.relocate(x));
+            frame
+                .locals
+                .borrow_mut()
+                .iter_mut()
+                .for_each(|x| relocation.relocate(x));
+            let mut instantiated = frame.instantiated.borrow_mut();
+            *instantiated = instantiated
+                .iter()
+                .map(|ptr| relocation.forward(*ptr))
+                .filter(|ptr| *ptr != 0) //already freed by AutoFree
+                .collect();
+        }
+    }
 }
 
 bitflags::bitflags! {
     #[derive(Default)]
     pub struct FrameModifiers: u16 {
-        const MEM_LOAD = 0x0001;
-        const MEM_SAVE = 0x0002;
+        const MEM_SAVE = 0x0002; //result is saved to memo of method
         const AUTO_FREE = 0x0004;
+        const NATIVE_BRIDGE = 0x0008; //receives result of method called from rust
+        const ARENA = 0x0010; //releases heap arena on return
+        const TRACED = 0x0020; //return is logged
+        const TRACE_CALLEES = 0x0040; //called methods are traced
+        const PURE = 0x0080; //frame of @RVM.Pure method, its stores are checked at runtime
+        const IN_PURE = 0x0100; //runs within PURE frame, so its stores are checked too
+        const ENSURES = 0x0200; //arguments are kept in extra locals for postcondition
     }
 }
 
@@ -96,6 +134,22 @@ impl Type {
             Type::Void => panic!("cannot instantiate void"),
         }
     }
+
+    /// Field descriptor, class of references is not known
+    pub(crate) fn descriptor(&self) -> &'static str {
+        match self {
+            Type::Byte => "B",
+            Type::Short => "S",
+            Type::Int => "I",
+            Type::Long => "J",
+            Type::Char => "C",
+            Type::Float => "F",
+            Type::Double => "D",
+            Type::Boolean => "Z",
+            Type::Reference => "Ljava/lang/Object;",
+            Type::Void => "V",
+        }
+    }
 }
 
 #[derive(TryInto, Clone, Debug, PartialEq)]
@@ -111,13 +165,16 @@ pub enum Value {
     ReturnType,
     // ?
     Reference(HeapPtr), //0 == null
-    #[try_into(ignore)]
-    ClassIndex(ClassIdx, u16),
-    ArrayOf(Type, ClassIdx),
-    String(String), // simplification
     Void,
 }
 
+impl Value {
+    //long and double take two local variable slots
+    pub(crate) fn is_wide(&self) -> bool {
+        matches!(self, Value::Long(_) | Value::Double(_))
+    }
+}
+
 impl StackFrame {
     pub fn new(stack_size: u16, locals_count: u16) -> Self {
         Self {
@@ -130,9 +187,17 @@ impl StackFrame {
         }
     }
 
+    /// Empties frame for a method called in tail position, objects instantiated so far are kept
+    pub fn resize(&self, stack_size: u16, locals_count: u16) {
+        let mut stack = self.stack.borrow_mut();
+        stack.clear();
+        stack.reserve(stack_size as usize);
+        *self.locals.borrow_mut() = vec![Value::Int(0); locals_count as usize];
+    }
+
     pub fn pick(&self, offset: usize) -> Value {
         let stack_ref = self.stack.borrow();
-        return stack_ref[stack_ref.len() - offset - 1].clone();
+        stack_ref[stack_ref.len() - offset - 1].clone()
     }
 
     pub fn pick_u8(&self, code: &VM) -> u8 {
@@ -142,25 +207,25 @@ impl StackFrame {
     pub fn read_u8(&self, code: &VM) -> u8 {
         let pc = self.pc.get();
         self.pc.set(pc + 1);
-        return code.code_read_u8(pc);
+        code.code_read_u8(pc)
     }
 
     pub fn read_i8(&self, code: &VM) -> i8 {
         let pc = self.pc.get();
         self.pc.set(pc + 1);
-        return code.code_read_u8(pc) as i8;
+        code.code_read_u8(pc) as i8
     }
 
     pub fn read_u16(&self, code: &VM) -> u16 {
         let high = self.read_u8(code) as u16;
         let low = self.read_u8(code) as u16;
-        return (high << 8) | low;
+        (high << 8) | low
     }
 
     pub fn read_code_offset(&self, code: &VM) -> i16 {
         let high = self.read_u8(code) as u16;
         let low = self.read_u8(code) as u16;
-        return ((high << 8) | low) as i16 - 3;
+        ((high << 8) | low) as i16 - 3
     }
 
     pub fn jmp_relative(&self, offset: i16) {
@@ -184,6 +249,10 @@ impl StackFrame {
         return self.stack.borrow_mut().pop().unwrap();
     }
 
+    pub fn clear_stack(&self) {
+        self.stack.borrow_mut().clear()
+    }
+
     pub fn dup(&self) {
         let mut stack_mut = self.stack.borrow_mut();
         let value = (*stack_mut.last().unwrap()).clone();
@@ -199,6 +268,22 @@ impl StackFrame {
         stack_mut.push(value1.clone());
     }
 
+    //long and double occupy single stack entry, so they are duplicated alone
+    pub fn dup2(&self) {
+        let mut stack_mut = self.stack.borrow_mut();
+        let value1 = stack_mut.pop().unwrap();
+        if value1.is_wide() {
+            stack_mut.push(value1.clone());
+            stack_mut.push(value1);
+        } else {
+            let value2 = stack_mut.pop().unwrap();
+            stack_mut.push(value2.clone());
+            stack_mut.push(value1.clone());
+            stack_mut.push(value2);
+            stack_mut.push(value1);
+        }
+    }
+
     pub fn inspect_stack(&self) -> Vec<Value> {
         self.stack.borrow().clone()
     }
@@ -207,7 +292,7 @@ impl StackFrame {
     }
 
     pub fn on_instantiate(&self, value: HeapPtr) {
-        self.instantiated.borrow_mut().push(value.clone())
+        self.instantiated.borrow_mut().push(value)
     }
 
     pub fn get_instantiated(&self) -> Vec<HeapPtr> {
@@ -0,0 +1,322 @@
+use crate::vm::memory::{HeapObject, HeapPtr};
+use crate::vm::stack::{FrameModifiers, StackFrame, Type, Value};
+use crate::vm::vm::VM;
+use serde_json::{json, Value as Json};
+use std::fs::OpenOptions;
+use std::io::Write;
+
+/// `RVM.logState()` output: `text` (default) or `json`
+pub const STATE_FORMAT_PROPERTY: &str = "rjava.state.format";
+/// File to append JSON states to, one per line. Without it JSON goes to the log
+pub const STATE_PATH_PROPERTY: &str = "rjava.state.path";
+
+const MODIFIER_NAMES: [(FrameModifiers, &str); 9] = [
+    (FrameModifiers::MEM_SAVE, "mem_save"),
+    (FrameModifiers::AUTO_FREE, "auto_free"),
+    (FrameModifiers::NATIVE_BRIDGE, "native_bridge"),
+    (FrameModifiers::ARENA, "arena"),
+    (FrameModifiers::TRACED, "traced"),
+    (FrameModifiers::TRACE_CALLEES, "trace_callees"),
+    (FrameModifiers::PURE, "pure"),
+    (FrameModifiers::IN_PURE, "in_pure"),
+    (FrameModifiers::ENSURES, "ensures"),
+];
+
+impl VM {
+    /// Snapshot of stack, heap, loaded classes and memo tables
+    pub fn state_json(&self) -> Json {
+        json!({
+            "heap_size": self.heap.size(),
+            "heap_used": self.heap.used_space(),
+            "frames": self.stack.inspect().iter().rev().map(|x| self.frame_json(x)).collect::<Vec<_>>(),
+            "heap": self.heap.objects().into_iter().map(|x| self.object_json(x)).collect::<Vec<_>>(),
+            "classes": self.classes_json(),
+            "memo": self.memo_json(),
+        })
+    }
+
+    /// Writes state to `rjava.state.path` as a line of JSON, or to the log
+    pub fn log_state_json(&self) -> std::io::Result<()> {
+        let state = self.state_json().to_string();
+        match self.get_property(STATE_PATH_PROPERTY) {
+            Some(path) => {
+                let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+                writeln!(file, "{}", state)
+            }
+            None => {
+                log::info!("{}", state);
+                Ok(())
+            }
+        }
+    }
+
+    fn frame_json(&self, frame: &StackFrame) -> Json {
+        let (class_idx, method_idx) = frame.class_method_idxs;
+        let class = self.get_class(class_idx);
+        let method = &class.methods[method_idx];
+        let pc = frame.pc.get();
+        let modifiers: Vec<_> = MODIFIER_NAMES
+            .iter()
+            .filter(|(modifier, _)| frame.modifiers.contains(*modifier))
+            .map(|(_, name)| *name)
+            .collect();
+        json!({
+            "class": class.name,
+            "method": method.name,
+            "pc": pc.saturating_sub(method.code_ptr),
+            "line": method.line_number(pc.saturating_sub(1)),
+            "modifiers": modifiers,
+            "locals": frame.inspect_locals().iter().map(typed).collect::<Vec<_>>(),
+            "stack": frame.inspect_stack().iter().map(typed).collect::<Vec<_>>(),
+        })
+    }
+
+    fn object_json(&self, ptr: HeapPtr) -> Json {
+        let heap = &self.heap;
+        let mut object = json!({ "ptr": ptr, "size": heap.size_of(ptr) });
+        let details = match heap.get_object(ptr) {
+            Some(HeapObject::Object(class_idx)) => {
+                let class = self.get_class(class_idx);
+                let fields: Vec<_> = class
+                    .fields
+                    .iter()
+                    .enumerate()
+                    .map(|(i, field)| {
+                        named(&field.name, &field.value_type, &heap.get_field(ptr, i))
+                    })
+                    .collect();
+                json!({ "kind": "object", "class": class.name, "fields": fields })
+            }
+            Some(HeapObject::Array(element_type, class_idx)) => {
+                let elements: Vec<_> = (0..heap.array_length(ptr))
+                    .map(|i| typed(&heap.get_array_element(ptr, i)))
+                    .collect();
+                let element_class = match element_type {
+                    Type::Reference => self.get_class(class_idx).name.clone(),
+                    _ => type_name(&element_type.default_value()).to_string(),
+                };
+                json!({ "kind": "array", "class": element_class + "[]", "elements": elements })
+            }
+            Some(HeapObject::String) => {
+                json!({ "kind": "string", "class": "java/lang/String", "value": heap.get_string(ptr) })
+            }
+            None => json!({ "kind": "freed" }),
+        };
+        let map = object.as_object_mut().unwrap();
+        map.extend(details.as_object().unwrap().clone());
+        let region = heap.region_of(ptr);
+        if region != 0 {
+            map.insert("region".to_string(), json!(region));
+        }
+        object
+    }
+
+    fn classes_json(&self) -> Json {
+        let program = self.program.borrow();
+        let classes: Vec<_> = program
+            .classes
+            .iter()
+            .enumerate()
+            .map(|(i, class)| {
+                let statics: Vec<_> = class
+                    .static_fields
+                    .iter()
+                    .zip(&class.static_values)
+                    .map(|(field, value)| named(&field.name, &field.value_type, value))
+                    .collect();
+                json!({
+                    "idx": i,
+                    "name": class.name,
+                    "super": class.super_class_idx,
+                    "initialized": class.initialized,
+                    "statics": statics,
+                })
+            })
+            .collect();
+        json!(classes)
+    }
+
+    //tables and statistics of @RVM.Mem methods
+    fn memo_json(&self) -> Json {
+        let memo: Vec<_> = self
+            .mem_stats(None)
+            .into_iter()
+            .map(|(name, table)| {
+                let buckets = match table.buckets {
+                    0 => 0,
+                    ptr => self.heap.array_length(ptr),
+                };
+                json!({
+                    "method": name,
+                    "entries": table.size,
+                    "buckets": buckets,
+                    "max_entries": table.max_entries,
+                    "policy": format!("{:?}", table.eviction).to_uppercase(),
+                    "ttl": table.ttl,
+                    "hits": table.stats.hits,
+                    "misses": table.stats.misses,
+                    "evictions": table.stats.evictions,
+                    "saved_nanos": table.stats.saved_nanos,
+                })
+            })
+            .collect();
+        json!(memo)
+    }
+}
+
+//fields of narrow types are kept as int, so value is shown with declared type
+fn named(name: &str, value_type: &Type, value: &Value) -> Json {
+    let value = match (value_type, value) {
+        (Type::Byte, Value::Int(x)) => Value::Byte(*x as i8),
+        (Type::Short, Value::Int(x)) => Value::Short(*x as i16),
+        (Type::Char, Value::Int(x)) => Value::Char(*x as u16),
+        (Type::Boolean, Value::Int(x)) => Value::Boolean(*x),
+        _ => value.clone(),
+    };
+    let mut json = typed(&value);
+    json.as_object_mut()
+        .unwrap()
+        .insert("name".to_string(), json!(name));
+    json
+}
+
+fn typed(value: &Value) -> Json {
+    let json_value = match value {
+        Value::Byte(x) => json!(x),
+        Value::Short(x) => json!(x),
+        Value::Int(x) => json!(x),
+        Value::Long(x) => json!(x),
+        Value::Char(x) => json!(char::from_u32(*x as u32).map(String::from)),
+        Value::Float(x) => json!(x),
+        Value::Double(x) => json!(x),
+        Value::Boolean(x) => json!(*x != 0),
+        Value::Reference(0) => Json::Null,
+        Value::Reference(ptr) => json!(ptr),
+        Value::ReturnType | Value::Void => Json::Null,
+    };
+    json!({ "type": type_name(value), "value": json_value })
+}
+
+fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Byte(_) => "byte",
+        Value::Short(_) => "short",
+        Value::Int(_) => "int",
+        Value::Long(_) => "long",
+        Value::Char(_) => "char",
+        Value::Float(_) => "float",
+        Value::Double(_) => "double",
+        Value::Boolean(_) => "boolean",
+        Value::Reference(_) => "reference",
+        Value::ReturnType => "return_address",
+        Value::Void => "void",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Json, STATE_FORMAT_PROPERTY, STATE_PATH_PROPERTY};
+    use crate::vm::testing::run;
+
+    #[test]
+    fn json_states_are_appended_per_call() {
+        let source = r#"
+            import io.github.rvm.RVM;
+
+            public class StateDump {
+                static StateDump last;
+
+                byte level = 3;
+                boolean done;
+                String name = "dump";
+
+                @RVM.Mem
+                static int square(int x) {
+                    return x * x;
+                }
+
+                static void nested(long step, StateDump state) {
+                    RVM.logState();
+                }
+
+                public static void main(String[] args) {
+                    last = new StateDump();
+                    square(4);
+                    square(4);
+                    nested(5L, last);
+                    last.done = true;
+                    RVM.logState();
+                }
+            }
+        "#;
+        let path = std::env::temp_dir().join(format!("rjava-state-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let (status, output) = run(
+            "state-dump",
+            &[("StateDump", source)],
+            &[
+                (STATE_FORMAT_PROPERTY, "json"),
+                (STATE_PATH_PROPERTY, path.to_str().unwrap()),
+            ],
+        );
+        let text = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!((status, output.as_str()), (0, ""));
+        let states: Vec<Json> = text
+            .lines()
+            .map(|x| serde_json::from_str(x).unwrap())
+            .collect();
+        assert_eq!(states.len(), 2);
+
+        //innermost frame comes first, narrow fields are shown with declared type
+        let frames = &states[0]["frames"];
+        assert_eq!(frames[0]["class"], "StateDump");
+        assert_eq!(frames[0]["method"], "nested");
+        assert_eq!(frames[0]["line"], 17);
+        assert_eq!(frames[0]["locals"][0]["type"], "long");
+        assert_eq!(frames[0]["locals"][0]["value"], 5);
+        assert_eq!(frames[0]["locals"][2]["type"], "reference");
+        let ptr = frames[0]["locals"][2]["value"].clone();
+        assert_eq!(frames[1]["method"], "main");
+        let object = states[0]["heap"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|x| x["ptr"] == ptr)
+            .unwrap();
+        assert_eq!(object["kind"], "object");
+        assert_eq!(object["class"], "StateDump");
+        let fields = &object["fields"];
+        assert_eq!(fields[0]["name"], "level");
+        assert_eq!(fields[0]["type"], "byte");
+        assert_eq!(fields[0]["value"], 3);
+        assert_eq!(fields[1]["value"], false);
+        let name = states[0]["heap"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|x| x["ptr"] == fields[2]["value"])
+            .unwrap();
+        assert_eq!(name["kind"], "string");
+        assert_eq!(name["value"], "dump");
+
+        let class = states[1]["classes"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|x| x["name"] == "StateDump")
+            .unwrap();
+        assert_eq!(class["initialized"], true);
+        assert_eq!(class["statics"][0]["name"], "last");
+        assert_eq!(class["statics"][0]["value"], ptr);
+        let memo = states[1]["memo"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|x| x["method"] == "StateDump.square")
+            .unwrap();
+        assert_eq!(memo["entries"], 1);
+        assert_eq!(memo["hits"], 1);
+        assert_eq!(memo["misses"], 1);
+    }
+}
@@ -0,0 +1,237 @@
+use crate::vm::class_loader::parse_signature;
+use crate::vm::classes::{AccessFlags, CodePtr, ConstantPoolValue, Method};
+use std::convert::TryInto;
+
+/// Call followed by return, found in bytecode of a method when its class is loaded
+#[derive(Clone, Debug)]
+struct TailSite {
+    pub next_pc: usize, //relative offset right after the call, where interpreter is when calling
+    pub method_ref: u16, //constant pool index of called method
+    pub return_code: u8, //return reached from the call
+}
+
+/// Calls reaching return through `goto` and `nop` only.
+/// Calls covered by exception handlers are skipped, as handlers would be lost with the frame
+fn tail_sites(code: &[u8], handlers: &[(usize, usize)]) -> Vec<TailSite> {
+    let mut sites = vec![];
+    let mut pc = 0;
+    while pc < code.len() {
+        let next_pc = pc + instruction_length(code, pc);
+        //invokevirtual, invokespecial, invokestatic, invokeinterface
+        if (182..=185).contains(&code[pc]) && !handlers.iter().any(|x| x.0 <= pc && pc < x.1) {
+            if let Some(return_code) = reached_return(code, next_pc) {
+                sites.push(TailSite {
+                    next_pc,
+                    method_ref: u16::from_be_bytes([code[pc + 1], code[pc + 2]]),
+                    return_code,
+                });
+            }
+        }
+        pc = next_pc;
+    }
+    sites
+}
+
+/// Absolute pcs after calls which can replace the frame of annotated `method`, sorted.
+/// `constant_pool` starts at the pool of method class
+pub(crate) fn tail_calls(
+    method: &Method,
+    code: &[u8],
+    constant_pool: &[ConstantPoolValue],
+) -> Vec<CodePtr> {
+    let handlers: Vec<_> = method
+        .exception_table
+        .iter()
+        .map(|x| (x.start_pc - method.code_ptr, x.end_pc - method.code_ptr))
+        .collect();
+    tail_sites(code, &handlers)
+        .iter()
+        .filter(|site| match &constant_pool[site.method_ref as usize] {
+            ConstantPoolValue::UnresolvedMethodRef {
+                method_name,
+                signature: descriptor,
+                ..
+            } => {
+                if method.flags.contains(AccessFlags::TAIL_CALL) {
+                    //result of callee is returned by caller as is, 177 is `return` of void
+                    descriptor.ends_with(")V") == (site.return_code == 177)
+                } else {
+                    *method_name == method.name && parse_signature(descriptor) == method.signature
+                }
+            }
+            _ => false,
+        })
+        .map(|site| method.code_ptr + site.next_pc)
+        .collect()
+}
+
+fn reached_return(code: &[u8], mut pc: usize) -> Option<u8> {
+    //loop of gotos never returns
+    for _ in 0..code.len() {
+        match code[pc] {
+            0 => pc += 1, //nop
+            167 => pc = jump(pc, i16::from_be_bytes([code[pc + 1], code[pc + 2]]) as i32),
+            200 => {
+                pc = jump(
+                    pc,
+                    i32::from_be_bytes(code[pc + 1..pc + 5].try_into().unwrap()),
+                )
+            }
+            op_code @ 172..=177 => return Some(op_code),
+            _ => return None,
+        }
+    }
+    None
+}
+
+fn jump(pc: usize, offset: i32) -> usize {
+    (pc as isize + offset as isize) as usize
+}
+
+pub(crate) fn instruction_length(code: &[u8], pc: usize) -> usize {
+    //switches are aligned to 4 bytes from method start
+    let padding = (4 - (pc + 1) % 4) % 4;
+    let int_at = |offset: usize| {
+        let at = pc + 1 + padding + offset;
+        i32::from_be_bytes(code[at..at + 4].try_into().unwrap())
+    };
+    match code[pc] {
+        16 | 18 | 21..=25 | 54..=58 | 169 | 188 => 2,
+        17 | 19 | 20 | 132 | 153..=168 | 178..=184 | 187 | 189 | 192 | 193 | 198 | 199 => 3,
+        197 => 4,
+        185 | 186 | 200 | 201 => 5,
+        170 => 1 + padding + 12 + (int_at(8) - int_at(4) + 1) as usize * 4, //tableswitch
+        171 => 1 + padding + 8 + int_at(4) as usize * 8,                    //lookupswitch
+        196 if code[pc + 1] == 132 => 6,                                    //wide iinc
+        196 => 4,
+        _ => 1,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{instruction_length, tail_sites};
+    use crate::vm::testing::run;
+
+    //(next pc, method ref, return) of sites
+    fn sites(code: &[u8], handlers: &[(usize, usize)]) -> Vec<(usize, u16, u8)> {
+        tail_sites(code, handlers)
+            .into_iter()
+            .map(|x| (x.next_pc, x.method_ref, x.return_code))
+            .collect()
+    }
+
+    #[test]
+    fn calls_reaching_return_through_gotos_are_sites() {
+        //invokestatic #5, goto over nop to nop, ireturn
+        let code = [184, 0, 5, 167, 0, 4, 0, 0, 172];
+        assert_eq!(sites(&code, &[]), [(3, 5, 172)]);
+        //invokevirtual #6, goto_w to return
+        let code = [182, 0, 6, 200, 0, 0, 0, 5, 177];
+        assert_eq!(sites(&code, &[]), [(3, 6, 177)]);
+        //invokeinterface #7 with count and zero, areturn
+        let code = [185, 0, 7, 1, 0, 176];
+        assert_eq!(sites(&code, &[]), [(5, 7, 176)]);
+    }
+
+    #[test]
+    fn calls_in_handlers_or_before_other_code_are_skipped() {
+        let code = [184, 0, 5, 172];
+        assert!(sites(&code, &[(0, 3)]).is_empty());
+        assert_eq!(sites(&code, &[(3, 4)]), [(3, 5, 172)]);
+        //result is used by iadd
+        let code = [184, 0, 5, 96, 172];
+        assert!(sites(&code, &[]).is_empty());
+        //goto to itself
+        let code = [184, 0, 5, 167, 0, 0];
+        assert!(sites(&code, &[]).is_empty());
+    }
+
+    #[test]
+    fn switches_are_padded_from_method_start() {
+        //tableswitch 0..=1 at pc 0 has 3 bytes of padding
+        let mut code = vec![170, 0, 0, 0];
+        code.extend([0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]);
+        code.extend([0; 8]);
+        assert_eq!(instruction_length(&code, 0), 24);
+        //lookupswitch with one pair at pc 1 has 2
+        let mut code = vec![0, 171, 0, 0];
+        code.extend([0, 0, 0, 0, 0, 0, 0, 1]);
+        code.extend([0; 8]);
+        assert_eq!(instruction_length(&code, 1), 19);
+        assert_eq!(instruction_length(&[196, 132, 0, 1, 0, 1], 0), 6);
+        assert_eq!(instruction_length(&[196, 21, 0, 1], 0), 4);
+    }
+
+    #[test]
+    fn frames_are_reused_by_tail_calls() {
+        let source = r#"
+            import io.github.rvm.RVM;
+
+            public class TailCalls {
+                int steps;
+
+                @RVM.TailCall
+                static boolean isEven(int n) {
+                    if (n == 0) {
+                        throw new IllegalStateException("even");
+                    }
+                    return isOdd(n - 1);
+                }
+
+                @RVM.TailCall
+                static boolean isOdd(int n) {
+                    return n == 0 ? false : isEven(n - 1);
+                }
+
+                @RVM.TailCall
+                int count(TailCalls other, int n) {
+                    steps++;
+                    if (n == 0) {
+                        throw new IllegalStateException("count " + steps + " " + other.steps);
+                    }
+                    return other.count(this, n - 1);
+                }
+
+                @RVM.TailCall
+                static int guarded(int n) {
+                    try {
+                        return fail(n);
+                    } catch (IllegalStateException e) {
+                        return -n;
+                    }
+                }
+
+                static int fail(int n) {
+                    throw new IllegalStateException();
+                }
+
+                @RVM.TailCall
+                static long sum(long total, int n) {
+                    return n == 0 ? total : sum(total + n, n - 1);
+                }
+
+                public static void main(String[] args) {
+                    System.out.println(sum(0, 1000000));
+                    System.out.println(guarded(4));
+                    try {
+                        new TailCalls().count(new TailCalls(), 5);
+                    } catch (IllegalStateException e) {
+                        System.out.println(e.getMessage());
+                    }
+                    isEven(8);
+                }
+            }
+        "#;
+        let (status, output) = run("tail-calls", &[("TailCalls", source)], &[]);
+        //stack trace has no frames replaced on the way to isEven(0)
+        assert_eq!(
+            output,
+            "500000500000\n-4\ncount 3 3\n\
+             Exception in thread \"main\" java.lang.IllegalStateException: even\n\
+             \tat TailCalls.isEven(TailCalls.java:10)\n\
+             \tat TailCalls.main(TailCalls.java:55)\n"
+        );
+        assert_eq!(status, 1);
+    }
+}
@@ -0,0 +1,85 @@
+//! Helpers for tests running java code. Sources are compiled by `javac --release 8`
+//! together with `io.github.rvm` classes from examples, as vm loads some of them itself
+use crate::vm::vm::VM;
+use std::cell::RefCell;
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::Command;
+use std::rc::Rc;
+
+/// Stream collecting what program prints
+#[derive(Clone, Default)]
+pub(crate) struct Output(Rc<RefCell<Vec<u8>>>);
+
+impl Output {
+    pub(crate) fn text(&self) -> String {
+        String::from_utf8_lossy(&self.0.borrow()).to_string()
+    }
+}
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Compiles `(class name, source)` pairs into a directory named after the test, returns the directory
+pub(crate) fn compile(test_name: &str, sources: &[(&str, &str)]) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("rjava-test-{}-{}", test_name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    let mut files = vec![];
+    for (class_name, source) in sources {
+        let file = dir.join(format!("{}.java", class_name));
+        std::fs::write(&file, source).unwrap();
+        files.push(file);
+    }
+    let rvm = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("examples/io/github/rvm");
+    for entry in std::fs::read_dir(rvm).unwrap() {
+        let file = entry.unwrap().path();
+        if file.extension().is_some_and(|x| x == "java") {
+            files.push(file);
+        }
+    }
+    let result = Command::new("javac")
+        .args(["--release", "8", "-nowarn", "-d"])
+        .arg(&dir)
+        .args(&files)
+        .output()
+        .expect("javac is needed to run tests");
+    assert!(
+        result.status.success(),
+        "javac failed: {}",
+        String::from_utf8_lossy(&result.stderr)
+    );
+    dir
+}
+
+/// VM with compiled classes on classpath, stdout and stderr are captured
+pub(crate) fn new_vm(test_name: &str, sources: &[(&str, &str)]) -> (VM, Output, Output) {
+    let dir = compile(test_name, sources);
+    let vm = VM::new(dir.to_str().unwrap());
+    let (out, err) = (Output::default(), Output::default());
+    vm.set_stdout(out.clone());
+    vm.set_stderr(err.clone());
+    (vm, out, err)
+}
+
+/// Runs `main` of the first class with given properties, returns exit status and output
+pub(crate) fn run(
+    test_name: &str,
+    sources: &[(&str, &str)],
+    properties: &[(&str, &str)],
+) -> (i32, String) {
+    let (mut vm, out, err) = new_vm(test_name, sources);
+    for (key, value) in properties {
+        vm.set_property(key, value);
+    }
+    let status = vm.start(sources[0].0);
+    (status, out.text() + &err.text())
+}
@@ -0,0 +1,281 @@
+use crate::vm::classes::{
+    AccessFlags, Annotation, AnnotationHandler, AnnotationValue, Class, Method,
+};
+use crate::vm::java_lang::{cut, throwable_to_string};
+use crate::vm::memory::{HeapObject, HeapPtr};
+use crate::vm::stack::{Type, Value};
+use crate::vm::vm::VM;
+use log::info;
+
+/// Methods traced as if annotated with `@RVM.Trace`: comma separated `method` or `pkg.Class.method`,
+/// `*` at the end matches by prefix (e.g. `com.example.*`)
+pub const TRACE_PROPERTY: &str = "rjava.trace";
+/// `true` to trace methods called by methods of `rjava.trace` too
+pub const TRACE_CALLEES_PROPERTY: &str = "rjava.trace.callees";
+
+const MAX_TEXT: usize = 40;
+
+/// Traced call in progress
+pub(crate) struct TraceEntry {
+    name: String,
+    start: i32, //tick
+}
+
+/// `@RVM.Trace`, with `callees = true` methods it calls are traced as well
+pub(crate) struct TraceAnnotation;
+
+impl AnnotationHandler for TraceAnnotation {
+    fn on_method(&self, _class: &Class, method: &mut Method, annotation: &Annotation) {
+        method.flags.insert(AccessFlags::TRACE);
+        if annotation.element("callees") == Some(&AnnotationValue::Int(1)) {
+            method.flags.insert(AccessFlags::TRACE_CALLEES);
+        }
+    }
+}
+
+impl VM {
+    /// Flags of method selected by `rjava.trace`
+    pub(crate) fn trace_flags(&self, class_name: &str, method_name: &str) -> AccessFlags {
+        let filter = self.get_property(TRACE_PROPERTY).unwrap_or_default();
+        let full_name = format!("{}.{}", class_name.replace('/', "."), method_name);
+        let selected = filter
+            .split(',')
+            .map(str::trim)
+            .filter(|x| !x.is_empty())
+            .any(|x| match x.strip_suffix('*') {
+                Some(prefix) => full_name.starts_with(prefix),
+                None => x == method_name || x == full_name,
+            });
+        match (
+            selected,
+            self.get_property(TRACE_CALLEES_PROPERTY).as_deref(),
+        ) {
+            (false, _) => AccessFlags::empty(),
+            (true, Some("true")) => AccessFlags::TRACE | AccessFlags::TRACE_CALLEES,
+            (true, _) => AccessFlags::TRACE,
+        }
+    }
+
+    /// Logs arguments (`this` included) of call whose frame is on top of the stack
+    pub(crate) fn trace_enter(&self, class_name: &str, method: &Method, args: &[Value]) {
+        let name = format!("{}.{}", class_name.replace('/', "."), method.name);
+        info!(
+            "{}-> {}({}) depth {}",
+            self.trace_indent(),
+            name,
+            self.trace_arguments(method, args),
+            self.stack.depth()
+        );
+        self.traces.borrow_mut().push(TraceEntry {
+            name,
+            start: self.tick(),
+        });
+    }
+
+    /// Logs call answered from `@RVM.Mem` cache, which takes no frame
+    pub(crate) fn trace_mem_hit(
+        &self,
+        class_name: &str,
+        method: &Method,
+        args: &[Value],
+        answer: &Value,
+    ) {
+        info!(
+            "{}-> {}.{}({}) = {} from mem",
+            self.trace_indent(),
+            class_name.replace('/', "."),
+            method.name,
+            self.trace_arguments(method, args),
+            self.trace_value(answer, &method.signature.return_type)
+        );
+    }
+
+    /// Logs return of the traced top frame, `None` for void methods
+    pub(crate) fn trace_return(&self, value: Option<&Value>) {
+        let entry = match self.traces.borrow_mut().pop() {
+            Some(entry) => entry,
+            None => return,
+        };
+        let (class_idx, method_idx) = self.stack.top_frame().class_method_idxs;
+        let return_type = self
+            .get_method(class_idx, method_idx)
+            .signature
+            .return_type
+            .clone();
+        let result = match value {
+            Some(value) => format!(" = {}", self.trace_value(value, &return_type)),
+            None => String::new(),
+        };
+        info!(
+            "{}<- {}{}, {} ticks",
+            self.trace_indent(),
+            entry.name,
+            result,
+            self.tick() - entry.start
+        );
+    }
+
+    /// Logs traced top frame left by exception
+    pub(crate) fn trace_throw(&self, exception: HeapPtr) {
+        let entry = self.traces.borrow_mut().pop();
+        if let Some(entry) = entry {
+            info!(
+                "{}<- {} threw {}, {} ticks",
+                self.trace_indent(),
+                entry.name,
+                throwable_to_string(self, exception),
+                self.tick() - entry.start
+            );
+        }
+    }
+
+    fn trace_indent(&self) -> String {
+        "  ".repeat(self.traces.borrow().len())
+    }
+
+    pub(crate) fn trace_arguments(&self, method: &Method, args: &[Value]) -> String {
+        //`this` has no declared type
+        let this = args.len() - method.signature.arguments.len();
+        let types = std::iter::repeat_n(&Type::Reference, this).chain(&method.signature.arguments);
+        args.iter()
+            .zip(types)
+            .map(|(value, value_type)| self.trace_value(value, value_type))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    //shown without calling `toString`, so tracing runs no java code
+    pub(crate) fn trace_value(&self, value: &Value, value_type: &Type) -> String {
+        match (value, value_type) {
+            (Value::Int(x), Type::Boolean) | (Value::Boolean(x), _) => (*x != 0).to_string(),
+            (Value::Int(x), Type::Char) => {
+                format!("'{}'", char::from_u32(*x as u32).unwrap_or('?'))
+            }
+            (Value::Char(x), _) => format!("'{}'", char::from_u32(*x as u32).unwrap_or('?')),
+            (Value::Reference(0), _) => "null".to_string(),
+            (Value::Reference(ptr), _) => match self.heap.get_object(*ptr) {
+                Some(HeapObject::String) => {
+                    format!("{:?}", cut(&self.heap.get_string(*ptr), MAX_TEXT))
+                }
+                Some(HeapObject::Array(element_type, _)) if element_type != Type::Reference => {
+                    let name = format!("{:?}", element_type).to_lowercase();
+                    format!("{}[{}]@{}", name, self.heap.array_length(*ptr), ptr)
+                }
+                _ => format!("{}@{}", self.heap_class_name(*ptr), ptr),
+            },
+            (Value::Byte(x), _) => x.to_string(),
+            (Value::Short(x), _) => x.to_string(),
+            (Value::Int(x), _) => x.to_string(),
+            (Value::Long(x), _) => x.to_string(),
+            (Value::Float(x), _) => x.to_string(),
+            (Value::Double(x), _) => x.to_string(),
+            (other, _) => format!("{:?}", other),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{TRACE_CALLEES_PROPERTY, TRACE_PROPERTY};
+    use crate::vm::classes::AccessFlags;
+    use crate::vm::stack::{Type, Value};
+    use crate::vm::testing::new_vm;
+    use crate::vm::vm::VM;
+
+    fn traced(filter: &str, class_name: &str, method_name: &str) -> AccessFlags {
+        let vm = VM::new(".");
+        vm.set_property(TRACE_PROPERTY, filter);
+        vm.trace_flags(class_name, method_name)
+    }
+
+    #[test]
+    fn filter_selects_by_name_full_name_and_prefix() {
+        assert_eq!(traced("", "com/example/A", "run"), AccessFlags::empty());
+        assert_eq!(traced("run", "com/example/A", "run"), AccessFlags::TRACE);
+        assert_eq!(
+            traced("other, com.example.A.run", "com/example/A", "run"),
+            AccessFlags::TRACE
+        );
+        assert_eq!(
+            traced("com.example.B.run", "com/example/A", "run"),
+            AccessFlags::empty()
+        );
+        assert_eq!(
+            traced("com.example.*", "com/example/A", "run"),
+            AccessFlags::TRACE
+        );
+        assert_eq!(
+            traced("com.example.A.r*", "com/example/A", "run"),
+            AccessFlags::TRACE
+        );
+        assert_eq!(
+            traced("com.other.*", "com/example/A", "run"),
+            AccessFlags::empty()
+        );
+        assert_eq!(traced("ru", "com/example/A", "run"), AccessFlags::empty());
+    }
+
+    #[test]
+    fn callees_are_traced_by_property() {
+        let vm = VM::new(".");
+        vm.set_property(TRACE_PROPERTY, "run");
+        vm.set_property(TRACE_CALLEES_PROPERTY, "true");
+        assert_eq!(
+            vm.trace_flags("A", "run"),
+            AccessFlags::TRACE | AccessFlags::TRACE_CALLEES
+        );
+        assert_eq!(vm.trace_flags("A", "stop"), AccessFlags::empty());
+    }
+
+    #[test]
+    fn annotated_methods_are_flagged() {
+        let source = r#"
+            import io.github.rvm.RVM;
+
+            public class Traced {
+                @RVM.Trace
+                static void alone() {}
+
+                @RVM.Trace(callees = true)
+                static void withCallees() {}
+
+                static void plain() {}
+            }
+        "#;
+        let (vm, _, _) = new_vm("trace-flags", &[("Traced", source)]);
+        let class_idx = vm.get_or_load_class_idx(&"Traced".to_string());
+        let class = vm.get_class(class_idx);
+        let flags = |name: &str| {
+            let method = class.methods.iter().find(|x| x.name == name).unwrap();
+            method.flags & (AccessFlags::TRACE | AccessFlags::TRACE_CALLEES)
+        };
+        assert_eq!(flags("alone"), AccessFlags::TRACE);
+        assert_eq!(
+            flags("withCallees"),
+            AccessFlags::TRACE | AccessFlags::TRACE_CALLEES
+        );
+        assert_eq!(flags("plain"), AccessFlags::empty());
+    }
+
+    #[test]
+    fn values_are_shown_by_declared_type() {
+        let vm = VM::new(".");
+        assert_eq!(vm.trace_value(&Value::Int(1), &Type::Boolean), "true");
+        assert_eq!(vm.trace_value(&Value::Int(65), &Type::Char), "'A'");
+        assert_eq!(vm.trace_value(&Value::Int(65), &Type::Int), "65");
+        assert_eq!(
+            vm.trace_value(&Value::Reference(0), &Type::Reference),
+            "null"
+        );
+        let text = vm.new_string(&"x".repeat(50));
+        assert_eq!(
+            vm.trace_value(&Value::Reference(text), &Type::Reference),
+            format!("\"{}...\"", "x".repeat(40))
+        );
+        let quoted = vm.new_string("say \"hi\"");
+        assert_eq!(
+            vm.trace_value(&Value::Reference(quoted), &Type::Reference),
+            "\"say \\\"hi\\\"\""
+        );
+    }
+}
@@ -1,12 +1,21 @@
+use crate::vm::auto_free::EscapePolicy;
 use crate::vm::class_loader::ClassLoader;
 use crate::vm::classes::{
-    Class, ClassIdx, CodePtr, ConstantPoolIdx, ConstantPoolValue, Method, MethodInClassIdx,
+    AnnotationHandler, Class, ClassIdx, CodePtr, ConstantPoolIdx, ConstantPoolValue, FieldIdx,
+    Method, MethodInClassIdx, NativeMethod,
 };
-use crate::vm::memory::{HeapMemory, HeapPtr};
+use crate::vm::java_io::StandardStreams;
+use crate::vm::java_lang::CLASS_INIT_METHOD;
+use crate::vm::java_lang::STRING_CLASS_IDX;
+use crate::vm::memory::{HeapMemory, HeapPtr, Layout, Relocation};
 use crate::vm::program::Program;
-use crate::vm::stack::{Stack, Value};
-use std::cell::{Ref, RefCell, RefMut};
-use std::time::SystemTime;
+use crate::vm::purity::SideEffectPolicy;
+use crate::vm::stack::{Stack, Type, Value};
+use crate::vm::trace::TraceEntry;
+use std::cell::{Cell, Ref, RefCell, RefMut};
+use std::collections::{HashMap, HashSet};
+use std::io::{Read, Write};
+use std::time::Instant;
 
 pub struct VM {
     pub stack: Stack,
@@ -14,22 +23,104 @@ pub struct VM {
 
     class_loader: ClassLoader,
     pub(crate) program: RefCell<Program>,
-    pub(crate) start_time: SystemTime,
+    pub(crate) start_time: Instant, //monotonic, unlike wall clock
+    pub(crate) native_methods: Vec<Box<dyn NativeMethod>>,
+    pub(crate) streams: StandardStreams,
+    properties: RefCell<HashMap<String, String>>,
+    exit_status: Cell<Option<i32>>,
+    pending_exception: Cell<HeapPtr>,
+    pub(crate) native_calls: Cell<usize>, //java methods invoked from natives, which are in progress
+    pub(crate) escape_policy: Cell<EscapePolicy>,
+    pub(crate) traces: RefCell<Vec<TraceEntry>>, //calls of traced frames, innermost last
+    pub(crate) side_effect_policy: Cell<SideEffectPolicy>,
+    pub(crate) pure_marks: RefCell<Vec<u64>>, //heap marks of running @RVM.Pure calls, innermost last
+    pub(crate) reported_side_effects: RefCell<HashSet<String>>,
+    //bytecode check results by method and its fresh arguments, reason for impure ones
+    pub(crate) purity: RefCell<HashMap<(ClassIdx, MethodInClassIdx, u64), Option<String>>>,
 }
 
 impl VM {
     pub fn new(classpath: &str) -> Self {
-        let vm = Self {
+        let mut vm = Self {
             class_loader: ClassLoader::new(classpath),
-            start_time: SystemTime::now(),
+            start_time: Instant::now(),
             stack: Stack::new(),
             heap: HeapMemory::new(),
             program: RefCell::new(Program::default()),
+            native_methods: vec![],
+            streams: Default::default(),
+            properties: RefCell::new(default_properties(classpath)),
+            exit_status: Cell::new(None),
+            pending_exception: Cell::new(0),
+            native_calls: Cell::new(0),
+            escape_policy: Cell::new(EscapePolicy::Ignore),
+            traces: RefCell::new(vec![]),
+            side_effect_policy: Cell::new(SideEffectPolicy::Ignore),
+            pure_marks: RefCell::new(vec![]),
+            reported_side_effects: RefCell::new(HashSet::new()),
+            purity: RefCell::new(HashMap::new()),
         };
         vm.program.borrow_mut().init();
+        //natives may change program (e.g. load classes), so they live outside of it
+        vm.native_methods = std::mem::take(&mut vm.program.borrow_mut().native_methods);
         vm
     }
 
+    /// Adds behaviour of annotation given by descriptor, e.g. `Lcom/example/Log;`, to classes loaded later.
+    /// Replaces handler registered for it before, including built-in `@RVM.*` ones
+    pub fn register_annotation(&self, descriptor: &str, handler: impl AnnotationHandler + 'static) {
+        self.program
+            .borrow_mut()
+            .annotation_handlers
+            .insert(descriptor.to_string(), Box::new(handler));
+    }
+
+    /// Replaces stream behind `System.out` (and `RVM.print`)
+    pub fn set_stdout(&self, out: impl Write + 'static) {
+        self.streams.flush();
+        *self.streams.stdout.borrow_mut() = Box::new(out);
+    }
+
+    /// Replaces stream behind `System.err`
+    pub fn set_stderr(&self, err: impl Write + 'static) {
+        self.streams.flush();
+        *self.streams.stderr.borrow_mut() = Box::new(err);
+    }
+
+    /// Replaces stream behind `System.in`
+    pub fn set_stdin(&self, input: impl Read + 'static) {
+        *self.streams.stdin.borrow_mut() = Box::new(input);
+    }
+
+    pub fn set_property(&self, key: &str, value: &str) {
+        self.properties
+            .borrow_mut()
+            .insert(key.to_string(), value.to_string());
+    }
+
+    pub fn get_property(&self, key: &str) -> Option<String> {
+        self.properties.borrow().get(key).cloned()
+    }
+
+    /// Requests VM to stop, as `System.exit` does
+    pub fn exit(&self, status: i32) {
+        self.exit_status.set(Some(status));
+    }
+
+    pub fn exit_status(&self) -> Option<i32> {
+        self.exit_status.get()
+    }
+
+    /// Milliseconds since VM start, as returned by `RVM.tick`
+    pub fn tick(&self) -> i32 {
+        self.start_time.elapsed().as_millis() as i32
+    }
+
+    /// Nanoseconds since VM start, as returned by `System.nanoTime`
+    pub fn nano_time(&self) -> i64 {
+        self.start_time.elapsed().as_nanos() as i64
+    }
+
     pub fn get_or_load_class_idx(&self, name: &String) -> ClassIdx {
         let mut program_mut = self.program.borrow_mut();
         if let Some(idx) = program_mut.class_names_to_idxs.get(name) {
@@ -40,34 +131,161 @@ impl VM {
         }
     }
 
-    pub fn get_or_load_class(&self, name: &str) -> Ref<Class> {
+    /// Class is loaded or could be loaded from classpath
+    pub(crate) fn class_exists(&self, name: &str) -> bool {
+        self.program.borrow().class_names_to_idxs.contains_key(name)
+            || self.class_loader.class_exists(name)
+    }
+
+    pub fn get_or_load_class(&self, name: &str) -> Ref<'_, Class> {
         let idx = self.get_or_load_class_idx(&name.to_string());
         self.get_class(idx)
     }
 
-    pub fn get_class(&self, idx: ClassIdx) -> Ref<Class> {
+    pub fn get_class(&self, idx: ClassIdx) -> Ref<'_, Class> {
         let program_ref = self.program.borrow();
-        return Ref::map(program_ref, |x| &x.classes[idx]);
+        Ref::map(program_ref, |x| &x.classes[idx])
     }
 
-    pub fn get_method_mut(&self, idx: ClassIdx, method_idx: MethodInClassIdx) -> RefMut<Method> {
+    pub fn get_method_mut(
+        &self,
+        idx: ClassIdx,
+        method_idx: MethodInClassIdx,
+    ) -> RefMut<'_, Method> {
         let program_ref = self.program.borrow_mut();
-        return RefMut::map(program_ref, |x| &mut x.classes[idx].methods[method_idx]);
+        RefMut::map(program_ref, |x| &mut x.classes[idx].methods[method_idx])
     }
 
-    pub fn get_method(&self, idx: ClassIdx, method_idx: MethodInClassIdx) -> Ref<Method> {
+    pub fn get_method(&self, idx: ClassIdx, method_idx: MethodInClassIdx) -> Ref<'_, Method> {
         let program_ref = self.program.borrow();
-        return Ref::map(program_ref, |x| &x.classes[idx].methods[method_idx]);
+        Ref::map(program_ref, |x| &x.classes[idx].methods[method_idx])
     }
 
-    pub fn new_object(&self, class_idx: ClassIdx) -> HeapPtr {
+    /// Checks that `class_idx` is `target_idx` or one of its subclasses
+    pub fn is_subclass_of(&self, class_idx: ClassIdx, target_idx: ClassIdx) -> bool {
         let program = self.program.borrow();
-        let class = &program.classes[class_idx];
-        let obj_ptr = self.heap.new_object(class_idx, class.fields.len() as u16);
-        for field in &class.fields {
-            self.heap.new_object_field(field.value_type.default_value())
+        let mut idx = class_idx;
+        loop {
+            if idx == target_idx {
+                return true;
+            }
+            if idx == 0 {
+                return false;
+            }
+            idx = program.classes[idx].super_class_idx;
+        }
+    }
+
+    /// Checks `instanceof`: class or interface is given by name, so it is not required to be loaded
+    pub fn is_instance_of(&self, class_idx: ClassIdx, target_name: &str) -> bool {
+        let program = self.program.borrow();
+        let mut pending = vec![];
+        let mut idx = class_idx;
+        loop {
+            let class = &program.classes[idx];
+            if class.name == target_name {
+                return true;
+            }
+            pending.extend(class.interfaces.iter().cloned());
+            if idx == 0 {
+                break;
+            }
+            idx = class.super_class_idx;
+        }
+        //interfaces and their superinterfaces, if loaded
+        while let Some(interface) = pending.pop() {
+            if interface == target_name {
+                return true;
+            }
+            if let Some(idx) = program.class_names_to_idxs.get(&interface) {
+                pending.extend(program.classes[*idx].interfaces.iter().cloned());
+            }
+        }
+        false
+    }
+
+    /// Raises java exception. It is thrown as soon as control returns to interpreter,
+    /// so natives should return right after this call.
+    pub fn throw(&self, exception: HeapPtr) {
+        self.pending_exception.set(exception);
+    }
+
+    pub(crate) fn pending_exception(&self) -> HeapPtr {
+        self.pending_exception.get()
+    }
+
+    pub(crate) fn relocate_pending_exception(&self, relocation: &Relocation) {
+        self.pending_exception
+            .set(relocation.forward(self.pending_exception.get()));
+    }
+
+    pub(crate) fn take_pending_exception(&self) -> Option<HeapPtr> {
+        match self.pending_exception.replace(0) {
+            0 => None,
+            exception => Some(exception),
+        }
+    }
+
+    pub fn get_static(&self, class_idx: ClassIdx, field_idx: FieldIdx) -> Value {
+        self.program.borrow().classes[class_idx].static_values[field_idx].clone()
+    }
+
+    /// Statics are roots, so stored object is never freed by AutoFree
+    pub fn set_static(&self, class_idx: ClassIdx, field_idx: FieldIdx, value: Value) {
+        if let Value::Reference(ptr) = value {
+            self.heap.promote(ptr);
+        }
+        self.program.borrow_mut().classes[class_idx].static_values[field_idx] = value
+    }
+
+    /// Finds next `<clinit>` to call before class could be used. Superclasses are initialized first.
+    /// Classes are marked as initialized as soon as their initializer is returned.
+    pub(crate) fn next_class_initializer(
+        &self,
+        class_idx: ClassIdx,
+    ) -> Option<(ClassIdx, MethodInClassIdx)> {
+        let mut program = self.program.borrow_mut();
+        let mut hierarchy = vec![class_idx];
+        let mut idx = class_idx;
+        while idx != 0 {
+            idx = program.classes[idx].super_class_idx;
+            hierarchy.push(idx);
+        }
+        for idx in hierarchy.into_iter().rev() {
+            if program.classes[idx].initialized {
+                continue;
+            }
+            program.classes[idx].initialized = true;
+            let clinit = program.method_names_to_idxs.get(&(
+                idx,
+                CLASS_INIT_METHOD.to_string(),
+                "()V".to_string(),
+            ));
+            if let Some(method_idx) = clinit {
+                return Some((idx, *method_idx));
+            }
+        }
+        None
+    }
+
+    pub fn new_object(&self, class_idx: ClassIdx) -> HeapPtr {
+        self.define_layout(class_idx);
+        self.heap.new_object(class_idx) //zeroed fields are default values
+    }
+
+    /// Bytes taken by instance of the class
+    pub fn instance_size(&self, class_idx: ClassIdx) -> usize {
+        self.define_layout(class_idx);
+        self.heap.object_size(class_idx)
+    }
+
+    //layout is made on first use, as class fields are known only after loading
+    fn define_layout(&self, class_idx: ClassIdx) {
+        if !self.heap.has_layout(class_idx) {
+            let class = self.get_class(class_idx);
+            let types: Vec<Type> = class.fields.iter().map(|x| x.value_type.clone()).collect();
+            self.heap.set_layout(class_idx, Layout::new(&types));
         }
-        return obj_ptr;
     }
 
     pub fn new_object_array(&self, class_idx: ClassIdx, length: i32) -> HeapPtr {
@@ -80,10 +298,8 @@ impl VM {
         arr_ptr
     }
 
-    pub fn new_string(&self, string_value: &String) -> HeapPtr {
-        let obj_ptr = self.heap.new_object(1, 1);
-        self.heap.append(string_value);
-        return obj_ptr;
+    pub fn new_string(&self, string_value: &str) -> HeapPtr {
+        self.heap.new_string(STRING_CLASS_IDX, string_value)
     }
 
     pub fn code_read_u8(&self, code_ptr: CodePtr) -> u8 {
@@ -98,3 +314,31 @@ impl VM {
         self.program.borrow_mut().constant_pool[cpi] = value
     }
 }
+
+fn default_properties(classpath: &str) -> HashMap<String, String> {
+    let line_separator = if cfg!(windows) { "\r\n" } else { "\n" };
+    let file_separator = if cfg!(windows) { "\\" } else { "/" };
+    let path_separator = if cfg!(windows) { ";" } else { ":" };
+    let user_dir = std::env::current_dir()
+        .map(|dir| dir.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let user_home = std::env::var("HOME")
+        .or_else(|_| std::env::var("USERPROFILE"))
+        .unwrap_or_default();
+    [
+        ("java.version", "1.8"),
+        ("java.vendor", "rjava"),
+        ("java.vm.name", "RJAVA"),
+        ("java.class.path", classpath),
+        ("os.name", std::env::consts::OS),
+        ("os.arch", std::env::consts::ARCH),
+        ("file.separator", file_separator),
+        ("path.separator", path_separator),
+        ("line.separator", line_separator),
+        ("user.dir", &user_dir),
+        ("user.home", &user_home),
+    ]
+    .iter()
+    .map(|(key, value)| (key.to_string(), value.to_string()))
+    .collect()
+}